@@ -0,0 +1,45 @@
+//! Example PAM module built with the `pam` crate
+//!
+//! The module accepts the hard-coded password `hunter2` for every user and asks
+//! for the user name first if the application did not pass one. It understands
+//! the standard options like `use_first_pass`, see `StandardOptions`.
+//!
+//! Never use this module on a real system!
+
+use std::ffi::CStr;
+
+use pam::module::{ExposeSecret, ModuleResult, ModuleSuccess, StandardOptions};
+use pam::{
+    export_pam_module, get_user, AuthFlags, PamError, PamHandle, PamModule, PamReturnCode,
+    SetCredFlags,
+};
+
+/// The only password accepted by this module
+pub const PASSWORD: &str = "hunter2";
+
+pub struct ExampleModule;
+
+impl PamModule for ExampleModule {
+    fn authenticate(handle: &mut PamHandle, args: Vec<&CStr>, _flags: AuthFlags) -> ModuleResult {
+        let opts = StandardOptions::parse(&args);
+
+        // Prompts via the conversation function if `PAM_USER` is not set yet
+        get_user(handle, None)?;
+        let token = opts.obtain_authtok(handle, None)?;
+        if token.expose_secret() == PASSWORD {
+            Ok(ModuleSuccess::Success)
+        } else {
+            Err(PamError::Pam(PamReturnCode::Auth_Err))
+        }
+    }
+
+    fn set_credentials(
+        _handle: &mut PamHandle,
+        _args: Vec<&CStr>,
+        _flags: SetCredFlags,
+    ) -> ModuleResult {
+        Ok(ModuleSuccess::Success)
+    }
+}
+
+export_pam_module!(ExampleModule);
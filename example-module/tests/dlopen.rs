@@ -0,0 +1,146 @@
+//! Load the example module like libpam does and call into its entry points
+//!
+//! `authenticate_with_handle` is ignored by default as it needs pam_wrapper, run it with
+//! `LD_PRELOAD=libpam_wrapper.so cargo test -- --ignored`.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::{env, fs, process};
+
+use pam::{Client, PamHandle, PamReturnCode, SequenceConv};
+use pam_example_module::PASSWORD;
+
+type SmFn = unsafe extern "C" fn(*mut c_void, c_uint, c_int, *const *const c_char) -> c_int;
+
+const SERVICE_NAME: &str = "pam_rs_dlopen_test";
+
+const ENTRY_POINTS: [&str; 6] = [
+    "pam_sm_acct_mgmt",
+    "pam_sm_authenticate",
+    "pam_sm_chauthtok",
+    "pam_sm_close_session",
+    "pam_sm_open_session",
+    "pam_sm_setcred",
+];
+
+struct Module(*mut c_void);
+
+impl Module {
+    fn open() -> Module {
+        // Cargo places the cdylib in the `deps` directory next to this test
+        let mut path = env::current_exe().unwrap();
+        path.set_file_name("libpam_example_module.so");
+
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let lib = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        assert!(!lib.is_null(), "dlopen failed: {}", dlerror());
+        Module(lib)
+    }
+
+    fn entry_point(&self, name: &str) -> SmFn {
+        let name = CString::new(name).unwrap();
+        let sym = unsafe { libc::dlsym(self.0, name.as_ptr()) };
+        assert!(!sym.is_null(), "dlsym failed: {}", dlerror());
+        unsafe { std::mem::transmute::<*mut c_void, SmFn>(sym) }
+    }
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.0) };
+    }
+}
+
+// Service directory for pam_wrapper, removed on drop. The entry points are called
+// directly, so the stack only has to be valid for `pam_start`.
+struct Service(PathBuf);
+
+impl Service {
+    fn create() -> Service {
+        let dir = env::temp_dir().join(format!("pam-rs-dlopen-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(SERVICE_NAME), "auth required pam_permit.so\n").unwrap();
+
+        assert!(
+            env::var("LD_PRELOAD")
+                .unwrap_or_default()
+                .contains("pam_wrapper"),
+            "This test requires LD_PRELOAD=libpam_wrapper.so"
+        );
+        env::set_var("PAM_WRAPPER", "1");
+        env::set_var("PAM_WRAPPER_SERVICE_DIR", &dir);
+        Service(dir)
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        env::remove_var("PAM_WRAPPER_SERVICE_DIR");
+        env::remove_var("PAM_WRAPPER");
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn dlerror() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(err) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+// Calls `sm` with a mock handle which must not be passed to libpam
+fn call(sm: SmFn, args: &[&str]) -> PamReturnCode {
+    // The opaque handle is zero-sized, so a dangling pointer is a valid reference
+    call_with_handle(sm, NonNull::<c_void>::dangling().as_ptr(), args)
+}
+
+fn call_with_handle(sm: SmFn, handle: *mut c_void, args: &[&str]) -> PamReturnCode {
+    let args: Vec<_> = args.iter().map(|a| CString::new(*a).unwrap()).collect();
+    let argv: Vec<_> = args.iter().map(|a| a.as_ptr()).collect();
+    unsafe { sm(handle, 0, argv.len() as c_int, argv.as_ptr()) }.into()
+}
+
+#[test]
+fn entry_points_are_exported() {
+    let module = Module::open();
+    for name in &ENTRY_POINTS {
+        module.entry_point(name);
+    }
+}
+
+#[test]
+fn entry_points_return_codes() {
+    let module = Module::open();
+
+    let setcred = module.entry_point("pam_sm_setcred");
+    assert_eq!(call(setcred, &[]), PamReturnCode::Success);
+
+    // Not implemented by the module
+    let open_session = module.entry_point("pam_sm_open_session");
+    assert_eq!(call(open_session, &["debug"]), PamReturnCode::Ignore);
+}
+
+#[test]
+#[ignore]
+fn authenticate_with_handle() {
+    let _service = Service::create();
+    let module = Module::open();
+    let authenticate = module.entry_point("pam_sm_authenticate");
+
+    // The module asks for the user and the password via the conversation of the client
+    let run = |password: &str| {
+        let conv = SequenceConv::new("alice", vec![password]);
+        let mut client =
+            Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+        let handle = unsafe { client.handle_mut() } as *mut PamHandle as *mut c_void;
+        call_with_handle(authenticate, handle, &[])
+    };
+    assert_eq!(run(PASSWORD), PamReturnCode::Success);
+    assert_eq!(run("wrong"), PamReturnCode::Auth_Err);
+}
@@ -0,0 +1,52 @@
+use std::io::{stdin, stdout, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use pam::{ChannelConv, Client, ConvError, PamMessageStyle};
+use rpassword::prompt_password;
+
+// A program which authenticates on a worker thread while the main thread interacts with the
+// user, like a GUI login manager would do from its UI thread.
+
+fn main() {
+    let (sender, requests) = mpsc::channel();
+
+    let worker = thread::spawn(move || {
+        // Give up if the user does not answer within a minute
+        let conv = ChannelConv::new(sender).with_timeout(Duration::from_secs(60));
+        let mut client = Client::with_conversation("system-auth", conv)?;
+        client.authenticate()
+    });
+
+    // Answer the requests until the worker is done and drops the conversation
+    for request in requests {
+        let answer = match request.style {
+            PamMessageStyle::Text_Info | PamMessageStyle::Error_Msg => {
+                println!("{}", request.text);
+                continue;
+            }
+            PamMessageStyle::Prompt_Echo_Off => {
+                prompt_password(&request.text).map_err(|_| ConvError)
+            }
+            _ => {
+                print!("{}", request.text);
+                stdout().flush().unwrap();
+                let mut line = String::new();
+                match stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => Err(ConvError),
+                    Ok(_) => Ok(line.trim_end_matches('\n').to_string()),
+                }
+            }
+        };
+        // The worker might have timed out meanwhile
+        if request.reply.send(answer).is_err() {
+            println!("Too late!");
+        }
+    }
+
+    match worker.join().expect("Worker panicked!") {
+        Ok(()) => println!("Authenticated!"),
+        Err(err) => println!("Authentication failed: {:?}", err),
+    }
+}
@@ -26,7 +26,12 @@ fn main() {
     client
         .conversation_mut()
         .set_credentials(login.clone(), password);
-    client.authenticate().expect("Authentication failed!");
+    let result = client.authenticate();
+    // Show messages like "Password expires in 3 days" sent by the PAM modules
+    for (_, msg) in client.conversation_mut().take_messages() {
+        println!("{}", msg);
+    }
+    result.expect("Authentication failed!");
     client.open_session().expect("Failed to open a session!");
 
     // we now try to spawn `/bin/bash` as this user
@@ -1,7 +1,23 @@
 //! Authentication related structure and functions
-use std::{env, ffi::CStr, os::raw::c_char};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::{
+    any::Any,
+    env,
+    ffi::CStr,
+    mem::ManuallyDrop,
+    os::raw::{c_char, c_int},
+    panic,
+    ptr::{self, NonNull},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::{conv, enums::*, functions::*, types::*};
+use crate::{conv, enums::*, env::EnvFilter, flags::*, functions::*, types::*};
+
+// Reexport secrecy types so clients don't need to depend on it
+pub use secrecy::{ExposeSecret, SecretString};
 
 /// Main struct to authenticate a user
 ///
@@ -22,187 +38,2742 @@ use crate::{conv, enums::*, functions::*, types::*};
 /// client.open_session().expect("Failed to open a session!");
 /// ```
 ///
+/// If your PAM stack asks for more than a password, e.g. a verification code for two-factor
+/// authentication, you can pre-seed all answers with a `SequenceConv`:
+///
+/// ```no_run
+/// use pam::{Client, SequenceConv};
+///
+/// let conv = SequenceConv::new("login", vec!["password", "123456"]);
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+///
 /// If you wish to customise the PAM conversation function, you should rather create your
 /// client with `Client::with_handler`, providing a struct implementing the
 /// `conv::Conversation` trait. You can then mutably access your conversation handler using the
 /// `Client::handler_mut` method.
 ///
+/// To set items like the remote host or other options right away, use a `ClientBuilder`.
+///
+/// A `Client` can be moved to another thread if its conversation handler can, e.g. to
+/// finish the transaction on a worker thread. The methods calling into libpam take
+/// `&mut self`, as a transaction must not be used from multiple threads at once.
+///
 /// By default, the `Client` will close any opened session when dropped. If you don't
 /// want this, you can change its `close_on_drop` field to `False`.
-pub struct Client<'a, C: conv::Conversation> {
+pub struct Client<C: conv::Conversation> {
     /// Flag indicating whether the Client should close the session on drop
     pub close_on_drop: bool,
-    handle: &'a mut PamHandle,
-    conversation: Box<C>,
+    // Leaked instead of dropped if a PAM call timed out, see `set_call_timeout`
+    conversation: ManuallyDrop<Box<C>>,
+    state: ClientState,
+}
+
+// The state of a `Client` which does not depend on the conversation handler, so
+// `replace_conversation` can move it to the new client as a whole
+struct ClientState {
+    end_on_drop: bool,
+    handle: NonNull<PamHandle>,
+    service: String,
+    requested_user: Option<String>,
     is_authenticated: bool,
+    has_valid_account: bool,
+    established_credentials: usize,
     has_open_session: bool,
+    sessions_opened: u32,
+    stage_codes: [Option<PamReturnCode>; 6],
     last_code: PamReturnCode,
+    last_error: Option<(PamOperation, PamError)>,
+    end_status: Option<PamReturnCode>,
+    conv_error: Option<conv::ConvError>,
+    silent: bool,
+    disallow_null_authtok: bool,
+    handle_expired_password: bool,
+    auto_clear_credentials: bool,
+    retry_unknown_user: bool,
+    setcred_failure_policy: SetCredFailurePolicy,
+    setcred_failure: Option<PamReturnCode>,
+    credential_sequence: CredentialSequence,
+    autodetect_tty: bool,
+    default_tty: Option<String>,
+    fail_delay: Option<Duration>,
+    #[cfg(feature = "watchdog")]
+    call_timeout: Option<Duration>,
+    // Set if a PAM call timed out, see `set_call_timeout`, or by `into_raw`
+    abandoned: bool,
+    environment_policy: EnvironmentPolicy,
+    environment_hook: Option<Box<EnvironmentHook>>,
+    env_filter: EnvFilter,
+    // Variables set or removed via `pam_putenv` and `pam_remove_env`
+    explicit_env: HashSet<String>,
+    lifecycle_hooks: LifecycleHooks,
+    #[cfg(feature = "utmp")]
+    utmp_warning: Option<Box<UtmpWarning>>,
 }
 
-impl<'a> Client<'a, conv::PasswordConv> {
-    /// Create a new `Client` with the given service name and a password-based conversation
-    pub fn with_password(service: &str) -> PamResult<Client<'a, conv::PasswordConv>> {
-        Client::with_conversation(service, conv::PasswordConv::new())
-    }
-}
-
-impl<'a, C: conv::Conversation> Client<'a, C> {
-    /// Create a new `Client` with the given service name and conversation handler
-    pub fn with_conversation(service: &str, conversation: C) -> PamResult<Client<'a, C>> {
-        let mut conversation = Box::new(conversation);
-        let conv = conv::into_pam_conv(&mut *conversation);
-
-        let handle = start(service, None, &conv)?;
-        Ok(Client {
-            close_on_drop: true,
+impl ClientState {
+    // The state of a transaction nothing happened on yet, with the default options
+    fn new(handle: NonNull<PamHandle>, service: String) -> ClientState {
+        ClientState {
+            end_on_drop: true,
             handle,
-            conversation,
+            service,
+            requested_user: None,
             is_authenticated: false,
+            has_valid_account: false,
+            established_credentials: 0,
             has_open_session: false,
+            sessions_opened: 0,
+            stage_codes: [None; 6],
             last_code: PamReturnCode::Success,
-        })
+            last_error: None,
+            end_status: None,
+            conv_error: None,
+            silent: false,
+            disallow_null_authtok: false,
+            handle_expired_password: false,
+            auto_clear_credentials: false,
+            retry_unknown_user: false,
+            setcred_failure_policy: SetCredFailurePolicy::default(),
+            setcred_failure: None,
+            credential_sequence: CredentialSequence::default(),
+            autodetect_tty: false,
+            default_tty: None,
+            fail_delay: None,
+            #[cfg(feature = "watchdog")]
+            call_timeout: None,
+            abandoned: false,
+            environment_policy: EnvironmentPolicy::default(),
+            environment_hook: None,
+            env_filter: EnvFilter::default(),
+            explicit_env: HashSet::new(),
+            lifecycle_hooks: LifecycleHooks::default(),
+            #[cfg(feature = "utmp")]
+            utmp_warning: None,
+        }
     }
+}
 
-    /// Immutable access to the conversation handler of this Client
-    pub fn conversation(&self) -> &C {
-        &*self.conversation
+type EnvironmentHook = dyn FnMut(&str) -> Vec<(String, String)> + Send;
+
+type AuthResultHook = dyn FnMut(&str, PamReturnCode) + Send;
+type SessionOpenedHook = dyn FnMut(&TransactionInfo) + Send;
+type SessionClosedHook = dyn FnMut(PamReturnCode) + Send;
+type DropErrorHook = dyn FnMut(DropStage, PamReturnCode) + Send;
+
+// The hook set via `ClientBuilder::on_drop_error`, shared by the clients built from clones
+#[derive(Clone)]
+struct SharedDropErrorHook(Arc<dyn Fn(DropStage, PamReturnCode) + Send + Sync>);
+
+impl std::fmt::Debug for SharedDropErrorHook {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str("SharedDropErrorHook")
     }
+}
 
-    /// Mutable access to the conversation handler of this Client
-    pub fn conversation_mut(&mut self) -> &mut C {
-        &mut *self.conversation
+// The hooks set via `Client::on_auth_result` and its siblings
+#[derive(Default)]
+struct LifecycleHooks {
+    auth_result: Option<Box<AuthResultHook>>,
+    session_opened: Option<Box<SessionOpenedHook>>,
+    session_closed: Option<Box<SessionClosedHook>>,
+    drop_error: Option<Box<DropErrorHook>>,
+}
+#[cfg(feature = "utmp")]
+type UtmpWarning = dyn FnMut(&std::io::Error) + Send;
+
+/// Where `Client::open_session` sets the environment variables of the user
+///
+/// These are USER, LOGNAME, HOME, PWD and SHELL unless the modules set them, plus the
+/// variables returned by the hook set with `Client::set_environment_hook`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnvironmentPolicy {
+    /// Don't set any variables
+    None,
+    /// Set the variables in the PAM environment only, see `Client::environment`
+    PamOnly,
+    /// Set the variables in the PAM environment and import the whole PAM environment,
+    /// including the variables set by the modules, into this process
+    #[default]
+    PamAndProcess,
+}
+
+// SAFETY: libpam allows to use a handle from any thread as long as it is not used
+// concurrently, which `&mut self` on all methods using it ensures. The conversation
+// handler lives on the heap and is only called from the thread calling into libpam,
+// or from a helper thread while that thread waits, see `Client::set_call_timeout`.
+// The thread-local state of the conversation trampoline is reset after each call.
+unsafe impl<C: conv::Conversation + Send> Send for Client<C> {}
+
+/// A `Client` with a boxed conversation handler, so its type doesn't depend on it
+///
+/// It supports all methods of `Client` and can be stored e.g. in the state of an
+/// application which keeps the authentication in progress between UI events, see
+/// `ClientBuilder::build_dyn`:
+///
+/// ```no_run
+/// use pam::{ClientBuilder, DynClient, SequenceConv};
+///
+/// #[derive(Default)]
+/// struct Greeter {
+///     // The authentication in progress, whichever handler it uses
+///     pending: Option<DynClient>,
+/// }
+///
+/// impl Greeter {
+///     fn on_login_submitted(&mut self, user: &str, password: &str) {
+///         let conv = SequenceConv::new(user, vec![password]);
+///         let client = ClientBuilder::new("login")
+///             .user(user)
+///             .build_dyn(conv)
+///             .expect("Failed to init PAM client.");
+///         self.pending = Some(client);
+///     }
+///
+///     fn on_confirm(&mut self) -> bool {
+///         match self.pending.as_mut() {
+///             Some(client) => client.authenticate().is_ok(),
+///             None => false,
+///         }
+///     }
+///
+///     fn on_cancel(&mut self) {
+///         // Dropping the client ends the transaction
+///         self.pending = None;
+///     }
+/// }
+/// ```
+pub type DynClient = Client<Box<dyn conv::Conversation + Send>>;
+
+/// The PAM function called by a `Client`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PamOperation {
+    /// `pam_authenticate`
+    Authenticate,
+    /// `pam_acct_mgmt`
+    AcctMgmt,
+    /// `pam_setcred`
+    SetCred,
+    /// `pam_open_session`
+    OpenSession,
+    /// `pam_close_session`
+    CloseSession,
+    /// `pam_chauthtok`
+    Chauthtok,
+}
+
+/// What `Client::open_session` does if `pam_setcred` fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetCredFailurePolicy {
+    /// Fail and reset the authentication state
+    #[default]
+    Fail,
+    /// Record the error, see `Client::setcred_failure`, and open the session anyway
+    WarnAndContinue,
+}
+
+/// The cleanup step which failed when dropping a `Client`, see `Client::on_drop_error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropStage {
+    /// `pam_close_session` for a session which was still open
+    CloseSession,
+    /// `pam_setcred` deleting the credentials
+    DeleteCredentials,
+    /// `pam_end`
+    End,
+}
+
+/// Whether `Client::authenticate_with_retries` continues after a failed attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Run `authenticate` again
+    Retry,
+    /// Return the error of the failed attempt
+    Abort,
+}
+
+/// The `pam_setcred` calls around `pam_open_session`, see
+/// `Client::set_credential_sequence`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CredentialSequence {
+    /// Establish the credentials before and reinitialize them after opening the
+    /// session, like OpenSSH
+    #[default]
+    Full,
+    /// Only establish the credentials before opening the session
+    EstablishOnly,
+    /// Only establish the credentials after opening the session, like util-linux
+    /// `login`
+    AfterSession,
+    /// Don't call `pam_setcred`, e.g. like `machinectl shell`
+    None,
+    /// Run the given actions before and after opening the session
+    ///
+    /// `Reinitialize` counts as establishing the credentials if none were established
+    /// yet, e.g. for stacks which only accept it.
+    Custom {
+        /// The actions before `pam_open_session`
+        before: Vec<CredAction>,
+        /// The actions after `pam_open_session`
+        after: Vec<CredAction>,
+    },
+}
+
+impl CredentialSequence {
+    // The actions before `pam_open_session`
+    fn before_session(&self) -> &[CredAction] {
+        match self {
+            CredentialSequence::Full | CredentialSequence::EstablishOnly => {
+                &[CredAction::Establish]
+            }
+            CredentialSequence::AfterSession | CredentialSequence::None => &[],
+            CredentialSequence::Custom { before, .. } => before,
+        }
     }
 
-    /// Perform authentication with the provided credentials
-    pub fn authenticate(&mut self) -> PamResult<()> {
-        self.last_code = authenticate(self.handle, PamFlag::None);
-        if self.last_code != PamReturnCode::Success {
-            // No need to reset here
-            return Err(From::from(self.last_code));
+    // The actions after `pam_open_session`
+    fn after_session(&self) -> &[CredAction] {
+        match self {
+            CredentialSequence::Full => &[CredAction::Reinitialize],
+            CredentialSequence::AfterSession => &[CredAction::Establish],
+            CredentialSequence::EstablishOnly | CredentialSequence::None => &[],
+            CredentialSequence::Custom { after, .. } => after,
         }
+    }
+}
 
-        self.is_authenticated = true;
+/// What a transaction is about, see `Client::info`
+///
+/// It is displayed on a single line for logs, e.g.
+/// `service="sshd" user="alice" requested_user="alice" rhost="10.0.0.5" tty="ssh" ruser=-`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionInfo {
+    /// The service name passed to `pam_start`
+    pub service: String,
+    /// The user passed to `pam_start`, if any
+    pub requested_user: Option<String>,
+    /// The current user (`PAM_USER`), which modules may have changed
+    pub user: Option<String>,
+    /// The remote host (`PAM_RHOST`)
+    pub rhost: Option<String>,
+    /// The terminal (`PAM_TTY`)
+    pub tty: Option<String>,
+    /// The remote user (`PAM_RUSER`)
+    pub ruser: Option<String>,
+}
 
-        self.last_code = acct_mgmt(self.handle, PamFlag::None);
-        if self.last_code != PamReturnCode::Success {
-            // Probably not strictly neccessary but better be sure
-            return self.reset();
+impl std::fmt::Display for TransactionInfo {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Quote the values, so e.g. a newline in a host name can't forge a log line
+        fn field(
+            fmt: &mut std::fmt::Formatter,
+            name: &str,
+            value: Option<&str>,
+        ) -> std::fmt::Result {
+            match value {
+                Some(value) => write!(fmt, " {}={:?}", name, value),
+                None => write!(fmt, " {}=-", name),
+            }
         }
-        Ok(())
+
+        write!(fmt, "service={:?}", self.service)?;
+        field(fmt, "user", self.user.as_deref())?;
+        field(fmt, "requested_user", self.requested_user.as_deref())?;
+        field(fmt, "rhost", self.rhost.as_deref())?;
+        field(fmt, "tty", self.tty.as_deref())?;
+        field(fmt, "ruser", self.ruser.as_deref())
     }
+}
 
-    /// Perform the chauthtok to support password update
-    pub fn change_authentication_token(&mut self, flags: PamFlag) -> PamResult<()> {
-        self.last_code = chauthtok(self.handle, flags);
-        if self.last_code != PamReturnCode::Success {
-            // No need to reset here
-            return Err(From::from(self.last_code));
+/// Items describing where a login comes from, set at once via `Client::apply`
+///
+/// ```no_run
+/// use pam::{ClientBuilder, NullConv, SessionInfo};
+///
+/// let info = SessionInfo {
+///     rhost: Some("10.0.0.5".to_string()),
+///     tty: Some("ssh".to_string()),
+///     ..SessionInfo::default()
+/// };
+/// let mut client = ClientBuilder::new("sshd")
+///     .session_info(info)
+///     .build(NullConv::new())
+///     .expect("Failed to init PAM client.");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// The remote host (`PAM_RHOST`), see `Client::set_rhost`
+    pub rhost: Option<String>,
+    /// The remote user (`PAM_RUSER`), see `Client::set_ruser`
+    pub ruser: Option<String>,
+    /// The terminal (`PAM_TTY`), see `Client::set_tty`
+    pub tty: Option<String>,
+    /// The X display (`PAM_XDISPLAY`), see `Client::set_xdisplay`
+    pub xdisplay: Option<String>,
+    /// The prompt for the user name (`PAM_USER_PROMPT`), see `Client::set_user_prompt`
+    pub user_prompt: Option<String>,
+}
+
+impl SessionInfo {
+    // Take over the populated fields of `other`
+    fn merge(&mut self, other: SessionInfo) {
+        update(&mut self.rhost, other.rhost);
+        update(&mut self.ruser, other.ruser);
+        update(&mut self.tty, other.tty);
+        update(&mut self.xdisplay, other.xdisplay);
+        update(&mut self.user_prompt, other.user_prompt);
+    }
+}
+
+// Replaces `field` if `value` is set, for merging partial settings
+fn update<T>(field: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *field = value;
+    }
+}
+
+/// The item `Client::apply` failed to set
+#[derive(Debug)]
+pub struct SessionInfoError {
+    /// The item which couldn't be set
+    pub item: PamItemType,
+    /// The error of `pam_set_item`
+    pub error: PamError,
+}
+
+impl std::fmt::Display for SessionInfoError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Failed to set {:?}: {}", self.item, self.error)
+    }
+}
+
+impl std::error::Error for SessionInfoError {}
+
+impl From<SessionInfoError> for PamError {
+    fn from(err: SessionInfoError) -> PamError {
+        err.error
+    }
+}
+
+/// The `XDG_*` variables pam_systemd reads to register a session, see
+/// `Client::set_xdg_session`
+///
+/// ```no_run
+/// use pam::{ClientBuilder, PasswordConv, XdgSession};
+///
+/// let session = XdgSession {
+///     session_type: Some("wayland".to_string()),
+///     class: Some("user".to_string()),
+///     desktop: Some("sway".to_string()),
+///     seat: Some("seat0".to_string()),
+///     vtnr: Some(2),
+/// };
+/// let mut client = ClientBuilder::new("greetd")
+///     .xdg_session(session)
+///     .build(PasswordConv::new())
+///     .expect("Failed to init PAM client.");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XdgSession {
+    /// `XDG_SESSION_TYPE`, e.g. `wayland`, `x11` or `tty`
+    pub session_type: Option<String>,
+    /// `XDG_SESSION_CLASS`, e.g. `user` or `greeter`
+    pub class: Option<String>,
+    /// `XDG_SESSION_DESKTOP`, e.g. `sway` or `gnome`
+    pub desktop: Option<String>,
+    /// `XDG_SEAT`, e.g. `seat0`
+    pub seat: Option<String>,
+    /// `XDG_VTNR`, the virtual terminal of the session
+    pub vtnr: Option<u32>,
+}
+
+impl XdgSession {
+    // The populated variables as name and value
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        let strings = [
+            ("XDG_SESSION_TYPE", &self.session_type),
+            ("XDG_SESSION_CLASS", &self.class),
+            ("XDG_SESSION_DESKTOP", &self.desktop),
+            ("XDG_SEAT", &self.seat),
+        ];
+        let mut variables: Vec<_> = strings
+            .iter()
+            .filter_map(|(name, value)| value.as_ref().map(|value| (*name, value.clone())))
+            .collect();
+        if let Some(vtnr) = self.vtnr {
+            variables.push(("XDG_VTNR", vtnr.to_string()));
         }
-        Ok(())
+        variables
     }
 
-    /// Perform the get_item / PAM_USER to retrive the username
-    pub fn get_user(&mut self) -> PamResult<String> {
-        get_item(self.handle, PamItemType::User).and_then(|result| {
-            // Pam user is a char *
-            let ptr: *const c_char = unsafe { std::mem::transmute(result) };
-            let username = unsafe { CStr::from_ptr(ptr) };
-            match username.to_str() {
-                Err(_) => Err(PamError(PamReturnCode::System_Err)),
-                Ok(username) => Ok(username.to_string()),
-            }
-        })
+    // Take over the populated fields of `other`
+    fn merge(&mut self, other: XdgSession) {
+        update(&mut self.session_type, other.session_type);
+        update(&mut self.class, other.class);
+        update(&mut self.desktop, other.desktop);
+        update(&mut self.seat, other.seat);
+        update(&mut self.vtnr, other.vtnr);
     }
+}
 
-    /// Open a session for a previously authenticated user and
-    /// initialize the environment appropriately (in PAM and regular enviroment variables).
-    pub fn open_session(&mut self) -> PamResult<()> {
-        if !self.is_authenticated {
-            //TODO: is this the right return code?
-            return Err(PamReturnCode::Perm_Denied.into());
+/// Whether an account may log in, see `Client::check_account`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    /// The account is valid (`PAM_SUCCESS`)
+    Valid,
+    /// The password has to be changed first (`PAM_NEW_AUTHTOK_REQD`)
+    PasswordExpired,
+    /// The account expired (`PAM_ACCT_EXPIRED`)
+    Expired,
+    /// A module denied the access, e.g. pam_access or pam_time (`PAM_PERM_DENIED`,
+    /// `PAM_AUTH_ERR`)
+    Denied,
+    /// The user is not known to the modules (`PAM_USER_UNKNOWN`)
+    UnknownUser,
+}
+
+/// Why `pam_acct_mgmt` failed, see `Client::account_error`
+///
+/// This tells e.g. whether to offer changing the password or to tell the user to
+/// contact an administrator. It converts back to the raw `PamReturnCode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccountError {
+    /// The password has to be changed first (`PAM_NEW_AUTHTOK_REQD`)
+    PasswordExpired,
+    /// The account expired (`PAM_ACCT_EXPIRED`)
+    Expired,
+    /// A module denied the access, e.g. pam_access or pam_time (`PAM_PERM_DENIED`,
+    /// `PAM_AUTH_ERR`)
+    Denied,
+    /// The user is not known to the modules (`PAM_USER_UNKNOWN`)
+    UnknownUser,
+    /// Any other error, e.g. `Conv_Err` or `System_Err`
+    Other(PamReturnCode),
+}
+
+impl AccountError {
+    /// The code returned by `pam_acct_mgmt`, `Perm_Denied` for `Denied`
+    pub fn code(&self) -> PamReturnCode {
+        match *self {
+            AccountError::PasswordExpired => PamReturnCode::New_Authtok_Reqd,
+            AccountError::Expired => PamReturnCode::Acct_Expired,
+            AccountError::Denied => PamReturnCode::Perm_Denied,
+            AccountError::UnknownUser => PamReturnCode::User_Unknown,
+            AccountError::Other(code) => code,
         }
+    }
+}
 
-        self.last_code = setcred(self.handle, PamFlag::Establish_Cred);
-        if self.last_code != PamReturnCode::Success {
-            return self.reset();
+impl From<PamReturnCode> for AccountError {
+    fn from(code: PamReturnCode) -> AccountError {
+        match code {
+            PamReturnCode::New_Authtok_Reqd => AccountError::PasswordExpired,
+            PamReturnCode::Acct_Expired => AccountError::Expired,
+            PamReturnCode::Perm_Denied | PamReturnCode::Auth_Err => AccountError::Denied,
+            PamReturnCode::User_Unknown => AccountError::UnknownUser,
+            code => AccountError::Other(code),
         }
+    }
+}
 
-        self.last_code = open_session(self.handle, false);
-        if self.last_code != PamReturnCode::Success {
-            return self.reset();
+impl From<AccountError> for PamReturnCode {
+    fn from(err: AccountError) -> PamReturnCode {
+        err.code()
+    }
+}
+
+impl From<AccountError> for PamError {
+    fn from(err: AccountError) -> PamError {
+        PamError::Pam(err.code())
+    }
+}
+
+impl std::fmt::Display for AccountError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            AccountError::PasswordExpired => write!(fmt, "The password expired"),
+            AccountError::Expired => write!(fmt, "The account expired"),
+            AccountError::Denied => write!(fmt, "Access denied"),
+            AccountError::UnknownUser => write!(fmt, "Unknown user"),
+            AccountError::Other(code) => write!(fmt, "Account management failed: {}", code),
         }
+    }
+}
 
-        // Follow openSSH and call pam_setcred before and after open_session
-        self.last_code = setcred(self.handle, PamFlag::Reinitialize_Cred);
-        if self.last_code != PamReturnCode::Success {
-            return self.reset();
+impl std::error::Error for AccountError {}
+
+/// What a `Client` knows about its transaction, see `Client::status`
+///
+/// The codes are the results of the last call of each PAM function, `None` if it
+/// wasn't called yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransactionStatus {
+    /// See `Client::is_authenticated`
+    pub is_authenticated: bool,
+    /// See `Client::has_valid_account`
+    pub has_valid_account: bool,
+    /// Whether the credentials were established and not deleted yet
+    pub has_credentials: bool,
+    /// See `Client::has_open_session`
+    pub has_open_session: bool,
+    /// How many sessions were opened successfully on this transaction
+    pub sessions_opened: u32,
+    /// The result of `pam_authenticate`
+    pub authenticate: Option<PamReturnCode>,
+    /// The result of `pam_acct_mgmt`
+    pub acct_mgmt: Option<PamReturnCode>,
+    /// The result of `pam_setcred`
+    pub setcred: Option<PamReturnCode>,
+    /// The result of `pam_open_session`
+    pub open_session: Option<PamReturnCode>,
+    /// The result of `pam_close_session`
+    pub close_session: Option<PamReturnCode>,
+    /// The result of `pam_chauthtok`
+    pub chauthtok: Option<PamReturnCode>,
+}
+
+/// The last failed PAM call of a `Client`, see `Client::last_error`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastError {
+    /// The failed call
+    pub operation: PamOperation,
+    /// The error returned by the `Client`
+    pub error: PamError,
+    /// The PAM code of `error`, see `PamError::code`
+    pub code: PamReturnCode,
+    /// The description of `error`, from `pam_strerror` for PAM codes, possibly localized
+    pub message: String,
+}
+
+impl std::fmt::Display for LastError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{:?} failed: {} ({})",
+            self.operation, self.message, self.code
+        )
+    }
+}
+
+impl std::error::Error for LastError {}
+
+/// Builder for a `Client` with items and options set right after `pam_start`
+///
+/// ```no_run
+/// use pam::{ClientBuilder, EnvironmentPolicy, NullConv};
+///
+/// let mut client = ClientBuilder::new("sshd")
+///     .user("alice")
+///     .rhost("10.0.0.5")
+///     .close_on_drop(false)
+///     .environment_policy(EnvironmentPolicy::PamOnly)
+///     .build(NullConv::new())
+///     .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    service: String,
+    user: Option<String>,
+    items: SessionInfo,
+    xdg_session: XdgSession,
+    close_on_drop: bool,
+    silent: bool,
+    disallow_null_authtok: bool,
+    handle_expired_password: bool,
+    auto_clear_credentials: bool,
+    retry_unknown_user: bool,
+    setcred_failure_policy: SetCredFailurePolicy,
+    credential_sequence: CredentialSequence,
+    autodetect_tty: bool,
+    default_tty: Option<String>,
+    environment_policy: EnvironmentPolicy,
+    env_filter: EnvFilter,
+    drop_error: Option<SharedDropErrorHook>,
+}
+
+impl ClientBuilder {
+    /// Create a new builder for a transaction with the given service name
+    pub fn new(service: &str) -> ClientBuilder {
+        ClientBuilder {
+            service: service.to_string(),
+            user: None,
+            items: SessionInfo::default(),
+            xdg_session: XdgSession::default(),
+            close_on_drop: true,
+            silent: false,
+            disallow_null_authtok: false,
+            handle_expired_password: false,
+            auto_clear_credentials: false,
+            retry_unknown_user: false,
+            setcred_failure_policy: SetCredFailurePolicy::default(),
+            credential_sequence: CredentialSequence::default(),
+            autodetect_tty: false,
+            default_tty: None,
+            environment_policy: EnvironmentPolicy::default(),
+            env_filter: EnvFilter::default(),
+            drop_error: None,
         }
+    }
 
-        self.has_open_session = true;
-        self.initialize_environment()
+    /// Pass `user` to `pam_start`, see `Client::with_conversation_for_user`
+    pub fn user(mut self, user: &str) -> ClientBuilder {
+        self.user = Some(user.to_string());
+        self
     }
 
-    // Initialize the client environment with common variables.
-    // Currently always called from Client.open_session()
-    fn initialize_environment(&mut self) -> PamResult<()> {
-        use uzers::os::unix::UserExt;
+    /// Set the remote host, see `Client::set_rhost`
+    pub fn rhost(mut self, rhost: &str) -> ClientBuilder {
+        self.items.rhost = Some(rhost.to_string());
+        self
+    }
 
-        let user = uzers::get_user_by_name(&self.get_user()?).unwrap_or_else(|| {
-            panic!(
-                "Could not get user by name: {:?}",
-                self.get_user()
-            )
-        });
+    /// Set the terminal, see `Client::set_tty`
+    pub fn tty(mut self, tty: &str) -> ClientBuilder {
+        self.items.tty = Some(tty.to_string());
+        self
+    }
 
-        // Set some common environment variables
-        self.set_env(
-            "USER",
-            user.name()
-                .to_str()
-                .expect("Unix usernames should be valid UTF-8"),
-        )?;
-        self.set_env(
-            "LOGNAME",
-            user.name()
-                .to_str()
-                .expect("Unix usernames should be valid UTF-8"),
-        )?;
-        self.set_env("HOME", user.home_dir().to_str().unwrap())?;
-        self.set_env("PWD", user.home_dir().to_str().unwrap())?;
-        self.set_env("SHELL", user.shell().to_str().unwrap())?;
-        // Note: We don't set PATH here, as this should be the job of `pam_env.so`
+    /// Set the remote user, see `Client::set_ruser`
+    pub fn ruser(mut self, ruser: &str) -> ClientBuilder {
+        self.items.ruser = Some(ruser.to_string());
+        self
+    }
 
-        Ok(())
+    /// Set the X display, see `Client::set_xdisplay`
+    pub fn xdisplay(mut self, xdisplay: &str) -> ClientBuilder {
+        self.items.xdisplay = Some(xdisplay.to_string());
+        self
     }
 
-    // Utility function to set an environment variable in PAM and the process
-    fn set_env(&mut self, key: &str, value: &str) -> PamResult<()> {
-        // Set regular environment variable
-        env::set_var(key, value);
+    /// Set the prompt for the user name, see `Client::set_user_prompt`
+    pub fn user_prompt(mut self, prompt: &str) -> ClientBuilder {
+        self.items.user_prompt = Some(prompt.to_string());
+        self
+    }
 
-        // Set pam environment variable
-        if getenv(self.handle, key).is_ok() {
-            let name_value = format!("{}={}", key, value);
-            putenv(self.handle, &name_value)
-        } else {
-            Ok(())
+    /// Put the populated variables of `session` into the PAM environment, see
+    /// `Client::set_xdg_session`
+    ///
+    /// They replace the ones set before.
+    pub fn xdg_session(mut self, session: XdgSession) -> ClientBuilder {
+        self.xdg_session.merge(session);
+        self
+    }
+
+    /// Set the populated items of `info`, see `Client::apply`
+    ///
+    /// They replace the ones set before, e.g. via `rhost`.
+    pub fn session_info(mut self, info: SessionInfo) -> ClientBuilder {
+        self.items.merge(info);
+        self
+    }
+
+    /// Whether the `Client` closes an open session when dropped, defaults to `true`
+    pub fn close_on_drop(mut self, close_on_drop: bool) -> ClientBuilder {
+        self.close_on_drop = close_on_drop;
+        self
+    }
+
+    /// Pass `PAM_SILENT` to all PAM calls, see `Client::set_silent`
+    pub fn silent(mut self, silent: bool) -> ClientBuilder {
+        self.silent = silent;
+        self
+    }
+
+    /// Reject empty passwords in `authenticate`, see `Client::set_disallow_null_authtok`
+    pub fn disallow_null_authtok(mut self, disallow: bool) -> ClientBuilder {
+        self.disallow_null_authtok = disallow;
+        self
+    }
+
+    /// Change an expired password in `authenticate`, see `Client::set_handle_expired_password`
+    pub fn handle_expired_password(mut self, handle: bool) -> ClientBuilder {
+        self.handle_expired_password = handle;
+        self
+    }
+
+    /// Wipe the credentials after authenticating, see `Client::set_auto_clear_credentials`
+    pub fn auto_clear_credentials(mut self, clear: bool) -> ClientBuilder {
+        self.auto_clear_credentials = clear;
+        self
+    }
+
+    /// Retry after `User_Unknown`, see `Client::set_retry_unknown_user`
+    pub fn retry_unknown_user(mut self, retry: bool) -> ClientBuilder {
+        self.retry_unknown_user = retry;
+        self
+    }
+
+    /// Set what happens if `pam_setcred` fails, see `Client::set_setcred_failure_policy`
+    pub fn setcred_failure_policy(mut self, policy: SetCredFailurePolicy) -> ClientBuilder {
+        self.setcred_failure_policy = policy;
+        self
+    }
+
+    /// Set the `pam_setcred` calls around the session, see
+    /// `Client::set_credential_sequence`
+    pub fn credential_sequence(mut self, sequence: CredentialSequence) -> ClientBuilder {
+        self.credential_sequence = sequence;
+        self
+    }
+
+    /// Set `PAM_TTY` to the controlling terminal, see `Client::set_autodetect_tty`
+    pub fn autodetect_tty(mut self, autodetect: bool) -> ClientBuilder {
+        self.autodetect_tty = autodetect;
+        self
+    }
+
+    /// Set `PAM_TTY` to `tty` if no terminal is detected, see `Client::set_default_tty`
+    pub fn default_tty(mut self, tty: &str) -> ClientBuilder {
+        self.default_tty = Some(tty.to_string());
+        self
+    }
+
+    /// Set the environment policy, see `Client::set_environment_policy`
+    pub fn environment_policy(mut self, policy: EnvironmentPolicy) -> ClientBuilder {
+        self.environment_policy = policy;
+        self
+    }
+
+    /// Only pass on the variables matching `patterns`, see `Client::set_env_allow`
+    pub fn env_allow<I, S>(mut self, patterns: I) -> ClientBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.env_filter
+            .set_allow(patterns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never pass on the variables matching `patterns`, see `Client::set_env_deny`
+    pub fn env_deny<I, S>(mut self, patterns: I) -> ClientBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.env_filter
+            .set_deny(patterns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Set a hook called if a cleanup step fails while dropping the client, see
+    /// `Client::on_drop_error`
+    ///
+    /// Unlike the hook set on the client, it also sees the failures of ending the
+    /// transaction if `build` can't apply the options.
+    pub fn on_drop_error<F>(mut self, hook: F) -> ClientBuilder
+    where
+        F: Fn(DropStage, PamReturnCode) + Send + Sync + 'static,
+    {
+        self.drop_error = Some(SharedDropErrorHook(Arc::new(hook)));
+        self
+    }
+
+    /// Start the transaction with the given conversation handler and apply the options
+    ///
+    /// Fails with `Service_Err` for an empty service name and with `Buf_Err` for strings
+    /// containing a nul byte. If an item can't be set, the transaction is ended again.
+    pub fn build<C: conv::Conversation>(self, conversation: C) -> PamResult<Client<C>> {
+        if self.service.is_empty() {
+            return Err(PamReturnCode::Service_Err.into());
+        }
+
+        let mut conversation = Box::new(conversation);
+        let conv = conv::into_pam_conv(&mut *conversation);
+
+        let handle = NonNull::from(start(&self.service, self.user.as_deref(), &conv)?);
+        let mut client = Client {
+            close_on_drop: self.close_on_drop,
+            conversation: ManuallyDrop::new(conversation),
+            state: ClientState {
+                requested_user: self.user.clone(),
+                silent: self.silent,
+                disallow_null_authtok: self.disallow_null_authtok,
+                handle_expired_password: self.handle_expired_password,
+                auto_clear_credentials: self.auto_clear_credentials,
+                retry_unknown_user: self.retry_unknown_user,
+                setcred_failure_policy: self.setcred_failure_policy,
+                credential_sequence: self.credential_sequence,
+                autodetect_tty: self.autodetect_tty,
+                default_tty: self.default_tty,
+                environment_policy: self.environment_policy,
+                env_filter: self.env_filter,
+                ..ClientState::new(handle, self.service.clone())
+            },
+        };
+        if let Some(SharedDropErrorHook(hook)) = self.drop_error {
+            client.on_drop_error(move |stage, code| hook(stage, code));
         }
+        client.apply(&self.items)?;
+        client.set_xdg_session(&self.xdg_session)?;
+        Ok(client)
     }
 
-    // Utility function to reset the pam handle in case of intermediate errors
-    fn reset(&mut self) -> PamResult<()> {
-        setcred(self.handle, PamFlag::Delete_Cred);
-        self.is_authenticated = false;
-        Err(From::from(self.last_code))
+    /// Like `build`, but box the conversation handler to get a `DynClient`
+    pub fn build_dyn<C>(self, conversation: C) -> PamResult<DynClient>
+    where
+        C: conv::Conversation + Send + 'static,
+    {
+        let conversation: Box<dyn conv::Conversation + Send> = Box::new(conversation);
+        self.build(conversation)
     }
 }
 
-impl<'a, C: conv::Conversation> Drop for Client<'a, C> {
-    fn drop(&mut self) {
-        if self.has_open_session && self.close_on_drop {
-            close_session(self.handle, false);
+impl Client<conv::PasswordConv> {
+    /// Create a new `Client` with the given service name and a password-based conversation
+    pub fn with_password(service: &str) -> PamResult<Client<conv::PasswordConv>> {
+        Client::with_conversation(service, conv::PasswordConv::new())
+    }
+
+    /// Like `with_password`, but pass `user` to `pam_start`, see `with_conversation_for_user`
+    pub fn with_password_for_user(
+        service: &str,
+        user: &str,
+    ) -> PamResult<Client<conv::PasswordConv>> {
+        Client::with_conversation_for_user(service, user, conv::PasswordConv::new())
+    }
+}
+
+/// Check whether `password` is valid for `user` with the PAM stack of `service`
+///
+/// This runs `pam_authenticate` and `pam_acct_mgmt` and ends the transaction again,
+/// without opening a session, setting credentials or changing the environment of the
+/// process. Use a `Client` for anything else, e.g. if the stack asks for more than a
+/// password.
+///
+/// It blocks until the modules are done, which includes the delay some of them
+/// add after a wrong password. Stacks checking `/etc/shadow`, like pam_unix,
+/// usually need the process to run as root or can only check the password of the
+/// user running it via a setuid helper.
+///
+/// ```no_run
+/// match pam::check("system-auth", "alice", "hunter2") {
+///     Ok(()) => println!("Welcome, alice"),
+///     Err(err) => println!("Login incorrect: {}", err),
+/// }
+/// ```
+pub fn check(service: &str, user: &str, password: &str) -> PamResult<()> {
+    let mut client = ClientBuilder::new(service)
+        .user(user)
+        .environment_policy(EnvironmentPolicy::None)
+        .build(conv::PasswordConv::new())?;
+    client.conversation_mut().set_credentials(user, password);
+    client.authenticate()
+}
+
+/// Check whether `user` may log in with the PAM stack of `service`, without
+/// authenticating them, see `Client::check_account`
+pub fn check_account(service: &str, user: &str) -> PamResult<AccountStatus> {
+    let mut client = ClientBuilder::new(service)
+        .environment_policy(EnvironmentPolicy::None)
+        .build(conv::NullConv::new())?;
+    client.check_account(user)
+}
+
+impl Client<conv::NullConv> {
+    /// Drive a transaction started elsewhere, e.g. by C code calling `pam_start`
+    ///
+    /// The conversation function of the handle is kept, the `NullConv` of the client is
+    /// not used until `replace_conversation` installs a handler of this crate. As the
+    /// client can't know what already happened on the handle, it assumes that nothing
+    /// did. Use e.g. `set_authenticated` and `set_has_open_session` to tell it
+    /// otherwise.
+    ///
+    /// If `end_on_drop` is `true`, the client owns the transaction and calls `pam_end`
+    /// when dropped, like a client created by this crate. Otherwise the transaction is
+    /// left open for its creator, which has to end it after the client was dropped. In
+    /// both cases an open session is closed and established credentials are deleted
+    /// on drop, unless `close_on_drop` is `false` or the state is reset first.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be a valid handle returned by `pam_start` which is not ended
+    /// before the client is dropped. With `end_on_drop`, nobody else may call `pam_end`
+    /// on it. The handle must not be used elsewhere while the client exists.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `handle` is null.
+    pub unsafe fn from_handle(handle: *mut PamHandle, end_on_drop: bool) -> Client<conv::NullConv> {
+        let handle = NonNull::new(handle).expect("PAM handle must not be null");
+        let service = get_item_str(handle.as_ref(), PamItemType::Service)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+            .to_string();
+        Client {
+            close_on_drop: true,
+            conversation: ManuallyDrop::new(Box::new(conv::NullConv::new())),
+            state: ClientState {
+                end_on_drop,
+                ..ClientState::new(handle, service)
+            },
+        }
+    }
+}
+
+impl<C: conv::Conversation> Client<C> {
+    /// Create a new `Client` with the given service name and conversation handler
+    pub fn with_conversation(service: &str, conversation: C) -> PamResult<Client<C>> {
+        ClientBuilder::new(service).build(conversation)
+    }
+
+    /// Create a new `Client` for `user` with the given service name and conversation handler
+    ///
+    /// The user is passed to `pam_start`, so `PAM_USER` is set before the first module
+    /// runs. Modules like pam_faillock see it from the beginning and don't prompt for
+    /// the login.
+    pub fn with_conversation_for_user(
+        service: &str,
+        user: &str,
+        conversation: C,
+    ) -> PamResult<Client<C>> {
+        ClientBuilder::new(service).user(user).build(conversation)
+    }
+
+    /// Replace the conversation handler for the rest of the transaction
+    ///
+    /// This allows e.g. to check the account with a non-interactive `NullConv` and switch
+    /// to an interactive handler once `change_authentication_token` is necessary. The old
+    /// handler is dropped after libpam switched to the new one. If libpam rejects the
+    /// new handler, the transaction is ended.
+    ///
+    /// A timeout set with `set_call_timeout` is cleared, as the new handler may not be
+    /// `Send`. Set it again on the returned client if necessary. If a call timed out
+    /// already, this fails with `PamError::Timeout` and the client is leaked.
+    ///
+    /// ```no_run
+    /// use pam::{Client, NullConv, PamFlag, TtyConv};
+    ///
+    /// let mut client = Client::with_conversation("system-auth", NullConv::new())
+    ///         .expect("Failed to init PAM client.");
+    /// if client.authenticate().is_err() {
+    ///     let mut client = client
+    ///         .replace_conversation(TtyConv::new())
+    ///         .expect("Failed to replace conversation.");
+    ///     client
+    ///         .change_authentication_token(PamFlag::Change_Expired_AuthTok)
+    ///         .expect("Failed to change password.");
+    /// }
+    /// ```
+    pub fn replace_conversation<D: conv::Conversation>(
+        self,
+        conversation: D,
+    ) -> PamResult<Client<D>> {
+        // Dropping an abandoned client leaks it, as the timed out call may still use it
+        self.check_abandoned()?;
+        let mut conversation = Box::new(conversation);
+        let conv = conv::into_pam_conv(&mut *conversation);
+
+        let mut old = ManuallyDrop::new(self);
+        if let Err(err) = set_conv(old.pamh_mut(), &conv) {
+            // libpam still uses the old handler, so end the transaction as usual
+            unsafe { ManuallyDrop::drop(&mut old) };
+            return Err(err);
+        }
+
+        // Take over the state and drop the old handler which libpam doesn't know anymore.
+        // `old` is not used afterwards, so its `Drop` impl must not run.
+        let state = unsafe { ptr::read(&old.state) };
+        unsafe { ManuallyDrop::drop(&mut old.conversation) };
+        Ok(Client {
+            close_on_drop: old.close_on_drop,
+            conversation: ManuallyDrop::new(conversation),
+            state: ClientState {
+                // PAM calls only run on a helper thread if the handler is `Send`
+                #[cfg(feature = "watchdog")]
+                call_timeout: None,
+                ..state
+            },
+        })
+    }
+
+    /// Immutable access to the conversation handler of this Client
+    pub fn conversation(&self) -> &C {
+        self.assert_not_abandoned();
+        &self.conversation
+    }
+
+    /// Mutable access to the conversation handler of this Client
+    pub fn conversation_mut(&mut self) -> &mut C {
+        self.assert_not_abandoned();
+        &mut self.conversation
+    }
+
+    /// Perform authentication with the provided credentials
+    ///
+    /// Fails with `PamError::Cancelled` if the conversation was cancelled, see
+    /// `Conversation::is_cancelled`, or `PamError::Conversation` with the error of the
+    /// conversation handler. This is also the case if a module reported the failed
+    /// conversation as another error, so a cancelled dialog can be told apart from a
+    /// wrong password. If the conversation handler panics, the panic is continued once
+    /// libpam returned.
+    ///
+    /// This runs `authenticate_only` and `validate_account` with the flags set on the
+    /// client. If the password expired, this fails with `PamError::PasswordExpired`
+    /// unless `set_handle_expired_password` is set.
+    ///
+    /// After a failure, e.g. a wrong password, `authenticate` can be called again on
+    /// the same transaction, so the modules can count the attempts. Once it failed with
+    /// an error for which `PamError::ends_transaction` is true, like `MaxTries` or
+    /// `Abort`, a new `Client` has to be created instead.
+    ///
+    /// With `set_auto_clear_credentials`, the credentials of the conversation handler
+    /// are wiped once this succeeded.
+    pub fn authenticate(&mut self) -> PamResult<()> {
+        // No credentials were established yet, so no need to reset here
+        self.authenticate_only(AuthFlags::default())?;
+
+        match self.validate_account(AuthFlags::default()) {
+            Ok(()) => {}
+            Err(err) if err.is_password_expired() && self.state.handle_expired_password => {
+                let flags = ChauthtokFlags::default().with_change_expired_authtok();
+                if self.change_password(flags).is_err() {
+                    return self.reset();
+                }
+                self.state.has_valid_account = true;
+            }
+            Err(err) if err.is_password_expired() => {
+                return self.reset().map_err(|_| PamError::PasswordExpired);
+            }
+            // Probably not strictly neccessary but better be sure
+            Err(_) => return self.reset(),
+        }
+
+        if self.state.auto_clear_credentials {
+            self.conversation.clear_credentials();
+        }
+        Ok(())
+    }
+
+    /// Run `authenticate` up to `max_attempts` times, like a login prompt
+    ///
+    /// The first attempt is always made, so a `max_attempts` of 0 behaves like 1.
+    /// After a failed attempt, `on_failure` is called with the conversation handler,
+    /// the number of the attempt starting at 1 and the error code, e.g. to ask for the
+    /// password again, and decides whether to retry. Only errors for which
+    /// `PamError::is_failed_attempt` is true are retried, see also
+    /// `set_retry_unknown_user`. Others like `MaxTries`, which modules return once
+    /// their own limit is reached, or `Conv_Err` are returned right away, as is the
+    /// last error once all attempts failed.
+    ///
+    /// ```no_run
+    /// use pam::{Client, RetryDecision};
+    ///
+    /// let mut client = Client::with_password("system-auth")
+    ///         .expect("Failed to init PAM client.");
+    /// client.conversation_mut().set_credentials("login", "wrong");
+    /// client
+    ///     .authenticate_with_retries(3, |conv, attempt, _| {
+    ///         eprintln!("Login incorrect ({} of 3)", attempt);
+    ///         conv.set_credentials("login", "password");
+    ///         RetryDecision::Retry
+    ///     })
+    ///     .expect("Authentication failed!");
+    /// ```
+    pub fn authenticate_with_retries<F>(
+        &mut self,
+        max_attempts: usize,
+        mut on_failure: F,
+    ) -> PamResult<()>
+    where
+        F: FnMut(&mut C, usize, PamReturnCode) -> RetryDecision,
+    {
+        let mut attempt = 1;
+        loop {
+            let err = match self.authenticate() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let retry = err.is_failed_attempt()
+                || (err.code() == PamReturnCode::User_Unknown && self.state.retry_unknown_user);
+            if !retry || attempt >= max_attempts {
+                return Err(err);
+            }
+            if on_failure(self.conversation_mut(), attempt, err.code()) == RetryDecision::Abort {
+                return Err(err);
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Authenticate the user via `pam_authenticate` without checking the account
+    ///
+    /// `flags` are combined with the ones set on the client, e.g. via `set_silent`.
+    /// Afterwards `open_session` can be called, even if `validate_account` was
+    /// skipped because the account is managed otherwise.
+    pub fn authenticate_only(&mut self, flags: AuthFlags) -> PamResult<()> {
+        self.check_abandoned()?;
+        let flags = AuthFlags::from_bits(flags.bits() | self.auth_flags().bits());
+        if self.state.autodetect_tty && self.tty()?.is_none() {
+            let tty = terminal_name(&[0, 1, 2]).or_else(|| self.state.default_tty.clone());
+            if let Some(tty) = tty {
+                self.set_tty(&tty)?;
+            }
+        }
+        // libpam resets the delay after every attempt
+        if let Some(delay) = self.state.fail_delay {
+            fail_delay(self.pamh_mut(), delay)?;
+        }
+        self.state.last_code = self.call(PamOperation::Authenticate, move |handle| {
+            authenticate_with_flags(handle, flags)
+        });
+        self.report_auth_result();
+        if self.state.last_code != PamReturnCode::Success {
+            self.state.is_authenticated = false;
+            self.state.has_valid_account = false;
+            #[cfg(feature = "utmp")]
+            self.record_utmp(crate::session::record_failed_login);
+            return Err(self.error());
+        }
+
+        self.state.is_authenticated = true;
+        Ok(())
+    }
+
+    /// Check whether the account of the user is valid via `pam_acct_mgmt`
+    ///
+    /// This can be repeated, e.g. to re-validate the account of a long-running session
+    /// periodically. `flags` are combined with the ones set on the client. Fails with
+    /// `New_Authtok_Reqd` if the password expired, see `change_password`.
+    pub fn validate_account(&mut self, flags: AuthFlags) -> PamResult<()> {
+        let flags = AuthFlags::from_bits(flags.bits() | self.auth_flags().bits());
+        self.state.last_code = self.call(PamOperation::AcctMgmt, move |handle| {
+            acct_mgmt_with_flags(handle, flags)
+        });
+        self.state.has_valid_account = self.state.last_code == PamReturnCode::Success;
+        if !self.state.has_valid_account {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    /// Check whether the account of `user` may log in via `pam_acct_mgmt`, without
+    /// authenticating
+    ///
+    /// This sets `PAM_USER` to `user`, so the client must not be used to authenticate
+    /// another user afterwards. Modules like pam_unix need to run as root to check
+    /// e.g. the password expiry of other users. Other errors than the ones mapped to
+    /// `AccountStatus` are passed on. Neither `is_authenticated` nor
+    /// `has_valid_account` change.
+    ///
+    /// ```no_run
+    /// use pam::{AccountStatus, Client, NullConv};
+    ///
+    /// let mut client = Client::with_conversation("login", NullConv::new())
+    ///         .expect("Failed to init PAM client.");
+    /// match client.check_account("alice").expect("Failed to check the account!") {
+    ///     AccountStatus::Valid => println!("alice may log in"),
+    ///     status => println!("alice may not log in: {:?}", status),
+    /// }
+    /// ```
+    pub fn check_account(&mut self, user: &str) -> PamResult<AccountStatus> {
+        self.check_abandoned()?;
+        set_item_str(self.pamh_mut(), PamItemType::User, user)?;
+        let flags = self.auth_flags();
+        self.state.last_code = self.call(PamOperation::AcctMgmt, move |handle| {
+            acct_mgmt_with_flags(handle, flags)
+        });
+        if self.state.last_code == PamReturnCode::Success {
+            return Ok(AccountStatus::Valid);
+        }
+        match AccountError::from(self.state.last_code) {
+            AccountError::PasswordExpired => Ok(AccountStatus::PasswordExpired),
+            AccountError::Expired => Ok(AccountStatus::Expired),
+            AccountError::Denied => Ok(AccountStatus::Denied),
+            AccountError::UnknownUser => Ok(AccountStatus::UnknownUser),
+            AccountError::Other(_) => Err(self.error()),
+        }
+    }
+
+    /// Why the last `pam_acct_mgmt` failed, `None` if it succeeded or wasn't called
+    ///
+    /// Use this after `authenticate` or `validate_account` failed to tell an expired
+    /// password from an expired account or a denied login, e.g.:
+    ///
+    /// ```no_run
+    /// use pam::{AccountError, Client};
+    ///
+    /// let mut client = Client::with_password("system-auth")
+    ///         .expect("Failed to init PAM client.");
+    /// client.conversation_mut().set_credentials("login", "password");
+    /// if let Err(err) = client.authenticate() {
+    ///     match client.account_error() {
+    ///         Some(AccountError::PasswordExpired) => println!("Please change your password"),
+    ///         Some(AccountError::Expired) => println!("Please contact your administrator"),
+    ///         Some(err) => println!("Login denied: {}", err),
+    ///         None => println!("Login incorrect: {}", err),
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// It is kept if `authenticate` changed an expired password afterwards, see
+    /// `set_handle_expired_password`.
+    pub fn account_error(&self) -> Option<AccountError> {
+        match self.state.stage_codes[PamOperation::AcctMgmt as usize]? {
+            PamReturnCode::Success => None,
+            code => Some(AccountError::from(code)),
+        }
+    }
+
+    /// The last failed PAM call, `None` if all calls succeeded so far
+    ///
+    /// Unlike the `PamError` returned by the methods, this tells which PAM function
+    /// failed, e.g. whether `open_session` failed in `pam_setcred` or
+    /// `pam_open_session`.
+    pub fn last_error(&self) -> Option<LastError> {
+        let (operation, error) = self.state.last_error?;
+        // Lossy, as the description might not be valid UTF-8 in the locale of libpam
+        Some(LastError {
+            operation,
+            error,
+            code: error.code(),
+            message: error.message(),
+        })
+    }
+
+    /// Set the status passed to `pam_end` when the client is dropped
+    ///
+    /// By default this is the result of the last PAM call, e.g. `Auth_Err` if the
+    /// authentication failed. Modules see it in the cleanup of their data.
+    pub fn set_end_status(&mut self, status: PamReturnCode) {
+        self.state.end_status = Some(status);
+    }
+
+    /// End the transaction, passing `status` to `pam_end`
+    ///
+    /// An open session is closed first unless `close_on_drop` is `false`. A client
+    /// created by `from_handle` without `end_on_drop` doesn't call `pam_end`.
+    pub fn end_with(mut self, status: PamReturnCode) {
+        self.set_end_status(status);
+    }
+
+    /// Release the handle without closing the session, deleting the credentials or
+    /// ending the transaction
+    ///
+    /// This is meant for programs which `exec` the user's shell in the same process
+    /// after opening the session, so the PAM state stays with the process on purpose.
+    /// Nobody cleans up after the call: the session is only closed if the caller
+    /// passes the handle to `pam_close_session` and `pam_end` itself, e.g. via
+    /// `Client::from_handle`. Otherwise it ends with the process, which modules like
+    /// pam_systemd notice when the user's processes exit. Prefer forking and keeping
+    /// the client in the parent until the child exits, as `login` and sshd do.
+    ///
+    /// The conversation handler is leaked, as the handle still refers to it.
+    ///
+    /// ```no_run
+    /// use pam::Client;
+    /// use std::os::unix::process::CommandExt;
+    /// use std::process::Command;
+    ///
+    /// let mut client = Client::with_password("login")
+    ///         .expect("Failed to init PAM client.");
+    /// client.conversation_mut().set_credentials("login", "password");
+    /// client.authenticate().expect("Authentication failed!");
+    /// client.open_session().expect("Failed to open a session!");
+    /// let _handle = client.into_raw();
+    /// // Only returns if the shell could not be started
+    /// let err = Command::new("/bin/sh").exec();
+    /// eprintln!("Failed to start the shell: {}", err);
+    /// ```
+    pub fn into_raw(mut self) -> *mut PamHandle {
+        let handle = self.state.handle.as_ptr();
+        // Makes `drop` skip all cleanup and leak the handler
+        self.state.abandoned = true;
+        handle
+    }
+
+    /// The PAM handle of the transaction, e.g. to read items without a typed getter
+    pub fn handle(&self) -> &PamHandle {
+        self.pamh()
+    }
+
+    /// Mutable access to the PAM handle of the transaction for calls not covered by
+    /// `Client`, e.g. the functions exported at the crate root like `putenv`
+    ///
+    /// # Safety
+    ///
+    /// The handle must not be ended with `pam_end`, and the conversation function must
+    /// not be replaced, use `replace_conversation` instead. Calls which change the state
+    /// tracked by `Client`, like `pam_authenticate` or `pam_open_session`, lead to
+    /// wrong results of e.g. `open_session` or `close_session`.
+    pub unsafe fn handle_mut(&mut self) -> &mut PamHandle {
+        self.pamh_mut()
+    }
+
+    /// Whether the user was authenticated successfully
+    pub fn is_authenticated(&self) -> bool {
+        self.state.is_authenticated
+    }
+
+    /// Whether the last `validate_account` succeeded
+    pub fn has_valid_account(&self) -> bool {
+        self.state.has_valid_account
+    }
+
+    /// Whether a session was opened by `open_session` and not closed yet
+    pub fn has_open_session(&self) -> bool {
+        self.state.has_open_session
+    }
+
+    /// The state of the transaction as tracked by the client, e.g. for debugging
+    pub fn status(&self) -> TransactionStatus {
+        let code = |operation: PamOperation| self.state.stage_codes[operation as usize];
+        TransactionStatus {
+            is_authenticated: self.state.is_authenticated,
+            has_valid_account: self.state.has_valid_account,
+            has_credentials: self.state.established_credentials > 0,
+            has_open_session: self.state.has_open_session,
+            sessions_opened: self.state.sessions_opened,
+            authenticate: code(PamOperation::Authenticate),
+            acct_mgmt: code(PamOperation::AcctMgmt),
+            setcred: code(PamOperation::SetCred),
+            open_session: code(PamOperation::OpenSession),
+            close_session: code(PamOperation::CloseSession),
+            chauthtok: code(PamOperation::Chauthtok),
+        }
+    }
+
+    /// Mark the user as authenticated or not, e.g. for a handle passed to `from_handle`
+    pub fn set_authenticated(&mut self, authenticated: bool) {
+        self.state.is_authenticated = authenticated;
+    }
+
+    /// Mark the account as validated or not, e.g. for a handle passed to `from_handle`
+    pub fn set_has_valid_account(&mut self, valid: bool) {
+        self.state.has_valid_account = valid;
+    }
+
+    /// Mark the credentials as established or not, so they are deleted on drop
+    pub fn set_has_credentials(&mut self, established: bool) {
+        self.state.established_credentials = if established {
+            self.state.established_credentials.max(1)
+        } else {
+            0
+        };
+    }
+
+    /// Mark a session as open or not, so it is closed by `close_session` or on drop
+    pub fn set_has_open_session(&mut self, open: bool) {
+        self.state.has_open_session = open;
+    }
+
+    /// Establish the credentials of the authenticated user via `pam_setcred` without
+    /// opening a session, e.g. to obtain Kerberos tickets for a screen unlocker
+    ///
+    /// Fails with `Perm_Denied` if the user is not authenticated. The error of the
+    /// modules is passed on, e.g. `Cred_Unavail` if a module could not retrieve the
+    /// credentials. Once this succeeded, the credentials are deleted when the client is
+    /// dropped.
+    pub fn establish_credentials(&mut self) -> PamResult<()> {
+        self.setcred(CredAction::Establish)?;
+        self.state.established_credentials += 1;
+        Ok(())
+    }
+
+    /// Extend the lifetime of the credentials of the authenticated user via
+    /// `pam_setcred`, e.g. to renew Kerberos tickets of a long-running process
+    ///
+    /// Fails with `Perm_Denied` if the user is not authenticated. The error of the
+    /// modules is passed on, e.g. `Cred_Expired` if the credentials can't be extended.
+    pub fn refresh_credentials(&mut self) -> PamResult<()> {
+        self.setcred(CredAction::Refresh)
+    }
+
+    /// Delete the credentials of the user via `pam_setcred`, e.g. to drop Kerberos
+    /// tickets when the screen is locked while the session stays open
+    ///
+    /// Does nothing if no credentials were established, otherwise they are deleted
+    /// once for every time they were established. Once called, the credentials are
+    /// considered deleted even if a module failed, so `close_session` and dropping the
+    /// client don't delete them again.
+    pub fn delete_credentials(&mut self) -> PamResult<()> {
+        if self.state.established_credentials == 0 {
+            #[cfg(feature = "log")]
+            log::debug!("No PAM credentials to delete");
+            return Ok(());
+        }
+        self.delete_established_credentials()
+    }
+
+    /// Change the password of the user via `pam_chauthtok`
+    ///
+    /// The conversation handler has to answer the prompts for the old password, unless
+    /// the caller is root, and the new one and its confirmation. No prior
+    /// `authenticate` is required. Use `ChauthtokFlags::with_change_expired_authtok`
+    /// to only change an expired password, e.g. after `authenticate` failed with
+    /// `New_Authtok_Reqd`.
+    ///
+    /// Fails with `Try_Again` if a module could not check whether the password can be
+    /// changed, see `PamError::is_retryable`.
+    ///
+    /// ```no_run
+    /// use pam::{ChauthtokFlags, Client, SequenceConv};
+    ///
+    /// let conv = SequenceConv::new("login", vec!["old password", "new password", "new password"]);
+    /// let mut client = Client::with_conversation("passwd", conv)
+    ///         .expect("Failed to init PAM client.");
+    /// client
+    ///     .change_password(ChauthtokFlags::default())
+    ///     .expect("Failed to change the password!");
+    /// ```
+    pub fn change_password(&mut self, flags: ChauthtokFlags) -> PamResult<()> {
+        let flags = if self.state.silent {
+            flags.with_silent()
+        } else {
+            flags
+        };
+        self.state.last_code = self.call(PamOperation::Chauthtok, move |handle| {
+            chauthtok_with_flags(handle, flags)
+        });
+        if self.state.last_code != PamReturnCode::Success {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    /// Perform the chauthtok to support password update
+    pub fn change_authentication_token(&mut self, flags: PamFlag) -> PamResult<()> {
+        self.change_password(ChauthtokFlags::from_bits(flags as c_int))
+    }
+
+    /// Perform the get_item / PAM_USER to retrive the username
+    pub fn get_user(&mut self) -> PamResult<String> {
+        self.check_abandoned()?;
+        get_item(self.pamh(), PamItemType::User).and_then(|result| {
+            // Pam user is a char *
+            let ptr: *const c_char = unsafe { std::mem::transmute(result) };
+            let username = unsafe { CStr::from_ptr(ptr) };
+            match username.to_str() {
+                Err(_) => Err(PamError::Pam(PamReturnCode::System_Err)),
+                Ok(username) => Ok(username.to_string()),
+            }
+        })
+    }
+
+    /// Switch the transaction to `user`, e.g. after a failed attempt when a greeter
+    /// picks a different account
+    ///
+    /// This sets `PAM_USER` and the client counts as unauthenticated again, so the
+    /// next `authenticate` checks the new user. The login answered by the
+    /// conversation handler is not changed, use `switch_user` for handlers
+    /// implementing `CredentialTarget`. Fails with `Perm_Denied` while a session is
+    /// open or credentials are established for the previous user.
+    ///
+    /// Most modules, e.g. pam_unix and pam_succeed_if, read `PAM_USER` on every call.
+    /// Some keep data from an earlier `pam_authenticate` for the rest of the
+    /// transaction: pam_faillock already counted a failed attempt against the previous
+    /// user, and modules like pam_krb5 or pam_sss keep state they stored under the
+    /// previous name until the new user authenticates successfully. Start a new
+    /// transaction if the stack relies on such state.
+    pub fn set_user(&mut self, user: &str) -> PamResult<()> {
+        self.check_abandoned()?;
+        if self.state.has_open_session || self.state.established_credentials > 0 {
+            return Err(PamReturnCode::Perm_Denied.into());
+        }
+        set_item_str(self.pamh_mut(), PamItemType::User, user)?;
+        self.state.requested_user = Some(user.to_string());
+        self.state.is_authenticated = false;
+        self.state.has_valid_account = false;
+        Ok(())
+    }
+
+    /// The user (`PAM_USER`) which was authenticated
+    ///
+    /// Modules may change the user while authenticating, e.g. to map it to a local
+    /// account or to normalize its case, so this can differ from the login passed to
+    /// the conversation handler. It is used to look up the user in `open_session`.
+    /// Fails with `Perm_Denied` if the user is not authenticated and with
+    /// `User_Unknown` if the item is unset.
+    pub fn authenticated_user(&mut self) -> PamResult<String> {
+        if !self.state.is_authenticated {
+            return Err(PamReturnCode::Perm_Denied.into());
+        }
+        self.check_abandoned()?;
+        match get_item_str(self.pamh(), PamItemType::User)? {
+            Some(user) => Ok(user.to_string()),
+            None => Err(PamReturnCode::User_Unknown.into()),
+        }
+    }
+
+    /// The service name and the items describing the transaction, e.g. for audit logs
+    ///
+    /// Credentials are never included.
+    pub fn info(&mut self) -> PamResult<TransactionInfo> {
+        self.check_abandoned()?;
+        let owned = |value: Option<&str>| value.map(str::to_string);
+        Ok(TransactionInfo {
+            service: self.state.service.clone(),
+            requested_user: self.state.requested_user.clone(),
+            user: owned(get_item_str(self.pamh(), PamItemType::User)?),
+            rhost: owned(self.rhost()?),
+            tty: owned(self.tty()?),
+            ruser: owned(self.ruser()?),
+        })
+    }
+
+    /// Set the remote host (`PAM_RHOST`) the user is connecting from
+    ///
+    /// Network services should set this before `authenticate`, so modules like
+    /// pam_faillock and the log messages attribute the attempt correctly.
+    /// An empty string unsets the item.
+    pub fn set_rhost(&mut self, rhost: &str) -> PamResult<()> {
+        self.set_string_item(PamItemType::RHost, rhost)
+    }
+
+    /// The remote host (`PAM_RHOST`), if set
+    pub fn rhost(&self) -> PamResult<Option<&str>> {
+        self.check_abandoned()?;
+        get_item_str(self.pamh(), PamItemType::RHost)
+    }
+
+    /// Set the terminal (`PAM_TTY`) of the user, e.g. `/dev/tty1` or `:0`
+    ///
+    /// An empty string unsets the item.
+    pub fn set_tty(&mut self, tty: &str) -> PamResult<()> {
+        self.set_string_item(PamItemType::TTY, tty)
+    }
+
+    /// The terminal (`PAM_TTY`), if set
+    pub fn tty(&self) -> PamResult<Option<&str>> {
+        self.check_abandoned()?;
+        get_item_str(self.pamh(), PamItemType::TTY)
+    }
+
+    /// Set the name of the remote user (`PAM_RUSER`) requesting the authentication
+    ///
+    /// An empty string unsets the item.
+    pub fn set_ruser(&mut self, ruser: &str) -> PamResult<()> {
+        self.set_string_item(PamItemType::RUser, ruser)
+    }
+
+    /// The remote user (`PAM_RUSER`), if set
+    pub fn ruser(&self) -> PamResult<Option<&str>> {
+        self.check_abandoned()?;
+        get_item_str(self.pamh(), PamItemType::RUser)
+    }
+
+    /// Set the X display (`PAM_XDISPLAY`) of a graphical login, e.g. `:0`
+    ///
+    /// An empty string unsets the item.
+    pub fn set_xdisplay(&mut self, xdisplay: &str) -> PamResult<()> {
+        self.set_string_item(PamItemType::XDisplay, xdisplay)
+    }
+
+    /// The X display (`PAM_XDISPLAY`), if set
+    pub fn xdisplay(&self) -> PamResult<Option<&str>> {
+        self.check_abandoned()?;
+        get_item_str(self.pamh(), PamItemType::XDisplay)
+    }
+
+    /// Set the populated items of `info` at once, e.g. before `authenticate`
+    ///
+    /// Fails with the item which couldn't be set. The items before it in the order
+    /// of the fields of `SessionInfo` are set nevertheless.
+    pub fn apply(&mut self, info: &SessionInfo) -> Result<(), SessionInfoError> {
+        let items = [
+            (PamItemType::RHost, &info.rhost),
+            (PamItemType::RUser, &info.ruser),
+            (PamItemType::TTY, &info.tty),
+            (PamItemType::XDisplay, &info.xdisplay),
+            (PamItemType::User_Prompt, &info.user_prompt),
+        ];
+        for (item, value) in items.iter() {
+            if let Some(value) = value {
+                self.set_string_item(*item, value)
+                    .map_err(|error| SessionInfoError { item: *item, error })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the prompt (`PAM_USER_PROMPT`) modules use to ask for the user name
+    ///
+    /// An empty string unsets the item, so modules fall back to their default prompt.
+    pub fn set_user_prompt(&mut self, prompt: &str) -> PamResult<()> {
+        self.set_string_item(PamItemType::User_Prompt, prompt)
+    }
+
+    /// The prompt for the user name (`PAM_USER_PROMPT`), if set
+    pub fn user_prompt(&self) -> PamResult<Option<&str>> {
+        self.check_abandoned()?;
+        get_item_str(self.pamh(), PamItemType::User_Prompt)
+    }
+
+    /// The variables of the PAM environment, e.g. set by pam_env or pam_systemd
+    ///
+    /// Modules usually export them while opening the session, as does `open_session`
+    /// itself unless the `EnvironmentPolicy` is `None`. They are meant for the
+    /// environment of the user's processes.
+    ///
+    /// Only the variables passing the filter set via `set_env_allow` and `set_env_deny`
+    /// are returned.
+    pub fn environment(&mut self) -> PamResult<HashMap<OsString, OsString>> {
+        self.check_abandoned()?;
+        let list = getenvlist(self.pamh_mut());
+        let filter = &self.state.env_filter;
+        Ok(list
+            .into_os_strings()
+            .filter(|(name, _)| filter.allows(name.as_bytes()))
+            .collect())
+    }
+
+    /// Set the variable `name` in the PAM environment, e.g. for pam_exec or pam_env
+    ///
+    /// This can be called at any point of the transaction. `open_session` doesn't
+    /// change variables set or removed this way. Fails with `Bad_Item` if `name` is
+    /// empty or contains `=`, and with `Buf_Err` for nul bytes.
+    pub fn pam_putenv(&mut self, name: &str, value: &str) -> PamResult<()> {
+        check_env_name(name)?;
+        self.check_abandoned()?;
+        putenv(self.pamh_mut(), &format!("{}={}", name, value))?;
+        self.state.explicit_env.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Remove the variable `name` from the PAM environment, see `pam_putenv`
+    ///
+    /// Succeeds if the variable is not set.
+    pub fn pam_remove_env(&mut self, name: &str) -> PamResult<()> {
+        check_env_name(name)?;
+        self.check_abandoned()?;
+        // A value which is not valid UTF-8 is removed as well
+        if getenv(self.pamh_mut(), name) != Ok(None) {
+            putenv(self.pamh_mut(), name)?;
+        }
+        self.state.explicit_env.insert(name.to_string());
+        Ok(())
+    }
+
+    /// The value of the variable `name` in the PAM environment, `None` if it is unset
+    ///
+    /// Unlike `environment`, this ignores the filter set via `set_env_allow`. Fails with
+    /// `System_Err` if the value is not valid UTF-8.
+    pub fn pam_getenv(&mut self, name: &str) -> PamResult<Option<String>> {
+        self.check_abandoned()?;
+        Ok(getenv(self.pamh_mut(), name)?.map(str::to_string))
+    }
+
+    /// Put the populated variables of `session` into the PAM environment, see
+    /// `pam_putenv`
+    ///
+    /// Call it before `open_session`, so pam_systemd registers the session with them.
+    /// Unpopulated fields leave their variables alone. Fails like `pam_putenv`, the
+    /// variables before the failing one stay set.
+    pub fn set_xdg_session(&mut self, session: &XdgSession) -> PamResult<()> {
+        for (name, value) in session.variables() {
+            self.pam_putenv(name, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Only pass on the variables of the PAM environment matching one of `patterns`
+    ///
+    /// The patterns match variable names, `*` matches any number of characters, e.g.
+    /// `XDG_*`. The filter applies to `environment` and the variables `open_session`
+    /// sets in the process environment, but not to the PAM environment itself.
+    pub fn set_env_allow<I, S>(&mut self, patterns: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.state
+            .env_filter
+            .set_allow(patterns.into_iter().map(Into::into).collect());
+    }
+
+    /// Never pass on the variables of the PAM environment matching one of `patterns`
+    ///
+    /// This takes precedence over `set_env_allow`, e.g. to exclude `LD_*`.
+    pub fn set_env_deny<I, S>(&mut self, patterns: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.state
+            .env_filter
+            .set_deny(patterns.into_iter().map(Into::into).collect());
+    }
+
+    /// Pass `PAM_SILENT` to all PAM calls, so modules don't send informational messages
+    ///
+    /// Prompts are unaffected, the conversation handler still has to answer them.
+    pub fn set_silent(&mut self, silent: bool) {
+        self.state.silent = silent;
+    }
+
+    /// Pass `PAM_DISALLOW_NULL_AUTHTOK` to `pam_authenticate` and `pam_acct_mgmt`
+    ///
+    /// Modules then reject users with an empty password, `authenticate` fails with the
+    /// code they return, usually `Auth_Err` or `Perm_Denied`.
+    pub fn set_disallow_null_authtok(&mut self, disallow: bool) {
+        self.state.disallow_null_authtok = disallow;
+    }
+
+    /// Change an expired password right away in `authenticate`
+    ///
+    /// If `pam_acct_mgmt` reports that the password expired, `authenticate` calls
+    /// `pam_chauthtok` with `PAM_CHANGE_EXPIRED_AUTHTOK`, so the conversation handler
+    /// has to answer the prompts for the new password and its confirmation, and
+    /// possibly the old one. `PasswordConv` answers all of them with the same password,
+    /// so the change fails, use e.g. a `SequenceConv` instead. Without this option,
+    /// `authenticate` fails with `PamError::PasswordExpired`, so the caller can change
+    /// the password via `change_password`.
+    pub fn set_handle_expired_password(&mut self, handle: bool) {
+        self.state.handle_expired_password = handle;
+    }
+
+    /// Wipe the credentials of the conversation handler once `authenticate` succeeded,
+    /// see `Conversation::clear_credentials`
+    ///
+    /// Otherwise e.g. the password stays in a `PasswordConv` as long as the client
+    /// lives, which can be the whole session of the user. After a failed attempt the
+    /// credentials are kept for a retry. `change_password` can't reuse them anymore,
+    /// set the old password again via `PasswordConv::set_credentials` or change an
+    /// expired one in `authenticate`, see `set_handle_expired_password`.
+    pub fn set_auto_clear_credentials(&mut self, clear: bool) {
+        self.state.auto_clear_credentials = clear;
+    }
+
+    /// Let `authenticate_with_retries` continue after `User_Unknown`
+    ///
+    /// By default it stops, as the same user would be unknown again. Login prompts
+    /// which ask for the user on every attempt, e.g. via `PAM_USER_PROMPT`, should
+    /// retry, so a typo in the name costs an attempt like a wrong password.
+    pub fn set_retry_unknown_user(&mut self, retry: bool) {
+        self.state.retry_unknown_user = retry;
+    }
+
+    /// Set what `open_session` does if `pam_setcred` fails
+    ///
+    /// By default it fails and the user has to authenticate again. Some stacks contain
+    /// modules whose credentials are optional, e.g. pam_krb5 without a ticket, so
+    /// display managers open the session anyway. With
+    /// `SetCredFailurePolicy::WarnAndContinue`, the error is available afterwards via
+    /// `setcred_failure`.
+    pub fn set_setcred_failure_policy(&mut self, policy: SetCredFailurePolicy) {
+        self.state.setcred_failure_policy = policy;
+    }
+
+    /// Set the `pam_setcred` calls made by `open_session`
+    ///
+    /// Defaults to `CredentialSequence::Full`. Some stacks misbehave if the
+    /// credentials are set twice, use `EstablishOnly` or `AfterSession` for them.
+    /// `close_session` and dropping the client delete the credentials once for every
+    /// time the sequence established them.
+    pub fn set_credential_sequence(&mut self, sequence: CredentialSequence) {
+        self.state.credential_sequence = sequence;
+    }
+
+    /// The error of `pam_setcred` ignored by the last `open_session`, see
+    /// `set_setcred_failure_policy`
+    pub fn setcred_failure(&self) -> Option<PamReturnCode> {
+        self.state.setcred_failure
+    }
+
+    /// Record sessions in utmp and wtmp and failed authentications in btmp
+    ///
+    /// `open_session` and `close_session` write the entries for `PAM_USER`, `PAM_TTY`
+    /// and `PAM_RHOST` with the id of this process, `authenticate` records failures,
+    /// see `session::record_login`. Accounting never fails these calls, its errors are
+    /// passed to `warn` instead, e.g. if `/run/utmp` is missing.
+    #[cfg(feature = "utmp")]
+    pub fn set_utmp_accounting<F>(&mut self, warn: F)
+    where
+        F: FnMut(&std::io::Error) + Send + 'static,
+    {
+        self.state.utmp_warning = Some(Box::new(warn));
+    }
+
+    /// Set `PAM_TTY` to the terminal of stdin, stdout or stderr before authenticating
+    ///
+    /// Modules like pam_securetty and pam_time rely on `PAM_TTY`. It is only set if
+    /// it was not set before, e.g. via `set_tty`. Without a terminal, the default set
+    /// via `set_default_tty` is used, otherwise the item stays unset.
+    pub fn set_autodetect_tty(&mut self, autodetect: bool) {
+        self.state.autodetect_tty = autodetect;
+    }
+
+    /// The value for `PAM_TTY` if `set_autodetect_tty` finds no terminal
+    ///
+    /// Services without a terminal use e.g. "ssh" by convention.
+    pub fn set_default_tty(&mut self, tty: &str) {
+        self.state.default_tty = Some(tty.to_string());
+    }
+
+    /// Delay the return of `authenticate` by at least `delay` if it fails
+    ///
+    /// This slows down brute-force attacks, modules like pam_unix may request a delay
+    /// as well. The longest one is used, randomized by up to 25 %.
+    pub fn set_fail_delay(&mut self, delay: Duration) {
+        self.state.fail_delay = Some(delay);
+    }
+
+    /// Don't wait after failed authentication attempts, even if modules request it
+    ///
+    /// This keeps e.g. GUI frontends responsive, which should then delay the next
+    /// attempt themselves. It applies to the rest of the transaction.
+    pub fn disable_fail_delay(&mut self) -> PamResult<()> {
+        self.check_abandoned()?;
+        disable_fail_delay(self.pamh_mut())
+    }
+
+    /// Set where `open_session` initializes the environment of the user
+    ///
+    /// Defaults to `EnvironmentPolicy::PamAndProcess`. Services acting on behalf of
+    /// other users should use `PamOnly` or `None`, so their own environment isn't
+    /// changed. With `PamOnly`, `open_session` never calls `std::env::set_var`, which
+    /// is racy in multi-threaded programs, and the variables can be passed to the
+    /// user's processes only:
+    ///
+    /// ```no_run
+    /// use pam::{Client, EnvironmentPolicy};
+    /// use std::process::Command;
+    ///
+    /// let mut client = Client::with_password("system-auth")
+    ///         .expect("Failed to init PAM client.");
+    /// client.conversation_mut().set_credentials("login", "password");
+    /// client.set_environment_policy(EnvironmentPolicy::PamOnly);
+    /// client.authenticate().expect("Authentication failed!");
+    /// client.open_session().expect("Failed to open a session!");
+    ///
+    /// let environment = client.environment().expect("Failed to get the environment!");
+    /// Command::new("/bin/sh")
+    ///     .env_clear()
+    ///     .envs(environment)
+    ///     .spawn()
+    ///     .expect("Failed to start the shell!");
+    /// ```
+    pub fn set_environment_policy(&mut self, policy: EnvironmentPolicy) {
+        self.state.environment_policy = policy;
+    }
+
+    /// Set a hook returning additional environment variables for the user
+    ///
+    /// It is called with the user name by `open_session` and the variables are set
+    /// after the common ones, according to the `EnvironmentPolicy`.
+    pub fn set_environment_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&str) -> Vec<(String, String)> + Send + 'static,
+    {
+        self.state.environment_hook = Some(Box::new(hook));
+    }
+
+    /// Set a hook called with the user and the result of every authentication attempt
+    ///
+    /// It is called by `authenticate` and `authenticate_only` once `pam_authenticate`
+    /// returned, e.g. to write audit records. The user is `PAM_USER`, or the one passed
+    /// to the transaction if no module set it, and may be empty. The code is `Conv_Err`
+    /// if the conversation failed, like the error returned by `authenticate`.
+    ///
+    /// The hooks set via this, `on_session_opened` and `on_session_closed` can't change
+    /// the outcome of the call and panics inside them are caught and ignored.
+    pub fn on_auth_result<F>(&mut self, hook: F)
+    where
+        F: FnMut(&str, PamReturnCode) + Send + 'static,
+    {
+        self.state.lifecycle_hooks.auth_result = Some(Box::new(hook));
+    }
+
+    /// Set a hook called with the transaction info once `open_session` opened a session
+    ///
+    /// It runs before the environment is initialized, so it is called even if that
+    /// fails afterwards.
+    pub fn on_session_opened<F>(&mut self, hook: F)
+    where
+        F: FnMut(&TransactionInfo) + Send + 'static,
+    {
+        self.state.lifecycle_hooks.session_opened = Some(Box::new(hook));
+    }
+
+    /// Set a hook called with the result of `pam_close_session`
+    ///
+    /// It is called by `close_session`, when dropping a `Session` and when dropping the
+    /// client closes the session.
+    pub fn on_session_closed<F>(&mut self, hook: F)
+    where
+        F: FnMut(PamReturnCode) + Send + 'static,
+    {
+        self.state.lifecycle_hooks.session_closed = Some(Box::new(hook));
+    }
+
+    /// Set a hook called if a cleanup step fails while dropping the client
+    ///
+    /// Dropping closes an open session, deletes the credentials and ends the
+    /// transaction, which can't report errors otherwise. A failure to close the session
+    /// e.g. means modules like pam_systemd may not have cleaned up. Without a hook,
+    /// the failures are logged as warnings if the `log` feature is enabled. Like the
+    /// other hooks, it can't panic out of `drop`.
+    pub fn on_drop_error<F>(&mut self, hook: F)
+    where
+        F: FnMut(DropStage, PamReturnCode) + Send + 'static,
+    {
+        self.state.lifecycle_hooks.drop_error = Some(Box::new(hook));
+    }
+
+    /// Open a session for a previously authenticated user and
+    /// initialize the environment appropriately, see `set_environment_policy`.
+    ///
+    /// Fails with `User_Unknown` if the user has no passwd entry, e.g. because it is
+    /// only known to a PAM module, and with `System_Err` if its home directory or shell
+    /// are not valid UTF-8. The session is open nevertheless. Set the
+    /// `EnvironmentPolicy` to `None` to skip the lookup.
+    pub fn open_session(&mut self) -> PamResult<()> {
+        if !self.state.is_authenticated {
+            //TODO: is this the right return code?
+            return Err(PamReturnCode::Perm_Denied.into());
+        }
+        self.state.setcred_failure = None;
+
+        let sequence = self.state.credential_sequence.clone();
+        for &action in sequence.before_session() {
+            if !self.run_sequence_step(action) {
+                return self.reset();
+            }
+        }
+
+        let silent = self.state.silent;
+        self.state.last_code = self.call(PamOperation::OpenSession, move |handle| {
+            open_session(handle, silent)
+        });
+        if self.state.last_code != PamReturnCode::Success {
+            return self.reset();
+        }
+
+        for &action in sequence.after_session() {
+            if !self.run_sequence_step(action) {
+                // The session is open already, don't leak it unless the step timed out
+                if !self.state.abandoned {
+                    self.cleanup_call(PamOperation::CloseSession, move |handle| {
+                        close_session(handle, silent)
+                    });
+                }
+                return self.reset();
+            }
+        }
+
+        self.state.has_open_session = true;
+        self.state.sessions_opened += 1;
+        #[cfg(feature = "utmp")]
+        self.record_utmp(|user, tty, rhost| {
+            crate::session::record_login(user, tty, rhost, std::process::id())
+        });
+        if self.state.lifecycle_hooks.session_opened.is_some() {
+            if let Ok(info) = self.info() {
+                if let Some(hook) = self.state.lifecycle_hooks.session_opened.as_mut() {
+                    run_hook(|| hook(&info));
+                }
+            }
+        }
+        self.initialize_environment()
+    }
+
+    /// Open a session for `user` WITHOUT AUTHENTICATING THEM, for trusted callers only
+    ///
+    /// **Security**: nobody proved to be `user`, this only checks the account if
+    /// `validate_account` is true. Only use it if the caller is already privileged
+    /// and acts on its own behalf, e.g. like cron starting a job or root running
+    /// `su`, never with a user name received from an untrusted party, e.g. over the
+    /// network. The PAM stack of the service should not grant anything in its session
+    /// or credential modules which relies on the user having authenticated.
+    ///
+    /// This sets `PAM_USER`, optionally runs `pam_acct_mgmt` and then does the same as
+    /// `open_session`. The client counts as authenticated afterwards, e.g. for
+    /// `refresh_credentials`, until the session fails to open.
+    pub fn open_session_unauthenticated(
+        &mut self,
+        user: &str,
+        validate_account: bool,
+    ) -> PamResult<()> {
+        self.check_abandoned()?;
+        set_item_str(self.pamh_mut(), PamItemType::User, user)?;
+        self.state.is_authenticated = true;
+        if validate_account {
+            if let Err(err) = self.validate_account(AuthFlags::default()) {
+                self.state.is_authenticated = false;
+                return Err(err);
+            }
+        }
+        self.open_session()
+    }
+
+    /// Like `open_session`, but return a guard which closes the session when dropped
+    ///
+    /// The guard borrows the client, so the transaction can't end while the session
+    /// is open. Use `Session::close` to observe the result of closing it.
+    ///
+    /// ```no_run
+    /// use pam::Client;
+    ///
+    /// let mut client = Client::with_password("system-auth")
+    ///         .expect("Failed to init PAM client.");
+    /// client.conversation_mut().set_credentials("login", "password");
+    /// client.authenticate().expect("Authentication failed!");
+    /// let mut session = client.session().expect("Failed to open a session!");
+    /// let environment = session.environment().expect("Failed to get the environment!");
+    /// // ... run the user's processes with `environment`
+    /// session.close().expect("Failed to close the session!");
+    /// ```
+    pub fn session(&mut self) -> PamResult<Session<'_, C>> {
+        self.open_session()?;
+        Ok(Session { client: self })
+    }
+
+    /// Close the session opened by `open_session` and delete the credentials
+    ///
+    /// The transaction stays alive, e.g. to refresh the credentials later. Fails with
+    /// `Session_Err` if no session is open. Once called, the session is considered
+    /// closed even if a module failed, so dropping the client doesn't close it again.
+    pub fn close_session(&mut self) -> PamResult<()> {
+        if !self.state.has_open_session {
+            return Err(PamReturnCode::Session_Err.into());
+        }
+        self.state.has_open_session = false;
+        #[cfg(feature = "utmp")]
+        self.record_utmp(|_, tty, _| crate::session::record_logout(tty, std::process::id()));
+
+        let silent = self.state.silent;
+        self.state.last_code = self.call(PamOperation::CloseSession, move |handle| {
+            close_session(handle, silent)
+        });
+        self.report_session_closed(self.error_code(self.state.last_code));
+        if self.state.last_code != PamReturnCode::Success {
+            return Err(self.error());
+        }
+        self.delete_established_credentials()
+    }
+
+    // Run a `pam_setcred` step of the `CredentialSequence`, false if `open_session`
+    // has to fail
+    fn run_sequence_step(&mut self, action: CredAction) -> bool {
+        let flags = self.cred_flags(action);
+        self.state.last_code = self.call(PamOperation::SetCred, move |handle| {
+            setcred_with_flags(handle, flags)
+        });
+        if self.state.last_code != PamReturnCode::Success {
+            return self.tolerate_setcred_failure();
+        }
+        match action {
+            CredAction::Establish => self.state.established_credentials += 1,
+            // Reinitializing establishes the credentials if there were none yet
+            CredAction::Reinitialize if self.state.established_credentials == 0 => {
+                self.state.established_credentials = 1
+            }
+            _ => {}
+        }
+        true
+    }
+
+    // Delete the credentials once for every time they were established, the first
+    // error wins
+    fn delete_established_credentials(&mut self) -> PamResult<()> {
+        let mut result = Ok(());
+        while self.state.established_credentials > 0 {
+            self.state.established_credentials -= 1;
+            let flags = self.cred_flags(CredAction::Delete);
+            self.state.last_code = self.call(PamOperation::SetCred, move |handle| {
+                setcred_with_flags(handle, flags)
+            });
+            if self.state.last_code != PamReturnCode::Success && result.is_ok() {
+                result = Err(self.error());
+            }
+        }
+        result
+    }
+
+    // Run `pam_setcred` with `action` for the authenticated user
+    fn setcred(&mut self, action: CredAction) -> PamResult<()> {
+        if !self.state.is_authenticated {
+            return Err(PamReturnCode::Perm_Denied.into());
+        }
+
+        let flags = self.cred_flags(action);
+        self.state.last_code = self.call(PamOperation::SetCred, move |handle| {
+            setcred_with_flags(handle, flags)
+        });
+        if self.state.last_code != PamReturnCode::Success {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    // Initialize the client environment with common variables.
+    // Currently always called from Client.open_session()
+    fn initialize_environment(&mut self) -> PamResult<()> {
+        use uzers::os::unix::UserExt;
+
+        fn utf8(value: &OsStr) -> PamResult<&str> {
+            value
+                .to_str()
+                .ok_or(PamError::Pam(PamReturnCode::System_Err))
+        }
+
+        if self.state.environment_policy == EnvironmentPolicy::None {
+            return Ok(());
+        }
+
+        let user = uzers::get_user_by_name(&self.authenticated_user()?)
+            .ok_or(PamError::Pam(PamReturnCode::User_Unknown))?;
+        let name = utf8(user.name())?;
+        let home = utf8(user.home_dir().as_os_str())?;
+        let shell = utf8(user.shell().as_os_str())?;
+
+        // Set some common environment variables, unless the modules set them already
+        self.set_default_env("USER", name)?;
+        self.set_default_env("LOGNAME", name)?;
+        self.set_default_env("HOME", home)?;
+        self.set_default_env("PWD", home)?;
+        self.set_default_env("SHELL", shell)?;
+        // Note: We don't set PATH here, as this should be the job of `pam_env.so`
+
+        if let Some(hook) = self.state.environment_hook.as_mut() {
+            for (key, value) in hook(name) {
+                self.set_env(&key, &value)?;
+            }
+        }
+
+        // Import the variables set by the modules as well, e.g. XDG_RUNTIME_DIR from
+        // pam_systemd or KRB5CCNAME from pam_krb5
+        if self.state.environment_policy == EnvironmentPolicy::PamAndProcess {
+            for (key, value) in self.environment()? {
+                env::set_var(key, value);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Utility function to set a variable in the PAM environment
+    fn set_env(&mut self, key: &str, value: &str) -> PamResult<()> {
+        // Keep what the caller set via `pam_putenv`
+        if self.state.explicit_env.contains(key) {
+            return Ok(());
+        }
+        // Set pam environment variable, unless it is set to the value already
+        if getenv(self.pamh_mut(), key) == Ok(Some(value)) {
+            return Ok(());
+        }
+        let name_value = format!("{}={}", key, value);
+        putenv(self.pamh_mut(), &name_value)
+    }
+
+    // Utility function to set a variable in the PAM environment if it is unset
+    fn set_default_env(&mut self, key: &str, value: &str) -> PamResult<()> {
+        // Keep a value which is not valid UTF-8 as well
+        if getenv(self.pamh_mut(), key) != Ok(None) {
+            return Ok(());
+        }
+        self.set_env(key, value)
+    }
+
+    // Utility function to set a string item, unsetting it for an empty value
+    fn set_string_item(&mut self, item_type: PamItemType, value: &str) -> PamResult<()> {
+        self.check_abandoned()?;
+        if value.is_empty() {
+            clear_item(self.pamh_mut(), item_type)
+        } else {
+            set_item_str(self.pamh_mut(), item_type, value)
+        }
+    }
+
+    fn pamh(&self) -> &PamHandle {
+        self.assert_not_abandoned();
+        // The handle is valid until `pam_end` is called when dropping the client
+        unsafe { self.state.handle.as_ref() }
+    }
+
+    fn pamh_mut(&mut self) -> &mut PamHandle {
+        self.assert_not_abandoned();
+        unsafe { self.state.handle.as_mut() }
+    }
+
+    // Fail with `Timeout` instead of using the handle after a call timed out
+    fn check_abandoned(&self) -> PamResult<()> {
+        if self.state.abandoned {
+            return Err(PamError::Timeout);
+        }
+        Ok(())
+    }
+
+    fn assert_not_abandoned(&self) {
+        assert!(
+            !self.state.abandoned,
+            "The PAM transaction was abandoned after a call timed out"
+        );
+    }
+
+    // Run a PAM function and continue a panic of the conversation handler afterwards,
+    // which was caught so it does not unwind through libpam
+    fn call<F>(&mut self, operation: PamOperation, function: F) -> PamReturnCode
+    where
+        F: FnOnce(&mut PamHandle) -> PamReturnCode + Send + 'static,
+    {
+        let (code, panic, conv_error) = self.dispatch_call(function);
+        self.state.stage_codes[operation as usize] = Some(code);
+        if let Some(payload) = panic {
+            panic::resume_unwind(payload);
+        }
+        self.state.conv_error = conv_error;
+        if code != PamReturnCode::Success {
+            self.state.last_error = Some((operation, self.error_for(code)));
+        }
+        code
+    }
+
+    // Run a PAM function on a helper thread if a timeout is set, `call` and `drop`
+    // handle the result
+    fn dispatch_call<F>(&mut self, function: F) -> CallResult
+    where
+        F: FnOnce(&mut PamHandle) -> PamReturnCode + Send + 'static,
+    {
+        #[cfg(feature = "watchdog")]
+        {
+            if self.state.abandoned {
+                // Reported as `PamError::Timeout`, see `error_for`
+                return (PamReturnCode::Abort, None, None);
+            }
+            if let Some(timeout) = self.state.call_timeout {
+                return self.call_with_timeout(function, timeout);
+            }
+        }
+        run_call(self.pamh_mut(), function)
+    }
+
+    // Run a cleanup step of `drop`, which can't continue a panic of the handler
+    fn drop_call<F>(&mut self, function: F) -> PamReturnCode
+    where
+        F: FnOnce(&mut PamHandle) -> PamReturnCode + Send + 'static,
+    {
+        let (code, _panic, conv_error) = self.dispatch_call(function);
+        self.state.conv_error = conv_error;
+        code
+    }
+
+    // Like `call`, but the failure which is cleaned up after stays the reported error
+    fn cleanup_call<F>(&mut self, operation: PamOperation, function: F) -> PamReturnCode
+    where
+        F: FnOnce(&mut PamHandle) -> PamReturnCode + Send + 'static,
+    {
+        let conv_error = self.state.conv_error.take();
+        let last_error = self.state.last_error.take();
+        let code = self.call(operation, function);
+        self.state.conv_error = conv_error;
+        self.state.last_error = last_error;
+        code
+    }
+
+    // Run `function` on a helper thread and abandon the transaction if it doesn't
+    // return within `timeout`
+    #[cfg(feature = "watchdog")]
+    fn call_with_timeout<F>(&mut self, function: F, timeout: Duration) -> CallResult
+    where
+        F: FnOnce(&mut PamHandle) -> PamReturnCode + Send + 'static,
+    {
+        // Only `&mut self` accesses the handle, and the handler is `Send`, see
+        // `set_call_timeout`. Once abandoned, neither is used or freed anymore.
+        struct SendHandle(NonNull<PamHandle>);
+        unsafe impl Send for SendHandle {}
+
+        let handle = SendHandle(self.state.handle);
+        let (result, answer) = std::sync::mpsc::channel();
+        let spawned = std::thread::Builder::new()
+            .name("pam-call".to_string())
+            .spawn(move || {
+                let mut handle = handle;
+                // The conversation trampoline leaves its state on this thread
+                let _ = result.send(run_call(unsafe { handle.0.as_mut() }, function));
+            });
+        if spawned.is_err() {
+            return (PamReturnCode::System_Err, None, None);
+        }
+        match answer.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                self.state.abandoned = true;
+                (PamReturnCode::Abort, None, None)
+            }
+            // The helper thread panicked outside of the conversation, e.g. in a module
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                (PamReturnCode::System_Err, None, None)
+            }
+        }
+    }
+
+    // Write a utmp entry with `record` if accounting is enabled, warning about errors
+    #[cfg(feature = "utmp")]
+    fn record_utmp<F>(&mut self, record: F)
+    where
+        F: FnOnce(&str, &str, Option<&str>) -> std::io::Result<()>,
+    {
+        if self.state.utmp_warning.is_none() || self.state.abandoned {
+            return;
+        }
+        let item = |item_type| get_item_str(self.pamh(), item_type).ok().flatten();
+        let user = item(PamItemType::User).unwrap_or("");
+        let tty = item(PamItemType::TTY).unwrap_or("");
+        let result = record(user, tty, item(PamItemType::RHost));
+        if let (Err(err), Some(warn)) = (result, self.state.utmp_warning.as_mut()) {
+            warn(&err);
+        }
+    }
+
+    // Pass the result of the last `pam_authenticate` to the hook, if any
+    fn report_auth_result(&mut self) {
+        if self.state.lifecycle_hooks.auth_result.is_none() {
+            return;
+        }
+        let item = if self.state.abandoned {
+            None
+        } else {
+            get_item_str(self.pamh(), PamItemType::User).ok().flatten()
+        };
+        let user = item
+            .or(self.state.requested_user.as_deref())
+            .unwrap_or("")
+            .to_string();
+        let code = self.error_code(self.state.last_code);
+        if let Some(hook) = self.state.lifecycle_hooks.auth_result.as_mut() {
+            run_hook(|| hook(&user, code));
+        }
+    }
+
+    fn report_session_closed(&mut self, code: PamReturnCode) {
+        if let Some(hook) = self.state.lifecycle_hooks.session_closed.as_mut() {
+            run_hook(|| hook(code));
+        }
+    }
+
+    // Pass a failed cleanup step of `drop` to the hook or log it
+    fn report_drop_error(&mut self, stage: DropStage, code: PamReturnCode) {
+        if code == PamReturnCode::Success {
+            return;
+        }
+        match self.state.lifecycle_hooks.drop_error.as_mut() {
+            Some(hook) => run_hook(|| hook(stage, code)),
+            #[cfg(feature = "log")]
+            None => log::warn!("Dropping the PAM client failed at {:?}: {:?}", stage, code),
+            #[cfg(not(feature = "log"))]
+            None => {}
+        }
+    }
+
+    // Record the failure of the last `pam_setcred` if the policy allows to continue
+    fn tolerate_setcred_failure(&mut self) -> bool {
+        if self.state.setcred_failure_policy == SetCredFailurePolicy::Fail {
+            return false;
+        }
+        // Keep the first error if both calls fail
+        if self.state.setcred_failure.is_none() {
+            self.state.setcred_failure = Some(self.error_code(self.state.last_code));
+        }
+        true
+    }
+
+    // Utility function to reset the pam handle in case of intermediate errors
+    fn reset(&mut self) -> PamResult<()> {
+        while self.state.established_credentials > 0 && !self.state.abandoned {
+            let flags = self.cred_flags(CredAction::Delete);
+            self.cleanup_call(PamOperation::SetCred, move |handle| {
+                setcred_with_flags(handle, flags)
+            });
+            self.state.established_credentials -= 1;
+        }
+        self.state.is_authenticated = false;
+        self.state.has_valid_account = false;
+        Err(self.error())
+    }
+
+    // The flags for `pam_authenticate` and `pam_acct_mgmt` as requested
+    fn auth_flags(&self) -> AuthFlags {
+        let mut flags = AuthFlags::default();
+        if self.state.silent {
+            flags = flags.with_silent();
+        }
+        if self.state.disallow_null_authtok {
+            flags = flags.with_disallow_null_authtok();
+        }
+        flags
+    }
+
+    // The flags for `pam_setcred` with `action`, silent if requested
+    fn cred_flags(&self, action: CredAction) -> SetCredFlags {
+        if self.state.silent {
+            SetCredFlags::from(action).with_silent()
+        } else {
+            SetCredFlags::from(action)
+        }
+    }
+
+    // The error for the last PAM call, `Cancelled` if the user cancelled the
+    // conversation or `Conversation` if the handler failed, even if a module reported
+    // e.g. `Auth_Err` instead
+    fn error(&self) -> PamError {
+        self.error_for(self.state.last_code)
+    }
+
+    // The error for the failed `code`, `Timeout` once the transaction was abandoned
+    fn error_for(&self, code: PamReturnCode) -> PamError {
+        if self.state.abandoned {
+            PamError::Timeout
+        } else if self.conversation.is_cancelled() {
+            PamError::Cancelled
+        } else if let Some(err) = self.state.conv_error {
+            PamError::Conversation(err)
+        } else {
+            PamError::Pam(self.error_code(code))
+        }
+    }
+
+    // The code reported to the hooks, a successful call is never turned into an error
+    fn error_code(&self, code: PamReturnCode) -> PamReturnCode {
+        if self.state.abandoned || code == PamReturnCode::Success {
+            code
+        } else if self.state.conv_error.is_some() || self.conversation.is_cancelled() {
+            PamReturnCode::Conv_Err
+        } else {
+            code
+        }
+    }
+}
+
+impl<C: conv::Conversation + conv::CredentialTarget> Client<C> {
+    /// Preset the username and password in the conversation handler, see
+    /// `CredentialTarget`
+    ///
+    /// The password is wrapped in a `SecretString` right away.
+    pub fn set_credentials<P: Into<String>>(&mut self, username: &str, password: P) {
+        let conversation = self.conversation_mut();
+        conversation.set_username(username);
+        conversation.set_password(SecretString::new(password.into()));
+    }
+
+    /// Like `set_user`, but also answer the login prompt with `user` from now on
+    pub fn switch_user(&mut self, user: &str) -> PamResult<()> {
+        self.set_user(user)?;
+        self.conversation_mut().set_username(user);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "watchdog")]
+impl<C: conv::Conversation + Send> Client<C> {
+    /// Give up on PAM calls which don't return within `timeout`, e.g. because a module
+    /// waits for an unreachable LDAP or Kerberos server
+    ///
+    /// **This abandons the transaction.** Every PAM call runs on a helper thread while
+    /// the calling thread waits at most `timeout`, including the time the conversation
+    /// handler takes. If the deadline passes, the call fails with `PamError::Timeout`,
+    /// but the helper thread keeps running in libpam.
+    /// It still uses the handle and the conversation handler, so they are leaked: the
+    /// session is not closed, the credentials are not deleted and `pam_end` is never
+    /// called. Afterwards, all PAM calls and item accessors fail with `Timeout` without
+    /// calling libpam, `last_error` still describes the timeout, and only `handle` and
+    /// `conversation` panic. Start a new transaction with a new `Client` instead.
+    /// The cleanup steps of `drop` are bounded the same way, so dropping the client
+    /// leaks it if one of them times out.
+    ///
+    /// Only use this if hanging is worse than leaking, e.g. for a login screen. Modules
+    /// have to be thread-safe as they are called from different threads.
+    ///
+    /// ```no_run
+    /// use pam::Client;
+    /// use std::time::Duration;
+    ///
+    /// let mut client = Client::with_password("system-auth")
+    ///         .expect("Failed to init PAM client.");
+    /// client.conversation_mut().set_credentials("login", "password");
+    /// client.set_call_timeout(Duration::from_secs(30));
+    /// match client.authenticate() {
+    ///     Err(err) if err.is_timeout() => eprintln!("The service did not answer in time"),
+    ///     result => result.expect("Authentication failed!"),
+    /// }
+    /// ```
+    pub fn set_call_timeout(&mut self, timeout: Duration) {
+        self.state.call_timeout = Some(timeout);
+    }
+
+    /// Whether a PAM call timed out, so the transaction was abandoned, see
+    /// `set_call_timeout`
+    pub fn has_timed_out(&self) -> bool {
+        self.state.abandoned
+    }
+}
+
+// Reject names `pam_putenv` would misinterpret, e.g. "A=B" as the variable A
+fn check_env_name(name: &str) -> PamResult<()> {
+    if name.is_empty() || name.contains('=') {
+        return Err(PamReturnCode::Bad_Item.into());
+    }
+    Ok(())
+}
+
+type CallResult = (
+    PamReturnCode,
+    Option<Box<dyn Any + Send>>,
+    Option<conv::ConvError>,
+);
+
+// Run a PAM function and take the panic and the failure of the conversation handler,
+// which the trampoline stores for the current thread
+fn run_call<F>(handle: &mut PamHandle, function: F) -> CallResult
+where
+    F: FnOnce(&mut PamHandle) -> PamReturnCode,
+{
+    // Don't report what a conversation outside of a call left behind, e.g. in `drop`
+    drop(conv::take_panic());
+    conv::take_conv_failure();
+    let code = function(handle);
+    (code, conv::take_panic(), conv::take_conv_failure())
+}
+
+// Run a lifecycle hook, ignoring panics so they can't skip the bookkeeping of the caller
+fn run_hook<F: FnOnce()>(hook: F) {
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(hook));
+}
+
+// The terminal of the first of `fds` which refers to one, e.g. "/dev/pts/0"
+fn terminal_name(fds: &[c_int]) -> Option<String> {
+    fds.iter().find_map(|&fd| {
+        let mut buf = [0 as c_char; 256];
+        if unsafe { libc::ttyname_r(fd, buf.as_mut_ptr(), buf.len()) } != 0 {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        name.to_str().ok().map(str::to_string)
+    })
+}
+
+/// An open session, see `Client::session`
+///
+/// Dropping it closes the session and deletes the credentials, ignoring errors.
+pub struct Session<'s, C: conv::Conversation> {
+    client: &'s mut Client<C>,
+}
+
+impl<'s, C: conv::Conversation> Session<'s, C> {
+    /// The variables of the PAM environment, see `Client::environment`
+    pub fn environment(&mut self) -> PamResult<HashMap<OsString, OsString>> {
+        self.client.environment()
+    }
+
+    /// Immutable access to the client of this session
+    pub fn client(&self) -> &Client<C> {
+        self.client
+    }
+
+    /// Close the session and delete the credentials, see `Client::close_session`
+    pub fn close(self) -> PamResult<()> {
+        // Drop doesn't close the session again, as it is marked as closed either way
+        self.client.close_session()
+    }
+}
+
+impl<'s, C: conv::Conversation> Drop for Session<'s, C> {
+    fn drop(&mut self) {
+        if self.client.state.has_open_session {
+            let _ = self.client.close_session();
+        }
+    }
+}
+
+impl<C: conv::Conversation> Drop for Client<C> {
+    fn drop(&mut self) {
+        if self.state.abandoned {
+            // The timed out call or the owner of the raw handle may still use the handle
+            // and the handler, so leak them
+            return;
+        }
+        if self.state.has_open_session && self.close_on_drop {
+            #[cfg(feature = "utmp")]
+            self.record_utmp(|_, tty, _| crate::session::record_logout(tty, std::process::id()));
+            let silent = self.state.silent;
+            let code = self.drop_call(move |handle| close_session(handle, silent));
+            // Like `close_session`, a failed conversation is reported as `Conv_Err`
+            self.report_session_closed(self.error_code(code));
+            self.report_drop_error(DropStage::CloseSession, code);
+        }
+        // A step which times out abandons the client like any other call, see
+        // `set_call_timeout`
+        for _ in 0..self.state.established_credentials {
+            if self.state.abandoned {
+                break;
+            }
+            let flags = self.cred_flags(CredAction::Delete);
+            let code = self.drop_call(move |handle| setcred_with_flags(handle, flags));
+            self.report_drop_error(DropStage::DeleteCredentials, code);
+        }
+        if self.state.end_on_drop && !self.state.abandoned {
+            let status = self.state.end_status.unwrap_or(self.state.last_code);
+            let code = self.drop_call(move |handle| end(handle, status));
+            self.report_drop_error(DropStage::End, code);
+        }
+        if !self.state.abandoned {
+            unsafe { ManuallyDrop::drop(&mut self.conversation) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn account_error_codes() {
+        for code in &[
+            PamReturnCode::New_Authtok_Reqd,
+            PamReturnCode::Acct_Expired,
+            PamReturnCode::Perm_Denied,
+            PamReturnCode::User_Unknown,
+            PamReturnCode::System_Err,
+        ] {
+            assert_eq!(PamReturnCode::from(AccountError::from(*code)), *code);
+        }
+        assert_eq!(
+            AccountError::from(PamReturnCode::Auth_Err),
+            AccountError::Denied
+        );
+        assert_eq!(
+            AccountError::from(PamReturnCode::Conv_Err),
+            AccountError::Other(PamReturnCode::Conv_Err)
+        );
+    }
+
+    #[test]
+    fn no_terminal() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(terminal_name(&fds), None);
+        assert_eq!(terminal_name(&[]), None);
+        for fd in &fds {
+            unsafe { libc::close(*fd) };
+        }
+    }
+
+    #[test]
+    fn pseudo_terminal() {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if master < 0 {
+            // No pseudo terminals available, e.g. in a container without /dev/pts
+            return;
+        }
+        assert_eq!(unsafe { libc::grantpt(master) }, 0);
+        assert_eq!(unsafe { libc::unlockpt(master) }, 0);
+        let path = unsafe { CStr::from_ptr(libc::ptsname(master)) }.to_owned();
+        let slave = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY) };
+        assert!(slave >= 0);
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(
+            terminal_name(&[fds[0], slave]),
+            Some(path.to_str().unwrap().to_string())
+        );
+        for fd in &[fds[0], fds[1], slave, master] {
+            unsafe { libc::close(*fd) };
         }
-        let code = setcred(self.handle, PamFlag::Delete_Cred);
-        end(self.handle, code);
     }
 }
@@ -1,9 +1,147 @@
-use libc::{c_int, c_void, calloc, free, size_t, strdup};
+use libc::{c_char, c_int, c_void, calloc, free, malloc, size_t};
 
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
-use std::mem;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use std::{fmt, mem, ptr, slice, thread};
 
-use crate::{ffi::pam_conv, PamMessage, PamMessageStyle, PamResponse, PamReturnCode};
+#[cfg(feature = "async")]
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedSender},
+        oneshot,
+    },
+    future::{self, Either},
+    StreamExt,
+};
+#[cfg(feature = "async")]
+use std::{future::Future, pin::Pin};
+
+use crate::{
+    ffi::pam_conv,
+    functions::{wipe_c_str, wipe_string},
+    PamMessage, PamMessageStyle, PamResponse, PamReturnCode,
+};
+
+/// Maximum length of a text response in bytes, excluding the terminating nul
+///
+/// Longer responses fail the conversation, see `Conversation::truncate_responses`.
+pub const PAM_MAX_RESP_SIZE: usize = 512;
+
+/// Error returned by a `Conversation` handler which could not answer a message
+///
+/// It is reported to the module as `PAM_CONV_ERR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvError;
+
+impl fmt::Display for ConvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("conversation failed")
+    }
+}
+
+impl std::error::Error for ConvError {}
+
+/// A single message of a conversation
+///
+/// Modules pass these to `converse` and conversation handlers receive them in
+/// `Conversation::converse`. Messages with a style unknown to this crate have
+/// `Prompt_Echo_On` as `style`, the actual value is available via `raw_style`.
+/// Invalid UTF-8 in messages received from PAM is replaced in `text`, the exact
+/// bytes are available via `bytes`.
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+    pub style: PamMessageStyle,
+    pub text: &'a str,
+    raw_style: c_int,
+    raw: Option<&'a CStr>,
+    data: Option<&'a [u8]>,
+    context: MessageContext,
+}
+
+/// The position of a `Message` within a single conversation call
+///
+/// Handlers can use it to display the messages of a call as one dialog, e.g.
+/// "step 2 of 3".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageContext {
+    /// Index of the message in the call, starting at 0
+    pub index: usize,
+    /// Number of messages in the call
+    pub total: usize,
+}
+
+impl<'a> Message<'a> {
+    /// Create a new `Message` with the given style and text
+    pub fn new(style: PamMessageStyle, text: &'a str) -> Message<'a> {
+        Message {
+            style,
+            text,
+            raw_style: style as c_int,
+            raw: None,
+            data: None,
+            context: MessageContext { index: 0, total: 1 },
+        }
+    }
+
+    /// The text as sent by PAM, which is not necessarily valid UTF-8
+    ///
+    /// Modules generate their messages according to the locale, so e.g. prompts
+    /// might be encoded in ISO-8859-1.
+    pub fn bytes(&self) -> &'a [u8] {
+        match self.raw {
+            Some(raw) => raw.to_bytes(),
+            None => self.text.as_bytes(),
+        }
+    }
+
+    /// The numeric style as sent by PAM, e.g. for vendor-specific styles
+    pub fn raw_style(&self) -> c_int {
+        self.raw_style
+    }
+
+    /// Whether the style is one of the `PamMessageStyle` variants
+    pub fn is_known_style(&self) -> bool {
+        self.style as c_int == self.raw_style
+    }
+
+    /// The payload of a `Binary_Prompt` message received from PAM
+    ///
+    /// The text of binary messages is empty.
+    pub fn data(&self) -> Option<&'a [u8]> {
+        self.data
+    }
+
+    /// The position of the message within the conversation call it was received in
+    ///
+    /// Messages created via `new` are the only message of their call.
+    pub fn context(&self) -> MessageContext {
+        self.context
+    }
+}
+
+/// The response of a conversation handler to a single `Message`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// No response, e.g. for informational messages
+    None,
+    /// The answer to a text prompt
+    Text(String),
+    /// The answer to a `Binary_Prompt` message
+    Binary(Vec<u8>),
+}
 
 /// A trait representing the PAM authentification conversation
 ///
@@ -15,127 +153,3972 @@ use crate::{ffi::pam_conv, PamMessage, PamMessageStyle, PamResponse, PamReturnCo
 /// This is the trait to implement if you want to customize the conversation with
 /// PAM. If you just want a simple login/password authentication, you can use the
 /// `PasswordConv` implementation provided by this crate.
+///
+/// Prompts which can't be answered, e.g. because the user closed the dialog, should
+/// return a `ConvError` instead of an empty answer. It is reported to the module as
+/// `PAM_CONV_ERR`, while an empty answer is usually treated as a wrong password.
+#[allow(unused_variables)]
 pub trait Conversation {
     /// PAM requests a value that should be echoed to the user as they type it
     ///
     /// This would typically be the username. The exact question is provided as the
     /// `msg` argument if you wish to display it to your user.
-    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()>;
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError>;
     /// PAM requests a value that should be typed blindly by the user
     ///
     /// This would typically be the password. The exact question is provided as the
     /// `msg` argument if you wish to display it to your user.
-    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()>;
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError>;
     /// This is an informational message from PAM
-    fn info(&mut self, msg: &CStr);
+    ///
+    /// Forwards to `text_info` by default.
+    fn info(&mut self, msg: &CStr) {
+        self.text_info(&msg.to_string_lossy());
+    }
     /// This is an error message from PAM
-    fn error(&mut self, msg: &CStr);
+    ///
+    /// Forwards to `error_msg` by default.
+    fn error(&mut self, msg: &CStr) {
+        self.error_msg(&msg.to_string_lossy());
+    }
+    /// PAM sent an informational message (`PAM_TEXT_INFO`) to display to the user
+    ///
+    /// This could be something like "Password expires in 3 days". Does nothing by default.
+    fn text_info(&mut self, msg: &str) {}
+    /// PAM sent an error message (`PAM_ERROR_MSG`) to display to the user
+    ///
+    /// This could be something like "Account locked". Does nothing by default.
+    fn error_msg(&mut self, msg: &str) {}
+    /// PAM asks a multiple choice question (`PAM_RADIO_TYPE`)
+    ///
+    /// This Linux-PAM extension is used for yes/no/maybe questions, e.g. by some
+    /// two-factor modules. The answer is passed to the module verbatim, so it should be
+    /// one of the options offered in `msg`. Falls back to `prompt_echo` by default.
+    fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+        let msg = CString::new(msg).map_err(|_| ConvError)?;
+        self.prompt_echo(&msg)
+    }
+    /// PAM requests binary data (`PAM_BINARY_PROMPT`), e.g. from a smartcard agent
+    ///
+    /// `data` is the payload of the message without its 4 byte length prefix. For
+    /// libpamc agents this starts with the control byte. The response is sent back
+    /// to the module in the same encoding. Fails by default.
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ConvError> {
+        Err(ConvError)
+    }
+    /// PAM sent a message with a style unknown to this crate
+    ///
+    /// Linux-PAM leaves room for vendor-specific styles, `style` is the numeric value
+    /// sent by the module. Return `None` if the message does not need an answer.
+    /// Fails by default.
+    fn unknown_style(&mut self, style: c_int, msg: &str) -> Result<Option<String>, ConvError> {
+        Err(ConvError)
+    }
+    /// Whether the user cancelled the conversation, e.g. via a `CancelToken`
+    ///
+    /// `Client` then reports a failed PAM call as `PamError::Cancelled` instead of the
+    /// code returned by the modules, so a cancelled attempt can be told apart from a
+    /// wrong password. Successful calls are not affected.
+    /// Returns `false` by default.
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+    /// Whether text responses longer than `PAM_MAX_RESP_SIZE` bytes are truncated
+    ///
+    /// Such responses fail the conversation by default. If this returns `true`, they
+    /// are cut at the last character boundary within the limit instead.
+    fn truncate_responses(&self) -> bool {
+        false
+    }
+    /// Wipe the stored credentials, they are not needed anymore
+    ///
+    /// `Client` calls this once `authenticate` succeeded if `auto_clear_credentials`
+    /// is set. Does nothing by default.
+    fn clear_credentials(&mut self) {}
+    /// PAM sends all `messages` of a single conversation call
+    ///
+    /// Modules send e.g. an informational message together with the prompt it
+    /// explains, which handlers can display at once by overriding this method. The
+    /// responses must be returned in the order of the messages. By default each
+    /// message is passed to the method for its style, stopping at the first error.
+    fn converse(&mut self, messages: &[Message<'_>]) -> Result<Vec<Response>, ConvError> {
+        messages.iter().map(|msg| respond(self, msg)).collect()
+    }
+}
+
+// Implement `Conversation` for a pointer type by forwarding every method to the pointee
+macro_rules! forward_conversation {
+    ($ty:ty) => {
+        impl<C: Conversation + ?Sized> Conversation for $ty {
+            fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+                (**self).prompt_echo(msg)
+            }
+            fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+                (**self).prompt_blind(msg)
+            }
+            fn info(&mut self, msg: &CStr) {
+                (**self).info(msg)
+            }
+            fn error(&mut self, msg: &CStr) {
+                (**self).error(msg)
+            }
+            fn text_info(&mut self, msg: &str) {
+                (**self).text_info(msg)
+            }
+            fn error_msg(&mut self, msg: &str) {
+                (**self).error_msg(msg)
+            }
+            fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+                (**self).radio_prompt(msg)
+            }
+            fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ConvError> {
+                (**self).binary_prompt(data)
+            }
+            fn unknown_style(
+                &mut self,
+                style: c_int,
+                msg: &str,
+            ) -> Result<Option<String>, ConvError> {
+                (**self).unknown_style(style, msg)
+            }
+            fn is_cancelled(&self) -> bool {
+                (**self).is_cancelled()
+            }
+            fn truncate_responses(&self) -> bool {
+                (**self).truncate_responses()
+            }
+            fn clear_credentials(&mut self) {
+                (**self).clear_credentials()
+            }
+            fn converse(&mut self, messages: &[Message<'_>]) -> Result<Vec<Response>, ConvError> {
+                (**self).converse(messages)
+            }
+        }
+    };
+}
+
+// Allows to choose the handler at runtime, e.g. `Client<Box<dyn Conversation>>`
+forward_conversation!(Box<C>);
+forward_conversation!(&mut C);
+
+/// A conversation handler which can be given the username and password upfront
+///
+/// This lets generic code preset the credentials without knowing the handler, see
+/// `Client::switch_user` and `Client::set_credentials`:
+///
+/// ```no_run
+/// use pam::{Client, Conversation, CredentialTarget};
+///
+/// fn login<C: Conversation + CredentialTarget>(conv: C, user: &str) -> bool {
+///     let mut client = match Client::with_conversation("system-auth", conv) {
+///         Ok(client) => client,
+///         Err(_) => return false,
+///     };
+///     client.conversation_mut().set_username(user);
+///     client.authenticate().is_ok()
+/// }
+/// ```
+#[cfg(feature = "client")]
+pub trait CredentialTarget {
+    /// Answer the login prompt with `username`
+    fn set_username(&mut self, username: &str);
+    /// Answer the password prompt with `password`
+    fn set_password(&mut self, password: secrecy::SecretString);
+}
+
+#[cfg(feature = "client")]
+impl<T: CredentialTarget + ?Sized> CredentialTarget for Box<T> {
+    fn set_username(&mut self, username: &str) {
+        (**self).set_username(username)
+    }
+    fn set_password(&mut self, password: secrecy::SecretString) {
+        (**self).set_password(password)
+    }
+}
+
+#[cfg(feature = "client")]
+impl<T: CredentialTarget + ?Sized> CredentialTarget for &mut T {
+    fn set_username(&mut self, username: &str) {
+        (**self).set_username(username)
+    }
+    fn set_password(&mut self, password: secrecy::SecretString) {
+        (**self).set_password(password)
+    }
+}
+
+// Pass `msg` to the method of `conv` for its style
+fn respond<C: Conversation + ?Sized>(conv: &mut C, msg: &Message) -> Result<Response, ConvError> {
+    if let Some(data) = msg.data {
+        return conv.binary_prompt(data).map(Response::Binary);
+    }
+    if !msg.is_known_style() {
+        let answer = conv.unknown_style(msg.raw_style, msg.text)?;
+        return Ok(answer.map_or(Response::None, Response::Text));
+    }
+    // Pass the exact bytes to the handler
+    let text = match msg.raw {
+        Some(raw) => Cow::Borrowed(raw),
+        None => Cow::Owned(CString::new(msg.text).map_err(|_| ConvError)?),
+    };
+    match msg.style {
+        PamMessageStyle::Prompt_Echo_On => conv.prompt_echo(&text).map(Response::Text),
+        PamMessageStyle::Prompt_Echo_Off => conv.prompt_blind(&text).map(Response::Text),
+        PamMessageStyle::Text_Info => {
+            conv.info(&text);
+            Ok(Response::None)
+        }
+        PamMessageStyle::Error_Msg => {
+            // Error messages are meant for the user and don't fail the conversation
+            conv.error(&text);
+            Ok(Response::None)
+        }
+        PamMessageStyle::Radio_Type => conv.radio_prompt(msg.text).map(Response::Text),
+        // Binary prompts without a valid payload
+        PamMessageStyle::Binary_Prompt => Err(ConvError),
+    }
 }
 
+/// A closure answering a prompt, see `PasswordConv::set_unmatched`
+pub type PromptAnswer = dyn FnMut(&str) -> Result<String, ConvError> + Send;
+// Whether a prompt asks for the password, see `PasswordConv::set_password_prompt_fn`
+type PasswordPrompt = dyn Fn(&str) -> bool + Send;
+
 /// A minimalistic conversation handler, that uses given login and password
 ///
 /// This conversation handler is not really interactive, but simply returns to
-/// PAM the value that have been set using the `set_credentials` method.
+/// PAM the value that have been set using the `set_credentials` method. The
+/// credentials are wiped from memory when they are replaced or the handler is dropped.
+/// Informational and error messages sent by PAM are collected and can be
+/// retrieved via `messages` to display them afterwards. Radio prompts can't be
+/// answered by it and fail the conversation.
+///
+/// PAM asks for the login with a `Prompt_Echo_On` message if no user was passed to
+/// `pam_start`. Without credentials set, such prompts fail the conversation unless
+/// a fallback is set via `set_login_fallback`.
+///
+/// By default every `Prompt_Echo_Off` message is answered with the password. If the
+/// PAM stack asks for other secrets as well, or the prompt should be checked before
+/// releasing the password, set a matcher via `set_password_prompt` and decide what
+/// happens with the other prompts via `set_unmatched`:
+///
+/// ```no_run
+/// use pam::{Client, ConvError, PasswordConv, Unmatched};
+///
+/// let mut conv = PasswordConv::new();
+/// conv.set_credentials("login", "password");
+/// conv.set_password_prompt("Password");
+/// conv.set_unmatched(Unmatched::Passthrough(Box::new(|prompt: &str| {
+///     if prompt.contains("Verification code") {
+///         Ok("123456".to_string())
+///     } else {
+///         Err(ConvError)
+///     }
+/// })));
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
 pub struct PasswordConv {
-    login: String,
+    login: Option<String>,
     passwd: String,
+    cleared: bool,
+    passwd_fn: Option<Box<dyn FnMut() -> Result<String, ConvError> + Send>>,
+    messages: Vec<(PamMessageStyle, String)>,
+    login_fallback: Option<Box<PromptAnswer>>,
+    password_prompt: Option<Box<PasswordPrompt>>,
+    unmatched: Unmatched<Box<PromptAnswer>>,
 }
 
 impl PasswordConv {
     /// Create a new `PasswordConv` handler
-    pub(crate) fn new() -> PasswordConv {
+    pub fn new() -> PasswordConv {
         PasswordConv {
-            login: String::new(),
+            login: None,
             passwd: String::new(),
+            cleared: false,
+            passwd_fn: None,
+            messages: Vec::new(),
+            login_fallback: None,
+            password_prompt: None,
+            unmatched: Unmatched::Fail,
         }
     }
 
+    /// Create a new `PasswordConv` handler retrieving the password only when asked for it
+    ///
+    /// `password` is called for every `Prompt_Echo_Off` message answered with the
+    /// password, so it is not kept in memory if PAM never asks for it, e.g. because a
+    /// module uses a ticket cache. The answer is wiped once it was passed to libpam.
+    /// An error of `password` fails the conversation.
+    ///
+    /// ```no_run
+    /// use pam::client::SecretString;
+    /// use pam::{Client, PasswordConv};
+    ///
+    /// let conv = PasswordConv::with_password_fn("login", || {
+    ///     // Ask a keyring or the user
+    ///     Ok(SecretString::new("password".to_string()))
+    /// });
+    /// let mut client = Client::with_conversation("system-auth", conv)
+    ///         .expect("Failed to init PAM client.");
+    /// client.authenticate().expect("Authentication failed!");
+    /// ```
+    #[cfg(feature = "secrecy")]
+    pub fn with_password_fn<U, F>(login: U, mut password: F) -> PasswordConv
+    where
+        U: Into<String>,
+        F: FnMut() -> Result<secrecy::SecretString, ConvError> + Send + 'static,
+    {
+        use secrecy::ExposeSecret;
+
+        let mut conv = PasswordConv::new();
+        conv.login = Some(login.into());
+        conv.passwd_fn = Some(Box::new(move || {
+            password().map(|password| password.expose_secret().clone())
+        }));
+        conv
+    }
+
     /// Set the credentials that this handler will provide to PAM
+    ///
+    /// The previous credentials are wiped from memory.
     pub fn set_credentials<U: Into<String>, V: Into<String>>(&mut self, login: U, password: V) {
-        self.login = login.into();
+        self.wipe_credentials();
+        self.login = Some(login.into());
         self.passwd = password.into();
+        self.passwd_fn = None;
+        self.cleared = false;
+    }
+
+    /// Set the credentials like `set_credentials`, keeping the password wrapped
+    ///
+    /// The password is only exposed to build the answer to a password prompt, which is
+    /// wiped once it was passed to libpam.
+    ///
+    /// ```no_run
+    /// use pam::client::SecretString;
+    /// use pam::{Client, PasswordConv};
+    ///
+    /// let mut conv = PasswordConv::new();
+    /// conv.set_secret_credentials("login", SecretString::new("password".to_string()));
+    /// let mut client = Client::with_conversation("system-auth", conv)
+    ///         .expect("Failed to init PAM client.");
+    /// client.authenticate().expect("Authentication failed!");
+    /// ```
+    #[cfg(feature = "secrecy")]
+    pub fn set_secret_credentials<U: Into<String>>(
+        &mut self,
+        login: U,
+        password: secrecy::SecretString,
+    ) {
+        use secrecy::ExposeSecret;
+
+        self.wipe_credentials();
+        self.login = Some(login.into());
+        self.passwd = String::new();
+        self.passwd_fn = Some(Box::new(move || Ok(password.expose_secret().clone())));
+        self.cleared = false;
+    }
+
+    /// Whether credentials were set via `set_credentials`, `set_secret_credentials` or
+    /// `with_password_fn`
+    pub fn has_credentials(&self) -> bool {
+        self.login.is_some()
+    }
+
+    /// Call `f` with the prompt to get the login if no credentials are set
+    pub fn set_login_fallback<F>(&mut self, f: F)
+    where
+        F: FnMut(&str) -> Result<String, ConvError> + Send + 'static,
+    {
+        self.login_fallback = Some(Box::new(f));
+    }
+
+    /// The informational and error messages sent by PAM so far in order
+    pub fn messages(&self) -> &[(PamMessageStyle, String)] {
+        &self.messages
+    }
+
+    /// Remove and return the messages sent by PAM so far
+    pub fn take_messages(&mut self) -> Vec<(PamMessageStyle, String)> {
+        mem::take(&mut self.messages)
+    }
+
+    /// Only answer `Prompt_Echo_Off` messages matching `pattern` with the password
+    ///
+    /// Other prompts are handled as set via `set_unmatched`.
+    pub fn set_password_prompt<P: Into<Pattern>>(&mut self, pattern: P) {
+        let pattern = pattern.into();
+        self.password_prompt = Some(Box::new(move |prompt: &str| pattern.matches(prompt)));
+    }
+
+    /// Only answer `Prompt_Echo_Off` messages for which `f` returns true with the password
+    ///
+    /// Other prompts are handled as set via `set_unmatched`.
+    pub fn set_password_prompt_fn<F>(&mut self, f: F)
+    where
+        F: Fn(&str) -> bool + Send + 'static,
+    {
+        self.password_prompt = Some(Box::new(f));
+    }
+
+    /// Set what happens with prompts which don't match the password prompt
+    ///
+    /// They fail the conversation by default. With `Unmatched::Passthrough` the
+    /// closure is called with the prompt to get the answer.
+    pub fn set_unmatched(&mut self, unmatched: Unmatched<Box<PromptAnswer>>) {
+        self.unmatched = unmatched;
+    }
+
+    fn wipe_credentials(&mut self) {
+        if let Some(ref mut login) = self.login {
+            wipe_string(login);
+        }
+        wipe_string(&mut self.passwd);
+    }
+}
+
+impl Drop for PasswordConv {
+    fn drop(&mut self) {
+        self.wipe_credentials();
+    }
+}
+
+// Never print the password
+impl fmt::Debug for PasswordConv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PasswordConv")
+            .field("login", &self.login)
+            .field("messages", &self.messages)
+            .finish()
+    }
+}
+
+impl Default for PasswordConv {
+    fn default() -> PasswordConv {
+        PasswordConv::new()
     }
 }
 
 impl Conversation for PasswordConv {
-    fn prompt_echo(&mut self, _msg: &CStr) -> Result<CString, ()> {
-        CString::new(self.login.clone()).map_err(|_| ())
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        match (&self.login, &mut self.login_fallback) {
+            (Some(login), _) => Ok(login.clone()),
+            (None, Some(fallback)) => fallback(&msg.to_string_lossy()),
+            (None, None) => Err(ConvError),
+        }
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        let msg = msg.to_string_lossy();
+        let matched = self
+            .password_prompt
+            .as_ref()
+            .map_or(true, |matches| matches(&msg));
+        if matched {
+            if self.cleared {
+                return Err(ConvError);
+            }
+            return match self.passwd_fn {
+                Some(ref mut password) => password(),
+                None => Ok(self.passwd.clone()),
+            };
+        }
+        match self.unmatched {
+            Unmatched::Fail => Err(ConvError),
+            Unmatched::Empty => Ok(String::new()),
+            Unmatched::Passthrough(ref mut f) => f(&msg),
+        }
     }
-    fn prompt_blind(&mut self, _msg: &CStr) -> Result<CString, ()> {
-        CString::new(self.passwd.clone()).map_err(|_| ())
+    fn text_info(&mut self, msg: &str) {
+        self.messages
+            .push((PamMessageStyle::Text_Info, msg.to_string()));
     }
-    fn info(&mut self, _msg: &CStr) {}
-    fn error(&mut self, msg: &CStr) {
-        eprintln!("[PAM ERROR] {}", msg.to_string_lossy());
+    fn error_msg(&mut self, msg: &str) {
+        self.messages
+            .push((PamMessageStyle::Error_Msg, msg.to_string()));
+    }
+    fn radio_prompt(&mut self, _msg: &str) -> Result<String, ConvError> {
+        Err(ConvError)
+    }
+    // Keep the login, e.g. for `pam_chauthtok`, but fail password prompts until new
+    // credentials are set
+    fn clear_credentials(&mut self) {
+        wipe_string(&mut self.passwd);
+        self.passwd_fn = None;
+        self.cleared = true;
     }
 }
 
-pub(crate) fn into_pam_conv<C: Conversation>(conv: &mut C) -> pam_conv {
-    pam_conv {
-        conv: Some(converse::<C>),
-        appdata_ptr: conv as *mut C as *mut c_void,
+// Like `set_secret_credentials`, but the login and the password can be set separately
+#[cfg(feature = "client")]
+impl CredentialTarget for PasswordConv {
+    fn set_username(&mut self, username: &str) {
+        if let Some(ref mut login) = self.login {
+            wipe_string(login);
+        }
+        self.login = Some(username.to_string());
+    }
+    fn set_password(&mut self, password: secrecy::SecretString) {
+        use secrecy::ExposeSecret;
+
+        wipe_string(&mut self.passwd);
+        self.passwd_fn = Some(Box::new(move || Ok(password.expose_secret().clone())));
+        self.cleared = false;
     }
 }
 
-// FIXME: verify this
-pub(crate) unsafe extern "C" fn converse<C: Conversation>(
-    num_msg: c_int,
-    msg: *mut *const PamMessage,
-    out_resp: *mut *mut PamResponse,
-    appdata_ptr: *mut c_void,
-) -> c_int {
-    // allocate space for responses
-    let resp =
-        calloc(num_msg as usize, mem::size_of::<PamResponse>() as size_t) as *mut PamResponse;
-    if resp.is_null() {
-        return PamReturnCode::Buf_Err as c_int;
+/// A conversation handler calling a closure for every message
+///
+/// The closure gets the style and text of each message and returns the answer for
+/// prompts. Its result for informational and error messages is ignored. A prompt
+/// answered with `None` fails the conversation. Binary prompts are not supported.
+///
+/// PAM usually asks for the login with a `Prompt_Echo_On` message. If the login is
+/// known upfront, set it via `with_login` to answer these prompts without calling
+/// the closure.
+///
+/// ```no_run
+/// use pam::{Client, FnConv, PamMessageStyle};
+///
+/// let conv = FnConv::new(|style, msg| match style {
+///     PamMessageStyle::Prompt_Echo_Off => Ok(Some("password".to_string())),
+///     _ => {
+///         println!("{}", msg);
+///         Ok(None)
+///     }
+/// })
+/// .with_login("login");
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+pub struct FnConv<F> {
+    login: Option<String>,
+    f: F,
+}
+
+impl<F> FnConv<F>
+where
+    F: FnMut(PamMessageStyle, &str) -> Result<Option<String>, ConvError>,
+{
+    /// Create a new `FnConv` handler calling `f` for every message
+    pub fn new(f: F) -> FnConv<F> {
+        FnConv { login: None, f }
     }
 
-    let handler = &mut *(appdata_ptr as *mut C);
+    /// Answer `Prompt_Echo_On` messages with `login` instead of calling the closure
+    pub fn with_login<S: Into<String>>(mut self, login: S) -> FnConv<F> {
+        self.login = Some(login.into());
+        self
+    }
 
-    let mut result: PamReturnCode = PamReturnCode::Success;
-    for i in 0..num_msg as isize {
-        // get indexed values
-        // FIXME: check this
-        let m: &mut PamMessage = &mut *(*(msg.offset(i)) as *mut PamMessage);
-        let r: &mut PamResponse = &mut *(resp.offset(i));
-
-        let msg = CStr::from_ptr(m.msg);
-        // match on msg_style
-        match PamMessageStyle::from(m.msg_style) {
-            PamMessageStyle::Prompt_Echo_On => {
-                if let Ok(handler_response) = handler.prompt_echo(msg) {
-                    r.resp = strdup(handler_response.as_ptr());
-                } else {
-                    result = PamReturnCode::Conv_Err;
-                }
-            }
-            PamMessageStyle::Prompt_Echo_Off => {
-                if let Ok(handler_response) = handler.prompt_blind(msg) {
-                    r.resp = strdup(handler_response.as_ptr());
-                } else {
-                    result = PamReturnCode::Conv_Err;
+    // Call the closure for a prompt which has to be answered
+    fn answer(&mut self, style: PamMessageStyle, msg: &str) -> Result<String, ConvError> {
+        (self.f)(style, msg)?.ok_or(ConvError)
+    }
+}
+
+impl<F> Conversation for FnConv<F>
+where
+    F: FnMut(PamMessageStyle, &str) -> Result<Option<String>, ConvError>,
+{
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        match self.login {
+            Some(ref login) => Ok(login.clone()),
+            None => self.answer(PamMessageStyle::Prompt_Echo_On, &msg.to_string_lossy()),
+        }
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.answer(PamMessageStyle::Prompt_Echo_Off, &msg.to_string_lossy())
+    }
+    fn text_info(&mut self, msg: &str) {
+        let _ = (self.f)(PamMessageStyle::Text_Info, msg);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        let _ = (self.f)(PamMessageStyle::Error_Msg, msg);
+    }
+    fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+        self.answer(PamMessageStyle::Radio_Type, msg)
+    }
+}
+
+/// A conversation handler recording the messages passed to another handler
+///
+/// All calls are forwarded to the wrapped handler. The transcript contains the
+/// style and text of every message and whether the wrapped handler answered it.
+/// The answers themselves are never recorded. The wrapped handler is reachable
+/// via `Deref`, so e.g. `set_credentials` can still be called:
+///
+/// ```no_run
+/// use pam::{Client, PasswordConv, Recorder};
+///
+/// let conv = Recorder::new(PasswordConv::new());
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// client.conversation_mut().set_credentials("login", "password");
+/// let result = client.authenticate();
+/// for (style, text, responded) in client.conversation().transcript() {
+///     println!("{:?} {:?} answered: {}", style, text, responded);
+/// }
+/// result.expect("Authentication failed!");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Recorder<C> {
+    inner: C,
+    transcript: Vec<(PamMessageStyle, String, bool)>,
+}
+
+impl<C: Conversation> Recorder<C> {
+    /// Create a new `Recorder` forwarding to `inner`
+    pub fn new(inner: C) -> Recorder<C> {
+        Recorder {
+            inner,
+            transcript: Vec::new(),
+        }
+    }
+
+    /// The recorded `(style, text, responded)` entries in order
+    pub fn transcript(&self) -> &[(PamMessageStyle, String, bool)] {
+        &self.transcript
+    }
+
+    /// Remove all recorded entries
+    pub fn clear(&mut self) {
+        self.transcript.clear();
+    }
+
+    /// The wrapped handler
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn record(&mut self, style: PamMessageStyle, text: &str, responded: bool) {
+        self.transcript.push((style, text.to_string(), responded));
+    }
+}
+
+impl<C> Deref for Recorder<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> DerefMut for Recorder<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+}
+
+#[cfg(feature = "client")]
+impl<C: CredentialTarget> CredentialTarget for Recorder<C> {
+    fn set_username(&mut self, username: &str) {
+        self.inner.set_username(username)
+    }
+    fn set_password(&mut self, password: secrecy::SecretString) {
+        self.inner.set_password(password)
+    }
+}
+
+impl<C: Conversation> Conversation for Recorder<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        let result = self.inner.prompt_echo(msg);
+        let style = PamMessageStyle::Prompt_Echo_On;
+        self.record(style, &msg.to_string_lossy(), result.is_ok());
+        result
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        let result = self.inner.prompt_blind(msg);
+        let style = PamMessageStyle::Prompt_Echo_Off;
+        self.record(style, &msg.to_string_lossy(), result.is_ok());
+        result
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.inner.info(msg);
+        self.record(PamMessageStyle::Text_Info, &msg.to_string_lossy(), false);
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.inner.error(msg);
+        self.record(PamMessageStyle::Error_Msg, &msg.to_string_lossy(), false);
+    }
+    fn text_info(&mut self, msg: &str) {
+        self.inner.text_info(msg);
+        self.record(PamMessageStyle::Text_Info, msg, false);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        self.inner.error_msg(msg);
+        self.record(PamMessageStyle::Error_Msg, msg, false);
+    }
+    fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+        let result = self.inner.radio_prompt(msg);
+        self.record(PamMessageStyle::Radio_Type, msg, result.is_ok());
+        result
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ConvError> {
+        let result = self.inner.binary_prompt(data);
+        let text = format!("<{} bytes>", data.len());
+        self.record(PamMessageStyle::Binary_Prompt, &text, result.is_ok());
+        result
+    }
+    fn is_cancelled(&self) -> bool {
+        self.inner.is_cancelled()
+    }
+    fn truncate_responses(&self) -> bool {
+        self.inner.truncate_responses()
+    }
+    fn clear_credentials(&mut self) {
+        self.inner.clear_credentials()
+    }
+    fn unknown_style(&mut self, style: c_int, msg: &str) -> Result<Option<String>, ConvError> {
+        let result = self.inner.unknown_style(style, msg);
+        let responded = result.as_ref().map_or(false, Option::is_some);
+        self.record(PamMessageStyle::from(style), msg, responded);
+        result
+    }
+    fn converse(&mut self, messages: &[Message<'_>]) -> Result<Vec<Response>, ConvError> {
+        // Forward the whole batch in case the wrapped handler overrides `converse`.
+        // If it fails, none of the messages counts as answered.
+        let result = self.inner.converse(messages);
+        for (i, msg) in messages.iter().enumerate() {
+            let responded = match result {
+                Ok(ref responses) => responses.get(i).map_or(false, |r| *r != Response::None),
+                Err(_) => false,
+            };
+            match msg.data {
+                Some(data) => {
+                    let text = format!("<{} bytes>", data.len());
+                    self.record(msg.style, &text, responded);
                 }
+                None => self.record(msg.style, msg.text, responded),
             }
-            PamMessageStyle::Text_Info => {
-                handler.info(msg);
-            }
-            PamMessageStyle::Error_Msg => {
-                handler.error(msg);
-                result = PamReturnCode::Conv_Err;
-            }
         }
-        if result != PamReturnCode::Success {
-            break;
+        result
+    }
+}
+
+/// What `SequenceConv` does once all of its responses are used up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExhaustionPolicy {
+    /// Fail the conversation
+    Fail,
+    /// Answer with the last response again, fail if there was none
+    RepeatLast,
+    /// Answer with an empty string
+    Empty,
+}
+
+/// A conversation handler answering blind prompts with a sequence of responses
+///
+/// Like `PasswordConv`, but every `Prompt_Echo_Off` message consumes the next of
+/// the given responses. This allows to pre-seed e.g. a password and a verification
+/// code for stacks asking for both. `Prompt_Echo_On` messages are answered with the
+/// login. By default the conversation fails if there are more prompts than
+/// responses, see `on_exhausted`.
+#[derive(Clone)]
+pub struct SequenceConv {
+    login: String,
+    responses: VecDeque<String>,
+    last: Option<String>,
+    policy: ExhaustionPolicy,
+}
+
+impl SequenceConv {
+    /// Create a new `SequenceConv` handler answering with `login` and `responses`
+    pub fn new<U, I, S>(login: U, responses: I) -> SequenceConv
+    where
+        U: Into<String>,
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        SequenceConv {
+            login: login.into(),
+            responses: responses.into_iter().map(Into::into).collect(),
+            last: None,
+            policy: ExhaustionPolicy::Fail,
+        }
+    }
+
+    /// Set what happens when there are more prompts than responses
+    pub fn on_exhausted(mut self, policy: ExhaustionPolicy) -> SequenceConv {
+        self.policy = policy;
+        self
+    }
+
+    /// Number of responses which were not used yet
+    pub fn remaining(&self) -> usize {
+        self.responses.len()
+    }
+
+    fn next_response(&mut self) -> Option<String> {
+        if let Some(response) = self.responses.pop_front() {
+            self.last = Some(response.clone());
+            return Some(response);
+        }
+        match self.policy {
+            ExhaustionPolicy::Fail => None,
+            ExhaustionPolicy::RepeatLast => self.last.clone(),
+            ExhaustionPolicy::Empty => Some(String::new()),
         }
     }
 
-    // free allocated memory if an error occured
-    if result != PamReturnCode::Success {
-        free(resp as *mut c_void);
-    } else {
-        *out_resp = resp;
+    fn wipe_responses(&mut self) {
+        for response in self.responses.iter_mut() {
+            wipe_string(response);
+        }
+        self.responses.clear();
+        if let Some(ref mut last) = self.last {
+            wipe_string(last);
+        }
+        self.last = None;
     }
+}
 
-    result as c_int
+impl Drop for SequenceConv {
+    fn drop(&mut self) {
+        self.wipe_responses();
+    }
+}
+
+// Never print the responses
+impl fmt::Debug for SequenceConv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SequenceConv")
+            .field("login", &self.login)
+            .field("remaining", &self.responses.len())
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+// The password replaces the first remaining response, so e.g. a verification code
+// following it is kept
+#[cfg(feature = "client")]
+impl CredentialTarget for SequenceConv {
+    fn set_username(&mut self, username: &str) {
+        self.login = username.to_string();
+    }
+    fn set_password(&mut self, password: secrecy::SecretString) {
+        use secrecy::ExposeSecret;
+
+        let password = password.expose_secret().clone();
+        match self.responses.front_mut() {
+            Some(first) => {
+                wipe_string(first);
+                *first = password;
+            }
+            None => self.responses.push_back(password),
+        }
+    }
+}
+
+impl Conversation for SequenceConv {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        Ok(self.login.clone())
+    }
+    fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        self.next_response().ok_or(ConvError)
+    }
+    fn radio_prompt(&mut self, _msg: &str) -> Result<String, ConvError> {
+        Err(ConvError)
+    }
+    // Keep the login, but answer blind prompts according to the policy as if all
+    // responses were used up
+    fn clear_credentials(&mut self) {
+        self.wipe_responses();
+    }
+}
+
+/// A pattern matched against prompts by `ScriptedConv` and `PasswordConv`
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// Matches prompts containing the string
+    Substring(String),
+    /// Matches prompts matching the regular expression, requires the `regex` feature
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl Pattern {
+    /// Whether `prompt` matches this pattern
+    pub fn matches(&self, prompt: &str) -> bool {
+        match self {
+            Pattern::Substring(substring) => prompt.contains(substring.as_str()),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(regex) => regex.is_match(prompt),
+        }
+    }
+}
+
+impl From<&str> for Pattern {
+    fn from(substring: &str) -> Pattern {
+        Pattern::Substring(substring.to_string())
+    }
+}
+
+impl From<String> for Pattern {
+    fn from(substring: String) -> Pattern {
+        Pattern::Substring(substring)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl From<regex::Regex> for Pattern {
+    fn from(regex: regex::Regex) -> Pattern {
+        Pattern::Regex(regex)
+    }
+}
+
+/// What `ScriptedConv` and `PasswordConv` do with prompts they don't expect
+#[derive(Debug, Clone)]
+pub enum Unmatched<C> {
+    /// Fail the conversation
+    Fail,
+    /// Answer with an empty string
+    Empty,
+    /// Pass the prompt to another handler
+    Passthrough(C),
+}
+
+/// A conversation handler answering prompts according to a script
+///
+/// The script is an ordered list of `(pattern, response)` pairs. Each prompt is
+/// matched against the pattern of the next entry, and if it matches, answered with
+/// its response. Other prompts fail the conversation by default, see `on_unmatched`.
+/// All prompts are recorded, so tests can assert on the behavior of a PAM stack:
+///
+/// ```no_run
+/// use pam::{Client, ScriptedConv};
+///
+/// let conv = ScriptedConv::new(vec![("Password:", "hunter2"), ("Verification code:", "123456")]);
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// assert_eq!(client.conversation().remaining(), 0);
+/// for (style, prompt) in client.conversation().prompts() {
+///     println!("{:?} {}", style, prompt);
+/// }
+/// ```
+///
+/// Patterns are substrings by default. With the `regex` feature, `regex::Regex` can
+/// be used as well.
+#[derive(Clone)]
+pub struct ScriptedConv<C = NullConv> {
+    script: VecDeque<(Pattern, String)>,
+    unmatched: Unmatched<C>,
+    prompts: Vec<(PamMessageStyle, String)>,
+}
+
+impl ScriptedConv {
+    /// Create a new `ScriptedConv` handler following `script`
+    pub fn new<I, P, R>(script: I) -> ScriptedConv
+    where
+        I: IntoIterator<Item = (P, R)>,
+        P: Into<Pattern>,
+        R: Into<String>,
+    {
+        ScriptedConv {
+            script: script
+                .into_iter()
+                .map(|(pattern, response)| (pattern.into(), response.into()))
+                .collect(),
+            unmatched: Unmatched::Fail,
+            prompts: Vec::new(),
+        }
+    }
+}
+
+// Never print the scripted responses
+impl<C: fmt::Debug> fmt::Debug for ScriptedConv<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let patterns: Vec<_> = self.script.iter().map(|(pattern, _)| pattern).collect();
+        f.debug_struct("ScriptedConv")
+            .field("patterns", &patterns)
+            .field("unmatched", &self.unmatched)
+            .field("prompts", &self.prompts)
+            .finish()
+    }
+}
+
+impl<C: Conversation> ScriptedConv<C> {
+    /// Set what happens with prompts which don't match the next script entry
+    ///
+    /// With `Unmatched::Passthrough` the other handler also receives all informational
+    /// and error messages.
+    pub fn on_unmatched<D: Conversation>(mut self, unmatched: Unmatched<D>) -> ScriptedConv<D> {
+        ScriptedConv {
+            script: mem::take(&mut self.script),
+            unmatched,
+            prompts: mem::take(&mut self.prompts),
+        }
+    }
+
+    /// The prompts sent by PAM so far in order, matched or not
+    pub fn prompts(&self) -> &[(PamMessageStyle, String)] {
+        &self.prompts
+    }
+
+    /// Number of script entries which were not used yet
+    pub fn remaining(&self) -> usize {
+        self.script.len()
+    }
+
+    fn answer<F>(
+        &mut self,
+        style: PamMessageStyle,
+        msg: &str,
+        passthrough: F,
+    ) -> Result<String, ConvError>
+    where
+        F: FnOnce(&mut C) -> Result<String, ConvError>,
+    {
+        self.prompts.push((style, msg.to_string()));
+        let matched = self
+            .script
+            .front()
+            .map_or(false, |(pattern, _)| pattern.matches(msg));
+        if matched {
+            return self
+                .script
+                .pop_front()
+                .map(|(_, response)| response)
+                .ok_or(ConvError);
+        }
+        match self.unmatched {
+            Unmatched::Fail => Err(ConvError),
+            Unmatched::Empty => Ok(String::new()),
+            Unmatched::Passthrough(ref mut inner) => passthrough(inner),
+        }
+    }
+}
+
+impl<C> ScriptedConv<C> {
+    fn wipe_script(&mut self) {
+        for (_, response) in self.script.iter_mut() {
+            wipe_string(response);
+        }
+        self.script.clear();
+    }
+}
+
+impl<C> Drop for ScriptedConv<C> {
+    fn drop(&mut self) {
+        self.wipe_script();
+    }
+}
+
+impl<C: Conversation> Conversation for ScriptedConv<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        let style = PamMessageStyle::Prompt_Echo_On;
+        self.answer(style, &msg.to_string_lossy(), |inner| {
+            inner.prompt_echo(msg)
+        })
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        let style = PamMessageStyle::Prompt_Echo_Off;
+        self.answer(style, &msg.to_string_lossy(), |inner| {
+            inner.prompt_blind(msg)
+        })
+    }
+    fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+        self.answer(PamMessageStyle::Radio_Type, msg, |inner| {
+            inner.radio_prompt(msg)
+        })
+    }
+    fn info(&mut self, msg: &CStr) {
+        if let Unmatched::Passthrough(ref mut inner) = self.unmatched {
+            inner.info(msg);
+        }
+    }
+    fn error(&mut self, msg: &CStr) {
+        if let Unmatched::Passthrough(ref mut inner) = self.unmatched {
+            inner.error(msg);
+        }
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ConvError> {
+        match self.unmatched {
+            Unmatched::Passthrough(ref mut inner) => inner.binary_prompt(data),
+            _ => Err(ConvError),
+        }
+    }
+    fn unknown_style(&mut self, style: c_int, msg: &str) -> Result<Option<String>, ConvError> {
+        match self.unmatched {
+            Unmatched::Passthrough(ref mut inner) => inner.unknown_style(style, msg),
+            _ => Err(ConvError),
+        }
+    }
+    fn is_cancelled(&self) -> bool {
+        match self.unmatched {
+            Unmatched::Passthrough(ref inner) => inner.is_cancelled(),
+            _ => false,
+        }
+    }
+    // The remaining entries are dropped, so their prompts count as unmatched
+    fn clear_credentials(&mut self) {
+        self.wipe_script();
+        if let Unmatched::Passthrough(ref mut inner) = self.unmatched {
+            inner.clear_credentials();
+        }
+    }
+}
+
+/// A non-interactive conversation handler which fails all prompts
+///
+/// This is the handler of choice for daemons which e.g. only check the account via
+/// `acct_mgmt`: if a module unexpectedly asks a question, the conversation fails
+/// immediately with `PAM_CONV_ERR` instead of waiting for input. The only
+/// exception is the login prompt (`Prompt_Echo_On`), which is answered with the
+/// configured `username` if there is one. Informational and error messages are
+/// dropped unless recording was enabled via `record_messages`.
+///
+/// ```no_run
+/// use pam::{Client, NullConv};
+///
+/// let conv = NullConv::new().with_username("alice").record_messages();
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// let result = client.authenticate();
+/// for (_, msg) in client.conversation().messages() {
+///     println!("{}", msg);
+/// }
+/// result.expect("Authentication failed!");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct NullConv {
+    username: Option<String>,
+    messages: Option<Vec<(PamMessageStyle, String)>>,
+}
+
+impl NullConv {
+    /// Create a new `NullConv` handler without a username
+    pub fn new() -> NullConv {
+        NullConv::default()
+    }
+
+    /// Answer the login prompt with `username`
+    pub fn with_username<S: Into<String>>(mut self, username: S) -> NullConv {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Record the informational and error messages sent by PAM
+    pub fn record_messages(mut self) -> NullConv {
+        self.messages = Some(Vec::new());
+        self
+    }
+
+    /// The configured username
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// The recorded messages in order, empty if recording is disabled
+    pub fn messages(&self) -> &[(PamMessageStyle, String)] {
+        self.messages.as_deref().unwrap_or(&[])
+    }
+
+    fn record(&mut self, style: PamMessageStyle, msg: &str) {
+        if let Some(ref mut messages) = self.messages {
+            messages.push((style, msg.to_string()));
+        }
+    }
+}
+
+impl Conversation for NullConv {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        self.username.clone().ok_or(ConvError)
+    }
+    fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        Err(ConvError)
+    }
+    fn text_info(&mut self, msg: &str) {
+        self.record(PamMessageStyle::Text_Info, msg);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        self.record(PamMessageStyle::Error_Msg, msg);
+    }
+    fn radio_prompt(&mut self, _msg: &str) -> Result<String, ConvError> {
+        Err(ConvError)
+    }
+}
+
+/// A handle to cancel a conversation from another thread
+///
+/// Clones share the same state. Handlers can check `is_cancelled` before prompting,
+/// `ChannelConv` additionally stops waiting for the pending answer once `cancel` is
+/// called.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<Mutex<CancelState>>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: bool,
+    pending: Option<SyncSender<Result<String, ConvError>>>,
+}
+
+impl CancelToken {
+    /// Create a new token which is not cancelled
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancel the conversation, failing the pending and all following prompts
+    pub fn cancel(&self) {
+        let mut state = self.state();
+        state.cancelled = true;
+        if let Some(pending) = state.pending.take() {
+            // Fails if the answer was sent already, which is ignored once cancelled
+            let _ = pending.try_send(Err(ConvError));
+        }
+    }
+
+    /// Whether `cancel` was called since the token was created or reset
+    pub fn is_cancelled(&self) -> bool {
+        self.state().cancelled
+    }
+
+    /// Reset the token, e.g. to start another authentication attempt
+    pub fn reset(&self) {
+        self.state().cancelled = false;
+    }
+
+    // Remember where to send the cancellation while waiting for an answer
+    fn register(&self, reply: &SyncSender<Result<String, ConvError>>) -> Result<(), ConvError> {
+        let mut state = self.state();
+        if state.cancelled {
+            return Err(ConvError);
+        }
+        state.pending = Some(reply.clone());
+        Ok(())
+    }
+
+    fn unregister(&self) {
+        self.state().pending = None;
+    }
+
+    // The state is always consistent, so a panic while holding the lock doesn't matter
+    fn state(&self) -> MutexGuard<'_, CancelState> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// A message forwarded by `ChannelConv` to the thread interacting with the user
+#[derive(Debug)]
+pub struct PromptRequest {
+    /// The style of the message
+    pub style: PamMessageStyle,
+    /// The message to display
+    pub text: String,
+    /// Where to send the answer
+    ///
+    /// Informational and error messages don't wait for a reply, so it can be dropped.
+    pub reply: PromptReply,
+}
+
+/// The sending half for the answer to a `PromptRequest`
+///
+/// Dropping it without sending an answer fails the prompt.
+#[derive(Debug)]
+pub struct PromptReply(SyncSender<Result<String, ConvError>>);
+
+impl PromptReply {
+    /// Send the answer to the waiting conversation
+    ///
+    /// Fails if the conversation stopped waiting, e.g. because of a timeout.
+    pub fn send(self, answer: Result<String, ConvError>) -> Result<(), ConvError> {
+        match self.0.send(answer) {
+            Ok(()) => Ok(()),
+            // Nobody reads the answer anymore, don't leave it behind
+            Err(mpsc::SendError(Ok(mut answer))) => {
+                wipe_string(&mut answer);
+                Err(ConvError)
+            }
+            Err(mpsc::SendError(Err(_))) => Err(ConvError),
+        }
+    }
+}
+
+/// A conversation handler forwarding all messages over a channel
+///
+/// This is meant for GUI frontends, which run PAM on a worker thread but have to
+/// display prompts on the UI thread. Every message is sent as a `PromptRequest` to
+/// the receiver of the channel given to `new`. For prompts the conversation then
+/// blocks until the answer is sent via `PromptRequest::reply`, or the timeout set
+/// with `with_timeout` expires. If the receiver or the reply is dropped instead,
+/// the prompt fails with `ConvError`. The same happens if the `CancelToken` set with
+/// `with_cancel_token` is cancelled. Binary prompts are not forwarded and fail.
+///
+/// ```no_run
+/// use pam::{ChannelConv, Client};
+/// use std::sync::mpsc;
+/// use std::thread;
+///
+/// let (sender, requests) = mpsc::channel();
+/// let worker = thread::spawn(move || {
+///     let mut client = Client::with_conversation("system-auth", ChannelConv::new(sender))?;
+///     client.authenticate()
+/// });
+/// for request in requests {
+///     // Display `request.text` and ask the user if necessary
+///     let _ = request.reply.send(Ok("secret".to_string()));
+/// }
+/// worker.join().unwrap().expect("Authentication failed!");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChannelConv {
+    sender: Sender<PromptRequest>,
+    timeout: Option<Duration>,
+    cancel: Option<CancelToken>,
+}
+
+impl ChannelConv {
+    /// Create a new `ChannelConv` handler sending its messages to `sender`
+    pub fn new(sender: Sender<PromptRequest>) -> ChannelConv {
+        ChannelConv {
+            sender,
+            timeout: None,
+            cancel: None,
+        }
+    }
+
+    /// Fail prompts which are not answered within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> ChannelConv {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fail the pending and all following prompts once `token` is cancelled
+    pub fn with_cancel_token(mut self, token: CancelToken) -> ChannelConv {
+        self.cancel = Some(token);
+        self
+    }
+
+    fn send(
+        &self,
+        style: PamMessageStyle,
+        text: &str,
+        reply: SyncSender<Result<String, ConvError>>,
+    ) -> Result<(), ConvError> {
+        let request = PromptRequest {
+            style,
+            text: text.to_string(),
+            reply: PromptReply(reply),
+        };
+        self.sender.send(request).map_err(|_| ConvError)
+    }
+
+    fn notify(&self, style: PamMessageStyle, text: &str) {
+        let (reply, _) = mpsc::sync_channel(1);
+        let _ = self.send(style, text, reply);
+    }
+
+    fn ask(&self, style: PamMessageStyle, text: &str) -> Result<String, ConvError> {
+        let (reply, answer) = mpsc::sync_channel(1);
+        if let Some(ref token) = self.cancel {
+            token.register(&reply)?;
+        }
+        let result = self
+            .send(style, text, reply)
+            .and_then(|_| match self.timeout {
+                Some(timeout) => answer.recv_timeout(timeout).map_err(|_| ConvError)?,
+                None => answer.recv().map_err(|_| ConvError)?,
+            });
+        match self.cancel {
+            Some(ref token) => {
+                token.unregister();
+                if token.is_cancelled() {
+                    // The answer might have arrived just before the cancellation
+                    if let Ok(mut answer) = result {
+                        wipe_string(&mut answer);
+                    }
+                    return Err(ConvError);
+                }
+                result
+            }
+            None => result,
+        }
+    }
+}
+
+impl Conversation for ChannelConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.ask(PamMessageStyle::Prompt_Echo_On, &msg.to_string_lossy())
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.ask(PamMessageStyle::Prompt_Echo_Off, &msg.to_string_lossy())
+    }
+    fn text_info(&mut self, msg: &str) {
+        self.notify(PamMessageStyle::Text_Info, msg);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        self.notify(PamMessageStyle::Error_Msg, msg);
+    }
+    fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+        self.ask(PamMessageStyle::Radio_Type, msg)
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .map_or(false, CancelToken::is_cancelled)
+    }
+}
+
+/// The future returned by the methods of `AsyncConversation`
+#[cfg(feature = "async")]
+pub type ConvFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An asynchronous conversation handler
+///
+/// libpam calls the conversation synchronously, so async handlers can't be passed
+/// to `Client` directly. Instead, `AsyncBridge::run` runs the PAM transaction on a
+/// separate thread and forwards the messages to the handler on the async runtime.
+/// Radio prompts are answered via `prompt_echo`, binary prompts are not supported.
+///
+/// Requires the `async` feature.
+#[cfg(feature = "async")]
+#[allow(unused_variables)]
+pub trait AsyncConversation: Send {
+    /// PAM requests a value that should be echoed to the user as they type it
+    fn prompt_echo(&mut self, msg: String) -> ConvFuture<'_, Result<String, ConvError>>;
+    /// PAM requests a value that should be typed blindly by the user
+    fn prompt_blind(&mut self, msg: String) -> ConvFuture<'_, Result<String, ConvError>>;
+    /// This is an informational message from PAM
+    fn text_info(&mut self, msg: String) -> ConvFuture<'_, ()> {
+        Box::pin(async {})
+    }
+    /// This is an error message from PAM
+    fn error_msg(&mut self, msg: String) -> ConvFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// A conversation handler forwarding all messages to an `AsyncConversation`
+///
+/// It is created by `AsyncBridge::run` for the thread running the PAM transaction.
+/// Prompts block this thread until the async handler answered them, or the timeout
+/// set with `with_timeout` expires. A prompt which timed out still has to be
+/// answered by the async handler before it gets the next message.
+///
+/// ```no_run
+/// use pam::{AsyncBridge, AsyncConversation, Client, ConvError, ConvFuture};
+///
+/// struct Answers;
+///
+/// impl AsyncConversation for Answers {
+///     fn prompt_echo(&mut self, _msg: String) -> ConvFuture<'_, Result<String, ConvError>> {
+///         Box::pin(async { Ok("login".to_string()) })
+///     }
+///     fn prompt_blind(&mut self, _msg: String) -> ConvFuture<'_, Result<String, ConvError>> {
+///         Box::pin(async { Ok("password".to_string()) })
+///     }
+/// }
+///
+/// # async fn login() {
+/// let (result, _) = AsyncBridge::run(Answers, |bridge| {
+///     let mut client = Client::with_conversation("system-auth", bridge)?;
+///     client.authenticate()
+/// })
+/// .await;
+/// result.expect("Authentication failed!");
+/// # }
+/// ```
+///
+/// Requires the `async` feature.
+#[cfg(feature = "async")]
+#[derive(Debug, Clone)]
+pub struct AsyncBridge {
+    sender: UnboundedSender<AsyncRequest>,
+    timeout: Option<Duration>,
+}
+
+// A message sent by `AsyncBridge`, prompts are answered via `reply`
+#[cfg(feature = "async")]
+#[derive(Debug)]
+struct AsyncRequest {
+    style: PamMessageStyle,
+    text: String,
+    reply: Option<SyncSender<Result<String, ConvError>>>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncBridge {
+    /// Call `f` with a bridge to `conv` on a new thread and answer its messages with `conv`
+    ///
+    /// `f` usually creates a `Client` with the bridge and drives the PAM transaction.
+    /// The future resolves to the result of `f` and `conv` once `f` returned. If `f`
+    /// panics, the panic is continued by the future.
+    pub async fn run<A, F, T>(mut conv: A, f: F) -> (T, A)
+    where
+        A: AsyncConversation,
+        F: FnOnce(AsyncBridge) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, mut requests) = unbounded();
+        let (done, mut finished) = oneshot::channel();
+        let bridge = AsyncBridge {
+            sender,
+            timeout: None,
+        };
+        thread::spawn(move || {
+            let _ = done.send(panic::catch_unwind(AssertUnwindSafe(|| f(bridge))));
+        });
+
+        let result = loop {
+            let request = match future::select(requests.next(), &mut finished).await {
+                Either::Left((Some(request), _)) => request,
+                Either::Left((None, finished)) => break finished.await,
+                Either::Right((result, _)) => break result,
+            };
+            // Stop serving a prompt abandoned by a timeout once `f` returned
+            let serve = Box::pin(serve_request(&mut conv, request));
+            if let Either::Right((result, _)) = future::select(serve, &mut finished).await {
+                break result;
+            }
+        };
+        // Deliver the messages sent right before `f` returned
+        while let Ok(Some(request)) = requests.try_next() {
+            if request.reply.is_none() {
+                serve_request(&mut conv, request).await;
+            }
+        }
+        match result {
+            Ok(Ok(value)) => (value, conv),
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            Err(_) => unreachable!("the thread always sends the result of `f`"),
+        }
+    }
+
+    /// Fail prompts which are not answered within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> AsyncBridge {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn send(
+        &self,
+        style: PamMessageStyle,
+        text: &str,
+        reply: Option<SyncSender<Result<String, ConvError>>>,
+    ) -> Result<(), ConvError> {
+        let request = AsyncRequest {
+            style,
+            text: text.to_string(),
+            reply,
+        };
+        self.sender.unbounded_send(request).map_err(|_| ConvError)
+    }
+
+    fn ask(&self, style: PamMessageStyle, text: &str) -> Result<String, ConvError> {
+        let (reply, answer) = mpsc::sync_channel(1);
+        self.send(style, text, Some(reply))?;
+        match self.timeout {
+            Some(timeout) => answer.recv_timeout(timeout).map_err(|_| ConvError)?,
+            None => answer.recv().map_err(|_| ConvError)?,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Conversation for AsyncBridge {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.ask(PamMessageStyle::Prompt_Echo_On, &msg.to_string_lossy())
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.ask(PamMessageStyle::Prompt_Echo_Off, &msg.to_string_lossy())
+    }
+    fn text_info(&mut self, msg: &str) {
+        let _ = self.send(PamMessageStyle::Text_Info, msg, None);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        let _ = self.send(PamMessageStyle::Error_Msg, msg, None);
+    }
+}
+
+// Pass `request` to the method of `conv` for its style and send back the answer
+#[cfg(feature = "async")]
+async fn serve_request<A: AsyncConversation>(conv: &mut A, request: AsyncRequest) {
+    let reply = match request.reply {
+        Some(reply) => reply,
+        None => {
+            match request.style {
+                PamMessageStyle::Error_Msg => conv.error_msg(request.text).await,
+                _ => conv.text_info(request.text).await,
+            }
+            return;
+        }
+    };
+    let answer = match request.style {
+        PamMessageStyle::Prompt_Echo_Off => conv.prompt_blind(request.text).await,
+        _ => conv.prompt_echo(request.text).await,
+    };
+    // The bridge stopped waiting, e.g. because of a timeout
+    if let Err(mpsc::SendError(Ok(mut answer))) = reply.send(answer) {
+        wipe_string(&mut answer);
+    }
+}
+
+/// A conversation handler failing prompts which the wrapped handler doesn't answer in time
+///
+/// Every call is passed to the wrapped handler on a helper thread while the calling
+/// thread waits for the result at most `timeout`. This is the time for all messages
+/// PAM sends at once. If the deadline passes, the conversation fails with `ConvError`
+/// and the helper thread is abandoned until the wrapped handler returns. Its late
+/// answers are wiped. Until then the handler is busy, so following conversations time
+/// out as well.
+///
+/// This only limits the time spent waiting for an answer. The PAM call itself can
+/// still block in libpam or a module, e.g. on network timeouts, after the conversation
+/// returned.
+///
+/// ```no_run
+/// use pam::{Client, Timeout, TtyConv};
+/// use std::time::Duration;
+///
+/// let conv = Timeout::new(TtyConv::new(), Duration::from_secs(30));
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+#[derive(Debug)]
+pub struct Timeout<C> {
+    inner: Arc<Mutex<C>>,
+    timeout: Duration,
+}
+
+// A result of `Timeout::call`, wiped if it arrives after the deadline
+trait LateResult {
+    fn wipe(&mut self) {}
+}
+
+impl LateResult for () {}
+
+impl LateResult for String {
+    fn wipe(&mut self) {
+        wipe_string(self);
+    }
+}
+
+impl LateResult for Option<String> {
+    fn wipe(&mut self) {
+        if let Some(text) = self {
+            wipe_string(text);
+        }
+    }
+}
+
+impl LateResult for Vec<u8> {
+    fn wipe(&mut self) {
+        for byte in self.iter_mut() {
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+impl LateResult for Vec<Response> {
+    fn wipe(&mut self) {
+        for response in self.iter_mut() {
+            match response {
+                Response::Text(text) => wipe_string(text),
+                Response::Binary(data) => data.wipe(),
+                Response::None => {}
+            }
+        }
+    }
+}
+
+impl<C: Conversation + Send + 'static> Timeout<C> {
+    /// Create a new `Timeout` handler giving `inner` at most `timeout` per conversation
+    pub fn new(inner: C, timeout: Duration) -> Timeout<C> {
+        Timeout {
+            inner: Arc::new(Mutex::new(inner)),
+            timeout,
+        }
+    }
+
+    /// The wrapped handler, `None` if it is still busy with a message which timed out
+    pub fn into_inner(self) -> Option<C> {
+        let inner = Arc::try_unwrap(self.inner).ok()?;
+        // The handler is still usable if it panicked
+        Some(inner.into_inner().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    fn call<T, F>(&self, f: F) -> Result<T, ConvError>
+    where
+        T: LateResult + Send + 'static,
+        F: FnOnce(&mut C) -> Result<T, ConvError> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        // Without a buffer, a result is either received or handed back to the sender
+        let (result, answer) = mpsc::sync_channel(0);
+        thread::Builder::new()
+            .name("pam-conversation".to_string())
+            .spawn(move || {
+                let mut inner = inner.lock().unwrap_or_else(|err| err.into_inner());
+                // Fails if the calling thread stopped waiting, nobody reads the answer
+                if let Err(mpsc::SendError(Ok(mut late))) = result.send(f(&mut inner)) {
+                    late.wipe();
+                }
+            })
+            .map_err(|_| ConvError)?;
+        answer.recv_timeout(self.timeout).map_err(|_| ConvError)?
+    }
+}
+
+impl<C: Conversation + Send + 'static> Conversation for Timeout<C> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        let msg = msg.to_owned();
+        self.call(move |inner| inner.prompt_echo(&msg))
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        let msg = msg.to_owned();
+        self.call(move |inner| inner.prompt_blind(&msg))
+    }
+    fn info(&mut self, msg: &CStr) {
+        let msg = msg.to_owned();
+        let _ = self.call(move |inner| {
+            inner.info(&msg);
+            Ok(())
+        });
+    }
+    fn error(&mut self, msg: &CStr) {
+        let msg = msg.to_owned();
+        let _ = self.call(move |inner| {
+            inner.error(&msg);
+            Ok(())
+        });
+    }
+    fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+        let msg = msg.to_string();
+        self.call(move |inner| inner.radio_prompt(&msg))
+    }
+    fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ConvError> {
+        let data = data.to_vec();
+        self.call(move |inner| inner.binary_prompt(&data))
+    }
+    fn unknown_style(&mut self, style: c_int, msg: &str) -> Result<Option<String>, ConvError> {
+        let msg = msg.to_string();
+        self.call(move |inner| inner.unknown_style(style, &msg))
+    }
+    fn converse(&mut self, messages: &[Message<'_>]) -> Result<Vec<Response>, ConvError> {
+        // Forward the whole batch in case the wrapped handler overrides `converse`
+        let owned: Vec<_> = messages
+            .iter()
+            .map(|m| {
+                let raw = m.raw.map(CStr::to_owned);
+                let data = m.data.map(<[u8]>::to_vec);
+                (
+                    m.style,
+                    m.text.to_string(),
+                    m.raw_style,
+                    raw,
+                    data,
+                    m.context,
+                )
+            })
+            .collect();
+        self.call(move |inner| {
+            let messages: Vec<_> = owned
+                .iter()
+                .map(|(style, text, raw_style, raw, data, context)| Message {
+                    style: *style,
+                    text,
+                    raw_style: *raw_style,
+                    raw: raw.as_deref(),
+                    data: data.as_deref(),
+                    context: *context,
+                })
+                .collect();
+            inner.converse(&messages)
+        })
+    }
+    fn is_cancelled(&self) -> bool {
+        // A busy handler can't be asked, but it is not cancelled by the timeout either
+        self.inner
+            .try_lock()
+            .map_or(false, |inner| inner.is_cancelled())
+    }
+    fn truncate_responses(&self) -> bool {
+        self.inner
+            .try_lock()
+            .map_or(false, |inner| inner.truncate_responses())
+    }
+    fn clear_credentials(&mut self) {
+        // A handler still busy with a timed out prompt keeps its credentials
+        if let Ok(mut inner) = self.inner.try_lock() {
+            inner.clear_credentials();
+        }
+    }
+}
+
+/// An interactive conversation handler using the terminal
+///
+/// Prompts are written to and answers read from the controlling terminal
+/// `/dev/tty`, or stdin/stdout if the process has none. Echo is disabled while
+/// reading the answer to `Prompt_Echo_Off` messages and restored afterwards, even
+/// if the process is terminated by `SIGINT`, `SIGQUIT`, `SIGTERM` or `SIGHUP`
+/// meanwhile. Informational and error messages are printed to stderr.
+///
+/// ```no_run
+/// use pam::{Client, TtyConv};
+///
+/// let mut client = Client::with_conversation("system-auth", TtyConv::new())
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+#[derive(Debug)]
+pub struct TtyConv {
+    tty: Option<File>,
+}
+
+impl TtyConv {
+    /// Create a new `TtyConv` handler
+    pub fn new() -> TtyConv {
+        let tty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .ok();
+        TtyConv { tty }
+    }
+
+    fn prompt(&mut self, msg: &CStr, echo: bool) -> Result<String, ConvError> {
+        let answer = match self.tty {
+            Some(ref tty) => {
+                let mut output = tty;
+                prompt_line(tty.as_raw_fd(), BufReader::new(tty), &mut output, msg, echo)
+            }
+            None => {
+                let stdin = io::stdin();
+                let fd = stdin.as_raw_fd();
+                prompt_line(fd, stdin.lock(), &mut io::stdout(), msg, echo)
+            }
+        };
+        answer.map_err(|_| ConvError)
+    }
+}
+
+impl Default for TtyConv {
+    fn default() -> TtyConv {
+        TtyConv::new()
+    }
+}
+
+impl Conversation for TtyConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.prompt(msg, true)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.prompt(msg, false)
+    }
+    fn text_info(&mut self, msg: &str) {
+        eprintln!("{}", msg);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        eprintln!("{}", msg);
+    }
+}
+
+/// A conversation handler answering every prompt with the next line of a reader
+///
+/// Prompts are answered in order regardless of their style, e.g. by piping
+/// `printf 'alice\nsecret\n'` to stdin. The trailing newline of each line is
+/// removed. If the reader has no more lines, the conversation fails. Prompts and
+/// informational and error messages can be written to a log via `with_echo`, the
+/// answers are never written.
+///
+/// ```no_run
+/// use std::io;
+/// use pam::{Client, PipeConv};
+///
+/// let conv = PipeConv::new(io::stdin()).with_echo(io::stderr());
+/// let mut client = Client::with_conversation("system-auth", conv)
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+#[derive(Debug)]
+pub struct PipeConv<R, W = io::Sink> {
+    input: BufReader<R>,
+    echo: Option<W>,
+}
+
+impl<R: Read> PipeConv<R> {
+    /// Create a new `PipeConv` handler reading the answers from `input`
+    pub fn new(input: R) -> PipeConv<R> {
+        PipeConv {
+            input: BufReader::new(input),
+            echo: None,
+        }
+    }
+}
+
+impl<R: Read, W: Write> PipeConv<R, W> {
+    /// Write all prompts and messages to `output`, each on its own line
+    pub fn with_echo<V: Write>(self, output: V) -> PipeConv<R, V> {
+        PipeConv {
+            input: self.input,
+            echo: Some(output),
+        }
+    }
+
+    /// Consume the handler and return the reader
+    ///
+    /// Input which was read ahead but not used for an answer is lost.
+    pub fn into_inner(self) -> R {
+        self.input.into_inner()
+    }
+
+    fn echo(&mut self, msg: &str) -> io::Result<()> {
+        match self.echo {
+            Some(ref mut output) => {
+                writeln!(output, "{}", msg)?;
+                output.flush()
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn read_answer(&mut self, msg: &str) -> Result<String, ConvError> {
+        self.echo(msg).map_err(|_| ConvError)?;
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => return Err(ConvError),
+            Ok(_) => {}
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(line)
+    }
+}
+
+impl<R: Read, W: Write> Conversation for PipeConv<R, W> {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.read_answer(&msg.to_string_lossy())
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.read_answer(&msg.to_string_lossy())
+    }
+    fn text_info(&mut self, msg: &str) {
+        let _ = self.echo(msg);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        let _ = self.echo(msg);
+    }
+    fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+        self.read_answer(msg)
+    }
+}
+
+/// An interactive conversation handler using `rpassword`
+///
+/// Answers to `Prompt_Echo_Off` messages are read with `rpassword` from the
+/// terminal, answers to `Prompt_Echo_On` messages from stdin after writing the
+/// prompt to stdout. If stdin is not a terminal, e.g. for `echo pw | mytool`, all
+/// answers are read line by line from stdin. Informational and error messages are
+/// printed to stderr.
+///
+/// Requires the `interactive` feature.
+///
+/// ```no_run
+/// use pam::{Client, RPasswordConv};
+///
+/// let mut client = Client::with_conversation("system-auth", RPasswordConv::new())
+///         .expect("Failed to init PAM client.");
+/// client.authenticate().expect("Authentication failed!");
+/// ```
+#[cfg(feature = "interactive")]
+#[derive(Debug, Clone, Default)]
+pub struct RPasswordConv {
+    _private: (),
+}
+
+#[cfg(feature = "interactive")]
+impl RPasswordConv {
+    /// Create a new `RPasswordConv` handler
+    pub fn new() -> RPasswordConv {
+        RPasswordConv::default()
+    }
+
+    fn prompt(&mut self, msg: &CStr, echo: bool) -> Result<String, ConvError> {
+        let stdin = io::stdin();
+        let fd = stdin.as_raw_fd();
+        rpassword_line(fd, stdin.lock(), &mut io::stdout(), msg, echo).map_err(|_| ConvError)
+    }
+}
+
+#[cfg(feature = "interactive")]
+impl Conversation for RPasswordConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.prompt(msg, true)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.prompt(msg, false)
+    }
+    fn text_info(&mut self, msg: &str) {
+        eprintln!("{}", msg);
+    }
+    fn error_msg(&mut self, msg: &str) {
+        eprintln!("{}", msg);
+    }
+}
+
+// Like `prompt_line`, but read hidden answers with `rpassword` if `fd` is a terminal
+#[cfg(feature = "interactive")]
+fn rpassword_line<R: BufRead, W: Write>(
+    fd: c_int,
+    input: R,
+    output: &mut W,
+    msg: &CStr,
+    echo: bool,
+) -> io::Result<String> {
+    if echo || unsafe { libc::isatty(fd) } == 0 {
+        prompt_line(fd, input, output, msg, true)
+    } else {
+        rpassword::prompt_password(msg.to_string_lossy())
+    }
+}
+
+// Write `msg` and read a line from `input` which has the file descriptor `fd`.
+// End of file is an error as the user did not answer.
+fn prompt_line<R: BufRead, W: Write>(
+    fd: c_int,
+    mut input: R,
+    output: &mut W,
+    msg: &CStr,
+    echo: bool,
+) -> io::Result<String> {
+    output.write_all(msg.to_bytes())?;
+    output.flush()?;
+
+    let _echo_guard = if echo { None } else { EchoGuard::disable(fd)? };
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    while line.ends_with('\n') || line.ends_with('\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+// Signals which are handled while echo is disabled to restore the terminal
+const RESTORE_SIGNALS: [c_int; 4] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM, libc::SIGHUP];
+
+// Terminal state and signal handlers before the echo was disabled
+struct SavedTerminal {
+    fd: c_int,
+    termios: libc::termios,
+    actions: [libc::sigaction; RESTORE_SIGNALS.len()],
+}
+
+// The state restored by `restore_terminal`, null if no signal handlers are installed
+static SAVED_TERMINAL: AtomicPtr<SavedTerminal> = AtomicPtr::new(ptr::null_mut());
+
+// Disables the echo of a terminal until it is dropped
+struct EchoGuard {
+    saved: Box<SavedTerminal>,
+    // Whether `saved` is published in `SAVED_TERMINAL` and the signal handlers are ours
+    handles_signals: bool,
+}
+
+impl EchoGuard {
+    // Returns `None` if `fd` is not a terminal
+    fn disable(fd: c_int) -> io::Result<Option<EchoGuard>> {
+        if unsafe { libc::isatty(fd) } == 0 {
+            return Ok(None);
+        }
+
+        let mut saved: Box<SavedTerminal> = Box::new(unsafe { mem::zeroed() });
+        saved.fd = fd;
+        if unsafe { libc::tcgetattr(fd, &mut saved.termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut guard = EchoGuard {
+            saved,
+            handles_signals: false,
+        };
+        guard.install_signal_handlers();
+
+        let mut termios = guard.saved.termios;
+        termios.c_lflag &= !libc::ECHO;
+        // Still echo the newline so the following output starts on a new line
+        termios.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(guard))
+    }
+
+    fn install_signal_handlers(&mut self) {
+        let saved: *mut SavedTerminal = &mut *self.saved;
+        // Another thread is prompting already, it restores the terminal on signals
+        if SAVED_TERMINAL
+            .compare_exchange(ptr::null_mut(), saved, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+        self.handles_signals = true;
+
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = restore_terminal as extern "C" fn(c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            for (signal, old) in RESTORE_SIGNALS.iter().zip((*saved).actions.iter_mut()) {
+                libc::sigaction(*signal, &action, old);
+            }
+        }
+    }
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.saved.fd, libc::TCSAFLUSH, &self.saved.termios) };
+        if self.handles_signals {
+            for (signal, old) in RESTORE_SIGNALS.iter().zip(self.saved.actions.iter()) {
+                unsafe { libc::sigaction(*signal, old, ptr::null_mut()) };
+            }
+            SAVED_TERMINAL.store(ptr::null_mut(), Ordering::SeqCst);
+        }
+    }
+}
+
+// Signal handler restoring the terminal and the previous handler before raising
+// the signal again. Only uses async-signal-safe functions.
+extern "C" fn restore_terminal(signal: c_int) {
+    let saved = SAVED_TERMINAL.swap(ptr::null_mut(), Ordering::SeqCst);
+    unsafe {
+        if saved.is_null() {
+            libc::signal(signal, libc::SIG_DFL);
+        } else {
+            let saved = &*saved;
+            libc::tcsetattr(saved.fd, libc::TCSANOW, &saved.termios);
+            for (sig, old) in RESTORE_SIGNALS.iter().zip(saved.actions.iter()) {
+                libc::sigaction(*sig, old, ptr::null_mut());
+            }
+        }
+        libc::raise(signal);
+    }
+}
+
+pub(crate) fn into_pam_conv<C: Conversation>(conv: &mut C) -> pam_conv {
+    pam_conv {
+        conv: Some(converse::<C>),
+        appdata_ptr: conv as *mut C as *mut c_void,
+    }
+}
+
+pub(crate) unsafe extern "C" fn converse<C: Conversation>(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    out_resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    let num_msg = match usize::try_from(num_msg) {
+        Ok(num_msg) => num_msg,
+        Err(_) => return PamReturnCode::Conv_Err as c_int,
+    };
+
+    // Decode the messages first so the handler gets all of them at once
+    let mut decoded = Vec::with_capacity(num_msg);
+    for i in 0..num_msg {
+        let m: &PamMessage = &**msg.add(i);
+        if m.msg_style == PamMessageStyle::Binary_Prompt as c_int {
+            // Binary messages are not NUL terminated
+            match binary_msg_data(m.msg) {
+                Some(data) => decoded.push((m.msg_style, None, Cow::Borrowed(""), Some(data))),
+                None => return PamReturnCode::Conv_Err as c_int,
+            }
+        } else if m.msg.is_null() {
+            return PamReturnCode::Conv_Err as c_int;
+        } else {
+            // The text might not be valid UTF-8, e.g. because of the locale
+            let raw = CStr::from_ptr(m.msg);
+            decoded.push((m.msg_style, Some(raw), raw.to_string_lossy(), None));
+        }
+    }
+    let messages: Vec<_> = decoded
+        .iter()
+        .enumerate()
+        .map(|(index, (raw_style, raw, text, data))| Message {
+            style: PamMessageStyle::from(*raw_style),
+            text,
+            raw_style: *raw_style,
+            raw: *raw,
+            data: *data,
+            context: MessageContext {
+                index,
+                total: num_msg,
+            },
+        })
+        .collect();
+
+    // Unwinding into libpam is undefined behavior. The handler might be left in an
+    // inconsistent state by the panic, which is fine as the panic is resumed by
+    // `Client` once libpam returned, see `take_panic`.
+    let handler = &mut *(appdata_ptr as *mut C);
+    let responses = match panic::catch_unwind(AssertUnwindSafe(|| handler.converse(&messages))) {
+        Ok(Ok(responses)) => responses,
+        Ok(Err(err)) => return conv_failed(err),
+        Err(payload) => {
+            PANIC.with(|panic| {
+                panic.borrow_mut().get_or_insert(payload);
+            });
+            return PamReturnCode::Conv_Err as c_int;
+        }
+    };
+    if responses.len() != num_msg {
+        wipe_responses(responses);
+        return conv_failed(ConvError);
+    }
+
+    // libpam and modules free the array and each response with free(3), so they must
+    // not come from the Rust allocator which might differ, e.g. with a custom global one
+    let resp = calloc(num_msg, mem::size_of::<PamResponse>() as size_t) as *mut PamResponse;
+    if resp.is_null() {
+        wipe_responses(responses);
+        return PamReturnCode::Buf_Err as c_int;
+    }
+
+    let truncate = handler.truncate_responses();
+    let mut result: PamReturnCode = PamReturnCode::Success;
+    let mut responses = responses.into_iter();
+    for (i, (message, response)) in messages.iter().zip(&mut responses).enumerate() {
+        let r: &mut PamResponse = &mut *resp.add(i);
+        let response = match (message.data, response) {
+            (_, Response::None) => Ok(ptr::null_mut()),
+            (None, Response::Text(answer)) => alloc_text_response(Ok(answer), truncate),
+            (Some(_), Response::Binary(data)) => {
+                let data = alloc_binary_msg(&data);
+                if data.is_null() {
+                    Err(PamReturnCode::Buf_Err)
+                } else {
+                    Ok(data)
+                }
+            }
+            // The response does not match the kind of the message
+            (_, response) => {
+                wipe_responses(vec![response]);
+                Err(PamReturnCode::Conv_Err)
+            }
+        };
+        match response {
+            Ok(response) => r.resp = response,
+            Err(code) => {
+                result = code;
+                break;
+            }
+        }
+    }
+    wipe_responses(responses.collect());
+
+    // free allocated memory including the responses to earlier messages if an error occured
+    if result != PamReturnCode::Success {
+        for (i, message) in messages.iter().enumerate() {
+            free_response((*resp.add(i)).resp, message.data.is_some());
+        }
+        free(resp as *mut c_void);
+    } else {
+        *out_resp = resp;
+    }
+
+    if result == PamReturnCode::Conv_Err {
+        // The handler returned an invalid response
+        return conv_failed(ConvError);
+    }
+    result as c_int
+}
+
+thread_local! {
+    // Payload of the first panic of a conversation handler on this thread, libpam calls
+    // the conversation function on the thread which called into libpam
+    static PANIC: RefCell<Option<Box<dyn Any + Send>>> = RefCell::new(None);
+    // The error of the last failed conversation handler on this thread, see
+    // `take_conv_failure`
+    static CONV_FAILED: Cell<Option<ConvError>> = const { Cell::new(None) };
+}
+
+// Remember the error of the handler and return `Conv_Err`
+fn conv_failed(err: ConvError) -> c_int {
+    CONV_FAILED.with(|failed| failed.set(Some(err)));
+    PamReturnCode::Conv_Err as c_int
+}
+
+// The error of a conversation handler on this thread which failed or returned invalid
+// responses since the last call. Modules might report this as another error, e.g.
+// `Auth_Err`, so users of `into_pam_conv` should check it after each PAM call.
+pub(crate) fn take_conv_failure() -> Option<ConvError> {
+    CONV_FAILED.with(|failed| failed.take())
+}
+
+// Take the payload of a panic caught in a conversation handler on this thread. Users
+// of `into_pam_conv` should continue it via `panic::resume_unwind` after each PAM call.
+pub(crate) fn take_panic() -> Option<Box<dyn Any + Send>> {
+    PANIC.with(|panic| panic.borrow_mut().take())
+}
+
+// Wipe the answers which are not passed to PAM as they might be passwords
+fn wipe_responses(responses: Vec<Response>) {
+    for response in responses {
+        if let Response::Text(mut answer) = response {
+            wipe_string(&mut answer);
+        }
+    }
+}
+
+// Wipe a response copied by `alloc_text_response` or `alloc_binary_msg` and free it, if
+// the conversation fails after it was copied. It might be a password.
+unsafe fn free_response(response: *mut c_char, binary: bool) {
+    if response.is_null() {
+        return;
+    }
+    if binary {
+        let len = binary_msg_data(response).map_or(0, <[u8]>::len);
+        let data = (response as *mut u8).add(BINARY_MSG_PREFIX_LEN);
+        for i in 0..len {
+            ptr::write_volatile(data.add(i), 0);
+        }
+    } else {
+        wipe_c_str(response);
+    }
+    free(response as *mut c_void);
+}
+
+// Copy the answer to a prompt into a string allocated with `malloc` as libpam frees it.
+// The answer is wiped afterwards as it might be a password.
+fn alloc_text_response(
+    answer: Result<String, ConvError>,
+    truncate: bool,
+) -> Result<*mut c_char, PamReturnCode> {
+    let mut answer = answer.map_err(|_| PamReturnCode::Conv_Err)?;
+    let mut len = answer.len();
+    if len > PAM_MAX_RESP_SIZE && truncate {
+        len = PAM_MAX_RESP_SIZE;
+        while !answer.is_char_boundary(len) {
+            len -= 1;
+        }
+    }
+    let response = if len > PAM_MAX_RESP_SIZE || answer.as_bytes().contains(&0) {
+        Err(PamReturnCode::Conv_Err)
+    } else {
+        unsafe {
+            let buf = malloc(len + 1) as *mut u8;
+            if buf.is_null() {
+                Err(PamReturnCode::Buf_Err)
+            } else {
+                ptr::copy_nonoverlapping(answer.as_ptr(), buf, len);
+                *buf.add(len) = 0;
+                Ok(buf as *mut c_char)
+            }
+        }
+    };
+    wipe_string(&mut answer);
+    response
+}
+
+// Size of the big endian length prefix of binary messages, which includes itself
+const BINARY_MSG_PREFIX_LEN: usize = 4;
+
+// Payload of a binary message, `None` if the length prefix is invalid
+unsafe fn binary_msg_data<'a>(msg: *const c_char) -> Option<&'a [u8]> {
+    if msg.is_null() {
+        return None;
+    }
+    let mut prefix = [0u8; BINARY_MSG_PREFIX_LEN];
+    ptr::copy_nonoverlapping(msg as *const u8, prefix.as_mut_ptr(), prefix.len());
+    let len = usize::try_from(u32::from_be_bytes(prefix)).ok()?;
+    let data_len = len.checked_sub(BINARY_MSG_PREFIX_LEN)?;
+    Some(slice::from_raw_parts(
+        (msg as *const u8).add(BINARY_MSG_PREFIX_LEN),
+        data_len,
+    ))
+}
+
+// Encode `data` as a binary message allocated with `malloc` as libpam frees it.
+// Returns a null pointer if `data` is too large or the allocation failed.
+fn alloc_binary_msg(data: &[u8]) -> *mut c_char {
+    let len = match data
+        .len()
+        .checked_add(BINARY_MSG_PREFIX_LEN)
+        .and_then(|len| u32::try_from(len).ok())
+    {
+        Some(len) => len,
+        None => return ptr::null_mut(),
+    };
+    unsafe {
+        let buf = malloc(len as size_t) as *mut u8;
+        if !buf.is_null() {
+            ptr::copy_nonoverlapping(len.to_be_bytes().as_ptr(), buf, BINARY_MSG_PREFIX_LEN);
+            ptr::copy_nonoverlapping(data.as_ptr(), buf.add(BINARY_MSG_PREFIX_LEN), data.len());
+        }
+        buf as *mut c_char
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        alloc_binary_msg, alloc_text_response, binary_msg_data, converse, prompt_line,
+        take_conv_failure, take_panic, wipe_string, CancelToken, ChannelConv, ConvError,
+        Conversation, ExhaustionPolicy, FnConv, Message, MessageContext, NullConv, PasswordConv,
+        PipeConv, PromptRequest, Recorder, Response, ScriptedConv, SequenceConv, Timeout,
+        Unmatched, PAM_MAX_RESP_SIZE,
+    };
+    use crate::{PamMessage, PamMessageStyle, PamResponse, PamReturnCode};
+
+    use libc::{c_char, c_int, c_void, free};
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Answers binary prompts with the reversed data
+    struct Reverse;
+    impl Conversation for Reverse {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            Err(ConvError)
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            Err(ConvError)
+        }
+        fn binary_prompt(&mut self, data: &[u8]) -> Result<Vec<u8>, ConvError> {
+            Ok(data.iter().rev().cloned().collect())
+        }
+    }
+
+    // Run the conversation trampoline for a single binary message
+    fn run_binary<C: Conversation>(conv: &mut C, msg: *const c_char) -> Result<Vec<u8>, c_int> {
+        let message = PamMessage {
+            msg_style: PamMessageStyle::Binary_Prompt as c_int,
+            msg,
+        };
+        let ptrs = [&message as *const PamMessage];
+
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        let code = unsafe {
+            converse::<C>(
+                1,
+                ptrs.as_ptr() as *mut _,
+                &mut resp,
+                conv as *mut C as *mut c_void,
+            )
+        };
+        if code != PamReturnCode::Success as c_int {
+            return Err(code);
+        }
+
+        unsafe {
+            let data = binary_msg_data((*resp).resp).unwrap().to_vec();
+            free((*resp).resp as *mut c_void);
+            free(resp as *mut c_void);
+            Ok(data)
+        }
+    }
+
+    // Run the conversation trampoline for `messages` and return the responses
+    fn run<C: Conversation>(
+        conv: &mut C,
+        messages: &[(PamMessageStyle, &str)],
+    ) -> Result<Vec<Option<String>>, c_int> {
+        let messages: Vec<_> = messages
+            .iter()
+            .map(|(style, text)| (*style as c_int, text.as_bytes()))
+            .collect();
+        run_raw(conv, &messages)
+    }
+
+    // Like `run`, but with raw styles and texts which need not be valid UTF-8
+    fn run_raw<C: Conversation>(
+        conv: &mut C,
+        messages: &[(c_int, &[u8])],
+    ) -> Result<Vec<Option<String>>, c_int> {
+        let texts: Vec<_> = messages
+            .iter()
+            .map(|(_, text)| CString::new(*text).unwrap())
+            .collect();
+        let messages: Vec<_> = messages
+            .iter()
+            .zip(&texts)
+            .map(|((style, _), text)| PamMessage {
+                msg_style: *style,
+                msg: text.as_ptr(),
+            })
+            .collect();
+        let ptrs: Vec<*const PamMessage> = messages.iter().map(|m| m as *const _).collect();
+
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        let code = unsafe {
+            converse::<C>(
+                ptrs.len() as c_int,
+                ptrs.as_ptr() as *mut _,
+                &mut resp,
+                conv as *mut C as *mut c_void,
+            )
+        };
+        if code != PamReturnCode::Success as c_int {
+            assert!(resp.is_null(), "Got responses alongside {}", code);
+            return Err(code);
+        }
+
+        let responses = (0..ptrs.len())
+            .map(|i| unsafe {
+                let r = &*resp.add(i);
+                if r.resp.is_null() {
+                    return None;
+                }
+                let answer = CStr::from_ptr(r.resp).to_string_lossy().into_owned();
+                free(r.resp as *mut c_void);
+                Some(answer)
+            })
+            .collect();
+        unsafe { free(resp as *mut c_void) };
+        Ok(responses)
+    }
+
+    #[test]
+    fn wipe_answers() {
+        let mut answer = String::from("secret");
+        let (ptr, len) = (answer.as_ptr(), answer.len());
+        wipe_string(&mut answer);
+        assert!(answer.is_empty());
+        // The allocation is still owned by `answer`
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, len) }, &[0; 6]);
+
+        let response = alloc_text_response(Ok("secret".to_string()), false).unwrap();
+        assert_eq!(unsafe { CStr::from_ptr(response) }.to_bytes(), b"secret");
+        unsafe { free(response as *mut c_void) };
+    }
+
+    #[test]
+    fn max_response_size() {
+        let response = |answer: String, truncate| {
+            alloc_text_response(Ok(answer), truncate).map(|response| unsafe {
+                let text = CStr::from_ptr(response).to_str().unwrap().to_string();
+                free(response as *mut c_void);
+                text
+            })
+        };
+
+        let at_limit = "a".repeat(PAM_MAX_RESP_SIZE);
+        assert_eq!(response(at_limit.clone(), false), Ok(at_limit.clone()));
+        let over_limit = "a".repeat(PAM_MAX_RESP_SIZE + 1);
+        assert_eq!(
+            response(over_limit.clone(), false),
+            Err(PamReturnCode::Conv_Err)
+        );
+        assert_eq!(response(over_limit, true), Ok(at_limit));
+
+        // Truncation never splits a character
+        let two_bytes = "a".repeat(PAM_MAX_RESP_SIZE - 1) + "é";
+        assert_eq!(
+            response(two_bytes.clone(), false),
+            Err(PamReturnCode::Conv_Err)
+        );
+        assert_eq!(
+            response(two_bytes, true),
+            Ok("a".repeat(PAM_MAX_RESP_SIZE - 1))
+        );
+        let three_bytes = "a".repeat(PAM_MAX_RESP_SIZE - 2) + "€";
+        assert_eq!(
+            response(three_bytes, true),
+            Ok("a".repeat(PAM_MAX_RESP_SIZE - 2))
+        );
+        let fits = "a".repeat(PAM_MAX_RESP_SIZE - 3) + "€";
+        assert_eq!(response(fits.clone(), true), Ok(fits));
+
+        // Handlers opt into truncation
+        struct Long(bool);
+        impl Conversation for Long {
+            fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+                Ok("a".repeat(PAM_MAX_RESP_SIZE + 1))
+            }
+            fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+                Ok("€".repeat(PAM_MAX_RESP_SIZE))
+            }
+            fn truncate_responses(&self) -> bool {
+                self.0
+            }
+        }
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+        ];
+        assert_eq!(
+            run(&mut Long(false), &messages),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        assert_eq!(
+            run(&mut Long(true), &messages),
+            Ok(vec![
+                Some("a".repeat(PAM_MAX_RESP_SIZE)),
+                Some("€".repeat(PAM_MAX_RESP_SIZE / 3))
+            ])
+        );
+    }
+
+    // Answers the prompts of a batch with the texts of the preceding info messages
+    struct Batch {
+        batches: Vec<Vec<(PamMessageStyle, String)>>,
+        responses: Option<usize>,
+    }
+    impl Conversation for Batch {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            panic!("Called for a single message");
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            panic!("Called for a single message");
+        }
+        fn converse(&mut self, messages: &[Message<'_>]) -> Result<Vec<Response>, ConvError> {
+            self.batches.push(
+                messages
+                    .iter()
+                    .map(|m| (m.style, m.text.to_string()))
+                    .collect(),
+            );
+            let mut info = String::new();
+            let mut responses: Vec<_> = messages
+                .iter()
+                .map(|m| match m.style {
+                    PamMessageStyle::Text_Info => {
+                        info = m.text.to_string();
+                        Response::None
+                    }
+                    _ => Response::Text(info.clone()),
+                })
+                .collect();
+            if let Some(len) = self.responses {
+                responses.resize(len, Response::None);
+            }
+            Ok(responses)
+        }
+    }
+
+    #[test]
+    fn converse_batch() {
+        let mut conv = Batch {
+            batches: Vec::new(),
+            responses: None,
+        };
+        let messages = [
+            (PamMessageStyle::Text_Info, "Enter the code from your phone"),
+            (PamMessageStyle::Prompt_Echo_Off, "Code: "),
+            (PamMessageStyle::Text_Info, "Touch your key"),
+            (PamMessageStyle::Prompt_Echo_On, "Key: "),
+        ];
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![
+                None,
+                Some("Enter the code from your phone".to_string()),
+                None,
+                Some("Touch your key".to_string())
+            ])
+        );
+        let batch: Vec<_> = messages
+            .iter()
+            .map(|(style, text)| (*style, text.to_string()))
+            .collect();
+        assert_eq!(conv.batches, vec![batch]);
+
+        // A response for every message is required
+        conv.responses = Some(1);
+        assert_eq!(
+            run(&mut conv, &messages),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        conv.responses = Some(5);
+        assert_eq!(
+            run(&mut conv, &messages),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        // Text responses to binary prompts are rejected
+        conv.responses = None;
+        let msg = alloc_binary_msg(&[1, 2, 3]);
+        assert_eq!(
+            run_binary(&mut conv, msg),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        unsafe { free(msg as *mut c_void) };
+    }
+
+    #[test]
+    fn message_context() {
+        // Records the context of every message
+        struct Steps(Vec<(PamMessageStyle, MessageContext)>);
+        impl Conversation for Steps {
+            fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+                panic!("Called for a single message");
+            }
+            fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+                panic!("Called for a single message");
+            }
+            fn converse(&mut self, messages: &[Message<'_>]) -> Result<Vec<Response>, ConvError> {
+                self.0
+                    .extend(messages.iter().map(|m| (m.style, m.context())));
+                Ok(messages
+                    .iter()
+                    .map(|m| match m.data() {
+                        Some(data) => Response::Binary(data.to_vec()),
+                        None => Response::Text(String::new()),
+                    })
+                    .collect())
+            }
+        }
+
+        let mut conv = Steps(Vec::new());
+        let messages = [
+            (PamMessageStyle::Text_Info, "Step one"),
+            (PamMessageStyle::Prompt_Echo_Off, "Code: "),
+            (PamMessageStyle::Error_Msg, "Step two"),
+        ];
+        assert!(run(&mut conv, &messages).is_ok());
+        assert!(run(&mut conv, &messages[1..2]).is_ok());
+        let msg = alloc_binary_msg(&[1, 2, 3]);
+        assert!(run_binary(&mut conv, msg).is_ok());
+        unsafe { free(msg as *mut c_void) };
+
+        let context = |index, total| MessageContext { index, total };
+        assert_eq!(
+            conv.0,
+            vec![
+                (PamMessageStyle::Text_Info, context(0, 3)),
+                (PamMessageStyle::Prompt_Echo_Off, context(1, 3)),
+                (PamMessageStyle::Error_Msg, context(2, 3)),
+                (PamMessageStyle::Prompt_Echo_Off, context(0, 1)),
+                (PamMessageStyle::Binary_Prompt, context(0, 1)),
+            ]
+        );
+        assert_eq!(
+            Message::new(PamMessageStyle::Text_Info, "").context(),
+            context(0, 1)
+        );
+    }
+
+    #[test]
+    fn conversation_failures() {
+        let messages = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        // Tests might share the thread
+        take_conv_failure();
+
+        // Handler errors and invalid responses are remembered until taken
+        assert!(run(&mut NullConv::new(), &messages).is_err());
+        assert_eq!(take_conv_failure(), Some(ConvError));
+        assert_eq!(take_conv_failure(), None);
+        let mut conv = FnConv::new(|_, _| Ok(Some("nul\0byte".to_string())));
+        assert!(run(&mut conv, &messages).is_err());
+        assert_eq!(take_conv_failure(), Some(ConvError));
+
+        // Errors in the messages sent by PAM are not the handler's fault
+        let mut conv = SequenceConv::new("alice", vec!["secret"]);
+        assert!(run_binary(&mut conv, std::ptr::null()).is_err());
+        assert_eq!(take_conv_failure(), None);
+        assert!(run(&mut conv, &messages).is_ok());
+        assert_eq!(take_conv_failure(), None);
+    }
+
+    #[test]
+    fn converse_default_dispatch() {
+        let mut conv = Recorder::new(PasswordConv::new());
+        conv.set_credentials("alice", "secret");
+        let messages = [
+            Message::new(PamMessageStyle::Prompt_Echo_On, "login: "),
+            Message::new(PamMessageStyle::Text_Info, "Welcome"),
+            Message::new(PamMessageStyle::Prompt_Echo_Off, "Password: "),
+        ];
+        assert_eq!(
+            conv.converse(&messages),
+            Ok(vec![
+                Response::Text("alice".to_string()),
+                Response::None,
+                Response::Text("secret".to_string())
+            ])
+        );
+        assert_eq!(conv.transcript().len(), 3);
+
+        let data = [1, 2, 3];
+        let binary = Message {
+            style: PamMessageStyle::Binary_Prompt,
+            text: "",
+            raw_style: PamMessageStyle::Binary_Prompt as c_int,
+            raw: None,
+            data: Some(&data),
+            context: MessageContext { index: 0, total: 1 },
+        };
+        assert_eq!(
+            Reverse.converse(&[binary]),
+            Ok(vec![Response::Binary(vec![3, 2, 1])])
+        );
+        assert_eq!(conv.converse(&[binary]), Err(ConvError));
+    }
+
+    // Answers vendor-specific prompts with their style
+    struct Vendor;
+    impl Conversation for Vendor {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            Ok("alice".to_string())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            Err(ConvError)
+        }
+        fn unknown_style(&mut self, style: c_int, msg: &str) -> Result<Option<String>, ConvError> {
+            match style {
+                42 => Ok(Some(format!("{} {}", msg, style))),
+                43 => Ok(None),
+                _ => Err(ConvError),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_styles() {
+        let login = PamMessageStyle::Prompt_Echo_On as c_int;
+        let messages = [
+            (login, &b"login: "[..]),
+            (42, &b"vendor"[..]),
+            (43, &b"note"[..]),
+        ];
+        assert_eq!(
+            run_raw(&mut Vendor, &messages),
+            Ok(vec![
+                Some("alice".to_string()),
+                Some("vendor 42".to_string()),
+                None
+            ])
+        );
+        assert_eq!(
+            run_raw(&mut Vendor, &[(44, &b"unsupported"[..])]),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        // Unknown styles are not mistaken for login prompts by default
+        let mut conv = SequenceConv::new("alice", vec!["secret"]);
+        assert_eq!(
+            run_raw(&mut conv, &[(42, &b"vendor"[..])]),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        let message = Message {
+            style: PamMessageStyle::from(42),
+            text: "vendor",
+            raw_style: 42,
+            raw: None,
+            data: None,
+            context: MessageContext { index: 0, total: 1 },
+        };
+        assert!(!message.is_known_style());
+        assert_eq!(message.raw_style(), 42);
+        let message = Message::new(PamMessageStyle::Text_Info, "Welcome");
+        assert!(message.is_known_style());
+    }
+
+    #[test]
+    fn panicking_handler() {
+        let mut conv = FnConv::new(|_style, _msg| -> Result<Option<String>, ConvError> {
+            panic!("poisoned");
+        });
+        let messages = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        assert_eq!(
+            run(&mut conv, &messages),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        let payload = take_panic().expect("Panic was not stashed");
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"poisoned"));
+        assert!(take_panic().is_none());
+    }
+
+    // Records the exact bytes of the prompts
+    #[derive(Default)]
+    struct Bytes {
+        prompts: Vec<Vec<u8>>,
+        texts: Vec<String>,
+    }
+    impl Conversation for Bytes {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            Err(ConvError)
+        }
+        fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+            self.prompts.push(msg.to_bytes().to_vec());
+            Ok("geheim".to_string())
+        }
+        fn text_info(&mut self, msg: &str) {
+            self.texts.push(msg.to_string());
+        }
+        fn radio_prompt(&mut self, msg: &str) -> Result<String, ConvError> {
+            self.texts.push(msg.to_string());
+            Ok("ja".to_string())
+        }
+    }
+
+    #[test]
+    fn latin1_prompts() {
+        // "Passwort für alice: " and "Grüße" encoded as ISO-8859-1
+        let prompt = &b"Passwort f\xfcr alice: "[..];
+        let info = &b"Gr\xfc\xdfe"[..];
+        let messages = [
+            (PamMessageStyle::Text_Info as c_int, info),
+            (PamMessageStyle::Prompt_Echo_Off as c_int, prompt),
+            (PamMessageStyle::Radio_Type as c_int, info),
+        ];
+
+        let mut conv = Bytes::default();
+        assert_eq!(
+            run_raw(&mut conv, &messages),
+            Ok(vec![
+                None,
+                Some("geheim".to_string()),
+                Some("ja".to_string())
+            ])
+        );
+        assert_eq!(conv.prompts, vec![prompt.to_vec()]);
+        assert_eq!(conv.texts, vec!["Gr\u{fffd}\u{fffd}e"; 2]);
+
+        // Handlers overriding `converse` get the exact bytes via `Message::bytes`
+        let raw = CString::new(prompt).unwrap();
+        let message = Message {
+            style: PamMessageStyle::Prompt_Echo_Off,
+            text: "Passwort f\u{fffd}r alice: ",
+            raw_style: PamMessageStyle::Prompt_Echo_Off as c_int,
+            raw: Some(&raw),
+            data: None,
+            context: MessageContext { index: 0, total: 1 },
+        };
+        assert_eq!(message.bytes(), prompt);
+        assert_eq!(
+            Message::new(PamMessageStyle::Text_Info, "Hi").bytes(),
+            b"Hi"
+        );
+    }
+
+    #[test]
+    fn boxed_handlers() {
+        fn handler(interactive: bool) -> Box<dyn Conversation> {
+            if interactive {
+                Box::new(SequenceConv::new("alice", vec!["secret"]))
+            } else {
+                Box::new(NullConv::new().with_username("alice"))
+            }
+        }
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+        ];
+
+        // The trampoline gets a pointer to the box and calls through the trait object
+        let mut conv = handler(true);
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![Some("alice".to_string()), Some("secret".to_string())])
+        );
+        let mut conv = handler(false);
+        assert_eq!(
+            run(&mut conv, &messages),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        // Overridden methods are reached through the box, too
+        let mut conv: Box<dyn Conversation> = Box::new(Batch {
+            batches: Vec::new(),
+            responses: None,
+        });
+        assert!(run(&mut conv, &messages).is_ok());
+
+        // Borrowed handlers stay usable afterwards
+        let mut inner = NullConv::new().with_username("alice").record_messages();
+        let mut conv = &mut inner;
+        let info = [(PamMessageStyle::Text_Info, "Welcome")];
+        assert_eq!(run(&mut conv, &info), Ok(vec![None]));
+        assert_eq!(inner.messages().len(), 1);
+    }
+
+    #[test]
+    fn password_conv_login() {
+        let login = [(PamMessageStyle::Prompt_Echo_On, "login: ")];
+
+        // Without credentials the login prompt is not answered with an empty string
+        let mut conv = PasswordConv::new();
+        assert!(!conv.has_credentials());
+        assert_eq!(
+            run(&mut conv, &login),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        conv.set_login_fallback(|prompt| {
+            assert_eq!(prompt, "login: ");
+            Ok("bob".to_string())
+        });
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("bob".to_string())]));
+
+        // Credentials take precedence over the fallback, even an empty login
+        conv.set_credentials("", "secret");
+        assert!(conv.has_credentials());
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some(String::new())]));
+    }
+
+    #[cfg(feature = "secrecy")]
+    #[test]
+    fn password_conv_password_fn() {
+        use secrecy::SecretString;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let mut conv = PasswordConv::with_password_fn("alice", move || {
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(SecretString::new("secret".to_string())),
+                _ => Err(ConvError),
+            }
+        });
+        assert!(conv.has_credentials());
+
+        // The password is not retrieved unless PAM asks for it
+        let login = [(PamMessageStyle::Prompt_Echo_On, "login: ")];
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("alice".to_string())]));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let password = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        assert_eq!(
+            run(&mut conv, &password),
+            Ok(vec![Some("secret".to_string())])
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Errors of the callback fail the conversation
+        assert_eq!(
+            run(&mut conv, &password),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Stored credentials replace the callback
+        conv.set_credentials("alice", "other");
+        assert_eq!(
+            run(&mut conv, &password),
+            Ok(vec![Some("other".to_string())])
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn password_conv_clear_credentials() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("alice", "secret");
+        conv.clear_credentials();
+        assert!(conv.has_credentials());
+
+        // The login is kept, but the password is gone
+        let login = [(PamMessageStyle::Prompt_Echo_On, "login: ")];
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("alice".to_string())]));
+        let password = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        assert_eq!(
+            run(&mut conv, &password),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        conv.set_credentials("alice", "other");
+        assert_eq!(
+            run(&mut conv, &password),
+            Ok(vec![Some("other".to_string())])
+        );
+    }
+
+    #[cfg(feature = "secrecy")]
+    #[test]
+    fn password_conv_secret_credentials() {
+        use secrecy::SecretString;
+
+        let mut conv = PasswordConv::new();
+        conv.set_secret_credentials("alice", SecretString::new("hunter2".to_string()));
+        assert!(conv.has_credentials());
+        assert!(!format!("{:?}", conv).contains("hunter2"));
+
+        let password = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        assert_eq!(
+            run(&mut conv, &password),
+            Ok(vec![Some("hunter2".to_string())])
+        );
+
+        conv.set_credentials("alice", "other");
+        assert!(!format!("{:?}", conv).contains("other"));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn credential_target() {
+        use super::CredentialTarget;
+        use secrecy::SecretString;
+
+        fn preset<C: CredentialTarget>(conv: &mut C) {
+            conv.set_username("alice");
+            conv.set_password(SecretString::new("hunter2".to_string()));
+        }
+
+        let login = [(PamMessageStyle::Prompt_Echo_On, "login: ")];
+        let password = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        let mut conv = PasswordConv::new();
+        preset(&mut conv);
+        assert!(conv.has_credentials());
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("alice".to_string())]));
+        assert_eq!(
+            run(&mut conv, &password),
+            Ok(vec![Some("hunter2".to_string())])
+        );
+
+        // The verification code after the password is kept
+        let mut conv = Recorder::new(SequenceConv::new("", vec!["", "123456"]));
+        preset(&mut conv);
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("alice".to_string())]));
+        assert_eq!(
+            run(&mut conv, &password),
+            Ok(vec![Some("hunter2".to_string())])
+        );
+        assert_eq!(
+            run(&mut conv, &password),
+            Ok(vec![Some("123456".to_string())])
+        );
+    }
+
+    #[test]
+    fn password_conv_collects_messages() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("alice", "secret");
+
+        let responses = run(
+            &mut conv,
+            &[
+                (PamMessageStyle::Error_Msg, "Account locked"),
+                (PamMessageStyle::Prompt_Echo_On, "login: "),
+                (PamMessageStyle::Text_Info, "Password expires in 3 days"),
+                (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            responses,
+            vec![
+                None,
+                Some("alice".to_string()),
+                None,
+                Some("secret".to_string())
+            ]
+        );
+        assert_eq!(
+            conv.take_messages(),
+            vec![
+                (PamMessageStyle::Error_Msg, "Account locked".to_string()),
+                (
+                    PamMessageStyle::Text_Info,
+                    "Password expires in 3 days".to_string()
+                ),
+            ]
+        );
+        assert!(conv.messages().is_empty());
+    }
+
+    #[test]
+    fn password_conv_prompt_matching() {
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("alice", "secret");
+        let stack = [
+            (PamMessageStyle::Prompt_Echo_Off, "Mot de passe : "),
+            (PamMessageStyle::Prompt_Echo_Off, "Verification code: "),
+        ];
+
+        // Without a matcher every prompt gets the password
+        assert_eq!(
+            run(&mut conv, &stack),
+            Ok(vec![Some("secret".to_string()), Some("secret".to_string())])
+        );
+
+        // Localized prompts are matched, others fail the conversation by default
+        conv.set_password_prompt("Mot de passe");
+        assert_eq!(
+            run(&mut conv, &stack[..1]),
+            Ok(vec![Some("secret".to_string())])
+        );
+        assert_eq!(
+            run(&mut conv, &stack),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        conv.set_unmatched(Unmatched::Empty);
+        assert_eq!(
+            run(&mut conv, &stack),
+            Ok(vec![Some("secret".to_string()), Some(String::new())])
+        );
+
+        // The second prompt of the stack is answered by the fallback
+        conv.set_password_prompt_fn(|prompt| prompt.starts_with("Mot de passe"));
+        conv.set_unmatched(Unmatched::Passthrough(Box::new(|prompt: &str| {
+            assert_eq!(prompt, "Verification code: ");
+            Ok("123456".to_string())
+        })));
+        assert_eq!(
+            run(&mut conv, &stack),
+            Ok(vec![Some("secret".to_string()), Some("123456".to_string())])
+        );
+
+        // The login prompt is not affected
+        assert_eq!(
+            run(&mut conv, &[(PamMessageStyle::Prompt_Echo_On, "login: ")]),
+            Ok(vec![Some("alice".to_string())])
+        );
+    }
+
+    #[test]
+    fn failed_prompt_aborts_conversation() {
+        let mut conv = SequenceConv::new("alice", vec!["secret"]);
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Verification code: "),
+        ];
+        // `run` panics if responses are returned alongside the error. The responses
+        // to the first two messages are freed by the trampoline.
+        assert_eq!(
+            run(&mut conv, &messages),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        // Answers containing NUL bytes can't be passed to the module
+        let mut conv = SequenceConv::new("alice", vec!["sec\0ret"]);
+        assert_eq!(
+            run(&mut conv, &messages[1..2]),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+    }
+
+    #[test]
+    fn fn_conv_scripted_login() {
+        let mut script = vec![
+            (PamMessageStyle::Prompt_Echo_On, "login: ", Some("alice")),
+            (PamMessageStyle::Text_Info, "Last login: yesterday", None),
+            (
+                PamMessageStyle::Prompt_Echo_Off,
+                "Password: ",
+                Some("secret"),
+            ),
+        ]
+        .into_iter();
+        let mut conv = FnConv::new(|style, msg| {
+            let (expected_style, expected_msg, answer) = script.next().expect("Unexpected message");
+            assert_eq!((style, msg), (expected_style, expected_msg));
+            Ok(answer.map(String::from))
+        });
+
+        let responses = run(
+            &mut conv,
+            &[
+                (PamMessageStyle::Prompt_Echo_On, "login: "),
+                (PamMessageStyle::Text_Info, "Last login: yesterday"),
+                (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            ],
+        );
+        assert_eq!(
+            responses,
+            Ok(vec![
+                Some("alice".to_string()),
+                None,
+                Some("secret".to_string())
+            ])
+        );
+        drop(conv);
+        assert_eq!(script.next(), None);
+    }
+
+    #[test]
+    fn fn_conv_login_and_failures() {
+        let mut conv = FnConv::new(|style, _msg| match style {
+            PamMessageStyle::Prompt_Echo_On => panic!("login was not used"),
+            PamMessageStyle::Radio_Type => Err(ConvError),
+            _ => Ok(None),
+        })
+        .with_login("alice");
+
+        let login = [(PamMessageStyle::Prompt_Echo_On, "login: ")];
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("alice".to_string())]));
+
+        // Prompts must be answered
+        let password = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        let radio = [(PamMessageStyle::Radio_Type, "Push? (yes/no)")];
+        for messages in &[&password[..], &radio[..]] {
+            assert_eq!(
+                run(&mut conv, messages),
+                Err(PamReturnCode::Conv_Err as c_int)
+            );
+        }
+    }
+
+    #[test]
+    fn recorder_transcript() {
+        let mut conv = Recorder::new(PasswordConv::new());
+        conv.set_credentials("alice", "secret");
+
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            (PamMessageStyle::Text_Info, "Welcome"),
+        ];
+        let responses = run(&mut conv, &messages).unwrap();
+        assert_eq!(responses[1].as_deref(), Some("secret"));
+
+        let radio = [(PamMessageStyle::Radio_Type, "Push? (yes/no)")];
+        assert!(run(&mut conv, &radio).is_err());
+
+        let transcript = conv.transcript().to_vec();
+        assert_eq!(
+            transcript,
+            vec![
+                (PamMessageStyle::Prompt_Echo_On, "login: ".to_string(), true),
+                (
+                    PamMessageStyle::Prompt_Echo_Off,
+                    "Password: ".to_string(),
+                    true
+                ),
+                (PamMessageStyle::Text_Info, "Welcome".to_string(), false),
+                (
+                    PamMessageStyle::Radio_Type,
+                    "Push? (yes/no)".to_string(),
+                    false
+                ),
+            ]
+        );
+        // Answers are never recorded
+        assert!(!transcript
+            .iter()
+            .any(|(_, text, _)| text.contains("secret")));
+        // The wrapped handler still sees all messages
+        assert_eq!(
+            conv.messages(),
+            &[(PamMessageStyle::Text_Info, "Welcome".to_string())][..]
+        );
+
+        conv.clear();
+        assert!(conv.transcript().is_empty());
+    }
+
+    #[test]
+    fn scripted_conv_follows_script() {
+        let mut conv = ScriptedConv::new(vec![
+            ("Password:", "hunter2"),
+            ("Verification code:", "123456"),
+        ]);
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            (PamMessageStyle::Text_Info, "Check your phone"),
+            (PamMessageStyle::Prompt_Echo_Off, "Verification code: "),
+        ];
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![
+                Some("hunter2".to_string()),
+                None,
+                Some("123456".to_string())
+            ])
+        );
+        assert_eq!(conv.remaining(), 0);
+        assert_eq!(
+            conv.prompts(),
+            &[
+                (PamMessageStyle::Prompt_Echo_Off, "Password: ".to_string()),
+                (
+                    PamMessageStyle::Prompt_Echo_Off,
+                    "Verification code: ".to_string()
+                ),
+            ][..]
+        );
+
+        // Prompts are matched in order
+        let mut conv = ScriptedConv::new(vec![("Password:", "hunter2"), ("code:", "123456")]);
+        let debug = format!("{:?}", conv);
+        assert!(debug.contains("Password:"));
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("123456"));
+        let code = [(PamMessageStyle::Prompt_Echo_Off, "Verification code: ")];
+        assert_eq!(run(&mut conv, &code), Err(PamReturnCode::Conv_Err as c_int));
+        assert_eq!(conv.remaining(), 2);
+        assert_eq!(conv.prompts().len(), 1);
+    }
+
+    #[test]
+    fn scripted_conv_unmatched() {
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+        ];
+
+        let mut conv = ScriptedConv::new(vec![("Password:", "hunter2")])
+            .on_unmatched(Unmatched::<NullConv>::Empty);
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![Some(String::new()), Some("hunter2".to_string())])
+        );
+
+        let inner = NullConv::new().with_username("alice").record_messages();
+        let mut conv = ScriptedConv::new(vec![("Password:", "hunter2")])
+            .on_unmatched(Unmatched::Passthrough(inner));
+        let info = [(PamMessageStyle::Text_Info, "Welcome")];
+        assert_eq!(run(&mut conv, &info), Ok(vec![None]));
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![Some("alice".to_string()), Some("hunter2".to_string())])
+        );
+        assert_eq!(conv.prompts().len(), 2);
+        if let Unmatched::Passthrough(ref inner) = conv.unmatched {
+            assert_eq!(
+                inner.messages(),
+                &[(PamMessageStyle::Text_Info, "Welcome".to_string())][..]
+            );
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn scripted_conv_regex() {
+        let code = regex::Regex::new(r"^(Verification|OTP) code: $").unwrap();
+        let mut conv = ScriptedConv::new(vec![(super::Pattern::from(code), "123456")]);
+        let messages = [(PamMessageStyle::Prompt_Echo_Off, "OTP code: ")];
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![Some("123456".to_string())])
+        );
+    }
+
+    #[test]
+    fn sequence_conv_two_factor() {
+        let mut conv = SequenceConv::new("alice", vec!["secret", "123456"]);
+        let prompts = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Verification code: "),
+        ];
+        assert_eq!(
+            run(&mut conv, &prompts),
+            Ok(vec![
+                Some("alice".to_string()),
+                Some("secret".to_string()),
+                Some("123456".to_string())
+            ])
+        );
+        assert_eq!(conv.remaining(), 0);
+    }
+
+    #[test]
+    fn sequence_conv_exhausted() {
+        let prompt = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        fn answer(response: &str) -> Result<Vec<Option<String>>, c_int> {
+            Ok(vec![Some(response.to_string())])
+        }
+
+        let mut conv = SequenceConv::new("alice", vec!["secret"]);
+        assert_eq!(run(&mut conv, &prompt), answer("secret"));
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        let mut conv =
+            SequenceConv::new("alice", vec!["secret"]).on_exhausted(ExhaustionPolicy::RepeatLast);
+        assert_eq!(run(&mut conv, &prompt), answer("secret"));
+        assert_eq!(run(&mut conv, &prompt), answer("secret"));
+
+        let mut conv = SequenceConv::new("alice", Vec::<String>::new())
+            .on_exhausted(ExhaustionPolicy::RepeatLast);
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        let mut conv =
+            SequenceConv::new("alice", vec!["secret"]).on_exhausted(ExhaustionPolicy::Empty);
+        assert_eq!(run(&mut conv, &prompt), answer("secret"));
+        assert_eq!(run(&mut conv, &prompt), answer(""));
+    }
+
+    #[test]
+    fn clear_credentials_wipes_responses() {
+        let prompt = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        let login = [(PamMessageStyle::Prompt_Echo_On, "login: ")];
+        let mut conv = SequenceConv::new("alice", vec!["secret", "123456"])
+            .on_exhausted(ExhaustionPolicy::RepeatLast);
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Ok(vec![Some("secret".to_string())])
+        );
+        conv.clear_credentials();
+        assert_eq!(conv.remaining(), 0);
+        // Neither the next nor the last response is left, but the login is kept
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("alice".to_string())]));
+
+        let mut conv = ScriptedConv::new(vec![("Password:", "hunter2")]);
+        conv.clear_credentials();
+        assert_eq!(conv.remaining(), 0);
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+    }
+
+    #[test]
+    fn sequence_conv_debug_hides_responses() {
+        let mut conv = SequenceConv::new("alice", vec!["secret", "123456"])
+            .on_exhausted(ExhaustionPolicy::RepeatLast);
+        assert!(!format!("{:?}", conv).contains("secret"));
+
+        let prompt = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Ok(vec![Some("secret".to_string())])
+        );
+        let debug = format!("{:?}", conv);
+        assert!(debug.contains("alice"));
+        assert!(!debug.contains("secret"));
+        assert!(!debug.contains("123456"));
+    }
+
+    #[test]
+    fn null_conv_fails_prompts() {
+        let mut conv = NullConv::new().with_username("alice").record_messages();
+        assert_eq!(conv.username(), Some("alice"));
+
+        let login = [
+            (PamMessageStyle::Text_Info, "Checking account"),
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+        ];
+        assert_eq!(
+            run(&mut conv, &login),
+            Ok(vec![None, Some("alice".to_string())])
+        );
+
+        // The conversation is aborted at the first unexpected prompt
+        let unexpected = [
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            (PamMessageStyle::Error_Msg, "never delivered"),
+        ];
+        assert_eq!(
+            run(&mut conv, &unexpected),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        let radio = [(PamMessageStyle::Radio_Type, "Continue? (yes/no)")];
+        assert_eq!(
+            run(&mut conv, &radio),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        assert_eq!(
+            conv.messages(),
+            &[(PamMessageStyle::Text_Info, "Checking account".to_string())][..]
+        );
+
+        // Without a username even the login prompt fails and nothing is recorded
+        let mut conv = NullConv::new();
+        assert_eq!(
+            run(&mut conv, &login),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        assert!(conv.messages().is_empty());
+    }
+
+    #[test]
+    fn channel_conv_forwards_messages() {
+        let (sender, requests) = mpsc::channel::<PromptRequest>();
+        let ui = thread::spawn(move || {
+            let mut shown = Vec::new();
+            for request in requests {
+                shown.push((request.style, request.text.clone()));
+                let answer = match request.style {
+                    PamMessageStyle::Prompt_Echo_On => Ok("alice".to_string()),
+                    PamMessageStyle::Prompt_Echo_Off => Ok("secret".to_string()),
+                    PamMessageStyle::Radio_Type => Err(ConvError),
+                    _ => continue,
+                };
+                request.reply.send(answer).unwrap();
+            }
+            shown
+        });
+
+        let mut conv = ChannelConv::new(sender);
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Text_Info, "Welcome"),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+        ];
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![
+                Some("alice".to_string()),
+                None,
+                Some("secret".to_string())
+            ])
+        );
+        let radio = [(PamMessageStyle::Radio_Type, "Push? (yes/no)")];
+        assert_eq!(
+            run(&mut conv, &radio),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        // Dropping the handler ends the loop on the UI thread
+        drop(conv);
+        let expected: Vec<_> = messages
+            .iter()
+            .chain(&radio)
+            .map(|(style, text)| (*style, text.to_string()))
+            .collect();
+        assert_eq!(ui.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn channel_conv_fails_instead_of_blocking() {
+        let prompt = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+
+        // The receiving side is gone
+        let (sender, requests) = mpsc::channel();
+        drop(requests);
+        let mut conv = ChannelConv::new(sender);
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        // Messages without a receiver don't fail the conversation
+        let info = [(PamMessageStyle::Text_Info, "Welcome")];
+        assert_eq!(run(&mut conv, &info), Ok(vec![None]));
+
+        // The reply is dropped without an answer
+        let (sender, requests) = mpsc::channel();
+        let ui = thread::spawn(move || requests.into_iter().count());
+        let mut conv = ChannelConv::new(sender);
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        drop(conv);
+        assert_eq!(ui.join().unwrap(), 1);
+
+        // Nobody answers in time
+        let (sender, requests) = mpsc::channel();
+        let mut conv = ChannelConv::new(sender).with_timeout(Duration::from_millis(10));
+        assert_eq!(
+            run(&mut conv, &prompt),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        let late = requests.recv().unwrap();
+        assert!(late.reply.send(Ok("secret".to_string())).is_err());
+    }
+
+    // Blocks in every prompt until the sender of `release` is dropped
+    struct Stall {
+        release: mpsc::Receiver<()>,
+    }
+    impl Conversation for Stall {
+        fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            let _ = self.release.recv();
+            Ok("alice".to_string())
+        }
+        fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+            Err(ConvError)
+        }
+    }
+
+    #[test]
+    fn timeout_stalled_handler() {
+        let (release, receiver) = mpsc::channel();
+        let mut conv = Timeout::new(Stall { release: receiver }, Duration::from_millis(10));
+        let login = [(PamMessageStyle::Prompt_Echo_On, "login: ")];
+        assert_eq!(
+            run(&mut conv, &login),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        // The handler is still busy with the first prompt
+        assert_eq!(
+            run(&mut conv, &login),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        // Once the handler returns it answers in time again
+        drop(release);
+        conv.timeout = Duration::from_secs(5);
+        assert_eq!(run(&mut conv, &login), Ok(vec![Some("alice".to_string())]));
+        let password = [(PamMessageStyle::Prompt_Echo_Off, "Password: ")];
+        assert_eq!(
+            run(&mut conv, &password),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+    }
+
+    #[test]
+    fn channel_conv_cancelled() {
+        let token = CancelToken::new();
+        let (sender, requests) = mpsc::channel::<PromptRequest>();
+        let ui_token = token.clone();
+        let ui = thread::spawn(move || {
+            // Keep the replies so only the cancellation can end the wait
+            let mut pending = Vec::new();
+            for request in requests {
+                match request.style {
+                    PamMessageStyle::Prompt_Echo_On => {
+                        request.reply.send(Ok("alice".to_string())).unwrap()
+                    }
+                    _ => {
+                        ui_token.cancel();
+                        pending.push(request.reply);
+                    }
+                }
+            }
+            pending.len()
+        });
+
+        let mut conv = Recorder::new(ChannelConv::new(sender).with_cancel_token(token.clone()));
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+        ];
+        // `run` checks that no responses are returned alongside the error
+        assert_eq!(
+            run(&mut conv, &messages),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        assert!(conv.is_cancelled());
+
+        // Following prompts fail without reaching the UI thread
+        assert_eq!(
+            run(&mut conv, &messages[..1]),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        token.reset();
+        assert!(!conv.is_cancelled());
+        assert_eq!(
+            run(&mut conv, &messages[..1]),
+            Ok(vec![Some("alice".to_string())])
+        );
+
+        drop(conv);
+        assert_eq!(ui.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn pipe_conv() {
+        let input = &b"alice\r\nsecret\n\nlast"[..];
+        let mut conv = PipeConv::new(input).with_echo(Vec::new());
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Text_Info, "Welcome"),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+            (PamMessageStyle::Radio_Type, "Continue?"),
+        ];
+        assert_eq!(
+            run(&mut conv, &messages),
+            Ok(vec![
+                Some("alice".to_string()),
+                None,
+                Some("secret".to_string()),
+                Some(String::new())
+            ])
+        );
+        // The last line needs no newline, but then the input is exhausted
+        assert_eq!(
+            run(&mut conv, &messages[2..3]),
+            Ok(vec![Some("last".to_string())])
+        );
+        assert_eq!(
+            run(&mut conv, &messages[2..3]),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+
+        // Only prompts and messages are logged, never the answers
+        let log = conv.echo.take().unwrap();
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "login: \nWelcome\nPassword: \nContinue?\nPassword: \nPassword: \n"
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_bridge() {
+        use super::{AsyncBridge, AsyncConversation, ConvFuture};
+        use futures::{executor::block_on, future};
+
+        // Answers prompts from an async task, but never the verification code
+        struct Answers(Vec<String>);
+        impl AsyncConversation for Answers {
+            fn prompt_echo(&mut self, _msg: String) -> ConvFuture<'_, Result<String, ConvError>> {
+                Box::pin(async { Ok("alice".to_string()) })
+            }
+            fn prompt_blind(&mut self, msg: String) -> ConvFuture<'_, Result<String, ConvError>> {
+                match msg.as_str() {
+                    "Password: " => Box::pin(async { Ok("secret".to_string()) }),
+                    _ => Box::pin(future::pending()),
+                }
+            }
+            fn text_info(&mut self, msg: String) -> ConvFuture<'_, ()> {
+                Box::pin(async move { self.0.push(msg) })
+            }
+        }
+
+        let messages = [
+            (PamMessageStyle::Prompt_Echo_On, "login: "),
+            (PamMessageStyle::Text_Info, "Welcome"),
+            (PamMessageStyle::Prompt_Echo_Off, "Password: "),
+        ];
+        let (result, conv) = block_on(AsyncBridge::run(Answers(Vec::new()), move |mut bridge| {
+            run(&mut bridge, &messages)
+        }));
+        assert_eq!(
+            result,
+            Ok(vec![
+                Some("alice".to_string()),
+                None,
+                Some("secret".to_string())
+            ])
+        );
+        assert_eq!(conv.0, vec!["Welcome"]);
+
+        // The bridge stops waiting for the answer after the timeout
+        let (result, _) = block_on(AsyncBridge::run(conv, |bridge| {
+            let mut bridge = bridge.with_timeout(Duration::from_millis(10));
+            run(
+                &mut bridge,
+                &[(PamMessageStyle::Prompt_Echo_Off, "Verification code: ")],
+            )
+        }));
+        assert_eq!(result, Err(PamReturnCode::Conv_Err as c_int));
+
+        // Panics are continued on the async side
+        let payload = std::panic::catch_unwind(|| {
+            block_on(AsyncBridge::run(Answers(Vec::new()), |_| {
+                panic!("PAM thread")
+            }))
+        })
+        .unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"PAM thread"));
+    }
+
+    #[test]
+    fn tty_prompt_line() {
+        let msg = CStr::from_bytes_with_nul(b"Password: \0").unwrap();
+        let mut output = Vec::new();
+        // -1 is not a terminal, so echo is left alone
+        let answer = prompt_line(-1, &b"secret\r\nnext\n"[..], &mut output, msg, false);
+        assert_eq!(answer.unwrap(), "secret");
+        assert_eq!(output, b"Password: ");
+
+        // An empty answer is valid, but no answer at all is not
+        let answer = prompt_line(-1, &b"\n"[..], &mut Vec::new(), msg, true);
+        assert_eq!(answer.unwrap(), "");
+        let answer = prompt_line(-1, &b""[..], &mut Vec::new(), msg, true);
+        assert_eq!(answer.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(feature = "interactive")]
+    #[test]
+    fn rpassword_line_without_tty() {
+        use super::rpassword_line;
+
+        // Piped input is read line by line for both kinds of prompts
+        let msg = CStr::from_bytes_with_nul(b"Password: \0").unwrap();
+        let mut input = &b"alice\nsecret\n"[..];
+        let mut output = Vec::new();
+        let answer = rpassword_line(-1, &mut input, &mut output, msg, true);
+        assert_eq!(answer.unwrap(), "alice");
+        let answer = rpassword_line(-1, &mut input, &mut output, msg, false);
+        assert_eq!(answer.unwrap(), "secret");
+        assert_eq!(output, b"Password: Password: ");
+
+        let answer = rpassword_line(-1, &mut input, &mut output, msg, false);
+        assert_eq!(answer.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn radio_prompt() {
+        // Without an implementation of `radio_prompt` the question is asked via `prompt_echo`
+        struct Answer;
+        impl Conversation for Answer {
+            fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+                assert_eq!(msg.to_bytes(), b"Push to phone? (yes/no)");
+                Ok("yes".to_string())
+            }
+            fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+                Err(ConvError)
+            }
+        }
+
+        let radio = [(PamMessageStyle::Radio_Type, "Push to phone? (yes/no)")];
+        assert_eq!(run(&mut Answer, &radio), Ok(vec![Some("yes".to_string())]));
+
+        // `PasswordConv` must not answer with the login
+        let mut conv = PasswordConv::new();
+        conv.set_credentials("alice", "secret");
+        assert_eq!(
+            run(&mut conv, &radio),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+    }
+
+    #[test]
+    fn binary_prompt_round_trip() {
+        for data in &[&b"\x01smart\0card\0"[..], &[]] {
+            let msg = alloc_binary_msg(data);
+            let response = run_binary(&mut Reverse, msg);
+            unsafe { free(msg as *mut c_void) };
+
+            let reversed: Vec<u8> = data.iter().rev().cloned().collect();
+            assert_eq!(response, Ok(reversed));
+        }
+    }
+
+    #[test]
+    fn binary_prompt_encoding() {
+        let msg = alloc_binary_msg(b"\0a");
+        let encoded = unsafe { std::slice::from_raw_parts(msg as *const u8, 6) }.to_vec();
+        assert_eq!(encoded, b"\0\0\0\x06\0a");
+        unsafe { free(msg as *mut c_void) };
+
+        // The length prefix counts itself, so anything below 4 is invalid
+        let invalid = b"\0\0\0\x03";
+        assert_eq!(
+            run_binary(&mut Reverse, invalid.as_ptr() as *const c_char),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+    }
+
+    #[test]
+    fn binary_prompt_unsupported() {
+        let mut conv = PasswordConv::new();
+        let msg = alloc_binary_msg(b"data");
+        assert_eq!(
+            run_binary(&mut conv, msg),
+            Err(PamReturnCode::Conv_Err as c_int)
+        );
+        unsafe { free(msg as *mut c_void) };
+    }
+
+    #[test]
+    fn info_forwards_to_text_info() {
+        struct Collect(Vec<String>);
+        impl Conversation for Collect {
+            fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+                Err(ConvError)
+            }
+            fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+                Err(ConvError)
+            }
+            fn text_info(&mut self, msg: &str) {
+                self.0.push(msg.to_string());
+            }
+        }
+
+        let mut conv = Collect(Vec::new());
+        conv.info(CStr::from_bytes_with_nul(b"caf\xc3\xa9 \xff\0").unwrap());
+        conv.error(CStr::from_bytes_with_nul(b"ignored\0").unwrap());
+        assert_eq!(conv.0, vec!["caf\u{e9} \u{fffd}".to_string()]);
+    }
 }
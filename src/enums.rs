@@ -231,6 +231,11 @@ pub enum PamMessageStyle {
     Prompt_Echo_Off,
     Error_Msg,
     Text_Info,
+
+    /// Linux-PAM extension for yes/no/maybe questions, see `Conversation::radio_prompt`
+    Radio_Type = 5,
+    /// Linux-PAM extension for binary data, see `Conversation::binary_prompt`
+    Binary_Prompt = 7,
 }
 
 impl std::fmt::Display for PamMessageStyle {
@@ -35,11 +35,66 @@ impl PamEnvList {
             }
         }
 
-        drop_env_list(ptr);
+        if !ptr.is_null() {
+            drop_env_list(ptr);
+        }
         return PamEnvList { inner: result.into_iter() };
     }
+
+    // The variables without converting them to UTF-8
+    pub(crate) fn into_os_strings(self) -> IntoIter<(OsString, OsString)> {
+        self.inner
+    }
+}
+
+// Which variables of the PAM environment a `Client` passes on, by name with `*` as
+// wildcard. Denied names are never passed on, if any are allowed only those are.
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EnvFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+#[cfg(feature = "client")]
+impl EnvFilter {
+    pub(crate) fn set_allow(&mut self, patterns: Vec<String>) {
+        self.allow = patterns;
+    }
+
+    pub(crate) fn set_deny(&mut self, patterns: Vec<String>) {
+        self.deny = patterns;
+    }
+
+    pub(crate) fn allows(&self, name: &[u8]) -> bool {
+        let matches = |pattern: &String| glob_match(pattern.as_bytes(), name);
+        !self.deny.iter().any(matches) && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
 }
 
+#[cfg(feature = "client")]
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to continue after the last `*` if the rest doesn't match
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            // Let the `*` match one more byte
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
 
 fn parse_env_line(input: &[u8]) -> Option<(OsString, OsString)> {
     // Strategy (copied from glibc): Variable name and value are separated
@@ -51,7 +106,7 @@ fn parse_env_line(input: &[u8]) -> Option<(OsString, OsString)> {
     if input.is_empty() {
         return None;
     }
-    let pos = memchr(b'=', input).map(|p| p + 1);
+    let pos = memchr(b'=', &input[1..]).map(|p| p + 1);
     pos.map(|p| {
         (
             OsStringExt::from_vec(input[..p].to_vec()),
@@ -75,3 +130,65 @@ fn drop_env_list(ptr: *const *const c_char) {
     }
     unsafe { free(ptr) };
 }
+
+#[cfg(test)]
+mod test {
+    use super::parse_env_line;
+
+    use std::ffi::OsString;
+
+    #[test]
+    fn env_lines() {
+        let pair = |key: &str, value: &str| Some((OsString::from(key), OsString::from(value)));
+        assert_eq!(
+            parse_env_line(b"HOME=/home/alice"),
+            pair("HOME", "/home/alice")
+        );
+        assert_eq!(parse_env_line(b"EMPTY="), pair("EMPTY", ""));
+        assert_eq!(parse_env_line(b"OPTS=a=b"), pair("OPTS", "a=b"));
+        assert_eq!(parse_env_line(b"=weird=1"), pair("=weird", "1"));
+        assert_eq!(parse_env_line(b"MALFORMED"), None);
+        assert_eq!(parse_env_line(b""), None);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn glob_patterns() {
+        use super::glob_match;
+
+        assert!(glob_match(b"LANG", b"LANG"));
+        assert!(!glob_match(b"LANG", b"LANGUAGE"));
+        assert!(glob_match(b"XDG_*", b"XDG_RUNTIME_DIR"));
+        assert!(glob_match(b"XDG_*", b"XDG_"));
+        assert!(glob_match(b"*_DIR", b"XDG_RUNTIME_DIR"));
+        assert!(glob_match(b"LD_*_PATH", b"LD_LIBRARY_PATH"));
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"XDG_*", b"MAIL"));
+        assert!(!glob_match(b"*_PATH", b"PATH"));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn env_filter() {
+        use super::EnvFilter;
+
+        let mut filter = EnvFilter::default();
+        assert!(filter.allows(b"MAIL"));
+
+        filter.set_deny(vec!["LD_*".to_string(), "MAIL".to_string()]);
+        assert!(!filter.allows(b"LD_PRELOAD"));
+        assert!(!filter.allows(b"MAIL"));
+        assert!(filter.allows(b"LANG"));
+
+        // Deny wins over allow
+        filter.set_allow(vec![
+            "XDG_*".to_string(),
+            "LANG".to_string(),
+            "LD_*".to_string(),
+        ]);
+        assert!(filter.allows(b"XDG_RUNTIME_DIR"));
+        assert!(filter.allows(b"LANG"));
+        assert!(!filter.allows(b"LD_PRELOAD"));
+        assert!(!filter.allows(b"TERM"));
+    }
+}
@@ -0,0 +1,294 @@
+//! Typed flags for the individual PAM functions
+//!
+//! libpam passes the flags as ORed integers. These types decode them per function
+//! while keeping all bits, so flags unknown to this crate are not lost.
+
+use std::os::raw::c_int;
+
+use crate::{ffi, PamFlag};
+
+macro_rules! impl_flags {
+    ($name:ident, $known:expr) => {
+        impl $name {
+            /// Decode the raw flags as passed by libpam
+            pub fn from_bits(bits: c_int) -> $name {
+                $name { bits }
+            }
+
+            /// The raw flags including unknown bits
+            pub fn bits(self) -> c_int {
+                self.bits
+            }
+
+            /// All bits which are not known for this function
+            pub fn unknown_bits(self) -> c_int {
+                self.bits & !($known)
+            }
+
+            /// `PAM_SILENT` is set, so no messages should be sent to the user
+            pub fn silent(self) -> bool {
+                self.bits & ffi::PAM_SILENT != 0
+            }
+
+            /// Set `PAM_SILENT`, so no messages are sent to the user
+            pub fn with_silent(self) -> $name {
+                $name::from_bits(self.bits | ffi::PAM_SILENT)
+            }
+        }
+    };
+}
+
+/// Flags for `pam_authenticate` and `pam_acct_mgmt`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AuthFlags {
+    bits: c_int,
+}
+
+impl_flags!(AuthFlags, ffi::PAM_SILENT | ffi::PAM_DISALLOW_NULL_AUTHTOK);
+
+impl AuthFlags {
+    /// `PAM_DISALLOW_NULL_AUTHTOK` is set, so empty authentication tokens must be rejected
+    pub fn disallow_null_authtok(self) -> bool {
+        self.bits & ffi::PAM_DISALLOW_NULL_AUTHTOK != 0
+    }
+
+    /// Set `PAM_DISALLOW_NULL_AUTHTOK`, so empty authentication tokens are rejected
+    pub fn with_disallow_null_authtok(self) -> AuthFlags {
+        AuthFlags::from_bits(self.bits | ffi::PAM_DISALLOW_NULL_AUTHTOK)
+    }
+}
+
+/// The credential action requested from `pam_setcred`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredAction {
+    /// Initialize the credentials (`PAM_ESTABLISH_CRED`)
+    Establish,
+    /// Delete the credentials (`PAM_DELETE_CRED`)
+    Delete,
+    /// Fully reinitialize the credentials (`PAM_REINITIALIZE_CRED`)
+    Reinitialize,
+    /// Extend the lifetime of the credentials (`PAM_REFRESH_CRED`)
+    Refresh,
+}
+
+impl From<CredAction> for PamFlag {
+    fn from(action: CredAction) -> PamFlag {
+        match action {
+            CredAction::Establish => PamFlag::Establish_Cred,
+            CredAction::Delete => PamFlag::Delete_Cred,
+            CredAction::Reinitialize => PamFlag::Reinitialize_Cred,
+            CredAction::Refresh => PamFlag::Refresh_Cred,
+        }
+    }
+}
+
+impl From<CredAction> for SetCredFlags {
+    fn from(action: CredAction) -> SetCredFlags {
+        SetCredFlags::from_bits(PamFlag::from(action) as c_int)
+    }
+}
+
+/// Flags for `pam_setcred`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetCredFlags {
+    bits: c_int,
+}
+
+impl_flags!(
+    SetCredFlags,
+    ffi::PAM_SILENT
+        | ffi::PAM_ESTABLISH_CRED
+        | ffi::PAM_DELETE_CRED
+        | ffi::PAM_REINITIALIZE_CRED
+        | ffi::PAM_REFRESH_CRED
+);
+
+impl SetCredFlags {
+    /// The requested action or `None` if not exactly one action flag is set
+    pub fn action(self) -> Option<CredAction> {
+        let actions = [
+            (ffi::PAM_ESTABLISH_CRED, CredAction::Establish),
+            (ffi::PAM_DELETE_CRED, CredAction::Delete),
+            (ffi::PAM_REINITIALIZE_CRED, CredAction::Reinitialize),
+            (ffi::PAM_REFRESH_CRED, CredAction::Refresh),
+        ];
+        let mut set = actions.iter().filter(|(bit, _)| self.bits & bit != 0);
+        match (set.next(), set.next()) {
+            (Some((_, action)), None) => Some(*action),
+            _ => None,
+        }
+    }
+}
+
+/// Flags for `pam_open_session` and `pam_close_session`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionFlags {
+    bits: c_int,
+}
+
+impl_flags!(SessionFlags, ffi::PAM_SILENT);
+
+/// The phase of a `pam_chauthtok` call, which libpam runs twice for every module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChauthtokPhase {
+    /// Only check whether the token can be changed (`PAM_PRELIM_CHECK`)
+    PrelimCheck,
+    /// Actually change the token (`PAM_UPDATE_AUTHTOK`)
+    UpdateAuthtok,
+}
+
+/// Flags for `pam_chauthtok`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChauthtokFlags {
+    bits: c_int,
+}
+
+impl_flags!(
+    ChauthtokFlags,
+    ffi::PAM_SILENT
+        | ffi::PAM_CHANGE_EXPIRED_AUTHTOK
+        | ffi::PAM_PRELIM_CHECK
+        | ffi::PAM_UPDATE_AUTHTOK
+);
+
+impl ChauthtokFlags {
+    /// Set `PAM_CHANGE_EXPIRED_AUTHTOK`, so only expired tokens are changed
+    pub fn with_change_expired_authtok(self) -> ChauthtokFlags {
+        ChauthtokFlags::from_bits(self.bits | ffi::PAM_CHANGE_EXPIRED_AUTHTOK)
+    }
+
+    /// `PAM_CHANGE_EXPIRED_AUTHTOK` is set, so only expired tokens should be changed
+    pub fn change_expired_authtok(self) -> bool {
+        self.bits & ffi::PAM_CHANGE_EXPIRED_AUTHTOK != 0
+    }
+
+    /// The phase set by libpam or `None` if not exactly one of them is set
+    pub fn phase(self) -> Option<ChauthtokPhase> {
+        let prelim = self.bits & ffi::PAM_PRELIM_CHECK != 0;
+        let update = self.bits & ffi::PAM_UPDATE_AUTHTOK != 0;
+        match (prelim, update) {
+            (true, false) => Some(ChauthtokPhase::PrelimCheck),
+            (false, true) => Some(ChauthtokPhase::UpdateAuthtok),
+            _ => None,
+        }
+    }
+
+    // The bits an application may pass to `pam_chauthtok`, the phases are set by libpam
+    pub(crate) fn application_bits(self) -> c_int {
+        self.bits & !(ffi::PAM_PRELIM_CHECK | ffi::PAM_UPDATE_AUTHTOK)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UNKNOWN: c_int = 0x0100_0000;
+
+    #[test]
+    fn auth_flags() {
+        let flags = AuthFlags::from_bits(ffi::PAM_SILENT | UNKNOWN);
+        assert!(flags.silent());
+        assert!(!flags.disallow_null_authtok());
+        assert_eq!(flags.unknown_bits(), UNKNOWN);
+        assert_eq!(flags.bits(), ffi::PAM_SILENT | UNKNOWN);
+
+        let flags = AuthFlags::from_bits(ffi::PAM_DISALLOW_NULL_AUTHTOK);
+        assert!(!flags.silent());
+        assert!(flags.disallow_null_authtok());
+        assert_eq!(flags.unknown_bits(), 0);
+
+        let flags = AuthFlags::default()
+            .with_disallow_null_authtok()
+            .with_silent();
+        assert_eq!(
+            flags.bits(),
+            ffi::PAM_DISALLOW_NULL_AUTHTOK | ffi::PAM_SILENT
+        );
+    }
+
+    #[test]
+    fn setcred_flags() {
+        let flags = SetCredFlags::from_bits(ffi::PAM_SILENT | ffi::PAM_REFRESH_CRED);
+        assert!(flags.silent());
+        assert_eq!(flags.action(), Some(CredAction::Refresh));
+        assert_eq!(flags.unknown_bits(), 0);
+
+        let flags = SetCredFlags::from_bits(ffi::PAM_DELETE_CRED | UNKNOWN);
+        assert_eq!(flags.action(), Some(CredAction::Delete));
+        assert_eq!(flags.unknown_bits(), UNKNOWN);
+
+        let both = SetCredFlags::from_bits(ffi::PAM_ESTABLISH_CRED | ffi::PAM_DELETE_CRED);
+        assert_eq!(both.action(), None);
+        assert_eq!(SetCredFlags::default().action(), None);
+
+        let flags = SetCredFlags::from(CredAction::Establish).with_silent();
+        assert_eq!(flags.bits(), ffi::PAM_ESTABLISH_CRED | ffi::PAM_SILENT);
+        assert_eq!(flags.action(), Some(CredAction::Establish));
+    }
+
+    #[test]
+    fn session_flags() {
+        let flags = SessionFlags::from_bits(ffi::PAM_SILENT);
+        assert!(flags.silent());
+        assert_eq!(flags.unknown_bits(), 0);
+
+        // Flags of other functions are unknown for sessions
+        let flags = SessionFlags::from_bits(ffi::PAM_ESTABLISH_CRED);
+        assert!(!flags.silent());
+        assert_eq!(flags.unknown_bits(), ffi::PAM_ESTABLISH_CRED);
+    }
+
+    #[test]
+    fn chauthtok_flags() {
+        let flags = ChauthtokFlags::from_bits(ffi::PAM_PRELIM_CHECK | ffi::PAM_SILENT);
+        assert_eq!(flags.phase(), Some(ChauthtokPhase::PrelimCheck));
+        assert!(flags.silent());
+        assert!(!flags.change_expired_authtok());
+
+        let flags =
+            ChauthtokFlags::from_bits(ffi::PAM_UPDATE_AUTHTOK | ffi::PAM_CHANGE_EXPIRED_AUTHTOK);
+        assert_eq!(flags.phase(), Some(ChauthtokPhase::UpdateAuthtok));
+        assert!(flags.change_expired_authtok());
+        assert_eq!(flags.unknown_bits(), 0);
+
+        let flags = ChauthtokFlags::from_bits(UNKNOWN);
+        assert_eq!(flags.phase(), None);
+        assert_eq!(flags.unknown_bits(), UNKNOWN);
+    }
+
+    #[test]
+    fn chauthtok_flags_for_applications() {
+        let flags = ChauthtokFlags::default();
+        assert_eq!(flags.application_bits(), 0);
+
+        let flags = ChauthtokFlags::default().with_change_expired_authtok();
+        assert!(flags.change_expired_authtok());
+        assert!(!flags.silent());
+        assert_eq!(flags.application_bits(), ffi::PAM_CHANGE_EXPIRED_AUTHTOK);
+
+        let flags = flags.with_silent();
+        assert_eq!(
+            flags.application_bits(),
+            ffi::PAM_CHANGE_EXPIRED_AUTHTOK | ffi::PAM_SILENT
+        );
+
+        // libpam rejects the phase flags from applications
+        let flags = ChauthtokFlags::from_bits(ffi::PAM_PRELIM_CHECK | ffi::PAM_SILENT);
+        assert_eq!(flags.application_bits(), ffi::PAM_SILENT);
+    }
+
+    #[test]
+    fn cred_action_flag() {
+        assert_eq!(
+            PamFlag::from(CredAction::Establish),
+            PamFlag::Establish_Cred
+        );
+        assert_eq!(PamFlag::from(CredAction::Delete), PamFlag::Delete_Cred);
+        assert_eq!(
+            PamFlag::from(CredAction::Reinitialize),
+            PamFlag::Reinitialize_Cred
+        );
+        assert_eq!(PamFlag::from(CredAction::Refresh), PamFlag::Refresh_Cred);
+    }
+}
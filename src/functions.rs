@@ -16,7 +16,10 @@ pub use misc::*;
 /* ------------------------ <security/pam_appl.h> -------------------------- */
 #[cfg(feature = "client")]
 mod appl {
-    use crate::{ffi, PamFlag, PamHandle, PamResult, PamReturnCode};
+    use crate::{
+        ffi, AuthFlags, ChauthtokFlags, PamFlag, PamHandle, PamResult, PamReturnCode, SessionFlags,
+        SetCredFlags,
+    };
 
     use std::ffi::CString;
     use libc::c_int;
@@ -35,7 +38,9 @@ mod appl {
             // Only service is required -> initialize handle
             let mut handle: *mut PamHandle = std::ptr::null_mut();
 
-            let user_ptr = super::try_str_option_to_ptr(user)?;
+            // The converted user has to outlive the call, libpam copies it
+            let user = super::try_str_option_to_cstring(user)?;
+            let user_ptr = super::option_as_ptr(&user);
             match unsafe { ffi::pam_start(service.as_ptr(), user_ptr, conversation, &mut handle) }
                 .into()
             {
@@ -72,6 +77,12 @@ mod appl {
         unsafe { ffi::pam_authenticate(handle, flags as c_int) }.into()
     }
 
+    /// Like `authenticate`, but allows to combine the flags
+    #[inline]
+    pub fn authenticate_with_flags(handle: &mut PamHandle, flags: AuthFlags) -> PamReturnCode {
+        unsafe { ffi::pam_authenticate(handle, flags.bits()) }.into()
+    }
+
     /// Modify the credentials of the user associated with the PAM transaction
     ///
     /// This function should be called after the user has been authenticated and
@@ -83,6 +94,12 @@ mod appl {
         unsafe { ffi::pam_setcred(handle, flags as c_int) }.into()
     }
 
+    /// Like `setcred`, but allows to combine the flags
+    #[inline]
+    pub fn setcred_with_flags(handle: &mut PamHandle, flags: SetCredFlags) -> PamReturnCode {
+        unsafe { ffi::pam_setcred(handle, flags.bits()) }.into()
+    }
+
     /// Determine if the user's account is valid
     ///
     /// This function is typically called after a user has been authenticated.
@@ -93,18 +110,34 @@ mod appl {
         unsafe { ffi::pam_acct_mgmt(handle, flags as c_int) }.into()
     }
 
+    /// Like `acct_mgmt`, but allows to combine the flags
+    #[inline]
+    pub fn acct_mgmt_with_flags(handle: &mut PamHandle, flags: AuthFlags) -> PamReturnCode {
+        unsafe { ffi::pam_acct_mgmt(handle, flags.bits()) }.into()
+    }
+
     /// Set up a user session for a previously authenticated user
     #[inline]
     pub fn open_session(handle: &mut PamHandle, silent: bool) -> PamReturnCode {
-        let flag = silent as c_int;
-        unsafe { ffi::pam_open_session(handle, flag) }.into()
+        open_session_with_flags(handle, session_flags(silent))
+    }
+
+    /// Like `open_session`, but takes the flags
+    #[inline]
+    pub fn open_session_with_flags(handle: &mut PamHandle, flags: SessionFlags) -> PamReturnCode {
+        unsafe { ffi::pam_open_session(handle, flags.bits()) }.into()
     }
 
     /// Indicate that an authenticated user session has ended
     #[inline]
     pub fn close_session(handle: &mut PamHandle, silent: bool) -> PamReturnCode {
-        let flag = silent as c_int;
-        unsafe { ffi::pam_close_session(handle, flag) }.into()
+        close_session_with_flags(handle, session_flags(silent))
+    }
+
+    /// Like `close_session`, but takes the flags
+    #[inline]
+    pub fn close_session_with_flags(handle: &mut PamHandle, flags: SessionFlags) -> PamReturnCode {
+        unsafe { ffi::pam_close_session(handle, flags.bits()) }.into()
     }
 
     /// Change the authentication token for the user associated with the PAM
@@ -115,6 +148,22 @@ mod appl {
     pub fn chauthtok(handle: &mut PamHandle, flags: PamFlag) -> PamReturnCode {
         unsafe { ffi::pam_chauthtok(handle, flags as c_int) }.into()
     }
+
+    /// Like `chauthtok`, but allows to combine the flags
+    ///
+    /// The phase flags are set by libpam for the modules and not passed on.
+    #[inline]
+    pub fn chauthtok_with_flags(handle: &mut PamHandle, flags: ChauthtokFlags) -> PamReturnCode {
+        unsafe { ffi::pam_chauthtok(handle, flags.application_bits()) }.into()
+    }
+
+    fn session_flags(silent: bool) -> SessionFlags {
+        if silent {
+            SessionFlags::default().with_silent()
+        } else {
+            SessionFlags::default()
+        }
+    }
 }
 /* ------------------------ <security/pam_appl.h> -------------------------- */
 
@@ -122,8 +171,15 @@ mod appl {
 mod types {
     use crate::{env, ffi, PamHandle, PamItemType, PamResult, PamReturnCode};
 
+    use std::convert::TryFrom;
     use std::ffi::{CStr, CString};
-    use libc::{c_char, c_int, c_void};
+    use std::time::Duration;
+    use libc::{c_char, c_int, c_uint, c_void};
+
+    extern "C" {
+        // Not exported by pam_sys
+        fn pam_fail_delay(pamh: *mut PamHandle, musec_delay: c_uint) -> c_int;
+    }
 
     /// Update PAM information of type `item_type` in the associated PAM transaction
     #[inline]
@@ -154,6 +210,93 @@ mod types {
         }
     }
 
+    /// Replace the conversation function (`PAM_CONV`) of the associated PAM transaction
+    ///
+    /// libpam stores a copy of `conversation`, but not of the data its `appdata_ptr`
+    /// points to. This has to stay alive until the conversation function is replaced
+    /// again or the transaction ends.
+    #[inline]
+    pub fn set_conv(handle: &mut PamHandle, conversation: &ffi::pam_conv) -> PamResult<()> {
+        let item = conversation as *const ffi::pam_conv as *const c_void;
+        set_item(handle, PamItemType::Conv, unsafe { &*item })
+    }
+
+    /// Request a delay of at least `delay` after a failed call of `pam_authenticate`
+    ///
+    /// libpam waits for the longest delay requested by the application and the modules,
+    /// randomized by up to 25 %. Delays above `u32::MAX` microseconds are clamped.
+    #[inline]
+    pub fn fail_delay(handle: &mut PamHandle, delay: Duration) -> PamResult<()> {
+        let usec = c_uint::try_from(delay.as_micros()).unwrap_or(c_uint::MAX);
+        match unsafe { pam_fail_delay(handle, usec) }.into() {
+            PamReturnCode::Success => Ok(()),
+            err => Err(err.into()),
+        }
+    }
+
+    /// Disable the delay after failed authentication attempts for the transaction
+    ///
+    /// This sets `PAM_FAIL_DELAY` to a function which doesn't wait, so libpam returns
+    /// right away. Applications can then e.g. delay the next attempt themselves without
+    /// blocking a GUI.
+    #[inline]
+    pub fn disable_fail_delay(handle: &mut PamHandle) -> PamResult<()> {
+        extern "C" fn no_delay(_retval: c_int, _usec_delay: c_uint, _appdata_ptr: *mut c_void) {}
+
+        let delay_fn: extern "C" fn(c_int, c_uint, *mut c_void) = no_delay;
+        let item = delay_fn as *const c_void;
+        set_item(handle, PamItemType::Fail_Delay, unsafe { &*item })
+    }
+
+    /// Update a string item of type `item_type` in the associated PAM transaction
+    ///
+    /// libpam stores a copy of `value`, so the passed string can be dropped afterwards.
+    #[inline]
+    pub fn set_item_str(
+        handle: &mut PamHandle,
+        item_type: PamItemType,
+        value: &str,
+    ) -> PamResult<()> {
+        if let Ok(value) = CString::new(value) {
+            let item = value.as_ptr() as *const c_void;
+            match unsafe { ffi::pam_set_item(handle, item_type as c_int, item) }.into() {
+                PamReturnCode::Success => Ok(()),
+                err => Err(err.into()),
+            }
+        } else {
+            super::buffer_error()
+        }
+    }
+
+    /// Unset the item of type `item_type` in the associated PAM transaction
+    ///
+    /// Only meaningful for string items, e.g. `PAM_RHOST`, as some items like
+    /// `PAM_CONV` must not be `NULL`.
+    #[inline]
+    pub fn clear_item(handle: &mut PamHandle, item_type: PamItemType) -> PamResult<()> {
+        let item: *const c_void = std::ptr::null();
+        match unsafe { ffi::pam_set_item(handle, item_type as c_int, item) }.into() {
+            PamReturnCode::Success => Ok(()),
+            err => Err(err.into()),
+        }
+    }
+
+    /// Retrieve a string item of type `item_type` from the associated PAM transaction
+    ///
+    /// Returns `None` if the item has not been set.
+    #[inline]
+    pub fn get_item_str(handle: &PamHandle, item_type: PamItemType) -> PamResult<Option<&str>> {
+        let mut item_ptr: *const c_void = std::ptr::null();
+        match unsafe { ffi::pam_get_item(handle, item_type as c_int, &mut item_ptr) }.into() {
+            PamReturnCode::Success if item_ptr.is_null() => Ok(None),
+            PamReturnCode::Success => unsafe { CStr::from_ptr(item_ptr as *const c_char) }
+                .to_str()
+                .map(Some)
+                .map_err(|_| PamReturnCode::System_Err.into()),
+            err => Err(err.into()),
+        }
+    }
+
     /// Retrieve a `CStr` describing the `PamReturnCode` passed, potentially
     /// using LC_MESSAGES to localize the result
     #[inline]
@@ -179,6 +322,8 @@ mod types {
 
     /// Get he value of a PAM environment variable associated with the PAM
     /// transaction
+    ///
+    /// Fails with `System_Err` if the value is not valid UTF-8.
     #[inline]
     pub fn getenv<'a>(handle: &'a mut PamHandle, name: &str) -> PamResult<Option<&'a str>> {
         if let Ok(name) = CString::new(name) {
@@ -186,11 +331,10 @@ mod types {
             let env = unsafe { ffi::pam_getenv(handle, name.as_ptr()) };
             if !env.is_null() {
                 // Convert to rust &str
-                Ok(Some(
-                    unsafe { CStr::from_ptr(env) }
-                        .to_str()
-                        .expect("Got invalid UTF-8 string from pam_getenv"),
-                ))
+                unsafe { CStr::from_ptr(env) }
+                    .to_str()
+                    .map(Some)
+                    .map_err(|_| PamReturnCode::System_Err.into())
             } else {
                 // This might still be an error, but we don't know for sure
                 Ok(None)
@@ -274,11 +418,22 @@ mod misc {
 /* ----------------------- <security/pam_modules.h> ------------------------ */
 #[cfg(feature = "module")]
 mod modules {
-    use crate::{ffi, PamHandle, PamResult, PamReturnCode};
+    use crate::{
+        conv::Message, ffi, PamError, PamHandle, PamItemType, PamMessageStyle, PamResult,
+        PamReturnCode,
+    };
 
+    use std::any::TypeId;
     use std::ffi::{CStr, CString};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Mutex, PoisonError};
     use libc::{c_char, c_int, c_void};
 
+    extern "C" {
+        // Blocked by pam_sys because of the varargs
+        fn pam_syslog(pamh: *const PamHandle, priority: c_int, fmt: *const c_char, ...);
+    }
+
     /// Associate a pointer to an object with the given `module_data_name` in
     /// the current PAM context
     #[inline]
@@ -286,7 +441,6 @@ mod modules {
         handle: &mut PamHandle,
         module_data_name: &str,
         data: &mut c_void,
-        // FIXME: Remove bare ptrs from closure signature
         cleanup: Option<unsafe extern "C" fn(*mut PamHandle, *mut c_void, c_int)>,
     ) -> PamResult<()> {
         if let Ok(module_data_name) = CString::new(module_data_name) {
@@ -301,19 +455,169 @@ mod modules {
         }
     }
 
-    // FIXME: implement this properly
-    /*/// Retrieve the object associated with the given `module_data_name` from
+    /// Reason passed to cleanup functions registered via `set_data_with_cleanup`
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct CleanupReason {
+        /// Status passed to `pam_end` (`Success` if the data is being replaced)
+        pub status: PamReturnCode,
+        /// `PAM_DATA_SILENT` was set, so the cleanup should not log anything
+        pub silent: bool,
+        /// `PAM_DATA_REPLACE` was set, so the data is replaced by another `set_data` call
+        pub replace: bool,
+    }
+
+    impl From<c_int> for CleanupReason {
+        fn from(error_status: c_int) -> CleanupReason {
+            let flags = ffi::PAM_DATA_SILENT | ffi::PAM_DATA_REPLACE;
+            CleanupReason {
+                status: PamReturnCode::from(error_status & !flags),
+                silent: error_status & ffi::PAM_DATA_SILENT != 0,
+                replace: error_status & ffi::PAM_DATA_REPLACE != 0,
+            }
+        }
+    }
+
+    // Module data together with its (optional) cleanup closure. This is what the
+    // pointer passed to `pam_set_data` by `set_data_with_cleanup` points to.
+    // `type_id` is the first field, so `get_data` can check it before knowing `T`.
+    #[repr(C)]
+    pub(crate) struct ModuleData<T> {
+        type_id: TypeId,
+        cleanup: Option<Box<dyn FnOnce(T, CleanupReason)>>,
+        value: T,
+    }
+
+    // Addresses of the `ModuleData` allocated by this copy of the crate which libpam
+    // still holds. Other modules of the stack and `set_data` can store anything under
+    // any name, so `get_data` only reads pointers listed here.
+    static MODULE_DATA: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    pub(crate) fn track_module_data(data: *const c_void, live: bool) {
+        let mut addresses = MODULE_DATA.lock().unwrap_or_else(PoisonError::into_inner);
+        if live {
+            addresses.push(data as usize);
+        } else {
+            addresses.retain(|&address| address != data as usize);
+        }
+    }
+
+    pub(crate) fn is_module_data(data: *const c_void) -> bool {
+        let addresses = MODULE_DATA.lock().unwrap_or_else(PoisonError::into_inner);
+        addresses.contains(&(data as usize))
+    }
+
+    impl<T: 'static> ModuleData<T> {
+        pub(crate) fn new<F>(value: T, cleanup: F) -> ModuleData<T>
+        where
+            F: FnOnce(T, CleanupReason) + 'static,
+        {
+            ModuleData {
+                type_id: TypeId::of::<T>(),
+                cleanup: Some(Box::new(cleanup)),
+                value,
+            }
+        }
+    }
+
+    /// Associate `value` with the given `module_data_name` in the current PAM context
+    ///
+    /// `value` is moved to the heap and `cleanup` is invoked with it once libpam
+    /// releases the data, either because the transaction ends or the data is
+    /// replaced by another call with the same `module_data_name`.
+    pub fn set_data_with_cleanup<T, F>(
+        handle: &mut PamHandle,
+        module_data_name: &str,
+        value: T,
+        cleanup: F,
+    ) -> PamResult<()>
+    where
+        T: 'static,
+        F: FnOnce(T, CleanupReason) + 'static,
+    {
+        let data = Box::into_raw(Box::new(ModuleData::new(value, cleanup)));
+        let result = set_data(
+            handle,
+            module_data_name,
+            unsafe { &mut *(data as *mut c_void) },
+            Some(cleanup_trampoline::<T>),
+        );
+        match result {
+            Ok(()) => track_module_data(data as *const c_void, true),
+            // libpam did not take ownership, so drop the data again without cleanup
+            Err(_) => drop(unsafe { Box::from_raw(data) }),
+        }
+        result
+    }
+
+    // Reclaims the boxed `ModuleData<T>` and runs its cleanup closure
+    pub(crate) unsafe extern "C" fn cleanup_trampoline<T>(
+        handle: *mut PamHandle,
+        data: *mut c_void,
+        error_status: c_int,
+    ) {
+        if data.is_null() {
+            return;
+        }
+        track_module_data(data, false);
+        let data = Box::from_raw(data as *mut ModuleData<T>);
+        let ModuleData { value, cleanup, .. } = *data;
+        if let Some(cleanup) = cleanup {
+            let reason = CleanupReason::from(error_status);
+            // Unwinding into `pam_end` is undefined behavior
+            let result = panic::catch_unwind(AssertUnwindSafe(|| cleanup(value, reason)));
+            if result.is_err() && !handle.is_null() {
+                syslog(&*handle, libc::LOG_CRIT, "Cleanup of module data panicked");
+            }
+        }
+    }
+
+    /// Retrieve the object associated with the given `module_data_name` from
     /// the current PAM context
-    //pub fn get_data(handle: *const PamHandle, module_data_name: *const c_char, data: *const *const c_void);
-     */
+    ///
+    /// Only data stored via `set_data_with_cleanup` of this module can be retrieved.
+    /// Returns `No_Module_Data` if no data is associated with `module_data_name` and
+    /// `System_Err` if the stored data is not of type `T` or was stored otherwise, e.g.
+    /// by another module or via `set_data`.
+    pub fn get_data<'a, T: 'static>(
+        handle: &'a PamHandle,
+        module_data_name: &str,
+    ) -> PamResult<&'a T> {
+        let module_data_name = match CString::new(module_data_name) {
+            Ok(name) => name,
+            Err(_) => return super::buffer_error(),
+        };
+
+        let mut data_ptr: *const c_void = std::ptr::null();
+        match unsafe { ffi::pam_get_data(handle, module_data_name.as_ptr(), &mut data_ptr) }.into()
+        {
+            PamReturnCode::Success if data_ptr.is_null() => {
+                Err(PamReturnCode::No_Module_Data.into())
+            }
+            PamReturnCode::Success => {
+                if !is_module_data(data_ptr) {
+                    return Err(PamReturnCode::System_Err.into());
+                }
+                // `type_id` is always the first field of the `#[repr(C)]` ModuleData
+                if unsafe { *(data_ptr as *const TypeId) } != TypeId::of::<T>() {
+                    return Err(PamReturnCode::System_Err.into());
+                }
+                let data = unsafe { &*(data_ptr as *const ModuleData<T>) };
+                Ok(&data.value)
+            }
+            err => Err(err.into()),
+        }
+    }
 
     /// Return the name of the user as specified via `start`
+    ///
+    /// Fails with `System_Err` if the name is not valid UTF-8.
     #[inline]
     pub fn get_user<'a>(handle: &'a PamHandle, prompt: Option<&str>) -> PamResult<&'a str> {
         // For some reason, bindgen marks the handl as mutable in pam_sys although man says const
         let handle = handle as *const PamHandle as *mut PamHandle;
         let mut user_ptr: *const c_char = std::ptr::null();
-        let prompt_ptr = super::try_str_option_to_ptr(prompt)?;
+        let prompt = super::try_str_option_to_cstring(prompt)?;
+        let prompt_ptr = super::option_as_ptr(&prompt);
 
         match unsafe { ffi::pam_get_user(handle, &mut user_ptr, prompt_ptr) }.into() {
             PamReturnCode::Success => {
@@ -321,13 +625,249 @@ mod modules {
                     !user_ptr.is_null(),
                     "Got PAM_Success from pam_get_user but ptr is null!"
                 );
-                Ok(unsafe { CStr::from_ptr(user_ptr) }
+                // The user might have answered the prompt in a non-UTF-8 locale
+                unsafe { CStr::from_ptr(user_ptr) }
                     .to_str()
-                    .expect("Got invalid UTF8 string from pam_get_user"))
+                    .map_err(|_| PamReturnCode::System_Err.into())
             }
             err => Err(err.into()),
         }
     }
+
+    /// Return the name of the service as passed to `pam_start`
+    ///
+    /// Returns `None` if the item is not set.
+    #[inline]
+    pub fn get_service(handle: &PamHandle) -> PamResult<Option<String>> {
+        optional_item(super::get_item_str(handle, PamItemType::Service))
+    }
+
+    /// Return the name of the remote host (`PAM_RHOST`), `None` if not set
+    #[inline]
+    pub fn get_rhost(handle: &PamHandle) -> PamResult<Option<String>> {
+        optional_item(super::get_item_str(handle, PamItemType::RHost))
+    }
+
+    /// Return the name of the terminal (`PAM_TTY`), `None` if not set
+    #[inline]
+    pub fn get_tty(handle: &PamHandle) -> PamResult<Option<String>> {
+        optional_item(super::get_item_str(handle, PamItemType::TTY))
+    }
+
+    /// Return the name of the remote user (`PAM_RUSER`), `None` if not set
+    #[inline]
+    pub fn get_ruser(handle: &PamHandle) -> PamResult<Option<String>> {
+        optional_item(super::get_item_str(handle, PamItemType::RUser))
+    }
+
+    /// Store `token` as `PAM_AUTHTOK` for the modules stacked below this one
+    ///
+    /// libpam duplicates the string, so the Rust copy of `token` can (and should)
+    /// be zeroized right after this call returns. Modules configured with
+    /// `use_first_pass` or `try_first_pass` will reuse the stored token.
+    #[inline]
+    pub fn set_authtok(handle: &mut PamHandle, token: &str) -> PamResult<()> {
+        super::set_item_str(handle, PamItemType::AuthTok, token)
+    }
+
+    // Copy a string item, treating items unknown to libpam like unset ones
+    pub(crate) fn optional_item(item: PamResult<Option<&str>>) -> PamResult<Option<String>> {
+        match item {
+            Ok(value) => Ok(value.map(String::from)),
+            Err(PamError::Pam(PamReturnCode::Bad_Item)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Types of audit records written by `audit_write`
+    ///
+    /// The values are the respective `AUDIT_*` constants from `<libaudit.h>`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AuditType {
+        /// Authentication (`AUDIT_USER_AUTH`)
+        UserAuth = 1100,
+        /// Account management (`AUDIT_USER_ACCT`)
+        UserAcct = 1101,
+        /// Credentials acquired (`AUDIT_CRED_ACQ`)
+        CredAcq = 1103,
+        /// Credentials disposed (`AUDIT_CRED_DISP`)
+        CredDisp = 1104,
+        /// Session started (`AUDIT_USER_START`)
+        UserStart = 1105,
+        /// Session ended (`AUDIT_USER_END`)
+        UserEnd = 1106,
+        /// Authentication token changed (`AUDIT_USER_CHAUTHTOK`)
+        UserChauthtok = 1108,
+        /// Credentials refreshed (`AUDIT_CRED_REFR`)
+        CredRefr = 1110,
+    }
+
+    /// Write an audit record via `pam_modutil_audit_write`
+    ///
+    /// `retval` is the result of the audited operation. Without the `audit` feature
+    /// this does nothing and returns `Ok(())`, so modules don't need their own cfgs.
+    #[cfg(all(feature = "audit", target_os = "linux"))]
+    pub fn audit_write(
+        handle: &mut PamHandle,
+        audit_type: AuditType,
+        message: &str,
+        retval: PamReturnCode,
+    ) -> PamResult<()> {
+        if let Ok(message) = CString::new(message) {
+            match unsafe {
+                ffi::pam_modutil_audit_write(
+                    handle,
+                    audit_type as c_int,
+                    message.as_ptr(),
+                    retval as c_int,
+                )
+            }
+            .into()
+            {
+                PamReturnCode::Success => Ok(()),
+                err => Err(err.into()),
+            }
+        } else {
+            super::buffer_error()
+        }
+    }
+
+    /// Write an audit record via `pam_modutil_audit_write`
+    ///
+    /// `retval` is the result of the audited operation. Without the `audit` feature
+    /// this does nothing and returns `Ok(())`, so modules don't need their own cfgs.
+    #[cfg(not(all(feature = "audit", target_os = "linux")))]
+    #[inline]
+    pub fn audit_write(
+        _handle: &mut PamHandle,
+        _audit_type: AuditType,
+        _message: &str,
+        _retval: PamReturnCode,
+    ) -> PamResult<()> {
+        Ok(())
+    }
+
+    /// Log `msg` to syslog with the given `priority` (e.g. `libc::LOG_ERR`)
+    ///
+    /// The message is prefixed with the module and service name by libpam.
+    /// Interior nul bytes in `msg` are replaced.
+    pub fn syslog(handle: &PamHandle, priority: c_int, msg: &str) {
+        let msg = CString::new(msg.replace('\0', "\u{fffd}"))
+            .expect("Message does not contain nul bytes anymore");
+        let fmt = "%s\0".as_ptr() as *const c_char;
+        unsafe { pam_syslog(handle, priority, fmt, msg.as_ptr()) };
+    }
+
+    /// Talk to the user via the conversation function stored in the `PAM_CONV` item
+    ///
+    /// All `messages` are passed to the application in a single call. The returned
+    /// vector contains the response for each message at the same index or `None` if
+    /// the application did not provide one. Responses to `Prompt_Echo_Off` messages
+    /// are wiped from the C buffers after they have been copied.
+    ///
+    /// Returns `Conv_Err` if the application did not install a conversation function
+    /// or one of the `messages` is a `Binary_Prompt`, as their payload can't be sent.
+    pub fn converse(handle: &PamHandle, messages: &[Message]) -> PamResult<Vec<Option<String>>> {
+        // The application would read the text as the length prefix of a binary message
+        if messages
+            .iter()
+            .any(|m| m.style == PamMessageStyle::Binary_Prompt)
+        {
+            return Err(PamReturnCode::Conv_Err.into());
+        }
+
+        let conv =
+            super::get_item(handle, PamItemType::Conv)? as *const c_void as *const ffi::pam_conv;
+        let conv = unsafe { &*conv };
+        let conv_fn = match conv.conv {
+            Some(conv_fn) => conv_fn,
+            None => return Err(PamReturnCode::Conv_Err.into()),
+        };
+
+        // Keep the CStrings alive until the conversation function returned
+        let texts = messages
+            .iter()
+            .map(|m| CString::new(m.text))
+            .collect::<Result<Vec<_>, _>>()
+            .or_else(|_| super::buffer_error())?;
+        let pam_msgs: Vec<_> = messages
+            .iter()
+            .zip(&texts)
+            .map(|(m, text)| ffi::pam_message {
+                msg_style: m.style as c_int,
+                msg: text.as_ptr(),
+            })
+            .collect();
+        let mut msg_ptrs: Vec<*const ffi::pam_message> =
+            pam_msgs.iter().map(|m| m as *const _).collect();
+
+        let mut resp_ptr: *mut ffi::pam_response = std::ptr::null_mut();
+        match unsafe {
+            conv_fn(
+                msg_ptrs.len() as c_int,
+                msg_ptrs.as_mut_ptr(),
+                &mut resp_ptr,
+                conv.appdata_ptr,
+            )
+        }
+        .into()
+        {
+            PamReturnCode::Success => {}
+            err => return Err(err.into()),
+        }
+
+        if resp_ptr.is_null() {
+            // Some applications don't allocate responses if no prompt was sent
+            return if messages.iter().any(|m| is_prompt(m.style)) {
+                Err(PamReturnCode::Conv_Err.into())
+            } else {
+                Ok(vec![None; messages.len()])
+            };
+        }
+
+        // Copy all responses and release the C structures afterwards, even if a
+        // response is invalid, so we never leak memory allocated by the application
+        let mut responses = Vec::with_capacity(messages.len());
+        let mut valid = true;
+        for (i, m) in messages.iter().enumerate() {
+            let resp = unsafe { &mut *resp_ptr.add(i) };
+            if resp.resp.is_null() {
+                responses.push(None);
+                continue;
+            }
+
+            match unsafe { CStr::from_ptr(resp.resp) }.to_str() {
+                Ok(text) => responses.push(Some(text.to_string())),
+                Err(_) => {
+                    valid = false;
+                    responses.push(None);
+                }
+            }
+
+            if m.style == PamMessageStyle::Prompt_Echo_Off {
+                unsafe { super::wipe_c_str(resp.resp) };
+            }
+            unsafe { libc::free(resp.resp as *mut c_void) };
+        }
+        unsafe { libc::free(resp_ptr as *mut c_void) };
+
+        if valid {
+            Ok(responses)
+        } else {
+            // Don't leave the passwords copied so far behind
+            for (m, response) in messages.iter().zip(responses.iter_mut()) {
+                if let (PamMessageStyle::Prompt_Echo_Off, Some(text)) = (m.style, response) {
+                    super::wipe_string(text);
+                }
+            }
+            Err(PamReturnCode::Conv_Err.into())
+        }
+    }
+
+    #[inline]
+    fn is_prompt(style: PamMessageStyle) -> bool {
+        style == PamMessageStyle::Prompt_Echo_On || style == PamMessageStyle::Prompt_Echo_Off
+    }
 }
 /* ----------------------- <security/pam_modules.h> ------------------------ */
 
@@ -336,13 +876,131 @@ fn buffer_error<T>() -> crate::PamResult<T> {
     Err(crate::PamReturnCode::Buf_Err.into())
 }
 
-fn try_str_option_to_ptr(opt: Option<&str>) -> crate::PamResult<*const libc::c_char> {
+fn try_str_option_to_cstring(opt: Option<&str>) -> crate::PamResult<Option<std::ffi::CString>> {
     match opt.map(std::ffi::CString::new) {
-        // Valid string given -> Return ptr of the converted CString
-        Some(Ok(content)) => Ok(content.as_ptr()),
-        // No string given -> Return null-ptr
-        None => Ok(std::ptr::null_mut()),
+        // Valid string given -> Return the converted CString
+        Some(Ok(content)) => Ok(Some(content)),
+        // No string given -> Return nothing
+        None => Ok(None),
         // Invalid string given -> Return BUF_ERR
-        _ => Err(crate::PamReturnCode::Buf_Err.into()),
+        _ => buffer_error(),
+    }
+}
+
+// The pointer to pass for an optional string, which has to be kept alive during the call
+fn option_as_ptr(opt: &Option<std::ffi::CString>) -> *const libc::c_char {
+    opt.as_ref()
+        .map_or(std::ptr::null(), |content| content.as_ptr())
+}
+
+/// Overwrite the contents of a nul-terminated C string with zeroes
+///
+/// Volatile writes are used so the compiler cannot optimize the wipe away.
+pub(crate) unsafe fn wipe_c_str(ptr: *mut libc::c_char) {
+    if ptr.is_null() {
+        return;
+    }
+    let len = libc::strlen(ptr);
+    for i in 0..len {
+        std::ptr::write_volatile(ptr.add(i), 0);
+    }
+}
+
+/// Overwrite the contents of a `String` with zeroes and clear it
+///
+/// Like `wipe_c_str` this uses volatile writes. The capacity is left untouched.
+pub(crate) fn wipe_string(s: &mut String) {
+    // Zeroes are valid UTF-8, so the string stays valid during the wipe
+    for byte in unsafe { s.as_bytes_mut() } {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    s.clear();
+}
+
+#[cfg(all(test, feature = "module"))]
+mod test {
+    use super::modules::{
+        cleanup_trampoline, is_module_data, optional_item, track_module_data, CleanupReason,
+        ModuleData,
+    };
+    use crate::{ffi, PamError, PamReturnCode};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn cleanup_reason_from_status() {
+        let reason = CleanupReason::from(ffi::PAM_AUTH_ERR | ffi::PAM_DATA_SILENT);
+        assert_eq!(reason.status, PamReturnCode::Auth_Err);
+        assert!(reason.silent);
+        assert!(!reason.replace);
+
+        let reason = CleanupReason::from(ffi::PAM_DATA_REPLACE);
+        assert_eq!(reason.status, PamReturnCode::Success);
+        assert!(!reason.silent);
+        assert!(reason.replace);
+    }
+
+    #[test]
+    fn cleanup_trampoline_runs_closure() {
+        let seen = Rc::new(RefCell::new(None));
+        let seen_cleanup = Rc::clone(&seen);
+        let data = Box::new(ModuleData::new(42u32, move |value, reason| {
+            *seen_cleanup.borrow_mut() = Some((value, reason));
+        }));
+
+        let data = Box::into_raw(data) as *mut libc::c_void;
+        track_module_data(data, true);
+        assert!(is_module_data(data));
+
+        let status = ffi::PAM_SUCCESS | ffi::PAM_DATA_SILENT;
+        unsafe { cleanup_trampoline::<u32>(std::ptr::null_mut(), data, status) };
+        // Freed data is never read by `get_data` again
+        assert!(!is_module_data(data));
+
+        let (value, reason) = seen.borrow_mut().take().expect("cleanup was not called");
+        assert_eq!(value, 42);
+        assert_eq!(reason, CleanupReason::from(status));
+    }
+
+    #[test]
+    fn cleanup_trampoline_catches_panics() {
+        let data = Box::new(ModuleData::new((), |_, _| panic!("cleanup failed")));
+        let data = Box::into_raw(data) as *mut libc::c_void;
+        unsafe { cleanup_trampoline::<()>(std::ptr::null_mut(), data, ffi::PAM_SUCCESS) };
+    }
+
+    #[test]
+    fn optional_items() {
+        assert_eq!(
+            optional_item(Ok(Some("sshd"))).unwrap(),
+            Some("sshd".to_string())
+        );
+        assert_eq!(optional_item(Ok(None)).unwrap(), None);
+        let bad_item = Err(PamError::Pam(PamReturnCode::Bad_Item));
+        assert_eq!(optional_item(bad_item).unwrap(), None);
+        let denied = Err(PamError::Pam(PamReturnCode::Perm_Denied));
+        assert_eq!(
+            optional_item(denied).unwrap_err().code(),
+            PamReturnCode::Perm_Denied
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "audit"))]
+    fn audit_write_disabled() {
+        use super::modules::{audit_write, AuditType};
+        use crate::PamHandle;
+        use std::ptr::NonNull;
+
+        // The opaque handle is zero-sized, so a dangling pointer is a valid reference
+        let handle = unsafe { &mut *NonNull::<PamHandle>::dangling().as_ptr() };
+        let result = audit_write(
+            handle,
+            AuditType::UserAuth,
+            "op=test",
+            PamReturnCode::Success,
+        );
+        assert!(result.is_ok());
     }
 }
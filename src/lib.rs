@@ -15,23 +15,45 @@ pub use pam_sys as ffi;
 mod conv;
 mod enums;
 mod env;
+mod flags;
 mod functions;
 mod types;
 
-pub use crate::{enums::*, functions::*, types::*};
+pub use crate::{enums::*, flags::*, functions::*, types::*};
 
 #[cfg(feature = "client")]
 pub mod client;
 #[cfg(feature = "module")]
 pub mod module;
+#[cfg(any(feature = "spawn", feature = "utmp"))]
+pub mod session;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
 pub use crate::{
-    conv::{Conversation, PasswordConv},
+    conv::{
+        CancelToken, ChannelConv, ConvError, Conversation, ExhaustionPolicy, FnConv, Message,
+        MessageContext, NullConv, PasswordConv, Pattern, PipeConv, PromptAnswer, PromptReply,
+        PromptRequest, Recorder, Response, ScriptedConv, SequenceConv, Timeout, TtyConv, Unmatched,
+        PAM_MAX_RESP_SIZE,
+    },
     enums::*,
 };
 
 #[cfg(feature = "client")]
-pub use client::Client;
+pub use crate::conv::CredentialTarget;
+#[cfg(feature = "interactive")]
+pub use crate::conv::RPasswordConv;
+#[cfg(feature = "async")]
+pub use crate::conv::{AsyncBridge, AsyncConversation, ConvFuture};
+
+#[cfg(feature = "client")]
+pub use client::{
+    check, check_account, AccountError, AccountStatus, Client, ClientBuilder, CredentialSequence,
+    DropStage, DynClient, EnvironmentPolicy, LastError, PamOperation, RetryDecision, Session,
+    SessionInfo, SessionInfoError, SetCredFailurePolicy, TransactionInfo, TransactionStatus,
+    XdgSession,
+};
 
 #[cfg(feature = "module")]
 pub use module::PamModule;
@@ -2,16 +2,39 @@
 //!
 //! Inspired by anowell/pam-rs
 
-use crate::{PamHandle, PamReturnCode};
+use crate::{
+    functions, AuthFlags, ChauthtokFlags, Message, PamError, PamHandle, PamItemType,
+    PamMessageStyle, PamResult, PamReturnCode, SessionFlags, SetCredFlags,
+};
+// Reexport secrecy types so modules don't need to depend on it
+pub use secrecy::{ExposeSecret, SecretString};
+use std::any::Any;
 use std::ffi::CStr;
-use std::os::raw::c_uint;
+use std::fmt;
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+
+// Reexport the syslog priorities for use with `pam_log!`
+pub use libc::{
+    LOG_ALERT, LOG_CRIT, LOG_DEBUG, LOG_EMERG, LOG_ERR, LOG_INFO, LOG_NOTICE, LOG_WARNING,
+};
+
+/// Prompt used by `obtain_authtok` if the caller did not supply one
+pub const DEFAULT_AUTHTOK_PROMPT: &str = "Password: ";
+
+/// Prompt used by `StandardOptions::obtain_new_authtok` if the caller did not supply one
+pub const DEFAULT_NEW_AUTHTOK_PROMPT: &str = "New password: ";
+
+/// Maximum length in bytes of messages logged via `pam_log!`
+pub const MAX_LOG_MESSAGE_LEN: usize = 1024;
 
-// FIXME: Find a solution for the flags containing ORed integers
 #[allow(unused_variables)]
 /// Trait representing a PAM module.
 ///
 /// Modules should override the desired functions and call the macro `impl_pam_module`.
 /// This exports the respective functions at the expected symbols prefixed with `pam_sm_`.
+/// The flags passed by libpam are decoded into the typed flags of the respective function
+/// and the returned `ModuleResult` is checked against the codes allowed for it.
 ///
 /// ```no_run
 /// use pam::{PamModule, export_pam_module};
@@ -23,35 +46,611 @@ use std::os::raw::c_uint;
 /// //export_pam_module!(MyModule);
 /// ```
 pub trait PamModule {
-    fn account_management(handle: &PamHandle, args: Vec<&CStr>, flags: c_uint) -> PamReturnCode {
-        PamReturnCode::Ignore
+    fn account_management(
+        handle: &mut PamHandle,
+        args: Vec<&CStr>,
+        flags: AuthFlags,
+    ) -> ModuleResult {
+        Ok(ModuleSuccess::Ignore)
+    }
+    fn authenticate(handle: &mut PamHandle, args: Vec<&CStr>, flags: AuthFlags) -> ModuleResult {
+        Ok(ModuleSuccess::Ignore)
+    }
+    fn change_auth_token(
+        handle: &mut PamHandle,
+        args: Vec<&CStr>,
+        flags: ChauthtokFlags,
+    ) -> ModuleResult {
+        Ok(ModuleSuccess::Ignore)
+    }
+    fn close_session(
+        handle: &mut PamHandle,
+        args: Vec<&CStr>,
+        flags: SessionFlags,
+    ) -> ModuleResult {
+        Ok(ModuleSuccess::Ignore)
+    }
+    fn open_session(handle: &mut PamHandle, args: Vec<&CStr>, flags: SessionFlags) -> ModuleResult {
+        Ok(ModuleSuccess::Ignore)
+    }
+    fn set_credentials(
+        handle: &mut PamHandle,
+        args: Vec<&CStr>,
+        flags: SetCredFlags,
+    ) -> ModuleResult {
+        Ok(ModuleSuccess::Ignore)
+    }
+}
+
+/// Operations on a PAM handle used by module code
+///
+/// This is implemented by `PamHandle` and allows to unit test module logic with a
+/// mock implementation (see `test_util::MockHandle` behind the `test-util` feature).
+/// All helpers in this module are generic over it.
+pub trait PamHandleOps {
+    /// Retrieve a string item, `None` if it is not set
+    fn get_item_str(&self, item_type: PamItemType) -> PamResult<Option<&str>>;
+    /// Update a string item
+    fn set_item_str(&mut self, item_type: PamItemType, value: &str) -> PamResult<()>;
+    /// Retrieve module data stored via `set_data`
+    fn get_data<T: 'static>(&self, module_data_name: &str) -> PamResult<&T>;
+    /// Store module data, replacing (and dropping) any previous value
+    fn set_data<T: 'static>(&mut self, module_data_name: &str, value: T) -> PamResult<()>;
+    /// Talk to the user via the application's conversation function
+    fn converse(&self, messages: &[Message]) -> PamResult<Vec<Option<String>>>;
+    /// Get the value of a PAM environment variable
+    fn getenv(&mut self, name: &str) -> PamResult<Option<String>>;
+    /// Add or change a PAM environment variable in the form `name=value`
+    fn putenv(&mut self, name_value: &str) -> PamResult<()>;
+    /// Log a message to syslog
+    fn syslog(&self, priority: c_int, msg: &str);
+}
+
+impl PamHandleOps for PamHandle {
+    fn get_item_str(&self, item_type: PamItemType) -> PamResult<Option<&str>> {
+        functions::get_item_str(self, item_type)
+    }
+    fn set_item_str(&mut self, item_type: PamItemType, value: &str) -> PamResult<()> {
+        functions::set_item_str(self, item_type, value)
+    }
+    fn get_data<T: 'static>(&self, module_data_name: &str) -> PamResult<&T> {
+        functions::get_data(self, module_data_name)
+    }
+    fn set_data<T: 'static>(&mut self, module_data_name: &str, value: T) -> PamResult<()> {
+        functions::set_data_with_cleanup(self, module_data_name, value, |_, _| {})
+    }
+    fn converse(&self, messages: &[Message]) -> PamResult<Vec<Option<String>>> {
+        functions::converse(self, messages)
+    }
+    fn getenv(&mut self, name: &str) -> PamResult<Option<String>> {
+        functions::getenv(self, name).map(|value| value.map(String::from))
+    }
+    fn putenv(&mut self, name_value: &str) -> PamResult<()> {
+        functions::putenv(self, name_value)
+    }
+    fn syslog(&self, priority: c_int, msg: &str) {
+        functions::syslog(self, priority, msg)
+    }
+}
+
+/// Standard options understood by most PAM modules
+///
+/// Parse the module arguments with `StandardOptions::parse` and handle the module
+/// specific ones via `unrecognized`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StandardOptions<'a> {
+    /// Log additional debug information
+    pub debug: bool,
+    /// Only use the authentication token of a previous module, never prompt
+    pub use_first_pass: bool,
+    /// Use the authentication token of a previous module and prompt if none is set
+    pub try_first_pass: bool,
+    /// Allow empty authentication tokens
+    pub nullok: bool,
+    /// Use the new authentication token set by a previous module when changing it, see
+    /// `obtain_new_authtok`
+    pub use_authtok: bool,
+    /// Don't log informational messages
+    pub quiet: bool,
+    /// Arguments which are not standard options, in their original order
+    pub unrecognized: Vec<&'a CStr>,
+}
+
+impl<'a> StandardOptions<'a> {
+    /// Parse the standard options from the module arguments
+    pub fn parse(args: &[&'a CStr]) -> StandardOptions<'a> {
+        let mut opts = StandardOptions::default();
+        for &arg in args {
+            match arg.to_bytes() {
+                b"debug" => opts.debug = true,
+                b"use_first_pass" => opts.use_first_pass = true,
+                b"try_first_pass" => opts.try_first_pass = true,
+                b"nullok" => opts.nullok = true,
+                b"use_authtok" => opts.use_authtok = true,
+                b"quiet" => opts.quiet = true,
+                _ => opts.unrecognized.push(arg),
+            }
+        }
+        opts
+    }
+
+    /// The least severe syslog priority which should be logged
+    ///
+    /// This is `LOG_DEBUG` with `debug`, `LOG_WARNING` with `quiet` and `LOG_INFO` otherwise.
+    pub fn log_level(&self) -> c_int {
+        if self.debug {
+            LOG_DEBUG
+        } else if self.quiet {
+            LOG_WARNING
+        } else {
+            LOG_INFO
+        }
+    }
+
+    /// Whether `pam_log!` logs messages with the given `priority` with these options
+    pub fn log_enabled(&self, priority: c_int) -> bool {
+        priority <= self.log_level()
+    }
+
+    /// Obtain the authentication token via `obtain_authtok` honoring these options
+    pub fn obtain_authtok<H: PamHandleOps>(
+        &self,
+        handle: &mut H,
+        prompt: Option<&str>,
+    ) -> PamResult<SecretString> {
+        obtain_authtok(handle, prompt, self.use_first_pass, self.try_first_pass)
+    }
+
+    /// Obtain the new authentication token in the `UpdateAuthtok` phase of
+    /// `change_authtok`, honoring `use_authtok`
+    ///
+    /// With `use_authtok`, the new token a previous module of the password stack
+    /// stored in `PAM_AUTHTOK` is reused and `AuthTok_Err` returned if there is none.
+    /// Otherwise the user is prompted with `prompt` (or `DEFAULT_NEW_AUTHTOK_PROMPT`)
+    /// and the answer is stored in `PAM_AUTHTOK` for the following modules.
+    pub fn obtain_new_authtok<H: PamHandleOps>(
+        &self,
+        handle: &mut H,
+        prompt: Option<&str>,
+    ) -> PamResult<SecretString> {
+        // Only copied if it is reused, see `resolve_authtok`
+        let existing = handle.get_item_str(PamItemType::AuthTok)?;
+        let (token, prompted) = resolve_new_authtok(existing, self.use_authtok, || {
+            let prompt = prompt.unwrap_or(DEFAULT_NEW_AUTHTOK_PROMPT);
+            let msg = Message::new(PamMessageStyle::Prompt_Echo_Off, prompt);
+            handle
+                .converse(&[msg])
+                .map(|responses| responses.into_iter().next().flatten())
+        })?;
+
+        if prompted {
+            handle.set_item_str(PamItemType::AuthTok, &token)?;
+        }
+        Ok(SecretString::new(token))
+    }
+}
+
+/// Obtain the authentication token following the standard stacking workflow
+///
+/// If `use_first_pass` or `try_first_pass` is set and a previous module already
+/// stored `PAM_AUTHTOK`, that token is reused. With `use_first_pass` a missing token
+/// results in `Auth_Err`, otherwise the user is prompted with `prompt` (or
+/// `DEFAULT_AUTHTOK_PROMPT`) and the answer is stored in `PAM_AUTHTOK` for the
+/// following modules.
+///
+/// An empty answer is returned as an empty token, while an application not answering
+/// the prompt at all results in `Conv_Err`.
+pub fn obtain_authtok<H: PamHandleOps>(
+    handle: &mut H,
+    prompt: Option<&str>,
+    use_first_pass: bool,
+    try_first_pass: bool,
+) -> PamResult<SecretString> {
+    // Only copied if it is reused, see `resolve_authtok`
+    let existing = handle.get_item_str(PamItemType::AuthTok)?;
+    let (token, prompted) = resolve_authtok(existing, use_first_pass, try_first_pass, || {
+        let prompt = prompt.unwrap_or(DEFAULT_AUTHTOK_PROMPT);
+        let msg = Message::new(PamMessageStyle::Prompt_Echo_Off, prompt);
+        handle
+            .converse(&[msg])
+            .map(|responses| responses.into_iter().next().flatten())
+    })?;
+
+    if prompted {
+        handle.set_item_str(PamItemType::AuthTok, &token)?;
+    }
+    Ok(SecretString::new(token))
+}
+
+// Decision tree of `obtain_authtok`. Returns the token and whether the user was prompted.
+// `existing` is borrowed, so no copy of it is left unwiped if the user is prompted.
+fn resolve_authtok<F>(
+    existing: Option<&str>,
+    use_first_pass: bool,
+    try_first_pass: bool,
+    prompt: F,
+) -> PamResult<(String, bool)>
+where
+    F: FnOnce() -> PamResult<Option<String>>,
+{
+    if use_first_pass || try_first_pass {
+        if let Some(token) = existing {
+            return Ok((token.to_string(), false));
+        }
+        if use_first_pass {
+            return Err(PamError::Pam(PamReturnCode::Auth_Err));
+        }
+    }
+
+    match prompt()? {
+        Some(token) => Ok((token, true)),
+        None => Err(PamError::Pam(PamReturnCode::Conv_Err)),
+    }
+}
+
+// Decision tree of `StandardOptions::obtain_new_authtok`, `use_authtok` works like
+// `use_first_pass` but fails with the error of the password stack
+fn resolve_new_authtok<F>(
+    existing: Option<&str>,
+    use_authtok: bool,
+    prompt: F,
+) -> PamResult<(String, bool)>
+where
+    F: FnOnce() -> PamResult<Option<String>>,
+{
+    match resolve_authtok(existing, use_authtok, false, prompt) {
+        Err(PamError::Pam(PamReturnCode::Auth_Err)) => {
+            Err(PamError::Pam(PamReturnCode::AuthTok_Err))
+        }
+        result => result,
+    }
+}
+
+/// Module data storage with keys namespaced per module instance
+///
+/// All modules of a stack share the keys of `pam_set_data`. A `DataStore` prefixes
+/// every key with the module name and a discriminator derived from the module
+/// arguments, so neither other modules nor other instances of the same module
+/// (configured with different arguments) can clobber the stored values.
+///
+/// ```
+/// use pam::module::{DataStore, PamHandleOps};
+/// use std::ffi::CStr;
+///
+/// fn count<H: PamHandleOps>(handle: &mut H, args: &[&CStr]) -> u32 {
+///     let store = DataStore::new("pam_example", args);
+///     let count = store.fetch::<u32>(handle, "counter").map_or(1, |c| c + 1);
+///     store.store(handle, "counter", count).expect("Failed to store counter");
+///     count
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataStore {
+    prefix: String,
+}
+
+impl DataStore {
+    /// Create a store for the instance of `module_name` called with `args`
+    pub fn new(module_name: &str, args: &[&CStr]) -> DataStore {
+        DataStore {
+            prefix: format!("{}:{:016x}:", module_name, instance_discriminator(args)),
+        }
+    }
+
+    /// The key under which `name` is passed to `pam_set_data`
+    pub fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// Store `value` under `name`, replacing (and dropping) any previous value
+    pub fn store<T: 'static>(
+        &self,
+        handle: &mut impl PamHandleOps,
+        name: &str,
+        value: T,
+    ) -> PamResult<()> {
+        handle.set_data(&self.key(name), value)
+    }
+
+    /// Retrieve the value stored under `name`
+    ///
+    /// Fails with `No_Module_Data` if nothing was stored by this instance.
+    pub fn fetch<'a, T: 'static>(
+        &self,
+        handle: &'a impl PamHandleOps,
+        name: &str,
+    ) -> PamResult<&'a T> {
+        handle.get_data(&self.key(name))
+    }
+}
+
+// FNV-1a over the NUL terminated arguments. Unlike `DefaultHasher` this is stable
+// across Rust versions, so modules built with different toolchains agree.
+fn instance_discriminator(args: &[&CStr]) -> u64 {
+    args.iter()
+        .flat_map(|arg| arg.to_bytes_with_nul())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+#[doc(hidden)]
+pub fn format_log_message(args: fmt::Arguments) -> String {
+    let mut msg = fmt::format(args);
+    if msg.len() > MAX_LOG_MESSAGE_LEN {
+        // Never split a code point
+        let mut len = MAX_LOG_MESSAGE_LEN;
+        while !msg.is_char_boundary(len) {
+            len -= 1;
+        }
+        msg.truncate(len);
+    }
+    msg
+}
+
+#[macro_export]
+/// Log a formatted message via `pam_syslog`
+///
+/// Messages are prefixed with the module and service name by libpam and truncated
+/// to `MAX_LOG_MESSAGE_LEN` bytes. Messages less severe than the `log_level` of the
+/// `StandardOptions` of the current call are dropped without being formatted, so
+/// every line of the stack logs according to its own arguments.
+///
+/// ```no_run
+/// use pam::{module::StandardOptions, module::LOG_DEBUG, pam_log, PamHandle};
+///
+/// fn reject(handle: &mut PamHandle, opts: &StandardOptions, user: &str) {
+///     pam_log!(handle, opts, LOG_DEBUG, "user {} rejected", user);
+/// }
+/// ```
+macro_rules! pam_log {
+    ($handle:expr, $opts:expr, $priority:expr, $($arg:tt)+) => {{
+        let priority: ::std::os::raw::c_int = $priority;
+        if $crate::module::StandardOptions::log_enabled(&$opts, priority) {
+            let msg = $crate::module::format_log_message(format_args!($($arg)+));
+            $crate::module::PamHandleOps::syslog(&*$handle, priority, &msg);
+        }
+    }};
+}
+
+/// Convert the result of a module function into the value returned to libpam
+///
+/// `Ok(())` becomes `PAM_SUCCESS` and `Err` the contained code. An `Err` never
+/// results in `PAM_SUCCESS`: an error containing `Success` is mapped to
+/// `default_failure` (and triggers an assertion in debug builds).
+pub fn to_module_result(result: PamResult<()>, default_failure: PamReturnCode) -> c_int {
+    match result {
+        Ok(()) => PamReturnCode::Success as c_int,
+        Err(err) => {
+            let code = err.code();
+            debug_assert!(
+                code != PamReturnCode::Success,
+                "Got PAM_SUCCESS inside an Err result"
+            );
+            if code == PamReturnCode::Success {
+                default_failure as c_int
+            } else {
+                code as c_int
+            }
+        }
+    }
+}
+
+/// Conversion of module function results into the value returned to libpam
+///
+/// ```
+/// use pam::{module::IntoPamCode, PamError, PamResult, PamReturnCode};
+///
+/// let result: PamResult<()> = Err(PamError::Pam(PamReturnCode::User_Unknown));
+/// let code = result.into_pam_code(PamReturnCode::Auth_Err);
+/// assert_eq!(code, PamReturnCode::User_Unknown as i32);
+/// ```
+pub trait IntoPamCode {
+    /// Convert into the value returned to libpam, see `to_module_result`
+    fn into_pam_code(self, default_failure: PamReturnCode) -> c_int;
+}
+
+impl IntoPamCode for PamResult<()> {
+    fn into_pam_code(self, default_failure: PamReturnCode) -> c_int {
+        to_module_result(self, default_failure)
     }
-    fn authenticate(handle: &PamHandle, args: Vec<&CStr>, flags: c_uint) -> PamReturnCode {
-        PamReturnCode::Ignore
+}
+
+impl IntoPamCode for PamReturnCode {
+    fn into_pam_code(self, _default_failure: PamReturnCode) -> c_int {
+        self as c_int
+    }
+}
+
+impl IntoPamCode for ModuleResult {
+    fn into_pam_code(self, default_failure: PamReturnCode) -> c_int {
+        match self {
+            Ok(ModuleSuccess::Success) => PamReturnCode::Success as c_int,
+            Ok(ModuleSuccess::Ignore) => PamReturnCode::Ignore as c_int,
+            Err(err) => to_module_result(Err(err), default_failure),
+        }
     }
-    fn change_auth_token(handle: &PamHandle, args: Vec<&CStr>, flags: c_uint) -> PamReturnCode {
-        PamReturnCode::Ignore
+}
+
+/// Non-error outcome of a module function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleSuccess {
+    /// The module succeeded (`PAM_SUCCESS`)
+    Success,
+    /// The module should not influence the result of the stack (`PAM_IGNORE`)
+    Ignore,
+}
+
+/// Result of a `PamModule` function
+///
+/// Errors must use a code which is allowed for the respective function, see
+/// `ModuleFunction::allows`. Other codes are reported as `PAM_SERVICE_ERR`.
+pub type ModuleResult = Result<ModuleSuccess, PamError>;
+
+/// The functions a PAM module can implement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleFunction {
+    AccountManagement,
+    Authenticate,
+    ChangeAuthToken,
+    CloseSession,
+    OpenSession,
+    SetCredentials,
+}
+
+impl ModuleFunction {
+    /// Name of the symbol exported for this function
+    pub fn symbol(self) -> &'static str {
+        match self {
+            ModuleFunction::AccountManagement => "pam_sm_acct_mgmt",
+            ModuleFunction::Authenticate => "pam_sm_authenticate",
+            ModuleFunction::ChangeAuthToken => "pam_sm_chauthtok",
+            ModuleFunction::CloseSession => "pam_sm_close_session",
+            ModuleFunction::OpenSession => "pam_sm_open_session",
+            ModuleFunction::SetCredentials => "pam_sm_setcred",
+        }
     }
-    fn close_session(handle: &PamHandle, args: Vec<&CStr>, flags: c_uint) -> PamReturnCode {
-        PamReturnCode::Ignore
+
+    /// Whether this function may return `code` according to the Linux-PAM Module Writers' Guide
+    ///
+    /// Besides the function specific codes, all functions may return `Success`,
+    /// `Ignore`, `Abort`, `Incomplete` and the generic `Buf_Err`, `Conv_Err`,
+    /// `Service_Err` and `System_Err`.
+    pub fn allows(self, code: PamReturnCode) -> bool {
+        use PamReturnCode::*;
+
+        let specific: &[PamReturnCode] = match self {
+            ModuleFunction::AccountManagement => &[
+                Acct_Expired,
+                Auth_Err,
+                New_Authtok_Reqd,
+                Perm_Denied,
+                User_Unknown,
+            ],
+            ModuleFunction::Authenticate => &[
+                Auth_Err,
+                Cred_Insufficient,
+                Authinfo_Unavail,
+                User_Unknown,
+                MaxTries,
+            ],
+            ModuleFunction::ChangeAuthToken => &[
+                AuthTok_Err,
+                AuthTok_Recovery_Err,
+                AuthTok_Lock_Busy,
+                AuthTok_Disable_Aging,
+                Perm_Denied,
+                Try_Again,
+                User_Unknown,
+            ],
+            ModuleFunction::CloseSession | ModuleFunction::OpenSession => &[Session_Err],
+            ModuleFunction::SetCredentials => &[Cred_Unavail, Cred_Expired, User_Unknown, Cred_Err],
+        };
+        let generic = [
+            Success,
+            Ignore,
+            Abort,
+            Incomplete,
+            Buf_Err,
+            Conv_Err,
+            Service_Err,
+            System_Err,
+        ];
+        generic.contains(&code) || specific.contains(&code)
     }
-    fn open_session(handle: &PamHandle, args: Vec<&CStr>, flags: c_uint) -> PamReturnCode {
-        PamReturnCode::Ignore
+
+    /// Convert the result of this function into the value returned to libpam
+    ///
+    /// Errors are converted like `to_module_result` with `Service_Err` as the default
+    /// failure. Codes not allowed for this function are logged and replaced by
+    /// `Service_Err`.
+    pub fn to_pam_code<H: PamHandleOps>(self, handle: &H, result: ModuleResult) -> c_int {
+        match self.check(result) {
+            Ok(code) => code as c_int,
+            Err(code) => {
+                let msg = format!("{} must not return {:?}", self.symbol(), code);
+                handle.syslog(LOG_ERR, &msg);
+                PamReturnCode::Service_Err as c_int
+            }
+        }
     }
-    fn set_credentials(handle: &PamHandle, args: Vec<&CStr>, flags: c_uint) -> PamReturnCode {
-        PamReturnCode::Ignore
+
+    // The code to return for `result` or the offending code if it is not allowed
+    fn check(self, result: ModuleResult) -> Result<PamReturnCode, PamReturnCode> {
+        let code = PamReturnCode::from(result.into_pam_code(PamReturnCode::Service_Err));
+        if self.allows(code) {
+            Ok(code)
+        } else {
+            Err(code)
+        }
+    }
+}
+
+/// Run the body of a module entry point, turning a panic into `PAM_SERVICE_ERR`
+///
+/// Unwinding into libpam is undefined behavior and usually aborts the application,
+/// so every `extern "C"` function called by libpam must catch panics. The panic
+/// message is logged via `pam_syslog` naming `function`. `export_pam_module!` uses
+/// this for all generated entry points; hand-written ones should do the same.
+///
+/// ```
+/// use pam::{module::catch_panic, PamHandle, PamReturnCode};
+/// use std::os::raw::c_int;
+///
+/// #[no_mangle]
+/// pub extern "C" fn pam_sm_open_session(
+///     handle: &mut PamHandle,
+///     _flags: c_int,
+///     _argc: c_int,
+///     _argv: *const *const std::os::raw::c_char,
+/// ) -> c_int {
+///     catch_panic(handle, "pam_sm_open_session", |_handle| {
+///         PamReturnCode::Success as c_int
+///     })
+/// }
+/// ```
+pub fn catch_panic<H, F>(handle: &mut H, function: &str, body: F) -> c_int
+where
+    H: PamHandleOps,
+    F: FnOnce(&mut H) -> c_int,
+{
+    match panic::catch_unwind(AssertUnwindSafe(|| body(&mut *handle))) {
+        Ok(code) => code,
+        Err(payload) => {
+            let msg = format!("{} panicked: {}", function, panic_message(&*payload));
+            handle.syslog(LOG_CRIT, &msg);
+            PamReturnCode::Service_Err as c_int
+        }
+    }
+}
+
+// Extract the message passed to `panic!` if possible
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
     }
 }
 
 #[macro_export]
 /// Export the given struct as a PAM module by wiring up the respective extern "C" functions
+///
+/// Panics inside the module functions are caught and reported as `PAM_SERVICE_ERR`,
+/// see `catch_panic`. The results are converted via `ModuleFunction::to_pam_code`, so
+/// errors are mapped like `to_module_result` in hand-written entry points.
 macro_rules! export_pam_module {
     ($struct:ident) => {
         pub use _pam_module_::*;
         mod _pam_module_ {
             use std::ffi::CStr;
-            use std::os::raw::{c_char, c_int, c_uint};
-            use $crate::{PamHandle, PamModule, PamReturnCode};
+            use std::os::raw::{c_char, c_int};
+            use $crate::module::{catch_panic, ModuleFunction};
+            use $crate::{
+                AuthFlags, ChauthtokFlags, PamHandle, PamModule, SessionFlags, SetCredFlags,
+            };
 
             fn convert_args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
                 (0..argc)
@@ -61,63 +660,87 @@ macro_rules! export_pam_module {
 
             #[no_mangle]
             pub extern "C" fn pam_sm_acct_mgmt(
-                handle: &PamHandle,
-                flags: c_uint,
+                handle: &mut PamHandle,
+                flags: c_int,
                 argc: c_int,
                 argv: *const *const c_char,
-            ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::account_management(handle, args, flags)
+            ) -> c_int {
+                catch_panic(handle, "pam_sm_acct_mgmt", |handle| {
+                    let args = convert_args(argc, argv);
+                    let flags = AuthFlags::from_bits(flags);
+                    let result = super::$struct::account_management(handle, args, flags);
+                    ModuleFunction::AccountManagement.to_pam_code(handle, result)
+                })
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_authenticate(
-                handle: &PamHandle,
-                flags: c_uint,
+                handle: &mut PamHandle,
+                flags: c_int,
                 argc: c_int,
                 argv: *const *const c_char,
-            ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::authenticate(handle, args, flags)
+            ) -> c_int {
+                catch_panic(handle, "pam_sm_authenticate", |handle| {
+                    let args = convert_args(argc, argv);
+                    let flags = AuthFlags::from_bits(flags);
+                    let result = super::$struct::authenticate(handle, args, flags);
+                    ModuleFunction::Authenticate.to_pam_code(handle, result)
+                })
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_chauthtok(
-                handle: &PamHandle,
-                flags: c_uint,
+                handle: &mut PamHandle,
+                flags: c_int,
                 argc: c_int,
                 argv: *const *const c_char,
-            ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::change_auth_token(handle, args, flags)
+            ) -> c_int {
+                catch_panic(handle, "pam_sm_chauthtok", |handle| {
+                    let args = convert_args(argc, argv);
+                    let flags = ChauthtokFlags::from_bits(flags);
+                    let result = super::$struct::change_auth_token(handle, args, flags);
+                    ModuleFunction::ChangeAuthToken.to_pam_code(handle, result)
+                })
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_close_session(
-                handle: &PamHandle,
-                flags: c_uint,
+                handle: &mut PamHandle,
+                flags: c_int,
                 argc: c_int,
                 argv: *const *const c_char,
-            ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::close_session(handle, args, flags)
+            ) -> c_int {
+                catch_panic(handle, "pam_sm_close_session", |handle| {
+                    let args = convert_args(argc, argv);
+                    let flags = SessionFlags::from_bits(flags);
+                    let result = super::$struct::close_session(handle, args, flags);
+                    ModuleFunction::CloseSession.to_pam_code(handle, result)
+                })
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_open_session(
-                handle: &PamHandle,
-                flags: c_uint,
+                handle: &mut PamHandle,
+                flags: c_int,
                 argc: c_int,
                 argv: *const *const c_char,
-            ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::open_session(handle, args, flags)
+            ) -> c_int {
+                catch_panic(handle, "pam_sm_open_session", |handle| {
+                    let args = convert_args(argc, argv);
+                    let flags = SessionFlags::from_bits(flags);
+                    let result = super::$struct::open_session(handle, args, flags);
+                    ModuleFunction::OpenSession.to_pam_code(handle, result)
+                })
             }
             #[no_mangle]
             pub extern "C" fn pam_sm_setcred(
-                handle: &PamHandle,
-                flags: c_uint,
+                handle: &mut PamHandle,
+                flags: c_int,
                 argc: c_int,
                 argv: *const *const c_char,
-            ) -> PamReturnCode {
-                let args = convert_args(argc, argv);
-                super::$struct::set_credentials(handle, args, flags)
+            ) -> c_int {
+                catch_panic(handle, "pam_sm_setcred", |handle| {
+                    let args = convert_args(argc, argv);
+                    let flags = SetCredFlags::from_bits(flags);
+                    let result = super::$struct::set_credentials(handle, args, flags);
+                    ModuleFunction::SetCredentials.to_pam_code(handle, result)
+                })
             }
         }
     };
@@ -125,11 +748,221 @@ macro_rules! export_pam_module {
 
 #[cfg(test)]
 pub mod test {
-    use super::PamModule;
+    use super::{
+        resolve_authtok, resolve_new_authtok, to_module_result, DataStore, ModuleFunction,
+        ModuleSuccess, PamModule, StandardOptions,
+    };
     use crate::export_pam_module;
+    use crate::{PamError, PamReturnCode};
+
+    use std::ffi::CStr;
 
     pub struct TestModule;
     impl PamModule for TestModule {}
 
     export_pam_module!(TestModule);
+
+    fn answer(token: Option<&str>) -> impl FnOnce() -> crate::PamResult<Option<String>> {
+        let token = token.map(String::from);
+        move || Ok(token)
+    }
+
+    fn no_prompt() -> crate::PamResult<Option<String>> {
+        panic!("prompted although a token was available")
+    }
+
+    #[test]
+    fn module_result() {
+        assert_eq!(
+            to_module_result(Ok(()), PamReturnCode::Auth_Err),
+            PamReturnCode::Success as i32
+        );
+        assert_eq!(
+            to_module_result(
+                Err(PamError::Pam(PamReturnCode::Ignore)),
+                PamReturnCode::Auth_Err
+            ),
+            PamReturnCode::Ignore as i32
+        );
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic)]
+    fn module_result_success_in_err() {
+        assert_eq!(
+            to_module_result(
+                Err(PamError::Pam(PamReturnCode::Success)),
+                PamReturnCode::Auth_Err
+            ),
+            PamReturnCode::Auth_Err as i32
+        );
+    }
+
+    #[test]
+    fn module_function_codes() {
+        use ModuleFunction::*;
+
+        let all = [
+            AccountManagement,
+            Authenticate,
+            ChangeAuthToken,
+            CloseSession,
+            OpenSession,
+            SetCredentials,
+        ];
+        for function in &all {
+            assert_eq!(
+                function.check(Ok(ModuleSuccess::Ignore)),
+                Ok(PamReturnCode::Ignore)
+            );
+            assert_eq!(
+                function.check(Err(PamError::Pam(PamReturnCode::System_Err))),
+                Ok(PamReturnCode::System_Err)
+            );
+        }
+
+        let new_authtok_reqd = || Err(PamError::Pam(PamReturnCode::New_Authtok_Reqd));
+        assert_eq!(
+            AccountManagement.check(new_authtok_reqd()),
+            Ok(PamReturnCode::New_Authtok_Reqd)
+        );
+        assert_eq!(
+            OpenSession.check(new_authtok_reqd()),
+            Err(PamReturnCode::New_Authtok_Reqd)
+        );
+
+        assert!(SetCredentials.allows(PamReturnCode::Cred_Unavail));
+        assert!(!Authenticate.allows(PamReturnCode::Cred_Unavail));
+        assert!(Authenticate.allows(PamReturnCode::User_Unknown));
+        assert!(!CloseSession.allows(PamReturnCode::User_Unknown));
+        assert!(ChangeAuthToken.allows(PamReturnCode::Try_Again));
+        assert!(!AccountManagement.allows(PamReturnCode::Try_Again));
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic)]
+    fn module_function_success_in_err() {
+        // An error must never be reported as success
+        assert_eq!(
+            ModuleFunction::Authenticate.check(Err(PamError::Pam(PamReturnCode::Success))),
+            Ok(PamReturnCode::Service_Err)
+        );
+    }
+
+    #[test]
+    fn standard_options() {
+        let args = [
+            CStr::from_bytes_with_nul(b"try_first_pass\0").unwrap(),
+            CStr::from_bytes_with_nul(b"retry=3\0").unwrap(),
+            CStr::from_bytes_with_nul(b"use_authtok\0").unwrap(),
+            CStr::from_bytes_with_nul(b"Debug\0").unwrap(),
+        ];
+        let opts = StandardOptions::parse(&args);
+        assert!(opts.try_first_pass && opts.use_authtok);
+        assert!(!opts.use_first_pass && !opts.debug && !opts.nullok && !opts.quiet);
+        // Options are case sensitive and unknown ones keep their order
+        assert_eq!(opts.unrecognized, vec![args[1], args[3]]);
+
+        let args = [
+            CStr::from_bytes_with_nul(b"debug\0").unwrap(),
+            CStr::from_bytes_with_nul(b"nullok\0").unwrap(),
+            CStr::from_bytes_with_nul(b"use_first_pass\0").unwrap(),
+            CStr::from_bytes_with_nul(b"quiet\0").unwrap(),
+        ];
+        let opts = StandardOptions::parse(&args);
+        assert!(opts.debug && opts.nullok && opts.use_first_pass && opts.quiet);
+        assert!(opts.unrecognized.is_empty());
+
+        assert_eq!(StandardOptions::parse(&[]), StandardOptions::default());
+    }
+
+    #[test]
+    fn data_store_keys() {
+        let nullok = CStr::from_bytes_with_nul(b"nullok\0").unwrap();
+        let debug = CStr::from_bytes_with_nul(b"debug\0").unwrap();
+        let nulldebug = CStr::from_bytes_with_nul(b"nulldebug\0").unwrap();
+        let ok = CStr::from_bytes_with_nul(b"ok\0").unwrap();
+
+        let store = DataStore::new("pam_test", &[nullok, debug]);
+        assert_eq!(store, DataStore::new("pam_test", &[nullok, debug]));
+        assert!(store.key("counter").starts_with("pam_test:"));
+        assert!(store.key("counter").ends_with(":counter"));
+
+        // Argument boundaries and order are part of the discriminator
+        assert_ne!(store, DataStore::new("pam_test", &[nulldebug, ok]));
+        assert_ne!(store, DataStore::new("pam_test", &[debug, nullok]));
+        assert_ne!(store, DataStore::new("pam_other", &[nullok, debug]));
+        assert_ne!(
+            DataStore::new("pam_test", &[]),
+            DataStore::new("pam_test", &[ok])
+        );
+    }
+
+    #[test]
+    fn authtok_use_first_pass() {
+        let existing = Some("stacked");
+        assert_eq!(
+            resolve_authtok(existing, true, false, no_prompt).unwrap(),
+            ("stacked".to_string(), false)
+        );
+        assert_eq!(
+            resolve_authtok(None, true, false, no_prompt)
+                .unwrap_err()
+                .code(),
+            PamReturnCode::Auth_Err
+        );
+    }
+
+    #[test]
+    fn authtok_try_first_pass() {
+        let existing = Some("stacked");
+        assert_eq!(
+            resolve_authtok(existing, false, true, no_prompt).unwrap(),
+            ("stacked".to_string(), false)
+        );
+        assert_eq!(
+            resolve_authtok(None, false, true, answer(Some("typed"))).unwrap(),
+            ("typed".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn authtok_prompt() {
+        // Without any of the flags, we always prompt
+        let existing = Some("stacked");
+        assert_eq!(
+            resolve_authtok(existing, false, false, answer(Some("typed"))).unwrap(),
+            ("typed".to_string(), true)
+        );
+        // An empty answer is a valid token..
+        assert_eq!(
+            resolve_authtok(None, false, false, answer(Some(""))).unwrap(),
+            (String::new(), true)
+        );
+        // ..while no answer at all is not
+        assert_eq!(
+            resolve_authtok(None, false, false, answer(None)).unwrap_err(),
+            PamError::Pam(PamReturnCode::Conv_Err)
+        );
+    }
+
+    #[test]
+    fn new_authtok_use_authtok() {
+        let existing = Some("stacked");
+        assert_eq!(
+            resolve_new_authtok(existing, true, no_prompt).unwrap(),
+            ("stacked".to_string(), false)
+        );
+        assert_eq!(
+            resolve_new_authtok(None, true, no_prompt)
+                .unwrap_err()
+                .code(),
+            PamReturnCode::AuthTok_Err
+        );
+        // Without `use_authtok`, the new token is always asked for
+        assert_eq!(
+            resolve_new_authtok(existing, false, answer(Some("typed"))).unwrap(),
+            ("typed".to_string(), true)
+        );
+    }
 }
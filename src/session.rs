@@ -0,0 +1,401 @@
+//! Running processes in a session opened by a `Client`
+//!
+//! Login-like tools usually run the shell of the user once the session is open.
+//! `spawn_command` (requires the `spawn` feature) does the steps needed for this: it
+//! switches to the user and its groups, replaces the environment with the PAM
+//! environment and changes to the home directory. Programs which continue as the
+//! user themselves call `become_user` instead.
+//!
+//! They are also expected to record the session in utmp and wtmp, which PAM doesn't
+//! do. `record_login`, `record_logout` and `record_failed_login` (requires the `utmp`
+//! feature) write these entries, `Client::set_utmp_accounting` calls them
+//! automatically.
+
+#[cfg(feature = "spawn")]
+use std::ffi::CString;
+#[cfg(feature = "spawn")]
+use std::io;
+#[cfg(feature = "spawn")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "spawn")]
+use std::os::unix::process::CommandExt;
+#[cfg(feature = "spawn")]
+use std::process::{Child, Command};
+
+#[cfg(feature = "spawn")]
+use uzers::os::unix::UserExt;
+
+#[cfg(feature = "spawn")]
+use crate::{conv, Client};
+
+/// Reason why `spawn_command` refused to run the command
+///
+/// It is returned inside the `io::Error`, see `io::Error::get_ref`.
+#[cfg(feature = "spawn")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The client has no open session
+    NoSession,
+    /// The caller is not root, so it can't switch to the user
+    NotRoot,
+}
+
+#[cfg(feature = "spawn")]
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SpawnError::NoSession => fmt.write_str("no PAM session is open"),
+            SpawnError::NotRoot => fmt.write_str("only root can run commands as another user"),
+        }
+    }
+}
+
+#[cfg(feature = "spawn")]
+impl std::error::Error for SpawnError {}
+
+/// Run `command` as the user of the open session of `client`
+///
+/// The child runs with the uid, gid and supplementary groups of the user in its home
+/// directory. Its environment is the PAM environment as returned by
+/// `Client::environment`, variables set on `command` via `Command::env` override it.
+///
+/// Fails with `SpawnError::NoSession` if no session is open and `SpawnError::NotRoot`
+/// if the process is not running as root. Fails with `NotFound` if the user has no
+/// passwd entry.
+///
+/// ```no_run
+/// use std::process::Command;
+///
+/// use pam::session::spawn_command;
+/// use pam::Client;
+///
+/// let mut client = Client::with_password("login").expect("Failed to init PAM client.");
+/// client.conversation_mut().set_credentials("login", "password");
+/// client.authenticate().expect("Authentication failed!");
+/// client.open_session().expect("Failed to open a session!");
+/// let mut shell = Command::new("/bin/sh");
+/// shell.arg("-l").env("TERM", "xterm");
+/// let status = spawn_command(&mut client, shell)
+///     .and_then(|mut child| child.wait())
+///     .expect("Failed to run the shell!");
+/// ```
+#[cfg(feature = "spawn")]
+pub fn spawn_command<C: conv::Conversation>(
+    client: &mut Client<C>,
+    mut command: Command,
+) -> io::Result<Child> {
+    if !client.has_open_session() {
+        return Err(io::Error::new(io::ErrorKind::Other, SpawnError::NoSession));
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            SpawnError::NotRoot,
+        ));
+    }
+
+    let name = client
+        .authenticated_user()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let user = uzers::get_user_by_name(&name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "user has no passwd entry"))?;
+    let uid = user.uid();
+    let gid = user.primary_group_id();
+    let groups: Vec<libc::gid_t> = uzers::get_user_groups(&name, gid)
+        .unwrap_or_default()
+        .iter()
+        .map(|group| group.gid())
+        .collect();
+    let home = CString::new(user.home_dir().as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let environment = client
+        .environment()
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    // Keep the variables set by the caller to apply them on top of the PAM environment
+    let overrides: Vec<_> = command
+        .get_envs()
+        .map(|(key, value)| (key.to_os_string(), value.map(|value| value.to_os_string())))
+        .collect();
+    command.env_clear().envs(environment);
+    for (key, value) in overrides {
+        match value {
+            Some(value) => command.env(key, value),
+            None => command.env_remove(key),
+        };
+    }
+
+    // `CommandExt::uid` runs after the groups are dropped and before `pre_exec`, so
+    // switch the user here. Only async-signal-safe functions may be called.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(uid) != 0
+                || libc::chdir(home.as_ptr()) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// The step of `become_user` which failed
+#[cfg(feature = "spawn")]
+#[derive(Debug)]
+pub enum BecomeUserError {
+    /// The client has no open session
+    NoSession,
+    /// The caller is not root, so it can't switch to the user
+    NotRoot,
+    /// The authenticated user has no passwd entry
+    UnknownUser,
+    /// `initgroups` failed, nothing was changed
+    InitGroups(io::Error),
+    /// `setgid` failed, the groups were restored
+    SetGid(io::Error),
+    /// `setuid` failed, the groups and the gid were restored
+    SetUid(io::Error),
+}
+
+#[cfg(feature = "spawn")]
+impl std::fmt::Display for BecomeUserError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BecomeUserError::NoSession => fmt.write_str("no PAM session is open"),
+            BecomeUserError::NotRoot => fmt.write_str("only root can become another user"),
+            BecomeUserError::UnknownUser => fmt.write_str("user has no passwd entry"),
+            BecomeUserError::InitGroups(err) => write!(fmt, "initgroups failed: {}", err),
+            BecomeUserError::SetGid(err) => write!(fmt, "setgid failed: {}", err),
+            BecomeUserError::SetUid(err) => write!(fmt, "setuid failed: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "spawn")]
+impl std::error::Error for BecomeUserError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BecomeUserError::InitGroups(err)
+            | BecomeUserError::SetGid(err)
+            | BecomeUserError::SetUid(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Switch this process to the user of the open session of `client`
+///
+/// This calls `initgroups`, `setgid` and `setuid` with the passwd entry of the
+/// authenticated user, in this order. **This can't be undone**, the process loses
+/// root privileges for good, so e.g. closing the session has to happen in another
+/// process. Usually a child forked after `open_session` calls this and the parent
+/// waits to close the session.
+///
+/// Fails with `NoSession` if no session is open and `NotRoot` if the process is not
+/// running as root. If a step fails, the previous ones are undone, so the process
+/// never continues with only some of its ids changed. If even that fails, the
+/// process is aborted.
+///
+/// ```no_run
+/// use pam::session::become_user;
+/// use pam::Client;
+///
+/// let mut client = Client::with_password("login").expect("Failed to init PAM client.");
+/// client.conversation_mut().set_credentials("login", "password");
+/// client.authenticate().expect("Authentication failed!");
+/// client.open_session().expect("Failed to open a session!");
+/// match unsafe { libc::fork() } {
+///     0 => {
+///         become_user(&mut client).expect("Failed to become the user!");
+///         // ... run as the user
+///     }
+///     _ => { /* wait for the child, then close the session */ }
+/// }
+/// ```
+#[cfg(feature = "spawn")]
+pub fn become_user<C: conv::Conversation>(client: &mut Client<C>) -> Result<(), BecomeUserError> {
+    if !client.has_open_session() {
+        return Err(BecomeUserError::NoSession);
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(BecomeUserError::NotRoot);
+    }
+
+    let name = client
+        .authenticated_user()
+        .map_err(|_| BecomeUserError::UnknownUser)?;
+    let user = uzers::get_user_by_name(&name).ok_or(BecomeUserError::UnknownUser)?;
+    let name = CString::new(name).map_err(|_| BecomeUserError::UnknownUser)?;
+    let uid = user.uid();
+    let gid = user.primary_group_id();
+
+    // Save the current groups and gid to restore them if a later step fails
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+    let count = unsafe { libc::getgroups(groups.len() as _, groups.as_mut_ptr()) };
+    if count < 0 {
+        return Err(BecomeUserError::InitGroups(io::Error::last_os_error()));
+    }
+    groups.truncate(count as usize);
+    let old_gid = unsafe { libc::getgid() };
+    let restore_groups = || {
+        if unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) } != 0 {
+            std::process::abort();
+        }
+    };
+
+    if unsafe { libc::initgroups(name.as_ptr(), gid) } != 0 {
+        return Err(BecomeUserError::InitGroups(io::Error::last_os_error()));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        let err = io::Error::last_os_error();
+        restore_groups();
+        return Err(BecomeUserError::SetGid(err));
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        let err = io::Error::last_os_error();
+        if unsafe { libc::setgid(old_gid) } != 0 {
+            std::process::abort();
+        }
+        restore_groups();
+        return Err(BecomeUserError::SetUid(err));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "utmp")]
+mod utmp {
+    use std::ffi::CStr;
+    use std::io;
+    use std::mem;
+    use std::os::raw::c_char;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    extern "C" {
+        // Not exported by libc
+        fn updwtmpx(wtmpx_file: *const c_char, utmpx: *const libc::utmpx);
+    }
+
+    const WTMP: &[u8] = b"/var/log/wtmp\0";
+    const BTMP: &[u8] = b"/var/log/btmp\0";
+
+    /// Record the login of `user` on `tty` in utmp and wtmp
+    ///
+    /// `tty` is the terminal like `PAM_TTY`, with or without `/dev/`, `rhost` the
+    /// remote host if any and `pid` the process of the session, usually the login
+    /// process itself. Fails if utmp can't be written, e.g. because `/run/utmp` is
+    /// missing or the caller isn't root. wtmp is written on a best-effort basis.
+    pub fn record_login(user: &str, tty: &str, rhost: Option<&str>, pid: u32) -> io::Result<()> {
+        let mut entry = entry(libc::USER_PROCESS, tty, pid);
+        copy(&mut entry.ut_user, user);
+        copy(&mut entry.ut_host, rhost.unwrap_or(""));
+        write(&entry, WTMP)
+    }
+
+    /// Record the end of the session on `tty` started by `pid`, see `record_login`
+    pub fn record_logout(tty: &str, pid: u32) -> io::Result<()> {
+        let entry = entry(libc::DEAD_PROCESS, tty, pid);
+        write(&entry, WTMP)
+    }
+
+    /// Record a failed login attempt of `user` on `tty` in btmp, as shown by `lastb`
+    ///
+    /// btmp is only readable by root, as users sometimes type their password as login.
+    /// Nothing is written to utmp.
+    pub fn record_failed_login(user: &str, tty: &str, rhost: Option<&str>) -> io::Result<()> {
+        let mut entry = entry(libc::USER_PROCESS, tty, std::process::id());
+        copy(&mut entry.ut_user, user);
+        copy(&mut entry.ut_host, rhost.unwrap_or(""));
+        let path = CStr::from_bytes_with_nul(BTMP).unwrap();
+        // updwtmpx reports no errors, so check whether btmp is writable at all
+        if unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { updwtmpx(path.as_ptr(), &entry) };
+        Ok(())
+    }
+
+    fn entry(ut_type: libc::c_short, tty: &str, pid: u32) -> libc::utmpx {
+        let line = tty.strip_prefix("/dev/").unwrap_or(tty);
+        // Like login, identify the entry by the end of the line, e.g. "ts/3" for "pts/3"
+        let id = &line.as_bytes()[line.len().saturating_sub(4)..];
+
+        let mut entry: libc::utmpx = unsafe { mem::zeroed() };
+        entry.ut_type = ut_type;
+        entry.ut_pid = pid as libc::pid_t;
+        copy(&mut entry.ut_line, line);
+        for (dst, src) in entry.ut_id.iter_mut().zip(id) {
+            *dst = *src as c_char;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        entry.ut_tv.tv_sec = now.as_secs() as _;
+        entry.ut_tv.tv_usec = now.subsec_micros() as _;
+        entry
+    }
+
+    // Copy `value` into a fixed-size field, truncating it. The fields need no nul
+    // terminator if they are filled completely.
+    fn copy(field: &mut [c_char], value: &str) {
+        for (dst, src) in field.iter_mut().zip(value.as_bytes()) {
+            *dst = *src as c_char;
+        }
+    }
+
+    fn write(entry: &libc::utmpx, log: &[u8]) -> io::Result<()> {
+        let written = unsafe {
+            libc::setutxent();
+            let written = !libc::pututxline(entry).is_null();
+            libc::endutxent();
+            written
+        };
+        if !written {
+            return Err(io::Error::last_os_error());
+        }
+        let path = CStr::from_bytes_with_nul(log).unwrap();
+        unsafe { updwtmpx(path.as_ptr(), entry) };
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        fn text(field: &[c_char]) -> String {
+            let bytes: Vec<u8> = field
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8)
+                .collect();
+            String::from_utf8(bytes).unwrap()
+        }
+
+        #[test]
+        fn login_entry() {
+            let login = entry(libc::USER_PROCESS, "/dev/pts/3", 42);
+            assert_eq!(login.ut_type, libc::USER_PROCESS);
+            assert_eq!(login.ut_pid, 42);
+            assert_eq!(text(&login.ut_line), "pts/3");
+            assert_eq!(text(&login.ut_id), "ts/3");
+            assert!(login.ut_tv.tv_sec > 0);
+
+            let logout = entry(libc::DEAD_PROCESS, "ssh", 1);
+            assert_eq!(text(&logout.ut_line), "ssh");
+            assert_eq!(text(&logout.ut_id), "ssh");
+        }
+
+        #[test]
+        fn truncated_fields() {
+            let mut field = [0 as c_char; 4];
+            copy(&mut field, "abcdef");
+            assert_eq!(text(&field), "abcd");
+        }
+    }
+}
+
+#[cfg(feature = "utmp")]
+pub use self::utmp::{record_failed_login, record_login, record_logout};
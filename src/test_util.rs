@@ -0,0 +1,612 @@
+//! Utilities for testing PAM modules
+//!
+//! `MockHandle` (requires the `module` feature) implements `module::PamHandleOps`
+//! so module logic can be unit tested without libpam.
+//!
+//! `ModuleTestHarness` drives a module through the real libpam with the help of
+//! [pam_wrapper](https://cwrap.org/pam_wrapper.html), so a module can be tested
+//! without installing it to `/lib/security` or touching `/etc/pam.d`.
+//!
+//! The test binary has to be run with pam_wrapper preloaded, e.g.
+//! `LD_PRELOAD=libpam_wrapper.so cargo test`. As the harness communicates with
+//! pam_wrapper via environment variables, tests using it must not run concurrently
+//! (`cargo test -- --test-threads=1`).
+
+#[cfg(feature = "module")]
+use std::any::Any;
+#[cfg(feature = "module")]
+use std::cell::RefCell;
+#[cfg(feature = "module")]
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+#[cfg(feature = "module")]
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    conv, functions::*, PamError, PamHandle, PamItemType, PamMessageStyle, PamResult, PamReturnCode,
+};
+
+/// Location of `pam_set_items.so` if `PAM_SET_ITEMS_SO` is not set
+pub const DEFAULT_SET_ITEMS_MODULE: &str = "/usr/lib/pam_wrapper/pam_set_items.so";
+
+const SERVICE_NAME: &str = "pam_rs_test";
+
+static INSTANCE: AtomicUsize = AtomicUsize::new(0);
+
+/// In-memory stand-in for a `PamHandle` to unit test module code
+///
+/// Items, module data and the PAM environment are kept in `HashMap`s. Prompts
+/// sent via `converse` are answered with the canned answers in order and all
+/// messages as well as syslog output are recorded.
+///
+/// ```
+/// use pam::{module, test_util::MockHandle, PamItemType};
+///
+/// let mut handle = MockHandle::new().with_answers(vec![Some("secret")]);
+/// let _token = module::obtain_authtok(&mut handle, None, false, false).unwrap();
+/// assert_eq!(handle.item(PamItemType::AuthTok), Some("secret"));
+/// ```
+#[cfg(feature = "module")]
+#[derive(Debug, Default)]
+pub struct MockHandle {
+    items: HashMap<c_int, String>,
+    data: HashMap<String, Box<dyn Any>>,
+    env: HashMap<String, String>,
+    // `converse` and `syslog` only get `&self`
+    answers: RefCell<VecDeque<Option<String>>>,
+    transcript: RefCell<Vec<(PamMessageStyle, String)>>,
+    log: RefCell<Vec<(c_int, String)>>,
+}
+
+#[cfg(feature = "module")]
+impl MockHandle {
+    /// Create an empty `MockHandle`
+    pub fn new() -> MockHandle {
+        MockHandle::default()
+    }
+
+    /// Preset the item `item_type` to `value`
+    pub fn with_item<S: Into<String>>(mut self, item_type: PamItemType, value: S) -> MockHandle {
+        self.items.insert(item_type as c_int, value.into());
+        self
+    }
+
+    /// Answer the prompts sent via `converse` with `answers` in order
+    ///
+    /// `None` simulates an application not answering a prompt. Once all answers
+    /// are used up, `converse` fails with `Conv_Err`.
+    pub fn with_answers<I, S>(mut self, answers: I) -> MockHandle
+    where
+        I: IntoIterator<Item = Option<S>>,
+        S: Into<String>,
+    {
+        self.answers
+            .get_mut()
+            .extend(answers.into_iter().map(|a| a.map(Into::into)));
+        self
+    }
+
+    /// Current value of the item `item_type`
+    pub fn item(&self, item_type: PamItemType) -> Option<&str> {
+        self.items.get(&(item_type as c_int)).map(String::as_str)
+    }
+
+    /// Current PAM environment
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    /// All messages passed to `converse` in order
+    pub fn transcript(&self) -> Vec<(PamMessageStyle, String)> {
+        self.transcript.borrow().clone()
+    }
+
+    /// All messages passed to `syslog` in order
+    pub fn log(&self) -> Vec<(c_int, String)> {
+        self.log.borrow().clone()
+    }
+}
+
+#[cfg(feature = "module")]
+impl crate::module::PamHandleOps for MockHandle {
+    fn get_item_str(&self, item_type: PamItemType) -> PamResult<Option<&str>> {
+        Ok(self.item(item_type))
+    }
+    fn set_item_str(&mut self, item_type: PamItemType, value: &str) -> PamResult<()> {
+        self.items.insert(item_type as c_int, value.to_string());
+        Ok(())
+    }
+    fn get_data<T: 'static>(&self, module_data_name: &str) -> PamResult<&T> {
+        match self.data.get(module_data_name) {
+            Some(data) => data
+                .downcast_ref()
+                .ok_or(PamError::Pam(PamReturnCode::System_Err)),
+            None => Err(PamError::Pam(PamReturnCode::No_Module_Data)),
+        }
+    }
+    fn set_data<T: 'static>(&mut self, module_data_name: &str, value: T) -> PamResult<()> {
+        self.data
+            .insert(module_data_name.to_string(), Box::new(value));
+        Ok(())
+    }
+    fn converse(&self, messages: &[crate::Message]) -> PamResult<Vec<Option<String>>> {
+        messages
+            .iter()
+            .map(|m| {
+                self.transcript
+                    .borrow_mut()
+                    .push((m.style, m.text.to_string()));
+                match m.style {
+                    PamMessageStyle::Prompt_Echo_On | PamMessageStyle::Prompt_Echo_Off => self
+                        .answers
+                        .borrow_mut()
+                        .pop_front()
+                        .ok_or(PamError::Pam(PamReturnCode::Conv_Err)),
+                    _ => Ok(None),
+                }
+            })
+            .collect()
+    }
+    fn getenv(&mut self, name: &str) -> PamResult<Option<String>> {
+        Ok(self.env.get(name).cloned())
+    }
+    fn putenv(&mut self, name_value: &str) -> PamResult<()> {
+        // Like pam_putenv, a name without '=' removes the variable
+        match name_value.find('=') {
+            Some(pos) => {
+                let (name, value) = name_value.split_at(pos);
+                self.env.insert(name.to_string(), value[1..].to_string());
+            }
+            None => {
+                self.env.remove(name_value);
+            }
+        }
+        Ok(())
+    }
+    fn syslog(&self, priority: c_int, msg: &str) {
+        self.log.borrow_mut().push((priority, msg.to_string()));
+    }
+}
+
+/// Outcome of running a module through the `ModuleTestHarness`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HarnessResult {
+    /// Code returned by the PAM function
+    pub code: PamReturnCode,
+    /// All messages the module sent to the application in order
+    pub transcript: Vec<(PamMessageStyle, String)>,
+}
+
+/// Test harness running a module via libpam and pam_wrapper
+///
+/// All `run_*` methods panic if pam_wrapper is not preloaded.
+///
+/// ```no_run
+/// use pam::{test_util::ModuleTestHarness, PamItemType, PamReturnCode};
+///
+/// let result = ModuleTestHarness::new("target/debug/libmymodule.so")
+///     .with_item(PamItemType::User, "alice")
+///     .with_authtok("secret")
+///     .run_authenticate()
+///     .expect("Failed to run module");
+/// assert_eq!(result.code, PamReturnCode::Success);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ModuleTestHarness {
+    module: PathBuf,
+    args: Vec<String>,
+    stacked: Vec<(PathBuf, Vec<String>)>,
+    items: Vec<(PamItemType, String)>,
+    set_items_module: PathBuf,
+    user: Option<String>,
+    login: Option<String>,
+    authtok: Option<String>,
+}
+
+impl ModuleTestHarness {
+    /// Create a new harness for the module at `module`
+    pub fn new<P: AsRef<Path>>(module: P) -> ModuleTestHarness {
+        let set_items_module = env::var_os("PAM_SET_ITEMS_SO")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_SET_ITEMS_MODULE));
+        ModuleTestHarness {
+            module: module.as_ref().to_path_buf(),
+            args: Vec::new(),
+            stacked: Vec::new(),
+            items: Vec::new(),
+            set_items_module,
+            user: None,
+            login: None,
+            authtok: None,
+        }
+    }
+
+    /// Pass `args` to the module in the generated service file
+    pub fn with_args<S: AsRef<str>>(mut self, args: &[S]) -> ModuleTestHarness {
+        self.args = args.iter().map(|a| a.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Run another module with `args` after the module under test
+    ///
+    /// All modules are `required`, so this allows to test the interaction of stacked
+    /// modules, e.g. the reuse of `PAM_AUTHTOK` via `use_first_pass`.
+    pub fn with_stacked_module<P, S>(mut self, module: P, args: &[S]) -> ModuleTestHarness
+    where
+        P: AsRef<Path>,
+        S: AsRef<str>,
+    {
+        let args = args.iter().map(|a| a.as_ref().to_string()).collect();
+        self.stacked.push((module.as_ref().to_path_buf(), args));
+        self
+    }
+
+    /// Set `item_type` to `value` via `pam_set_items.so` before the module runs
+    ///
+    /// Only string items are supported.
+    pub fn with_item<S: Into<String>>(
+        mut self,
+        item_type: PamItemType,
+        value: S,
+    ) -> ModuleTestHarness {
+        let value = value.into();
+        if item_type == PamItemType::User {
+            self.user = Some(value.clone());
+        }
+        self.items.push((item_type, value));
+        self
+    }
+
+    /// Set `PAM_AUTHTOK` and answer password prompts with `authtok`
+    pub fn with_authtok<S: Into<String>>(mut self, authtok: S) -> ModuleTestHarness {
+        let authtok = authtok.into();
+        self.authtok = Some(authtok.clone());
+        self.with_item(PamItemType::AuthTok, authtok)
+    }
+
+    /// Answer login prompts with `login` without setting `PAM_USER`
+    ///
+    /// This lets libpam ask for the user if the module calls `pam_get_user`.
+    pub fn with_login<S: Into<String>>(mut self, login: S) -> ModuleTestHarness {
+        self.login = Some(login.into());
+        self
+    }
+
+    /// Answer password prompts with `password` without setting `PAM_AUTHTOK`
+    pub fn with_password<S: Into<String>>(mut self, password: S) -> ModuleTestHarness {
+        self.authtok = Some(password.into());
+        self
+    }
+
+    /// Use a custom location for pam_wrapper's `pam_set_items.so`
+    pub fn with_set_items_module<P: AsRef<Path>>(mut self, path: P) -> ModuleTestHarness {
+        self.set_items_module = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Run `pam_authenticate` against the module
+    pub fn run_authenticate(&self) -> PamResult<HarnessResult> {
+        self.run("auth", |handle| authenticate(handle, crate::PamFlag::None))
+    }
+
+    /// Run `pam_acct_mgmt` against the module
+    pub fn run_acct_mgmt(&self) -> PamResult<HarnessResult> {
+        self.run("account", |handle| acct_mgmt(handle, crate::PamFlag::None))
+    }
+
+    /// Run `pam_chauthtok` against the module
+    pub fn run_chauthtok(&self) -> PamResult<HarnessResult> {
+        self.run("password", |handle| chauthtok(handle, crate::PamFlag::None))
+    }
+
+    /// Run `pam_open_session` against the module
+    pub fn run_open_session(&self) -> PamResult<HarnessResult> {
+        self.run("session", |handle| open_session(handle, false))
+    }
+
+    /// Run `pam_close_session` against the module
+    pub fn run_close_session(&self) -> PamResult<HarnessResult> {
+        self.run("session", |handle| close_session(handle, false))
+    }
+
+    fn run<F>(&self, facility: &str, call: F) -> PamResult<HarnessResult>
+    where
+        F: FnOnce(&mut PamHandle) -> PamReturnCode,
+    {
+        let preloaded = env::var("LD_PRELOAD").unwrap_or_default();
+        assert!(
+            preloaded.contains("pam_wrapper"),
+            "ModuleTestHarness requires LD_PRELOAD=libpam_wrapper.so"
+        );
+
+        let service_dir = ServiceDir::create(&self.service_file(facility)).map_err(system_err)?;
+        let env_vars = self.item_env_vars()?;
+        env::set_var("PAM_WRAPPER", "1");
+        env::set_var("PAM_WRAPPER_SERVICE_DIR", &service_dir.0);
+        for (key, value) in &env_vars {
+            env::set_var(key, value);
+        }
+
+        let mut recorder = Box::new(Recorder {
+            user: self
+                .login
+                .clone()
+                .or_else(|| self.user.clone())
+                .unwrap_or_default(),
+            authtok: self.authtok.clone().unwrap_or_default(),
+            transcript: Vec::new(),
+        });
+        let pam_conv = conv::into_pam_conv(&mut *recorder);
+        let result = start(SERVICE_NAME, self.user.as_deref(), &pam_conv).map(|handle| {
+            let code = call(handle);
+            end(handle, code);
+            code
+        });
+
+        for (key, _) in &env_vars {
+            env::remove_var(key);
+        }
+        env::remove_var("PAM_WRAPPER_SERVICE_DIR");
+        env::remove_var("PAM_WRAPPER");
+
+        result.map(|code| HarnessResult {
+            code,
+            transcript: recorder.transcript,
+        })
+    }
+
+    // Builds the service file which sets the items first and runs the modules afterwards
+    fn service_file(&self, facility: &str) -> String {
+        let mut content = format!(
+            "{} required {}\n",
+            facility,
+            self.set_items_module.display()
+        );
+        let modules = std::iter::once((&self.module, &self.args))
+            .chain(self.stacked.iter().map(|(module, args)| (module, args)));
+        for (module, args) in modules {
+            content.push_str(&format!("{} required {}", facility, module.display()));
+            for arg in args {
+                content.push(' ');
+                content.push_str(arg);
+            }
+            content.push('\n');
+        }
+        content
+    }
+
+    // Environment variables read by `pam_set_items.so`
+    fn item_env_vars(&self) -> PamResult<Vec<(&'static str, &str)>> {
+        self.items
+            .iter()
+            .map(|(item_type, value)| {
+                let key = match item_type {
+                    PamItemType::Service => "PAM_SERVICE",
+                    PamItemType::User => "PAM_USER",
+                    PamItemType::User_Prompt => "PAM_USER_PROMPT",
+                    PamItemType::TTY => "PAM_TTY",
+                    PamItemType::RUser => "PAM_RUSER",
+                    PamItemType::RHost => "PAM_RHOST",
+                    PamItemType::AuthTok => "PAM_AUTHTOK",
+                    PamItemType::OldAuthTok => "PAM_OLDAUTHTOK",
+                    PamItemType::XDisplay => "PAM_XDISPLAY",
+                    PamItemType::AuthTok_Type => "PAM_AUTHTOK_TYPE",
+                    _ => return Err(PamError::Pam(PamReturnCode::Bad_Item)),
+                };
+                Ok((key, value.as_str()))
+            })
+            .collect()
+    }
+}
+
+// Temporary directory containing the generated service file, removed on drop
+struct ServiceDir(PathBuf);
+
+impl ServiceDir {
+    fn create(content: &str) -> std::io::Result<ServiceDir> {
+        let instance = INSTANCE.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("pam-rs-{}-{}", std::process::id(), instance));
+        fs::create_dir_all(&dir)?;
+        let service_dir = ServiceDir(dir);
+        fs::write(service_dir.0.join(SERVICE_NAME), content)?;
+        Ok(service_dir)
+    }
+}
+
+impl Drop for ServiceDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Conversation handler answering with the configured credentials and
+// recording every message
+struct Recorder {
+    user: String,
+    authtok: String,
+    transcript: Vec<(PamMessageStyle, String)>,
+}
+
+impl Recorder {
+    fn record(&mut self, style: PamMessageStyle, msg: &CStr) {
+        self.transcript
+            .push((style, msg.to_string_lossy().into_owned()));
+    }
+}
+
+impl conv::Conversation for Recorder {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, conv::ConvError> {
+        self.record(PamMessageStyle::Prompt_Echo_On, msg);
+        Ok(self.user.clone())
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, conv::ConvError> {
+        self.record(PamMessageStyle::Prompt_Echo_Off, msg);
+        Ok(self.authtok.clone())
+    }
+    fn info(&mut self, msg: &CStr) {
+        self.record(PamMessageStyle::Text_Info, msg);
+    }
+    fn error(&mut self, msg: &CStr) {
+        self.record(PamMessageStyle::Error_Msg, msg);
+    }
+}
+
+fn system_err(_: std::io::Error) -> PamError {
+    PamError::Pam(PamReturnCode::System_Err)
+}
+
+#[cfg(all(test, feature = "module"))]
+mod test {
+    use super::MockHandle;
+    use crate::module::{
+        catch_panic, obtain_authtok, DataStore, PamHandleOps, StandardOptions, LOG_CRIT, LOG_DEBUG,
+        LOG_ERR, MAX_LOG_MESSAGE_LEN,
+    };
+    use crate::pam_log;
+    use crate::{Message, PamItemType, PamMessageStyle, PamReturnCode};
+
+    use secrecy::ExposeSecret;
+    use std::ffi::CStr;
+
+    #[test]
+    fn mock_obtain_authtok() {
+        let mut handle = MockHandle::new().with_answers(vec![Some("secret")]);
+        let token = obtain_authtok(&mut handle, Some("PIN: "), false, true).unwrap();
+        assert_eq!(token.expose_secret(), "secret");
+        assert_eq!(handle.item(PamItemType::AuthTok), Some("secret"));
+        assert_eq!(
+            handle.transcript(),
+            vec![(PamMessageStyle::Prompt_Echo_Off, "PIN: ".to_string())]
+        );
+
+        // The stored token is reused without prompting again
+        let token = obtain_authtok(&mut handle, None, true, false).unwrap();
+        assert_eq!(token.expose_secret(), "secret");
+        assert_eq!(handle.transcript().len(), 1);
+    }
+
+    #[test]
+    fn mock_converse_exhausted() {
+        let handle = MockHandle::new().with_answers(vec![Some("alice")]);
+        let messages = [
+            Message::new(PamMessageStyle::Text_Info, "Welcome"),
+            Message::new(PamMessageStyle::Prompt_Echo_On, "login: "),
+        ];
+        assert_eq!(
+            handle.converse(&messages).unwrap(),
+            vec![None, Some("alice".to_string())]
+        );
+        assert_eq!(
+            handle.converse(&messages).unwrap_err().code(),
+            PamReturnCode::Conv_Err
+        );
+    }
+
+    #[test]
+    fn mock_pam_log() {
+        let mut handle = MockHandle::new();
+        let opts = StandardOptions::default();
+        pam_log!(&mut handle, opts, LOG_DEBUG, "hidden {}", 1);
+        pam_log!(
+            &mut handle,
+            opts,
+            LOG_ERR,
+            "user {} rejected: {:?}",
+            "alice",
+            PamReturnCode::Auth_Err
+        );
+        assert_eq!(
+            handle.log(),
+            vec![(LOG_ERR, "user alice rejected: Auth_Err".to_string())]
+        );
+
+        let debug = StandardOptions {
+            debug: true,
+            ..StandardOptions::default()
+        };
+        pam_log!(
+            &mut handle,
+            debug,
+            LOG_DEBUG,
+            "{}",
+            "ä".repeat(MAX_LOG_MESSAGE_LEN)
+        );
+        let (priority, msg) = handle.log().pop().unwrap();
+        assert_eq!(priority, LOG_DEBUG);
+        // 'ä' is two bytes long, so the message is truncated at a code point boundary
+        assert_eq!(msg, "ä".repeat(MAX_LOG_MESSAGE_LEN / 2));
+
+        // The level of one call doesn't leak into the next
+        pam_log!(&mut handle, opts, LOG_DEBUG, "hidden again");
+        assert_eq!(handle.log().len(), 2);
+    }
+
+    #[test]
+    fn mock_data_and_env() {
+        let mut handle = MockHandle::new();
+        handle.set_data("counter", 1u32).unwrap();
+        assert_eq!(*handle.get_data::<u32>("counter").unwrap(), 1);
+        assert_eq!(
+            handle.get_data::<String>("counter").unwrap_err().code(),
+            PamReturnCode::System_Err
+        );
+        assert_eq!(
+            handle.get_data::<u32>("missing").unwrap_err().code(),
+            PamReturnCode::No_Module_Data
+        );
+
+        handle.putenv("FOO=bar=baz").unwrap();
+        assert_eq!(handle.getenv("FOO").unwrap().as_deref(), Some("bar=baz"));
+        handle.putenv("FOO").unwrap();
+        assert_eq!(handle.getenv("FOO").unwrap(), None);
+    }
+
+    #[test]
+    fn mock_data_store_instances() {
+        let mut handle = MockHandle::new();
+        let debug = CStr::from_bytes_with_nul(b"debug\0").unwrap();
+        let first = DataStore::new("pam_test", &[]);
+        let second = DataStore::new("pam_test", &[debug]);
+
+        first.store(&mut handle, "counter", 1u32).unwrap();
+        assert_eq!(
+            second.fetch::<u32>(&handle, "counter").unwrap_err().code(),
+            PamReturnCode::No_Module_Data
+        );
+        second.store(&mut handle, "counter", 2u32).unwrap();
+        assert_eq!(*first.fetch::<u32>(&handle, "counter").unwrap(), 1);
+        assert_eq!(*second.fetch::<u32>(&handle, "counter").unwrap(), 2);
+
+        // Neither clashes with the key used without a `DataStore`
+        handle.set_data("counter", 3u32).unwrap();
+        assert_eq!(*first.fetch::<u32>(&handle, "counter").unwrap(), 1);
+        assert_eq!(*handle.get_data::<u32>(&second.key("counter")).unwrap(), 2);
+    }
+
+    #[test]
+    fn mock_catch_panic() {
+        let mut handle = MockHandle::new();
+        let code = catch_panic(&mut handle, "pam_sm_authenticate", |_| {
+            PamReturnCode::Ignore as i32
+        });
+        assert_eq!(code, PamReturnCode::Ignore as i32);
+        assert!(handle.log().is_empty());
+
+        let code = catch_panic(&mut handle, "pam_sm_authenticate", |handle| {
+            handle.set_item_str(PamItemType::User, "alice").unwrap();
+            panic!("no {} here", "user");
+        });
+        assert_eq!(code, PamReturnCode::Service_Err as i32);
+        assert_eq!(
+            handle.log(),
+            vec![(
+                LOG_CRIT,
+                "pam_sm_authenticate panicked: no user here".to_string()
+            )]
+        );
+    }
+}
@@ -1,3 +1,7 @@
+use std::ffi::CStr;
+use std::os::raw::c_int;
+
+use crate::conv::ConvError;
 use crate::enums::PamReturnCode;
 
 /// Opaque PAM main structure. Used for nearly all application functions
@@ -7,21 +11,36 @@ pub type PamMessage = pam_sys::pam_message;
 /// PAM response returned by modules
 pub type PamResponse = pam_sys::pam_response;
 
-/// PAM related error with `PamReturnCode` inside it
-pub struct PamError(pub PamReturnCode);
+/// PAM related error
+///
+/// Errors returned by libpam are `Pam` with the `PamReturnCode` inside it. The other
+/// variants are raised by this crate, see `code` for the PAM code matching them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PamError {
+    /// A PAM function failed with the given code
+    Pam(PamReturnCode),
+    /// A PAM call did not return in time and the transaction was abandoned, see
+    /// `Client::set_call_timeout`
+    Timeout,
+    /// The user cancelled the conversation, see `Conversation::is_cancelled`
+    Cancelled,
+    /// The conversation handler failed or returned invalid responses, even if a module
+    /// reported this as another code like `Auth_Err`
+    Conversation(ConvError),
+    /// The password of the user expired and has to be changed, see
+    /// `Client::set_handle_expired_password`
+    PasswordExpired,
+}
 
 /// Convenience type for functions that might fail with a `PamError`
 pub type PamResult<T> = std::result::Result<T, PamError>;
 
-impl std::fmt::Debug for PamError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.0.fmt(fmt)
-    }
-}
-
 impl std::fmt::Display for PamError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.0.fmt(fmt)
+        match *self {
+            PamError::Pam(code) => write!(fmt, "{}: {}", code, self.message()),
+            _ => fmt.write_str(&self.message()),
+        }
     }
 }
 
@@ -29,10 +48,133 @@ impl std::error::Error for PamError {
     fn description(&self) -> &str {
         "PAM returned an error code"
     }
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            PamError::Conversation(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl PamError {
+    /// The code of a `Pam` error, or the closest PAM code for the others
+    ///
+    /// This is `Abort` for `Timeout`, as the transaction can't continue, and `Conv_Err`
+    /// for `Cancelled` and `Conversation`. `PasswordExpired` is `New_Authtok_Reqd`.
+    pub fn code(&self) -> PamReturnCode {
+        match *self {
+            PamError::Pam(code) => code,
+            PamError::Timeout => PamReturnCode::Abort,
+            PamError::Cancelled | PamError::Conversation(_) => PamReturnCode::Conv_Err,
+            PamError::PasswordExpired => PamReturnCode::New_Authtok_Reqd,
+        }
+    }
+
+    // The description of the error, libpam does not need a handle for it
+    pub(crate) fn message(&self) -> String {
+        match *self {
+            PamError::Pam(code) => {
+                let message = unsafe {
+                    CStr::from_ptr(pam_sys::pam_strerror(std::ptr::null_mut(), code as c_int))
+                };
+                message.to_string_lossy().into_owned()
+            }
+            PamError::Timeout => "PAM call timed out".to_string(),
+            PamError::Cancelled => "Conversation cancelled".to_string(),
+            PamError::Conversation(err) => err.to_string(),
+            PamError::PasswordExpired => PamError::Pam(self.code()).message(),
+        }
+    }
+
+    /// Whether the call can be retried later, e.g. because a password server was
+    /// unreachable during the preliminary check of `pam_chauthtok`
+    pub fn is_retryable(&self) -> bool {
+        *self == PamError::Pam(PamReturnCode::Try_Again)
+    }
+
+    /// Whether the password of the user expired and has to be changed, e.g. via
+    /// `pam_chauthtok` with `PAM_CHANGE_EXPIRED_AUTHTOK`
+    pub fn is_password_expired(&self) -> bool {
+        matches!(
+            self,
+            PamError::Pam(PamReturnCode::New_Authtok_Reqd) | PamError::PasswordExpired
+        )
+    }
+
+    /// Whether the transaction can't continue, e.g. because the maximum number of
+    /// authentication attempts was reached, so a new one has to be started
+    pub fn ends_transaction(&self) -> bool {
+        matches!(
+            self,
+            PamError::Pam(PamReturnCode::MaxTries)
+                | PamError::Pam(PamReturnCode::Abort)
+                | PamError::Timeout
+        )
+    }
+
+    /// Whether a PAM call did not return in time, see `Client::set_call_timeout`
+    pub fn is_timeout(&self) -> bool {
+        *self == PamError::Timeout
+    }
+
+    /// Whether authentication failed because of the given credentials or a temporary
+    /// problem, so another attempt in the same transaction may succeed
+    pub fn is_failed_attempt(&self) -> bool {
+        matches!(
+            self,
+            PamError::Pam(PamReturnCode::Auth_Err)
+                | PamError::Pam(PamReturnCode::Cred_Insufficient)
+                | PamError::Pam(PamReturnCode::Authinfo_Unavail)
+        )
+    }
 }
 
 impl From<PamReturnCode> for PamError {
     fn from(err: PamReturnCode) -> PamError {
-        PamError(err)
+        PamError::Pam(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_message() {
+        let err = PamError::Pam(PamReturnCode::Auth_Err);
+        let message = err.to_string();
+        assert!(message.starts_with("Auth_Err (7): "), "{}", message);
+        assert!(message.len() > "Auth_Err (7): ".len());
+    }
+
+    #[test]
+    fn transaction_errors() {
+        assert!(PamError::Pam(PamReturnCode::MaxTries).ends_transaction());
+        assert!(PamError::Pam(PamReturnCode::Abort).ends_transaction());
+        assert!(PamError::Timeout.ends_transaction());
+        assert!(PamError::Timeout.is_timeout());
+        assert!(!PamError::Pam(PamReturnCode::Abort).is_timeout());
+        assert_eq!(PamError::Timeout.code(), PamReturnCode::Abort);
+        assert_eq!(PamError::Timeout.to_string(), "PAM call timed out");
+        assert!(!PamError::Cancelled.ends_transaction());
+        assert_eq!(PamError::Cancelled.code(), PamReturnCode::Conv_Err);
+        assert_eq!(
+            PamError::Conversation(ConvError).code(),
+            PamReturnCode::Conv_Err
+        );
+        assert!(!PamError::Conversation(ConvError).is_failed_attempt());
+        assert!(!PamError::Pam(PamReturnCode::Auth_Err).ends_transaction());
+        assert!(PamError::Pam(PamReturnCode::Auth_Err).is_failed_attempt());
+        assert!(!PamError::Pam(PamReturnCode::MaxTries).is_failed_attempt());
+        assert!(!PamError::Pam(PamReturnCode::Conv_Err).is_failed_attempt());
+        assert!(!PamError::Pam(PamReturnCode::User_Unknown).is_failed_attempt());
+        assert!(PamError::Pam(PamReturnCode::Try_Again).is_retryable());
+        assert!(PamError::Pam(PamReturnCode::New_Authtok_Reqd).is_password_expired());
+        assert!(PamError::PasswordExpired.is_password_expired());
+        assert_eq!(
+            PamError::PasswordExpired.code(),
+            PamReturnCode::New_Authtok_Reqd
+        );
     }
 }
@@ -0,0 +1,175 @@
+//! PAM module for the integration tests of the `pam` crate
+//!
+//! Without arguments it behaves like the example module: it asks for the user if
+//! the application did not pass one and accepts the hard-coded password `hunter2`.
+//! The tests change its behaviour with these arguments:
+//!
+//! - `deny`: reject authentication without prompting, like `pam_deny.so`
+//! - `sleep_ms=<millis>`: block for `millis` milliseconds before authenticating
+//! - `end_status=<path>`: store module data whose cleanup writes the status passed
+//!   to `pam_end` to `path`
+//! - `map_user=<name>`: change `PAM_USER` to `name` after authenticating
+//! - `putenv=<name>=<value>`: set a PAM environment variable after authenticating,
+//!   may be passed several times
+//! - `greet`: send a `TEXT_INFO` message after authenticating unless silent
+//! - `binary`: send a `BINARY_PROMPT` message after authenticating
+//! - `expired`: report an expired password in the account stack
+//! - `setcred_log=<path>`: append the action of every `pam_setcred` call to `path`
+//! - `cred_unavail`: fail `pam_setcred` with `PAM_CRED_UNAVAIL`
+//! - `closed=<path>`: create `path` when the session is closed
+//! - `panic`: panic when the session is closed
+//!
+//! Never use this module on a real system!
+
+use std::ffi::CStr;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use pam::module::{ExposeSecret, ModuleResult, ModuleSuccess, StandardOptions};
+use pam::{
+    converse, export_pam_module, get_user, putenv, set_data_with_cleanup, set_item_str, AuthFlags,
+    Message, PamError, PamHandle, PamItemType, PamMessageStyle, PamModule, PamReturnCode,
+    SessionFlags, SetCredFlags,
+};
+
+/// The only password accepted by this module
+pub const PASSWORD: &str = "hunter2";
+
+// The arguments listed in the crate documentation, unknown ones are ignored
+#[derive(Default)]
+struct Args<'a> {
+    deny: bool,
+    sleep_ms: Option<u64>,
+    end_status: Option<&'a str>,
+    map_user: Option<&'a str>,
+    putenv: Vec<&'a str>,
+    greet: bool,
+    binary: bool,
+    expired: bool,
+    setcred_log: Option<&'a str>,
+    cred_unavail: bool,
+    closed: Option<&'a str>,
+    panic: bool,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &[&'a CStr]) -> Args<'a> {
+        let mut parsed = Args::default();
+        for arg in args.iter().filter_map(|arg| arg.to_str().ok()) {
+            match arg.split_once('=') {
+                Some(("sleep_ms", millis)) => parsed.sleep_ms = millis.parse().ok(),
+                Some(("end_status", path)) => parsed.end_status = Some(path),
+                Some(("map_user", user)) => parsed.map_user = Some(user),
+                Some(("putenv", variable)) => parsed.putenv.push(variable),
+                Some(("setcred_log", path)) => parsed.setcred_log = Some(path),
+                Some(("closed", path)) => parsed.closed = Some(path),
+                Some(_) => {}
+                None => match arg {
+                    "deny" => parsed.deny = true,
+                    "greet" => parsed.greet = true,
+                    "binary" => parsed.binary = true,
+                    "expired" => parsed.expired = true,
+                    "cred_unavail" => parsed.cred_unavail = true,
+                    "panic" => parsed.panic = true,
+                    _ => {}
+                },
+            }
+        }
+        parsed
+    }
+}
+
+pub struct TestModule;
+
+impl PamModule for TestModule {
+    fn account_management(
+        _handle: &mut PamHandle,
+        args: Vec<&CStr>,
+        _flags: AuthFlags,
+    ) -> ModuleResult {
+        if Args::parse(&args).expired {
+            return Err(PamError::Pam(PamReturnCode::New_Authtok_Reqd));
+        }
+        Ok(ModuleSuccess::Success)
+    }
+
+    fn authenticate(handle: &mut PamHandle, args: Vec<&CStr>, flags: AuthFlags) -> ModuleResult {
+        let opts = StandardOptions::parse(&args);
+        let args = Args::parse(&args);
+
+        if let Some(path) = args.end_status {
+            let path = path.to_string();
+            set_data_with_cleanup(handle, "end_status", path, |path, reason| {
+                let _ = fs::write(path, format!("{:?}", reason.status));
+            })?;
+        }
+        if let Some(millis) = args.sleep_ms {
+            thread::sleep(Duration::from_millis(millis));
+        }
+        if args.deny {
+            return Err(PamError::Pam(PamReturnCode::Auth_Err));
+        }
+
+        // Prompts via the conversation function if `PAM_USER` is not set yet
+        get_user(handle, None)?;
+        let token = opts.obtain_authtok(handle, None)?;
+        if token.expose_secret() != PASSWORD {
+            return Err(PamError::Pam(PamReturnCode::Auth_Err));
+        }
+
+        if let Some(user) = args.map_user {
+            set_item_str(handle, PamItemType::User, user)?;
+        }
+        for variable in args.putenv {
+            putenv(handle, variable)?;
+        }
+        if args.greet && !flags.silent() {
+            converse(
+                handle,
+                &[Message::new(PamMessageStyle::Text_Info, "Welcome!")],
+            )?;
+        }
+        if args.binary {
+            // Rejected by `converse` with `Conv_Err` before the application sees it
+            converse(handle, &[Message::new(PamMessageStyle::Binary_Prompt, "x")])?;
+        }
+        Ok(ModuleSuccess::Success)
+    }
+
+    fn close_session(
+        _handle: &mut PamHandle,
+        args: Vec<&CStr>,
+        _flags: SessionFlags,
+    ) -> ModuleResult {
+        let args = Args::parse(&args);
+        if args.panic {
+            // Caught by the generated entry point and reported as `Service_Err`
+            panic!("Asked to panic");
+        }
+        if let Some(path) = args.closed {
+            let _ = fs::write(path, "closed");
+        }
+        Ok(ModuleSuccess::Ignore)
+    }
+
+    fn set_credentials(
+        _handle: &mut PamHandle,
+        args: Vec<&CStr>,
+        flags: SetCredFlags,
+    ) -> ModuleResult {
+        let args = Args::parse(&args);
+        if let Some(path) = args.setcred_log {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                let _ = writeln!(file, "{:?}", flags.action());
+            }
+        }
+        if args.cred_unavail {
+            return Err(PamError::Pam(PamReturnCode::Cred_Unavail));
+        }
+        Ok(ModuleSuccess::Success)
+    }
+}
+
+export_pam_module!(TestModule);
@@ -0,0 +1,1782 @@
+//! Run the test module through `Client` via libpam and pam_wrapper
+//!
+//! These tests are ignored by default as they need pam_wrapper, run them with
+//! `LD_PRELOAD=libpam_wrapper.so cargo test -- --ignored --test-threads=1`.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, OsStr, OsString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
+
+use futures::executor::block_on;
+use pam::session::{self, spawn_command, BecomeUserError, SpawnError};
+use pam::{
+    AccountError, AccountStatus, AsyncBridge, AsyncConversation, AuthFlags, CancelToken,
+    ChannelConv, Client, ClientBuilder, ConvError, ConvFuture, Conversation, CredAction,
+    CredentialSequence, DropStage, DynClient, EnvironmentPolicy, NullConv, PamError, PamHandle,
+    PamItemType, PamMessageStyle, PamOperation, PamReturnCode, PasswordConv, Recorder,
+    RetryDecision, SequenceConv, SessionInfo, SetCredFailurePolicy, XdgSession,
+};
+use pam_test_module::PASSWORD;
+
+const SERVICE_NAME: &str = "pam_rs_client_test";
+
+// Service directory for pam_wrapper, removed on drop
+struct Service(PathBuf);
+
+impl Service {
+    fn create() -> Service {
+        Service::with_args("")
+    }
+
+    // Runs the test module with `args` for authentication, pam_permit for the rest
+    fn with_args(args: &str) -> Service {
+        Service::with_stack(&format!(
+            "auth required {} {}\nauth optional pam_permit.so\naccount required pam_permit.so\n\
+             session required pam_permit.so\n",
+            module_path().display(),
+            args
+        ))
+    }
+
+    fn with_stack(content: &str) -> Service {
+        let dir = env::temp_dir().join(format!("pam-rs-client-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(SERVICE_NAME), content).unwrap();
+
+        assert!(
+            env::var("LD_PRELOAD")
+                .unwrap_or_default()
+                .contains("pam_wrapper"),
+            "This test requires LD_PRELOAD=libpam_wrapper.so"
+        );
+        env::set_var("PAM_WRAPPER", "1");
+        env::set_var("PAM_WRAPPER_SERVICE_DIR", &dir);
+        Service(dir)
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        env::remove_var("PAM_WRAPPER_SERVICE_DIR");
+        env::remove_var("PAM_WRAPPER");
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Name of the user running the tests, which exists unlike e.g. alice
+fn current_user() -> String {
+    let passwd = unsafe { libc::getpwuid(libc::getuid()) };
+    assert!(!passwd.is_null(), "Failed to look up the current user");
+    let name = unsafe { CStr::from_ptr((*passwd).pw_name) };
+    name.to_str().unwrap().to_string()
+}
+
+// Answers with the current user and the password of the test module
+fn password_conv() -> SequenceConv {
+    SequenceConv::new(current_user(), vec![PASSWORD])
+}
+
+// Path of the test module, Cargo places the cdylib in the `deps` directory next to
+// this test
+fn module_path() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.set_file_name("libpam_test_module.so");
+    path
+}
+
+// Answers with the username and password and logs its calls and drop
+struct Tracked {
+    name: &'static str,
+    log: Rc<RefCell<Vec<String>>>,
+}
+
+impl Conversation for Tracked {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        self.log.borrow_mut().push(format!("{}: echo", self.name));
+        Ok("alice".to_string())
+    }
+    fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        // Not `Send`, so it must not be called on the helper thread of a call timeout
+        assert_ne!(thread::current().name(), Some("pam-call"));
+        self.log.borrow_mut().push(format!("{}: blind", self.name));
+        Ok(PASSWORD.to_string())
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.log.borrow_mut().push(format!("{}: drop", self.name));
+    }
+}
+
+#[test]
+#[ignore]
+fn replace_conversation() {
+    let _service = Service::create();
+
+    // The non-interactive handler can't answer the password prompt
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, NullConv::new().with_username("alice"))
+            .expect("Failed to start transaction");
+    client.set_call_timeout(Duration::from_secs(10));
+    assert!(client.authenticate().is_err());
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let first = Tracked {
+        name: "first",
+        log: Rc::clone(&log),
+    };
+    let mut client = client
+        .replace_conversation(first)
+        .expect("Failed to replace conversation");
+    client.authenticate().expect("Authentication failed");
+
+    let second = Tracked {
+        name: "second",
+        log: Rc::clone(&log),
+    };
+    let mut client = client
+        .replace_conversation(second)
+        .expect("Failed to replace conversation");
+    // The first handler was dropped only after libpam switched to the second one,
+    // which is now used for the rest of the transaction
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(
+        *log.borrow(),
+        vec!["first: blind", "first: drop", "second: blind"]
+    );
+
+    drop(client);
+    assert_eq!(log.borrow().last().unwrap(), "second: drop");
+}
+
+#[test]
+#[ignore]
+fn cancel_conversation() {
+    let _service = Service::create();
+
+    let token = CancelToken::new();
+    let (sender, requests) = mpsc::channel();
+    let conv = ChannelConv::new(sender).with_cancel_token(token.clone());
+    let worker = thread::spawn(move || {
+        let mut client = Client::with_conversation(SERVICE_NAME, conv)?;
+        client.authenticate()
+    });
+
+    // Answer the login prompt and cancel the password prompt
+    let mut pending = Vec::new();
+    for request in requests {
+        match request.style {
+            PamMessageStyle::Prompt_Echo_On => request.reply.send(Ok("alice".to_string())).unwrap(),
+            _ => {
+                token.cancel();
+                pending.push(request.reply);
+            }
+        }
+    }
+    assert_eq!(pending.len(), 1);
+    assert_eq!(worker.join().unwrap().unwrap_err(), PamError::Cancelled);
+}
+
+// Answers like `password_conv` and reports the state of `token` as cancellation
+struct CancelledLater {
+    inner: SequenceConv,
+    token: CancelToken,
+}
+
+impl Conversation for CancelledLater {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.inner.prompt_echo(msg)
+    }
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<String, ConvError> {
+        self.inner.prompt_blind(msg)
+    }
+    fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+#[test]
+#[ignore]
+fn cancel_after_success() {
+    let _service = Service::create();
+
+    let token = CancelToken::new();
+    let conv = CancelledLater {
+        inner: password_conv(),
+        token: token.clone(),
+    };
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .environment_policy(EnvironmentPolicy::None)
+        .build(conv)
+        .expect("Failed to start transaction");
+    let closed = Arc::new(Mutex::new(Vec::new()));
+    let log = closed.clone();
+    client.on_session_closed(move |code| log.lock().unwrap().push(code));
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+
+    // A cancellation doesn't turn calls which succeeded into errors
+    token.cancel();
+    client.close_session().expect("Failed to close session");
+    assert_eq!(*closed.lock().unwrap(), vec![PamReturnCode::Success]);
+}
+
+// Panics like a handler using a poisoned mutex
+struct Panicking;
+
+impl Conversation for Panicking {
+    fn prompt_echo(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        Ok("alice".to_string())
+    }
+    fn prompt_blind(&mut self, _msg: &CStr) -> Result<String, ConvError> {
+        panic!("PoisonError");
+    }
+}
+
+#[test]
+#[ignore]
+fn panicking_conversation() {
+    let _service = Service::create();
+
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, Panicking).expect("Failed to start transaction");
+    // The panic did not unwind through libpam but is continued after it returned
+    let payload = panic::catch_unwind(AssertUnwindSafe(|| client.authenticate())).unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"PoisonError"));
+}
+
+// Answers like a task of an async login daemon
+struct AsyncAnswers;
+
+impl AsyncConversation for AsyncAnswers {
+    fn prompt_echo(&mut self, _msg: String) -> ConvFuture<'_, Result<String, ConvError>> {
+        Box::pin(async { Ok("alice".to_string()) })
+    }
+    fn prompt_blind(&mut self, _msg: String) -> ConvFuture<'_, Result<String, ConvError>> {
+        Box::pin(async { Ok(PASSWORD.to_string()) })
+    }
+}
+
+#[test]
+#[ignore]
+fn async_conversation() {
+    let _service = Service::create();
+
+    let (result, _) = block_on(AsyncBridge::run(AsyncAnswers, |bridge| {
+        let mut client = Client::with_conversation(SERVICE_NAME, bridge)?;
+        client.authenticate()
+    }));
+    result.expect("Authentication failed");
+}
+
+#[test]
+#[ignore]
+fn close_session() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    // There is no session to close yet
+    assert_eq!(
+        client.close_session().unwrap_err().code(),
+        PamReturnCode::Session_Err
+    );
+
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    client.close_session().expect("Failed to close session");
+    // The session is not closed twice, neither explicitly nor on drop
+    assert_eq!(
+        client.close_session().unwrap_err().code(),
+        PamReturnCode::Session_Err
+    );
+}
+
+#[test]
+#[ignore]
+fn user_passed_to_pam_start() {
+    let user = current_user();
+    let _service = Service::with_stack(&format!(
+        "auth required pam_succeed_if.so user = {}\naccount required pam_permit.so\n",
+        user
+    ));
+
+    // The module finds `PAM_USER` set and does not prompt for the login
+    let mut client = Client::with_conversation_for_user(SERVICE_NAME, &user, NullConv::new())
+        .expect("Failed to start transaction");
+    assert_eq!(client.get_user().unwrap(), user);
+    client.authenticate().expect("Authentication failed");
+
+    let mut client =
+        Client::with_password_for_user(SERVICE_NAME, &user).expect("Failed to start transaction");
+    client.conversation_mut().set_credentials(&user, "unused");
+    client.authenticate().expect("Authentication failed");
+
+    // Without a user the login prompt can't be answered
+    let mut client = Client::with_conversation(SERVICE_NAME, NullConv::new())
+        .expect("Failed to start transaction");
+    assert_eq!(
+        client.authenticate().unwrap_err(),
+        PamError::Conversation(ConvError)
+    );
+}
+
+#[test]
+#[ignore]
+fn pam_environment() {
+    let dir = env::temp_dir().join(format!("pam-rs-client-{}", process::id()));
+    let conf = dir.join("pam_env.conf");
+    let _service = Service::with_stack(&format!(
+        "auth required {module}\nauth optional pam_permit.so\naccount required pam_permit.so\n\
+         session required pam_env.so readenv=0 conffile={conf}\n",
+        module = module_path().display(),
+        conf = conf.display()
+    ));
+    fs::write(&conf, "PAM_RS_TEST DEFAULT=exported\n").unwrap();
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    let environment = client.environment().expect("Failed to get environment");
+    assert!(!environment.contains_key(OsStr::new("PAM_RS_TEST")));
+
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    let environment = client.environment().expect("Failed to get environment");
+    assert_eq!(
+        environment.get(OsStr::new("PAM_RS_TEST")),
+        Some(&OsString::from("exported"))
+    );
+}
+
+#[test]
+#[ignore]
+fn remote_items() {
+    let _service = Service::with_stack(
+        "auth required pam_succeed_if.so rhost = client.example.org\n\
+         account required pam_permit.so\n",
+    );
+
+    let mut client =
+        Client::with_conversation_for_user(SERVICE_NAME, &current_user(), NullConv::new())
+            .expect("Failed to start transaction");
+    assert_eq!(client.rhost().unwrap(), None);
+    client.set_rhost("client.example.org").unwrap();
+    client.set_tty("ssh").unwrap();
+    client.set_ruser("remote").unwrap();
+    client.set_user_prompt("Login: ").unwrap();
+    assert_eq!(client.rhost().unwrap(), Some("client.example.org"));
+    assert_eq!(client.tty().unwrap(), Some("ssh"));
+    assert_eq!(client.ruser().unwrap(), Some("remote"));
+    assert_eq!(client.user_prompt().unwrap(), Some("Login: "));
+    client.authenticate().expect("Authentication failed");
+
+    // An empty string unsets the item again
+    client.set_rhost("").unwrap();
+    client.set_user_prompt("").unwrap();
+    assert_eq!(client.rhost().unwrap(), None);
+    assert_eq!(client.user_prompt().unwrap(), None);
+    assert!(client.authenticate().is_err());
+}
+
+#[test]
+#[ignore]
+fn session_info() {
+    let _service = Service::with_stack(
+        "auth required pam_succeed_if.so rhost = client.example.org\n\
+         account required pam_permit.so\n",
+    );
+
+    let info = SessionInfo {
+        rhost: Some("client.example.org".to_string()),
+        tty: Some("ssh".to_string()),
+        ..SessionInfo::default()
+    };
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .user(&current_user())
+        .rhost("other.example.org")
+        .session_info(info)
+        .build(NullConv::new())
+        .expect("Failed to start transaction");
+    assert_eq!(client.rhost().unwrap(), Some("client.example.org"));
+    assert_eq!(client.tty().unwrap(), Some("ssh"));
+    assert_eq!(client.xdisplay().unwrap(), None);
+    client.authenticate().expect("Authentication failed");
+
+    // Unpopulated fields leave the items alone
+    client
+        .apply(&SessionInfo {
+            xdisplay: Some(":0".to_string()),
+            ..SessionInfo::default()
+        })
+        .unwrap();
+    assert_eq!(client.xdisplay().unwrap(), Some(":0"));
+    assert_eq!(client.rhost().unwrap(), Some("client.example.org"));
+}
+
+#[test]
+#[ignore]
+fn set_user() {
+    let user = current_user();
+    let _service = Service::with_stack(&format!(
+        "auth required {}\nauth required pam_succeed_if.so user = {}\n\
+         account required pam_permit.so\nsession required pam_permit.so\n",
+        module_path().display(),
+        user
+    ));
+
+    let conv = SequenceConv::new("nobody", vec![PASSWORD, PASSWORD, PASSWORD]);
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .user("nobody")
+        .environment_policy(EnvironmentPolicy::None)
+        .build(conv)
+        .expect("Failed to start transaction");
+    assert!(client.authenticate().is_err());
+
+    // The second attempt in the same transaction checks the new user
+    client.switch_user(&user).unwrap();
+    assert_eq!(client.get_user().unwrap(), user);
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(client.authenticated_user().unwrap(), user);
+
+    client.set_user("nobody").unwrap();
+    assert!(!client.status().is_authenticated);
+    assert_eq!(
+        client.open_session().unwrap_err().code(),
+        PamReturnCode::Perm_Denied
+    );
+
+    // The user can't change under an open session
+    client.set_user(&user).unwrap();
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    assert_eq!(
+        client.set_user("nobody").unwrap_err().code(),
+        PamReturnCode::Perm_Denied
+    );
+}
+
+#[test]
+#[ignore]
+fn xdg_session() {
+    let _service = Service::create();
+
+    let session = XdgSession {
+        session_type: Some("wayland".to_string()),
+        class: Some("user".to_string()),
+        desktop: Some("sway".to_string()),
+        seat: Some("seat0".to_string()),
+        vtnr: Some(2),
+    };
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .xdg_session(session)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    let getenv = |client: &mut Client<SequenceConv>, name| client.pam_getenv(name).unwrap();
+    assert_eq!(
+        getenv(&mut client, "XDG_SESSION_TYPE").as_deref(),
+        Some("wayland")
+    );
+    assert_eq!(
+        getenv(&mut client, "XDG_SESSION_CLASS").as_deref(),
+        Some("user")
+    );
+    assert_eq!(
+        getenv(&mut client, "XDG_SESSION_DESKTOP").as_deref(),
+        Some("sway")
+    );
+    assert_eq!(getenv(&mut client, "XDG_SEAT").as_deref(), Some("seat0"));
+    assert_eq!(getenv(&mut client, "XDG_VTNR").as_deref(), Some("2"));
+
+    // Single variables can be changed, the others stay as they are
+    client
+        .set_xdg_session(&XdgSession {
+            vtnr: Some(3),
+            ..XdgSession::default()
+        })
+        .unwrap();
+    assert_eq!(getenv(&mut client, "XDG_VTNR").as_deref(), Some("3"));
+    assert_eq!(getenv(&mut client, "XDG_SEAT").as_deref(), Some("seat0"));
+
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    let environment = client.environment().unwrap();
+    assert_eq!(
+        environment.get(OsStr::new("XDG_SESSION_TYPE")),
+        Some(&OsString::from("wayland"))
+    );
+}
+
+#[test]
+#[ignore]
+fn environment_policy() {
+    let _service = Service::create();
+    let user = current_user();
+
+    let conv = SequenceConv::new(user.clone(), vec![PASSWORD]);
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.set_environment_policy(EnvironmentPolicy::PamOnly);
+    client.set_environment_hook(|user| vec![("PAM_RS_USER".to_string(), user.to_string())]);
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+
+    // Only the PAM environment contains the variables
+    let environment = client.environment().expect("Failed to get environment");
+    assert_eq!(
+        environment.get(OsStr::new("USER")),
+        Some(&OsString::from(&user))
+    );
+    assert_eq!(
+        environment.get(OsStr::new("PAM_RS_USER")),
+        Some(&OsString::from(&user))
+    );
+    assert!(env::var_os("PAM_RS_USER").is_none());
+
+    let conv = SequenceConv::new(user.clone(), vec![PASSWORD]);
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.set_environment_policy(EnvironmentPolicy::None);
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    let environment = client.environment().expect("Failed to get environment");
+    assert!(!environment.contains_key(OsStr::new("USER")));
+}
+
+#[test]
+#[ignore]
+fn client_builder() {
+    let user = current_user();
+    let _service = Service::with_stack(&format!(
+        "auth required pam_succeed_if.so user = {} rhost = client.example.org\n\
+         account required pam_permit.so\n",
+        user
+    ));
+
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .user(&user)
+        .rhost("client.example.org")
+        .tty("ssh")
+        .close_on_drop(false)
+        .build(NullConv::new())
+        .expect("Failed to start transaction");
+    assert_eq!(client.get_user().unwrap(), user);
+    assert_eq!(client.tty().unwrap(), Some("ssh"));
+    assert!(!client.close_on_drop);
+    client.authenticate().expect("Authentication failed");
+
+    let err = ClientBuilder::new("").build(NullConv::new()).err();
+    assert_eq!(err.map(|err| err.code()), Some(PamReturnCode::Service_Err));
+}
+
+#[test]
+#[ignore]
+fn silent_client() {
+    let _service = Service::with_args("greet");
+    let user = current_user();
+    let infos = |client: &Client<Recorder<SequenceConv>>| {
+        client
+            .conversation()
+            .transcript()
+            .iter()
+            .filter(|(style, _, _)| *style == PamMessageStyle::Text_Info)
+            .count()
+    };
+
+    let conv = Recorder::new(SequenceConv::new(user.clone(), vec![PASSWORD]));
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(infos(&client), 1);
+
+    // The prompts are still sent, but the module keeps quiet otherwise
+    let conv = Recorder::new(SequenceConv::new(user, vec![PASSWORD]));
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .silent(true)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(infos(&client), 0);
+    assert!(!client.conversation().transcript().is_empty());
+}
+
+#[test]
+#[ignore]
+fn binary_prompt_from_module() {
+    let _service = Service::with_args("binary");
+
+    let conv = Recorder::new(password_conv());
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    let err = client.authenticate().unwrap_err();
+    assert_eq!(err.code(), PamReturnCode::Conv_Err);
+    assert!(client
+        .conversation()
+        .transcript()
+        .iter()
+        .all(|(style, _, _)| *style != PamMessageStyle::Binary_Prompt));
+}
+
+#[test]
+#[ignore]
+fn separate_account_management() {
+    let _service = Service::with_stack(&format!(
+        "auth required {}\naccount required pam_deny.so\nsession required pam_permit.so\n",
+        module_path().display()
+    ));
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client
+        .authenticate_only(AuthFlags::default())
+        .expect("Authentication failed");
+    assert!(client.is_authenticated());
+    assert!(client.validate_account(AuthFlags::default()).is_err());
+    assert!(!client.has_valid_account());
+
+    // The account check is up to the caller when authenticating separately
+    client.open_session().expect("Failed to open session");
+}
+
+#[test]
+#[ignore]
+fn last_error() {
+    let _service = Service::with_stack(&format!(
+        "auth required {}\naccount required pam_deny.so\n",
+        module_path().display()
+    ));
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    assert!(client.last_error().is_none());
+    let err = client.authenticate().unwrap_err();
+
+    let last = client.last_error().expect("No error recorded");
+    assert_eq!(last.operation, PamOperation::AcctMgmt);
+    assert_eq!(last.code, err.code());
+    assert!(!last.message.is_empty());
+    assert!(err.to_string().ends_with(&last.message));
+}
+
+#[test]
+#[ignore]
+fn session_guard() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    let mut session = client.session().expect("Failed to open session");
+    assert!(session.environment().is_ok());
+    session.close().expect("Failed to close session");
+
+    // Dropping the guard closes the session as well
+    client.session().expect("Failed to open session");
+    assert_eq!(
+        client.close_session().unwrap_err().code(),
+        PamReturnCode::Session_Err
+    );
+}
+
+#[test]
+#[ignore]
+fn retry_authentication() {
+    let _service = Service::create();
+    let user = current_user();
+
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    for _ in 0..2 {
+        client.conversation_mut().set_credentials(&user, "wrong");
+        let err = client.authenticate().unwrap_err();
+        assert_eq!(err.code(), PamReturnCode::Auth_Err);
+        assert!(!err.ends_transaction());
+        assert!(!client.is_authenticated());
+    }
+
+    client.conversation_mut().set_credentials(&user, PASSWORD);
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+}
+
+#[test]
+#[ignore]
+fn expired_password() {
+    let _service = Service::with_stack(&format!(
+        "auth required {module}\naccount required {module} expired\n\
+         password required pam_permit.so\n",
+        module = module_path().display()
+    ));
+    let user = current_user();
+
+    let conv = SequenceConv::new(user.clone(), vec![PASSWORD]);
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    let err = client.authenticate().unwrap_err();
+    assert_eq!(err, PamError::PasswordExpired);
+    assert!(err.is_password_expired());
+    assert!(!client.is_authenticated());
+
+    // pam_permit accepts the change without prompting
+    let conv = SequenceConv::new(user, vec![PASSWORD]);
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .handle_expired_password(true)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert!(client.has_valid_account());
+}
+
+#[test]
+#[ignore]
+fn fail_delay() {
+    let _service = Service::create();
+    let user = current_user();
+    let delay = Duration::from_secs(1);
+
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.set_fail_delay(delay);
+    // The delay applies to every attempt, libpam randomizes it by up to 25 %
+    for _ in 0..2 {
+        client.conversation_mut().set_credentials(&user, "wrong");
+        let start = Instant::now();
+        assert!(client.authenticate().is_err());
+        assert!(start.elapsed() >= delay * 7 / 10);
+    }
+
+    client
+        .disable_fail_delay()
+        .expect("Failed to disable delay");
+    let start = Instant::now();
+    assert!(client.authenticate().is_err());
+    assert!(start.elapsed() < delay / 2);
+}
+
+#[test]
+#[ignore]
+fn client_on_worker_thread() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let _service = Service::create();
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    assert_send(&client);
+
+    client.authenticate().expect("Authentication failed");
+    let worker = thread::spawn(move || {
+        client.open_session().expect("Failed to open session");
+        client.close_session().expect("Failed to close session");
+    });
+    worker.join().unwrap();
+}
+
+#[test]
+#[ignore]
+fn end_status() {
+    let dir = env::temp_dir().join(format!("pam-rs-client-{}", process::id()));
+    let status = dir.join("end_status");
+    let _service = Service::with_args(&format!("end_status={}", status.display()));
+    let user = current_user();
+
+    // The result of the failed authentication is passed on
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.conversation_mut().set_credentials(&user, "wrong");
+    assert!(client.authenticate().is_err());
+    drop(client);
+    assert_eq!(fs::read_to_string(&status).unwrap(), "Auth_Err");
+
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.conversation_mut().set_credentials(&user, PASSWORD);
+    client.authenticate().expect("Authentication failed");
+    drop(client);
+    assert_eq!(fs::read_to_string(&status).unwrap(), "Success");
+
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.conversation_mut().set_credentials(&user, PASSWORD);
+    client.authenticate().expect("Authentication failed");
+    client.end_with(PamReturnCode::Abort);
+    assert_eq!(fs::read_to_string(&status).unwrap(), "Abort");
+}
+
+#[test]
+#[ignore]
+fn raw_handle() {
+    let _service = Service::create();
+
+    let mut client = Client::with_conversation(SERVICE_NAME, NullConv::new())
+        .expect("Failed to start transaction");
+    client.set_tty("ssh").unwrap();
+    assert_eq!(
+        pam::get_item_str(client.handle(), PamItemType::TTY).unwrap(),
+        Some("ssh")
+    );
+
+    pam::putenv(unsafe { client.handle_mut() }, "PAM_RS_RAW=1").unwrap();
+    let environment = client.environment().expect("Failed to get environment");
+    assert_eq!(
+        environment.get(OsStr::new("PAM_RS_RAW")),
+        Some(&OsString::from("1"))
+    );
+}
+
+#[test]
+#[ignore]
+fn credentials_without_session() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    assert_eq!(
+        client.establish_credentials().unwrap_err().code(),
+        PamReturnCode::Perm_Denied
+    );
+    client.authenticate().expect("Authentication failed");
+    client
+        .establish_credentials()
+        .expect("Failed to establish credentials");
+    client
+        .refresh_credentials()
+        .expect("Failed to refresh credentials");
+}
+
+#[test]
+#[ignore]
+fn credentials_unavailable() {
+    let _service = Service::with_args("cred_unavail");
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(
+        client.establish_credentials().unwrap_err().code(),
+        PamReturnCode::Cred_Unavail
+    );
+    assert_eq!(
+        client.last_error().unwrap().operation,
+        PamOperation::SetCred
+    );
+}
+
+#[test]
+#[ignore]
+fn spawn_in_session() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.set_environment_policy(EnvironmentPolicy::PamOnly);
+    client.authenticate().expect("Authentication failed");
+    let err = spawn_command(&mut client, Command::new("env")).unwrap_err();
+    assert_eq!(
+        err.get_ref().unwrap().downcast_ref::<SpawnError>(),
+        Some(&SpawnError::NoSession)
+    );
+
+    client.open_session().expect("Failed to open session");
+    let mut command = Command::new("env");
+    command.env("PAM_RS_OVERRIDE", "1").stdout(Stdio::piped());
+    match spawn_command(&mut client, command) {
+        Ok(child) => {
+            let output = child.wait_with_output().unwrap();
+            let output = String::from_utf8(output.stdout).unwrap();
+            assert!(output.lines().any(|line| line.starts_with("HOME=")));
+            assert!(output.lines().any(|line| line == "PAM_RS_OVERRIDE=1"));
+        }
+        Err(err) => {
+            assert_ne!(unsafe { libc::geteuid() }, 0);
+            assert_eq!(
+                err.get_ref().unwrap().downcast_ref::<SpawnError>(),
+                Some(&SpawnError::NotRoot)
+            );
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn autodetect_tty() {
+    let _service = Service::create();
+
+    let terminal = (0..3).find_map(|fd| {
+        let name = unsafe { libc::ttyname(fd) };
+        if name.is_null() {
+            None
+        } else {
+            let name = unsafe { CStr::from_ptr(name) };
+            Some(name.to_str().unwrap().to_string())
+        }
+    });
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .autodetect_tty(true)
+        .default_tty("ssh")
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    let expected = terminal.unwrap_or_else(|| "ssh".to_string());
+    assert_eq!(client.tty().unwrap(), Some(expected.as_str()));
+
+    // An explicitly set terminal is kept
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .tty("console")
+        .autodetect_tty(true)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(client.tty().unwrap(), Some("console"));
+}
+
+#[test]
+#[ignore]
+fn from_handle() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut owner =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    let handle: *mut PamHandle = unsafe { owner.handle_mut() };
+
+    // The conversation function of the owner answers the prompts
+    let mut client = unsafe { Client::from_handle(handle, false) };
+    assert!(!client.is_authenticated());
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(client.get_user().unwrap(), current_user());
+    drop(client);
+
+    // The transaction is still alive
+    assert_eq!(owner.get_user().unwrap(), current_user());
+    owner.set_authenticated(true);
+    owner.open_session().expect("Failed to open session");
+}
+
+#[test]
+#[ignore]
+fn transaction_status() {
+    let _service = Service::create();
+
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    let status = client.status();
+    assert!(!status.is_authenticated);
+    assert_eq!(status.authenticate, None);
+
+    client
+        .conversation_mut()
+        .set_credentials(current_user(), "wrong");
+    assert!(client.authenticate().is_err());
+    let status = client.status();
+    assert!(!status.is_authenticated);
+    assert_eq!(status.authenticate, Some(PamReturnCode::Auth_Err));
+    assert_eq!(status.acct_mgmt, None);
+
+    client
+        .conversation_mut()
+        .set_credentials(current_user(), PASSWORD);
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    let status = client.status();
+    assert!(status.is_authenticated && status.has_valid_account);
+    assert!(status.has_credentials && status.has_open_session);
+    assert_eq!(status.sessions_opened, 1);
+    assert_eq!(status.authenticate, Some(PamReturnCode::Success));
+    assert_eq!(status.open_session, Some(PamReturnCode::Success));
+
+    client.close_session().expect("Failed to close session");
+    client.open_session().expect("Failed to open session");
+    let status = client.status();
+    assert_eq!(status.close_session, Some(PamReturnCode::Success));
+    assert_eq!(status.sessions_opened, 2);
+}
+
+#[test]
+#[ignore]
+fn mapped_user() {
+    let user = current_user();
+    let _service = Service::with_args(&format!("map_user={}", user));
+
+    let conv = SequenceConv::new("pam-rs-alias", vec![PASSWORD]);
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    assert_eq!(
+        client.authenticated_user().unwrap_err().code(),
+        PamReturnCode::Perm_Denied
+    );
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(client.authenticated_user().unwrap(), user);
+
+    // The environment is set up for the mapped user
+    client.set_environment_policy(EnvironmentPolicy::PamOnly);
+    client.open_session().expect("Failed to open session");
+    let environment = client.environment().expect("Failed to get environment");
+    assert_eq!(
+        environment.get(OsStr::new("USER")),
+        Some(&OsString::from(user))
+    );
+}
+
+#[test]
+#[ignore]
+fn check_account() {
+    let service = Service::create();
+    let mut client = Client::with_conversation(SERVICE_NAME, NullConv::new())
+        .expect("Failed to start transaction");
+    assert_eq!(
+        client.check_account(&current_user()).unwrap(),
+        AccountStatus::Valid
+    );
+    assert!(!client.is_authenticated());
+    drop(client);
+    drop(service);
+
+    let _service = Service::with_stack(&format!(
+        "account required {} expired\n",
+        module_path().display()
+    ));
+    let mut client = Client::with_conversation(SERVICE_NAME, NullConv::new())
+        .expect("Failed to start transaction");
+    assert_eq!(
+        client.check_account(&current_user()).unwrap(),
+        AccountStatus::PasswordExpired
+    );
+}
+
+#[test]
+#[ignore]
+fn setcred_failure_policy() {
+    let _service = Service::with_args("cred_unavail");
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(
+        client.open_session().unwrap_err().code(),
+        PamReturnCode::Cred_Unavail
+    );
+    assert!(!client.is_authenticated());
+    assert_eq!(client.setcred_failure(), None);
+
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .setcred_failure_policy(SetCredFailurePolicy::WarnAndContinue)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    assert!(client.has_open_session());
+    assert_eq!(client.setcred_failure(), Some(PamReturnCode::Cred_Unavail));
+    assert!(!client.status().has_credentials);
+}
+
+#[test]
+#[ignore]
+fn credential_sequence() {
+    let _service = Service::with_args("cred_unavail");
+
+    // The failing pam_setcred is never called
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .credential_sequence(CredentialSequence::None)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    client.close_session().expect("Failed to close session");
+    assert_eq!(client.status().setcred, None);
+
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .credential_sequence(CredentialSequence::EstablishOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(
+        client.open_session().unwrap_err().code(),
+        PamReturnCode::Cred_Unavail
+    );
+}
+
+#[test]
+#[ignore]
+fn credential_sequence_deletes_as_established() {
+    let dir = env::temp_dir().join(format!("pam-rs-client-{}", process::id()));
+    let log = dir.join("setcred_log");
+    let _service = Service::with_args(&format!("setcred_log={}", log.display()));
+    let run = |sequence: CredentialSequence| {
+        let _ = fs::remove_file(&log);
+        let conv = password_conv();
+        let mut client = ClientBuilder::new(SERVICE_NAME)
+            .credential_sequence(sequence)
+            .environment_policy(EnvironmentPolicy::None)
+            .build(conv)
+            .expect("Failed to start transaction");
+        client.authenticate().expect("Authentication failed");
+        client.open_session().expect("Failed to open session");
+        drop(client);
+        fs::read_to_string(&log).unwrap()
+    };
+
+    assert_eq!(
+        run(CredentialSequence::AfterSession),
+        "Some(Establish)\nSome(Delete)\n"
+    );
+    assert_eq!(
+        run(CredentialSequence::Custom {
+            before: vec![CredAction::Establish],
+            after: vec![CredAction::Establish],
+        }),
+        "Some(Establish)\nSome(Establish)\nSome(Delete)\nSome(Delete)\n"
+    );
+    // Reinitializing alone establishes the credentials once
+    assert_eq!(
+        run(CredentialSequence::Custom {
+            before: vec![CredAction::Reinitialize],
+            after: vec![]
+        }),
+        "Some(Reinitialize)\nSome(Delete)\n"
+    );
+}
+
+#[test]
+#[ignore]
+fn credential_sequence_closes_session_on_failure() {
+    let dir = env::temp_dir().join(format!("pam-rs-client-{}", process::id()));
+    let closed = dir.join("closed");
+    let _service = Service::with_stack(&format!(
+        "auth required {0} cred_unavail\naccount required pam_permit.so\n\
+         session required {0} closed={1}\n",
+        module_path().display(),
+        closed.display()
+    ));
+
+    // Establishing the credentials fails after the session was opened
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .credential_sequence(CredentialSequence::AfterSession)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    assert_eq!(
+        client.open_session().unwrap_err().code(),
+        PamReturnCode::Cred_Unavail
+    );
+    assert!(!client.has_open_session());
+    assert!(closed.exists());
+}
+
+// Set in the helper process of `become_user`, which switches to nobody for good
+const BECOME_USER_HELPER: &str = "PAM_RS_BECOME_USER_HELPER";
+
+#[test]
+#[ignore]
+fn become_user() {
+    let service = Service::with_args("map_user=nobody");
+
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    if env::var_os(BECOME_USER_HELPER).is_some() {
+        client.open_session().expect("Failed to open session");
+        // The service directory can't be removed anymore after switching
+        drop(service);
+        session::become_user(&mut client).expect("Failed to become the user");
+        check_nobody();
+        return;
+    }
+
+    assert!(matches!(
+        session::become_user(&mut client),
+        Err(BecomeUserError::NoSession)
+    ));
+    client.open_session().expect("Failed to open session");
+    if unsafe { libc::geteuid() } != 0 {
+        assert!(matches!(
+            session::become_user(&mut client),
+            Err(BecomeUserError::NotRoot)
+        ));
+        return;
+    }
+
+    // Switching can't be undone, so run this test again in a helper process
+    let status = Command::new(env::current_exe().unwrap())
+        .args(&["become_user", "--exact", "--ignored", "--test-threads=1"])
+        .env(BECOME_USER_HELPER, "1")
+        .status()
+        .expect("Failed to run the helper process");
+    assert!(status.success(), "Helper process failed: {}", status);
+}
+
+// Asserts that this process runs as nobody with exactly its groups and no way back
+fn check_nobody() {
+    let name = b"nobody\0".as_ptr() as *const libc::c_char;
+    let nobody = unsafe { libc::getpwnam(name) };
+    assert!(!nobody.is_null(), "This test requires the user nobody");
+    let (uid, gid) = unsafe { ((*nobody).pw_uid, (*nobody).pw_gid) };
+    assert_eq!(unsafe { (libc::getuid(), libc::geteuid()) }, (uid, uid));
+    assert_eq!(unsafe { (libc::getgid(), libc::getegid()) }, (gid, gid));
+
+    let mut expected = vec![0; 64];
+    let mut count = expected.len() as libc::c_int;
+    while unsafe { libc::getgrouplist(name, gid, expected.as_mut_ptr(), &mut count) } < 0 {
+        expected.resize(count as usize, 0);
+    }
+    expected.truncate(count as usize);
+    let mut groups = vec![0; expected.len()];
+    let count = unsafe { libc::getgroups(groups.len() as _, groups.as_mut_ptr()) };
+    assert!(count >= 0, "getgroups failed");
+    groups.truncate(count as usize);
+    expected.sort_unstable();
+    expected.dedup();
+    groups.sort_unstable();
+    groups.dedup();
+    assert_eq!(groups, expected);
+
+    // Root privileges are gone for good
+    assert_ne!(unsafe { libc::setuid(0) }, 0);
+}
+
+#[test]
+#[ignore]
+fn transaction_info() {
+    let _service = Service::create();
+
+    let user = current_user();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .user(&user)
+        .rhost("10.0.0.5")
+        .tty("ssh")
+        .build(SequenceConv::new(user.as_str(), vec![PASSWORD]))
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    let info = client.info().expect("Failed to get the transaction info");
+    assert_eq!(info.service, SERVICE_NAME);
+    assert_eq!(info.requested_user.as_deref(), Some(user.as_str()));
+    assert_eq!(info.user.as_deref(), Some(user.as_str()));
+    assert_eq!(info.ruser, None);
+    assert_eq!(
+        info.to_string(),
+        format!(
+            "service={:?} user={:?} requested_user={:?} rhost=\"10.0.0.5\" tty=\"ssh\" ruser=-",
+            SERVICE_NAME, user, user
+        )
+    );
+    assert!(!format!("{} {:?}", info, info).contains(PASSWORD));
+}
+
+#[test]
+#[ignore]
+fn auto_clear_credentials() {
+    let _service = Service::create();
+
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .auto_clear_credentials(true)
+        .build(PasswordConv::new())
+        .expect("Failed to start transaction");
+    client
+        .conversation_mut()
+        .set_credentials(current_user(), "wrong");
+    assert!(client.authenticate().is_err());
+
+    // The credentials are kept for the retry
+    client
+        .conversation_mut()
+        .set_credentials(current_user(), PASSWORD);
+    client.authenticate().expect("Authentication failed");
+    let prompt = CStr::from_bytes_with_nul(b"Password: \0").unwrap();
+    assert_eq!(
+        client.conversation_mut().prompt_blind(prompt),
+        Err(ConvError)
+    );
+}
+
+#[test]
+#[ignore]
+fn session_environment() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+
+    let environment = client.environment().expect("Failed to get environment");
+    for name in &["USER", "LOGNAME", "HOME", "PWD", "SHELL"] {
+        assert!(
+            environment.contains_key(OsStr::new(name)),
+            "{} is missing from the PAM environment",
+            name
+        );
+    }
+    assert_eq!(
+        environment.get(OsStr::new("HOME")),
+        environment.get(OsStr::new("PWD"))
+    );
+}
+
+#[test]
+#[ignore]
+fn module_environment() {
+    let _service = Service::with_args("putenv=HOME=/pam-home putenv=PAM_RS_MODULE=1");
+
+    // Variables set by the modules take precedence over the defaults
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    let environment = client.environment().expect("Failed to get environment");
+    assert_eq!(
+        environment.get(OsStr::new("HOME")),
+        Some(&OsString::from("/pam-home"))
+    );
+    assert!(environment.contains_key(OsStr::new("USER")));
+
+    // The whole PAM environment is imported into the process, subject to the filter
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .env_allow(vec!["PAM_RS_*"])
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    assert_eq!(env::var("PAM_RS_MODULE").unwrap(), "1");
+    assert_ne!(env::var("HOME").unwrap(), "/pam-home");
+    env::remove_var("PAM_RS_MODULE");
+}
+
+#[test]
+#[ignore]
+fn lifecycle_hooks() {
+    let _service = Service::create();
+
+    let user = current_user();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    let log = events.clone();
+    client.on_auth_result(move |user, code| {
+        log.lock()
+            .unwrap()
+            .push(format!("auth {} {:?}", user, code));
+    });
+    let log = events.clone();
+    client.on_session_opened(move |info| {
+        log.lock().unwrap().push(format!("opened {:?}", info.user));
+        panic!("The hook failed");
+    });
+    let log = events.clone();
+    client.on_session_closed(move |code| {
+        log.lock().unwrap().push(format!("closed {:?}", code));
+    });
+
+    client.conversation_mut().set_credentials(&user, "wrong");
+    assert!(client.authenticate().is_err());
+    client.conversation_mut().set_credentials(&user, PASSWORD);
+    client.authenticate().expect("Authentication failed");
+    // The panic is caught, so the session is open nevertheless
+    client.open_session().expect("Failed to open session");
+    assert!(client.has_open_session());
+    drop(client);
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            format!("auth {} Auth_Err", user),
+            format!("auth {} Success", user),
+            format!("opened {:?}", Some(&user)),
+            "closed Success".to_string(),
+        ]
+    );
+}
+
+#[test]
+#[ignore]
+fn authenticate_with_retries() {
+    let _service = Service::create();
+
+    let user = current_user();
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.conversation_mut().set_credentials(&user, "wrong");
+    let mut failures = Vec::new();
+    client
+        .authenticate_with_retries(3, |conv, attempt, code| {
+            failures.push((attempt, code));
+            if attempt == 2 {
+                conv.set_credentials(current_user(), PASSWORD);
+            }
+            RetryDecision::Retry
+        })
+        .expect("Authentication failed");
+    assert_eq!(
+        failures,
+        vec![(1, PamReturnCode::Auth_Err), (2, PamReturnCode::Auth_Err)]
+    );
+
+    // The last error is returned once all attempts failed
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.conversation_mut().set_credentials(&user, "wrong");
+    let mut attempts = 0;
+    let err = client
+        .authenticate_with_retries(3, |_, attempt, _| {
+            attempts = attempt;
+            RetryDecision::Retry
+        })
+        .unwrap_err();
+    assert_eq!(err.code(), PamReturnCode::Auth_Err);
+    assert_eq!(attempts, 2);
+
+    // The callback can stop early
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.conversation_mut().set_credentials(&user, "wrong");
+    let mut attempts = 0;
+    assert!(client
+        .authenticate_with_retries(3, |_, attempt, _| {
+            attempts = attempt;
+            RetryDecision::Abort
+        })
+        .is_err());
+    assert_eq!(attempts, 1);
+}
+
+#[test]
+#[ignore]
+fn dyn_client() {
+    let _service = Service::create();
+
+    // Clients with different handlers can be stored in the same field
+    struct State {
+        pending: Option<DynClient>,
+    }
+    let user = current_user();
+    let mut state = State { pending: None };
+    state.pending = Some(
+        ClientBuilder::new(SERVICE_NAME)
+            .build_dyn(PasswordConv::new())
+            .expect("Failed to start transaction"),
+    );
+    let client = state.pending.as_mut().unwrap();
+    assert!(client.authenticate().is_err());
+
+    state.pending = Some(
+        ClientBuilder::new(SERVICE_NAME)
+            .build_dyn(SequenceConv::new(user.as_str(), vec![PASSWORD]))
+            .expect("Failed to start transaction"),
+    );
+    let client = state.pending.as_mut().unwrap();
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+
+    // It can be moved to another thread
+    let mut client = state.pending.take().unwrap();
+    thread::spawn(move || client.close_session())
+        .join()
+        .unwrap()
+        .expect("Failed to close session");
+}
+
+#[test]
+#[ignore]
+fn drop_error() {
+    let _service = Service::with_stack(&format!(
+        "auth required {0}\nauth optional pam_permit.so\naccount required pam_permit.so\n\
+         session required pam_permit.so\nsession required {0} panic\n",
+        module_path().display()
+    ));
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let log = errors.clone();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .on_drop_error(move |stage, code| {
+            log.lock().unwrap().push((stage, code));
+            panic!("The hook failed");
+        })
+        .build(password_conv())
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+
+    // The panic of the hook doesn't escape drop
+    drop(client);
+    assert_eq!(
+        *errors.lock().unwrap(),
+        vec![(DropStage::CloseSession, PamReturnCode::Service_Err)]
+    );
+}
+
+#[test]
+#[ignore]
+fn delete_credentials() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    // Nothing to delete yet
+    client
+        .delete_credentials()
+        .expect("Failed to delete credentials");
+    assert_eq!(client.status().setcred, None);
+
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    client
+        .delete_credentials()
+        .expect("Failed to delete credentials");
+    let status = client.status();
+    assert_eq!(status.setcred, Some(PamReturnCode::Success));
+    assert!(!status.has_credentials);
+    assert!(status.has_open_session);
+
+    // The session is still closed as usual
+    client.close_session().expect("Failed to close session");
+    assert!(!client.has_open_session());
+}
+
+#[test]
+#[ignore]
+fn open_session_unauthenticated() {
+    let _service = Service::with_stack(&format!(
+        "auth required {0} deny\naccount required {0} expired\n\
+         session required pam_permit.so\n",
+        module_path().display()
+    ));
+
+    // The auth stack is skipped entirely
+    let user = current_user();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .credential_sequence(CredentialSequence::None)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(NullConv::new())
+        .expect("Failed to start transaction");
+    client
+        .open_session_unauthenticated(&user, false)
+        .expect("Failed to open session");
+    assert!(client.has_open_session());
+    assert_eq!(client.authenticated_user().unwrap(), user);
+    assert_eq!(client.status().authenticate, None);
+    client.close_session().expect("Failed to close session");
+
+    // The account is checked if requested
+    let mut client = Client::with_conversation(SERVICE_NAME, NullConv::new())
+        .expect("Failed to start transaction");
+    assert_eq!(
+        client
+            .open_session_unauthenticated(&user, true)
+            .unwrap_err()
+            .code(),
+        PamReturnCode::New_Authtok_Reqd
+    );
+    assert!(!client.is_authenticated());
+    assert!(!client.has_open_session());
+}
+
+#[test]
+#[ignore]
+fn one_shot_check() {
+    let _service = Service::create();
+
+    let user = current_user();
+    let home = env::var_os("HOME");
+    pam::check(SERVICE_NAME, &user, PASSWORD).expect("Authentication failed");
+    assert_eq!(
+        pam::check(SERVICE_NAME, &user, "wrong").unwrap_err().code(),
+        PamReturnCode::Auth_Err
+    );
+    assert_eq!(env::var_os("HOME"), home);
+    assert_eq!(
+        pam::check_account(SERVICE_NAME, &user).unwrap(),
+        AccountStatus::Valid
+    );
+}
+
+#[test]
+#[ignore]
+fn call_timeout() {
+    let _service = Service::with_args("sleep_ms=500");
+
+    // The conversation runs on the helper thread
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.set_call_timeout(Duration::from_secs(10));
+    client.authenticate().expect("Authentication failed");
+    assert!(!client.has_timed_out());
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.set_call_timeout(Duration::from_millis(50));
+    let start = Instant::now();
+    assert_eq!(client.authenticate().unwrap_err(), PamError::Timeout);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert!(client.has_timed_out());
+
+    let last = client.last_error().expect("The timeout was not recorded");
+    assert_eq!(last.operation, PamOperation::Authenticate);
+    assert_eq!(last.error, PamError::Timeout);
+    assert_eq!(last.code, PamReturnCode::Abort);
+    assert_eq!(last.message, "PAM call timed out");
+
+    // The transaction can't be used anymore, but the handle is not touched
+    assert_eq!(client.authenticate().unwrap_err(), PamError::Timeout);
+    assert_eq!(client.info().unwrap_err(), PamError::Timeout);
+    assert_eq!(client.tty().unwrap_err(), PamError::Timeout);
+    assert_eq!(
+        client.check_account(&current_user()).unwrap_err(),
+        PamError::Timeout
+    );
+    assert_eq!(client.set_user("nobody").unwrap_err(), PamError::Timeout);
+    assert_eq!(
+        client.replace_conversation(NullConv::new()).err(),
+        Some(PamError::Timeout)
+    );
+    // Let the abandoned call finish before the service is removed
+    thread::sleep(Duration::from_secs(1));
+}
+
+#[test]
+#[ignore]
+fn client_set_credentials() {
+    let _service = Service::create();
+
+    let mut client = Client::with_password(SERVICE_NAME).expect("Failed to start transaction");
+    client.set_credentials(&current_user(), PASSWORD);
+    client.authenticate().expect("Authentication failed");
+
+    let conv = SequenceConv::new("", Vec::<String>::new());
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    client.set_credentials(&current_user(), PASSWORD);
+    client.authenticate().expect("Authentication failed");
+}
+
+#[test]
+#[ignore]
+fn account_error() {
+    let _service = Service::with_stack(&format!(
+        "auth required {0}\naccount required {0} expired\n",
+        module_path().display()
+    ));
+
+    let conv = password_conv();
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    assert_eq!(client.account_error(), None);
+    let err = client.authenticate().unwrap_err();
+    assert_eq!(client.account_error(), Some(AccountError::PasswordExpired));
+    assert_eq!(
+        PamReturnCode::from(AccountError::PasswordExpired),
+        err.code()
+    );
+
+    // A failed authentication doesn't get to the account
+    let conv = SequenceConv::new(current_user(), vec!["wrong"]);
+    let mut client =
+        Client::with_conversation(SERVICE_NAME, conv).expect("Failed to start transaction");
+    assert!(client.authenticate().is_err());
+    assert_eq!(client.account_error(), None);
+}
+
+#[test]
+#[ignore]
+fn pam_env_passthrough() {
+    let _service = Service::create();
+
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .environment_policy(EnvironmentPolicy::PamOnly)
+        .build(conv)
+        .expect("Failed to start transaction");
+    assert_eq!(client.pam_getenv("PAM_RS_CALLER").unwrap(), None);
+    client.pam_putenv("PAM_RS_CALLER", "1").unwrap();
+    client.pam_putenv("HOME", "/caller-home").unwrap();
+    client.pam_remove_env("SHELL").unwrap();
+    assert_eq!(
+        client.pam_putenv("A=B", "C").unwrap_err().code(),
+        PamReturnCode::Bad_Item
+    );
+    assert_eq!(
+        client.pam_getenv("PAM_RS_CALLER").unwrap().as_deref(),
+        Some("1")
+    );
+
+    // Opening the session keeps the variables set by the caller
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    assert_eq!(
+        client.pam_getenv("HOME").unwrap().as_deref(),
+        Some("/caller-home")
+    );
+    assert_eq!(client.pam_getenv("SHELL").unwrap(), None);
+    assert!(client.pam_getenv("USER").unwrap().is_some());
+    client.pam_remove_env("PAM_RS_CALLER").unwrap();
+    assert!(!client
+        .environment()
+        .unwrap()
+        .contains_key(OsStr::new("PAM_RS_CALLER")));
+}
+
+#[test]
+#[ignore]
+fn into_raw() {
+    let dir = env::temp_dir().join(format!("pam-rs-client-{}", process::id()));
+    let status = dir.join("end_status");
+    let closed = dir.join("closed");
+    let _service = Service::with_stack(&format!(
+        "auth required {0} end_status={1}\naccount required pam_permit.so\n\
+         session required pam_permit.so\nsession required {0} closed={2}\n",
+        module_path().display(),
+        status.display(),
+        closed.display()
+    ));
+
+    let conv = password_conv();
+    let mut client = ClientBuilder::new(SERVICE_NAME)
+        .environment_policy(EnvironmentPolicy::None)
+        .build(conv)
+        .expect("Failed to start transaction");
+    client.authenticate().expect("Authentication failed");
+    client.open_session().expect("Failed to open session");
+    let handle = client.into_raw();
+    assert!(!handle.is_null());
+
+    // Neither pam_close_session nor pam_end were called
+    assert!(!closed.exists());
+    assert!(!status.exists());
+
+    // The handle is still usable, so the caller can clean up
+    let handle = unsafe { &mut *handle };
+    assert_eq!(pam::close_session(handle, false), PamReturnCode::Success);
+    assert!(closed.exists());
+    assert_eq!(
+        pam::end(handle, PamReturnCode::Success),
+        PamReturnCode::Success
+    );
+    assert_eq!(fs::read_to_string(&status).unwrap(), "Success");
+}
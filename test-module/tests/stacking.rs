@@ -0,0 +1,88 @@
+//! Run the test module via libpam and pam_wrapper
+//!
+//! These tests are ignored by default as they need pam_wrapper, run them with
+//! `LD_PRELOAD=libpam_wrapper.so cargo test -- --ignored --test-threads=1`.
+
+use std::env;
+use std::path::PathBuf;
+
+use pam::test_util::ModuleTestHarness;
+use pam::{PamItemType, PamMessageStyle, PamReturnCode};
+use pam_test_module::PASSWORD;
+
+fn module_path() -> PathBuf {
+    // Cargo places the cdylib in the `deps` directory next to this test
+    let mut path = env::current_exe().unwrap();
+    path.set_file_name("libpam_test_module.so");
+    path
+}
+
+#[test]
+#[ignore]
+fn stacked_module_reuses_authtok() {
+    let result = ModuleTestHarness::new(module_path())
+        .with_item(PamItemType::User, "alice")
+        .with_password(PASSWORD)
+        .with_stacked_module(module_path(), &["use_first_pass"])
+        .run_authenticate()
+        .expect("Failed to run modules");
+
+    assert_eq!(result.code, PamReturnCode::Success);
+    // Only the first module prompted, the second one got the stored PAM_AUTHTOK
+    let prompts: Vec<_> = result
+        .transcript
+        .iter()
+        .filter(|(style, _)| *style == PamMessageStyle::Prompt_Echo_Off)
+        .collect();
+    assert_eq!(prompts.len(), 1);
+}
+
+#[test]
+#[ignore]
+fn responses_are_freed_by_libpam() {
+    // libpam asks for the user itself and frees the response with free(3), the module
+    // frees the response to the password prompt. Run under valgrind or with ASAN to
+    // check the responses come from the libc allocator.
+    let result = ModuleTestHarness::new(module_path())
+        .with_login("alice")
+        .with_password(PASSWORD)
+        .run_authenticate()
+        .expect("Failed to run module");
+
+    assert_eq!(result.code, PamReturnCode::Success);
+    let styles: Vec<_> = result.transcript.iter().map(|(style, _)| *style).collect();
+    assert_eq!(
+        styles,
+        vec![
+            PamMessageStyle::Prompt_Echo_On,
+            PamMessageStyle::Prompt_Echo_Off
+        ]
+    );
+}
+
+#[test]
+#[ignore]
+fn use_first_pass_without_authtok() {
+    let result = ModuleTestHarness::new(module_path())
+        .with_item(PamItemType::User, "alice")
+        .with_password(PASSWORD)
+        .with_args(&["use_first_pass"])
+        .run_authenticate()
+        .expect("Failed to run module");
+
+    assert_eq!(result.code, PamReturnCode::Auth_Err);
+    assert!(result.transcript.is_empty());
+}
+
+#[test]
+#[ignore]
+fn panic_is_reported_as_service_err() {
+    let result = ModuleTestHarness::new(module_path())
+        .with_item(PamItemType::User, "alice")
+        .with_args(&["panic"])
+        .run_close_session()
+        .expect("Failed to run module");
+
+    // The panic did not unwind into libpam and this process
+    assert_eq!(result.code, PamReturnCode::Service_Err);
+}
@@ -0,0 +1,58 @@
+//! Check that `PasswordConv` wipes the credentials before freeing them
+//!
+//! The global allocator inspects every freed block for the secret.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use pam::PasswordConv;
+
+const SECRET: &[u8] = b"correct horse battery staple";
+
+static SECRET_FREED: AtomicBool = AtomicBool::new(false);
+
+struct CheckingAlloc;
+
+unsafe impl GlobalAlloc for CheckingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let block = slice::from_raw_parts(ptr, layout.size());
+        if block.windows(SECRET.len()).any(|window| window == SECRET) {
+            SECRET_FREED.store(true, Ordering::SeqCst);
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CheckingAlloc = CheckingAlloc;
+
+fn secret() -> String {
+    String::from_utf8(SECRET.to_vec()).unwrap()
+}
+
+#[test]
+fn password_conv_wipes_credentials() {
+    // Make sure the allocator notices unwiped secrets
+    drop(secret());
+    assert!(SECRET_FREED.swap(false, Ordering::SeqCst));
+
+    let mut conv = PasswordConv::new();
+    conv.set_credentials("alice", secret());
+    conv.set_credentials("alice", "other");
+    assert!(
+        !SECRET_FREED.load(Ordering::SeqCst),
+        "replaced password was not wiped"
+    );
+
+    conv.set_credentials(secret(), secret());
+    drop(conv);
+    assert!(
+        !SECRET_FREED.load(Ordering::SeqCst),
+        "dropped credentials were not wiped"
+    );
+}